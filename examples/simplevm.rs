@@ -1,15 +1,15 @@
 extern crate virtualization_rs;
 
-use block::{Block, ConcreteBlock};
 use libc::sleep;
-use objc::rc::StrongPtr;
 use std::fs::canonicalize;
-use std::sync::{Arc, RwLock};
 use virtualization_rs::{
-    base::{dispatch_async, dispatch_queue_create, Id, NSError, NSFileHandle, NIL},
+    base::NSFileHandle,
+    dispatch::{DispatchQueue, Serial},
     virtualization::{
         boot_loader::VZLinuxBootLoaderBuilder,
         entropy_device::VZVirtioEntropyDeviceConfiguration,
+        future::block_on,
+        handle::VirtualMachineHandle,
         memory_device::VZVirtioTraditionalMemoryBalloonDeviceConfiguration,
         network_device::{
             VZMACAddress, VZNATNetworkDeviceAttachment, VZVirtioNetworkDeviceConfiguration,
@@ -106,7 +106,7 @@ fn main() {
         {
             Ok(x) => x,
             Err(err) => {
-                err.dump();
+                println!("{}", err);
                 return;
             }
         };
@@ -133,29 +133,11 @@ fn main() {
     match conf.validate_with_error() {
         Ok(_) => {
             println!("Validated");
-            let label = std::ffi::CString::new("second").unwrap();
-            let queue = unsafe { dispatch_queue_create(label.as_ptr(), NIL) };
-            let vm = Arc::new(RwLock::new(VZVirtualMachine::new(conf, queue)));
-            let dispatch_block = ConcreteBlock::new(move || {
-                let completion_handler = ConcreteBlock::new(|err: Id| {
-                    println!("Completion handler completed..");
-                    if err != NIL {
-                        let error = unsafe { NSError(StrongPtr::retain(err)) };
-                        error.dump();
-                    }
-                });
-                let completion_handler = completion_handler.copy();
-                let completion_handler: &Block<(Id,), ()> = &completion_handler;
-                vm.write()
-                    .unwrap()
-                    .start_with_completion_handler(completion_handler);
-            });
-            let dispatch_block = dispatch_block.copy();
-            let dispatch_block: &Block<(), ()> = &dispatch_block;
-            unsafe {
-                println!("dispatching..");
-                dispatch_async(queue, dispatch_block);
-                println!("dispatched");
+            let vm = VirtualMachineHandle::with_configuration(conf, DispatchQueue::new("second", Serial));
+            println!("starting..");
+            match block_on(vm.start()) {
+                Ok(()) => println!("Completion handler completed.."),
+                Err(e) => println!("{}", e),
             }
             loop {
                 unsafe {
@@ -165,8 +147,7 @@ fn main() {
             }
         }
         Err(e) => {
-            e.dump();
-            return;
+            println!("{}", e);
         }
     }
 }
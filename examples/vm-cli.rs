@@ -1,13 +1,30 @@
+#[cfg(target_os = "macos")]
 use block::{Block, ConcreteBlock};
+#[cfg(target_os = "macos")]
 use std::sync::mpsc::channel;
+#[cfg(target_os = "macos")]
 use libc::sleep;
+#[cfg(target_os = "macos")]
 use objc::rc::StrongPtr;
+#[cfg(target_os = "macos")]
 use objc::{class, msg_send, sel, sel_impl};
+#[cfg(target_os = "macos")]
 use std::fs::canonicalize;
+#[cfg(target_os = "macos")]
 use std::sync::{Arc, RwLock};
-use virtualization_rs::virtualization::image_installer::install_macos_image;
+#[cfg(target_os = "macos")]
+use virtualization_rs::bundle::{VmBundle, MANIFEST_FILE};
+#[cfg(target_os = "macos")]
+use virtualization_rs::catalog::RestoreImageCatalog;
+#[cfg(target_os = "macos")]
+use virtualization_rs::disk::raw::RawDiskImageBuilder;
+#[cfg(target_os = "macos")]
+use virtualization_rs::spec::{BootLoaderSpec, VmSpec};
+#[cfg(target_os = "macos")]
+use virtualization_rs::virtualization::image_installer::{install_macos_image_version, InstallProgress};
+#[cfg(target_os = "macos")]
 use virtualization_rs::{
-    base::{dispatch_async, dispatch_queue_create, Id, NSError, NSFileHandle, NSURL, NIL},
+    base::{Id, NSError, NSFileHandle, NSURL, NIL},
     virtualization::{
         entropy_device::VZVirtioEntropyDeviceConfiguration,
         graphics_device::VZMacGraphicsDeviceConfiguration,
@@ -27,16 +44,22 @@ use virtualization_rs::{
     },
 };
 
+#[cfg(target_os = "macos")]
 use cocoa::base::{selector, nil, NO};
+#[cfg(target_os = "macos")]
 use cocoa::foundation::{NSRect, NSPoint, NSSize, NSAutoreleasePool, NSProcessInfo,
                         NSString as CocoaNSString};
+#[cfg(target_os = "macos")]
 use cocoa::appkit::{NSApp, NSApplication, NSApplicationActivationPolicyRegular, NSWindow,
                     NSBackingStoreBuffered, NSMenu, NSMenuItem, NSWindowStyleMask,
                     NSRunningApplication, NSApplicationActivateIgnoringOtherApps};
 
+#[cfg(target_os = "macos")]
 use std::path::PathBuf;
+#[cfg(target_os = "macos")]
 use structopt::StructOpt;
 
+#[cfg(target_os = "macos")]
 #[derive(StructOpt, Debug)]
 #[structopt(name = "simplevm")]
 struct Opt {
@@ -56,18 +79,32 @@ struct Opt {
 
     #[structopt(short, long, default_value = "2147483648")]
     memory_size: usize,
-}
 
-// TODO: Turn into argument
-const IMAGE_LOCATION: &str = "./macos_image.ipsw";
-const AUXILIARY_STORAGE_URL: &str = "./auxiliary_storage";
-const HARDWARE_MODEL_STORAGE_URL: &str = "./hardware_model";
-const MACHINE_IDENTIFIER_STORAGE_URL: &str = "./machine_identifier";
+    /// directory of downloaded restore images
+    #[structopt(long, parse(from_os_str), default_value = "./restore_images")]
+    image_cache: PathBuf,
+
+    /// macOS version or build to install, the latest supported when omitted
+    #[structopt(long)]
+    macos_version: Option<String>,
+
+    /// bundle holding the platform files and disks of the virtual machine, created when missing
+    #[structopt(long, parse(from_os_str), default_value = "./macos.vmbundle")]
+    bundle: PathBuf,
 
+    /// size in GiB of the disk created in a bundle without disks
+    #[structopt(long, default_value = "64")]
+    disk_size: u64,
+}
+
+#[cfg(target_os = "macos")]
 const PIXEL_WIDTH: i32 = 1920;
+#[cfg(target_os = "macos")]
 const PIXEL_HEIGHT: i32 = 1200;
+#[cfg(target_os = "macos")]
 const PIXEL_PER_INCH: i32 = 80;
 
+#[cfg(target_os = "macos")]
 fn create_app_and_view() -> (Id, Id) {
     unsafe {
         let _pool = NSAutoreleasePool::new(nil);
@@ -113,6 +150,7 @@ fn create_app_and_view() -> (Id, Id) {
     }
 }
 
+#[cfg(target_os = "macos")]
 fn attach_vm_view_to_window(app: Id, window: Id, vm_view: Id) {
     let content_view = unsafe { window.contentView() };
     let title = unsafe {CocoaNSString::alloc(nil).init_str("My window")};
@@ -121,6 +159,38 @@ fn attach_vm_view_to_window(app: Id, window: Id, vm_view: Id) {
     unsafe { app.run() };
 }
 
+/// progress callback printing every step and every whole percent of the download and installation
+#[cfg(target_os = "macos")]
+fn print_progress() -> impl FnMut(InstallProgress) {
+    let mut reported = None;
+    move |progress| match progress {
+        InstallProgress::DownloadStarted { url } => println!("Downloading {}", url),
+        InstallProgress::Downloading(progress) => {
+            if let Some(fraction) = progress.fraction() {
+                let percent = (fraction * 100.0) as u32;
+                if reported != Some(percent) {
+                    reported = Some(percent);
+                    println!("Downloaded {}%", percent);
+                }
+            }
+        }
+        InstallProgress::DownloadFinished => println!("Image download complete"),
+        InstallProgress::DownloadSkipped => println!("Skipping download because file already exists"),
+        InstallProgress::InstallStarted { product_version, build_version } => {
+            reported = None;
+            println!("Installing macOS {} ({})", product_version, build_version)
+        }
+        InstallProgress::Installing { fraction } => {
+            let percent = (fraction * 100.0) as u32;
+            if reported != Some(percent) {
+                reported = Some(percent);
+                println!("Installed {}%", percent);
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "macos")]
 fn main() {
     let (app, window) = create_app_and_view();
     // Start VM with given options
@@ -130,7 +200,7 @@ fn main() {
     let memory_size = opt.memory_size;
     let command_line = opt.command_line;
     //let kernel = opt.kernel;
-    let disks: Vec<PathBuf> = opt.disk;
+    let mut disks: Vec<PathBuf> = opt.disk;
     //let initrd = opt.initrd;
 
     if !VZVirtualMachine::supported() {
@@ -138,8 +208,64 @@ fn main() {
         return;
     }
 
+    let bundle = if opt.bundle.join(MANIFEST_FILE).exists() {
+        VmBundle::open(&opt.bundle)
+    } else {
+        VmBundle::create(&opt.bundle, "macOS", VmSpec {
+            cpu_count,
+            memory_size: memory_size as u64,
+            entropy: true,
+            memory_balloon: true,
+            boot_loader: Some(BootLoaderSpec::MacOS),
+            platform: None,
+            storage: Vec::new(),
+            network: Vec::new(),
+            serial_ports: Vec::new(),
+            graphics: Vec::new(),
+        })
+    };
+    let mut bundle = match bundle {
+        Ok(bundle) => bundle,
+        Err(err) => {
+            println!("{}", err);
+            return;
+        }
+    };
+    if bundle.manifest().spec.storage.is_empty() {
+        let created = RawDiskImageBuilder::new()
+            .path(bundle.disk_path("root.img"))
+            .size(opt.disk_size * 1024 * 1024 * 1024)
+            .build()
+            .and_then(|_| bundle.add_disk("root.img", false));
+        if let Err(err) = created {
+            println!("{}", err);
+            return;
+        }
+    }
+    disks.extend(bundle.spec().storage.into_iter().map(|storage| PathBuf::from(storage.path)));
+    let _lock = match bundle.lock() {
+        Ok(lock) => lock,
+        Err(err) => {
+            println!("{}", err);
+            return;
+        }
+    };
+    let auxiliary_storage_path = bundle.auxiliary_storage_path();
+    let hardware_model_path = bundle.hardware_model_path();
+    let machine_identifier_path = bundle.machine_identifier_path();
+
     // TODO: If we need to install macos then install it
-    let vm = install_macos_image(IMAGE_LOCATION, cpu_count, memory_size, disks, PIXEL_HEIGHT, PIXEL_WIDTH, PIXEL_PER_INCH, AUXILIARY_STORAGE_URL, HARDWARE_MODEL_STORAGE_URL, MACHINE_IDENTIFIER_STORAGE_URL);
+    let mut catalog = match RestoreImageCatalog::open(&opt.image_cache) {
+        Ok(catalog) => catalog,
+        Err(err) => {
+            println!("{}", err);
+            return;
+        }
+    };
+    if let Err(err) = install_macos_image_version(&mut catalog, opt.macos_version.as_deref(), cpu_count, memory_size, disks, PIXEL_HEIGHT, PIXEL_WIDTH, PIXEL_PER_INCH, &auxiliary_storage_path.to_string_lossy(), &hardware_model_path.to_string_lossy(), &machine_identifier_path.to_string_lossy(), print_progress()) {
+        println!("{}", err);
+        return;
+    }
 
     //let vm_view: Id = unsafe { msg_send![class!(VZVirtualMachineView), new] };
     //let _: () = unsafe { msg_send![vm_view, setVirtualMachine:*vm.0] };
@@ -150,7 +276,7 @@ fn main() {
     //let dispatch_block = ConcreteBlock::new(move || {
     //    let mut vm = vm.write().unwrap();
     //    // Install macOS
-    //    let restore_image_url = NSURL::file_url_with_path(IMAGE_LOCATION, false);
+    //    let restore_image_url = NSURL::file_url_with_path(image_location, false);
     //    let macos_installer: Id = unsafe { msg_send![class!(VZMacOSInstaller), alloc] };
     //    let macos_installer: Id = unsafe { msg_send![macos_installer, initWithVirtualMachine:*vm.0 restoreImageURL:*restore_image_url.0] };
 
@@ -169,7 +295,7 @@ fn main() {
     //    let install_macos_block = install_macos_block.copy();
     //    let install_macos_block: &Block<(Id,), ()> = &install_macos_block;
 
-    //    println!("Installing while the VM is in the {:?} state", unsafe { vm.state() });
+    //    println!("Installing while the VM is in the {:?} state", vm.state());
     //    let _: Id = unsafe { msg_send![macos_installer, installWithCompletionHandler:install_macos_block] };
     //    let progress: Id = unsafe {msg_send![macos_installer, progress]};
     //    s.send(progress).unwrap();
@@ -209,3 +335,8 @@ fn main() {
         }
     }
 }
+
+#[cfg(not(target_os = "macos"))]
+fn main() {
+    println!("not supported");
+}
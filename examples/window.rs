@@ -1,12 +1,17 @@
-  extern crate cocoa;
+#[cfg(target_os = "macos")]
+extern crate cocoa;
 
+#[cfg(target_os = "macos")]
 use cocoa::base::{selector, nil, NO};
+#[cfg(target_os = "macos")]
 use cocoa::foundation::{NSRect, NSPoint, NSSize, NSAutoreleasePool, NSProcessInfo,
                         NSString};
+#[cfg(target_os = "macos")]
 use cocoa::appkit::{NSApp, NSApplication, NSApplicationActivationPolicyRegular, NSWindow,
                     NSBackingStoreBuffered, NSMenu, NSMenuItem, NSWindowStyleMask,
                     NSRunningApplication, NSApplicationActivateIgnoringOtherApps};
 
+#[cfg(target_os = "macos")]
 fn main() {
     unsafe {
         let _pool = NSAutoreleasePool::new(nil);
@@ -50,4 +55,9 @@ fn main() {
         current_app.activateWithOptions_(NSApplicationActivateIgnoringOtherApps);
         app.run();
     }
-} 
+}
+
+#[cfg(not(target_os = "macos"))]
+fn main() {
+    println!("not supported");
+}
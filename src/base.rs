@@ -4,27 +4,84 @@ use std::marker::PhantomData;
 use std::slice;
 use std::str;
 
-use block::Block;
-use objc::rc::StrongPtr;
-use objc::runtime::{Object, BOOL, NO, YES};
-use objc::{class, msg_send, sel, sel_impl};
+use crate::sys::Block;
+use crate::sys::StrongPtr;
+use crate::sys::{Object, BOOL, NO, YES};
+use crate::sys::{class, msg_send, sel, sel_impl};
 
+#[cfg(target_os = "macos")]
 #[link(name = "Virtualization", kind = "framework")]
 extern "C" {}
 
-//#[link(name = "Dispatch", kind = "framework")]
-//extern "C" {
-//    pub fn dispatch_get_main_queue() -> Id;
-//    pub fn dispatch_main() -> Id;
-//}
-
-#[link(name = "Foundation", kind = "framework")]
-extern "C" {
-    pub fn dispatch_queue_create(label: *const libc::c_char, attr: Id) -> Id;
-    pub fn dispatch_sync(queue: Id, block: &Block<(), ()>);
-    pub fn dispatch_async(queue: Id, block: &Block<(), ()>);
+#[cfg(target_os = "macos")]
+mod ffi {
+    use super::Id;
+    use crate::sys::Block;
+
+    #[link(name = "System", kind = "dylib")]
+    extern "C" {
+        pub fn dispatch_queue_create(label: *const libc::c_char, attr: Id) -> Id;
+        pub fn dispatch_sync(queue: Id, block: &Block<(), ()>);
+        pub fn dispatch_async(queue: Id, block: &Block<(), ()>);
+    }
+}
+
+/// create a dispatch queue
+///
+/// # Safety
+/// `label` must be null or a NUL-terminated string, `attr` null or a queue attribute.
+#[cfg(target_os = "macos")]
+#[deprecated(note = "use `dispatch::DispatchQueue::new`")]
+pub unsafe fn dispatch_queue_create(label: *const libc::c_char, attr: Id) -> Id {
+    ffi::dispatch_queue_create(label, attr)
+}
+
+/// run `block` on `queue` and wait for it
+///
+/// # Safety
+/// `queue` must be a dispatch queue.
+#[cfg(target_os = "macos")]
+#[deprecated(note = "use `dispatch::Queue::exec_sync`")]
+pub unsafe fn dispatch_sync(queue: Id, block: &Block<(), ()>) {
+    ffi::dispatch_sync(queue, block)
+}
+
+/// run `block` on `queue` without waiting
+///
+/// # Safety
+/// `queue` must be a dispatch queue, `block` must have been copied to the heap.
+#[cfg(target_os = "macos")]
+#[deprecated(note = "use `dispatch::Queue::exec_async`")]
+pub unsafe fn dispatch_async(queue: Id, block: &Block<(), ()>) {
+    ffi::dispatch_async(queue, block)
+}
+
+/// stub of `dispatch_queue_create`, returns `NIL`
+///
+/// # Safety
+/// Has the same signature as the macOS binding; the stub never dereferences its arguments.
+#[cfg(not(target_os = "macos"))]
+#[deprecated(note = "use `dispatch::DispatchQueue::new`")]
+pub unsafe fn dispatch_queue_create(_label: *const libc::c_char, _attr: Id) -> Id {
+    NIL
 }
 
+/// stub of `dispatch_sync`, the block is not invoked
+///
+/// # Safety
+/// Has the same signature as the macOS binding; the stub never dereferences its arguments.
+#[cfg(not(target_os = "macos"))]
+#[deprecated(note = "use `dispatch::Queue::exec_sync`")]
+pub unsafe fn dispatch_sync(_queue: Id, _block: &Block<(), ()>) {}
+
+/// stub of `dispatch_async`, the block is not invoked
+///
+/// # Safety
+/// Has the same signature as the macOS binding; the stub never dereferences its arguments.
+#[cfg(not(target_os = "macos"))]
+#[deprecated(note = "use `dispatch::Queue::exec_async`")]
+pub unsafe fn dispatch_async(_queue: Id, _block: &Block<(), ()>) {}
+
 pub type Id = *mut Object;
 pub const NIL: Id = 0 as Id;
 
@@ -40,7 +97,7 @@ impl<T> NSArray<T> {
                 msg_send![class!(NSArray), arrayWithObjects:objects.as_slice().as_ptr() count:objects.len()],
             );
             NSArray {
-                p: p,
+                p,
                 _phantom: PhantomData,
             }
         }
@@ -76,12 +133,19 @@ impl NSString {
         unsafe { msg_send![*self.0, lengthOfBytesUsingEncoding: UTF8_ENCODING] }
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
     pub fn as_str(&self) -> &str {
         unsafe {
             let bytes = {
                 let bytes: *const libc::c_char = msg_send![*self.0, UTF8String];
                 bytes as *const u8
             };
+            if bytes.is_null() {
+                return "";
+            }
             let len = self.len();
             let bytes = slice::from_raw_parts(bytes, len);
             str::from_utf8(bytes).unwrap()
@@ -158,6 +222,12 @@ impl NSFileHandle {
     }
 }
 
+impl Default for NSFileHandle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 pub struct NSDictionary(pub StrongPtr);
 
 impl NSDictionary {
@@ -194,6 +264,10 @@ impl NSError {
         unsafe { msg_send![*self.0, code] }
     }
 
+    pub fn domain(&self) -> NSString {
+        unsafe { NSString(StrongPtr::retain(msg_send![*self.0, domain])) }
+    }
+
     pub fn localized_description(&self) -> NSString {
         unsafe { NSString(StrongPtr::retain(msg_send![*self.0, localizedDescription])) }
     }
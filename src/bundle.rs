@@ -0,0 +1,465 @@
+//! virtual machine bundle module
+//!
+//! A [`VmBundle`] is a directory holding everything a virtual machine needs: its [`VmSpec`],
+//! disk images and platform identity. Paths in the manifest are relative to the bundle, so it can
+//! be moved or copied as a whole.
+//!
+//! ```text
+//! example.vmbundle/
+//!     bundle.toml            manifest
+//!     disks/                 disk images
+//!     auxiliary_storage      VZMacAuxiliaryStorage
+//!     hardware_model         VZMacHardwareModel data representation
+//!     machine_identifier     VZMacMachineIdentifier data representation
+//!     nvram                  EFI variable store, see VmBundle::use_efi_boot_loader
+//!     snapshots/             see the snapshot module
+//!     .lock                  locked while the bundle is in use
+//! ```
+
+use crate::{
+    catalog::move_file,
+    error::{Error, Result},
+    lock::{self, FileLock, LockMode},
+    snapshot::{self, Snapshot},
+    spec::{BootLoaderSpec, MacPlatformSpec, StorageSpec, VmSpec},
+    validation::{self, Diagnostic, ValidationLimits},
+    virtualization::{
+        image_installer::VZMacOsConfigurationRequirements,
+        mac_platform_configuration::VZMacPlatformConfiguration,
+        virtual_machine::VZVirtualMachineConfiguration,
+    },
+};
+
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// version of the manifest format written by this crate
+pub const FORMAT_VERSION: u32 = 1;
+
+pub const MANIFEST_FILE: &str = "bundle.toml";
+pub const DISKS_DIR: &str = "disks";
+pub const AUXILIARY_STORAGE_FILE: &str = "auxiliary_storage";
+pub const HARDWARE_MODEL_FILE: &str = "hardware_model";
+pub const MACHINE_IDENTIFIER_FILE: &str = "machine_identifier";
+pub const NVRAM_FILE: &str = "nvram";
+pub const LOCK_FILE: &str = lock::DIRECTORY_LOCK_FILE;
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+/// manifest of a bundle, stored in `bundle.toml`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BundleManifest {
+    pub format_version: u32,
+    pub name: String,
+    /// seconds since the Unix epoch
+    pub created: u64,
+    /// specification of the virtual machine, relative paths are relative to the bundle
+    pub spec: VmSpec,
+}
+
+/// virtual machine bundle directory
+///
+/// # Examples
+/// ```rust
+/// use virtualization_rs::bundle::VmBundle;
+/// use virtualization_rs::spec::VmSpec;
+/// use virtualization_rs::validation::{has_errors, ValidationLimits};
+///
+/// let root = std::env::temp_dir().join(format!("example-{}.vmbundle", std::process::id()));
+/// let spec = VmSpec::from_toml("cpu_count = 2\nmemory_size = 2147483648\n\n[boot_loader]\ntype = \"macos\"\n").unwrap();
+/// let mut bundle = VmBundle::create(&root, "example", spec).unwrap();
+/// let disk = bundle.add_disk("root.img", false).unwrap();
+/// std::fs::write(&disk, b"").unwrap();
+///
+/// let bundle = VmBundle::open(&root).unwrap();
+/// assert_eq!(bundle.manifest().spec.storage[0].path, "disks/root.img");
+/// assert_eq!(bundle.spec().storage[0].path, disk.to_str().unwrap());
+/// // the platform files are missing
+/// assert!(has_errors(&bundle.validate(&ValidationLimits::default())));
+/// # std::fs::remove_dir_all(&root).unwrap();
+/// ```
+pub struct VmBundle {
+    root: PathBuf,
+    manifest: BundleManifest,
+}
+
+impl VmBundle {
+    /// create the bundle `name` in `root`, which must not exist or be empty
+    pub fn create<P: AsRef<Path>>(root: P, name: &str, spec: VmSpec) -> Result<VmBundle> {
+        let root = root.as_ref().to_path_buf();
+        if root.exists() && fs::read_dir(&root)?.next().is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already exists and is not empty", root.display()),
+            )
+            .into());
+        }
+        fs::create_dir_all(root.join(DISKS_DIR))?;
+        let bundle = VmBundle {
+            root,
+            manifest: BundleManifest {
+                format_version: FORMAT_VERSION,
+                name: name.to_string(),
+                created: now(),
+                spec,
+            },
+        };
+        bundle.save()?;
+        Ok(bundle)
+    }
+
+    /// open the bundle in `root`
+    pub fn open<P: AsRef<Path>>(root: P) -> Result<VmBundle> {
+        let root = root.as_ref().to_path_buf();
+        let manifest: BundleManifest =
+            toml::from_str(&fs::read_to_string(root.join(MANIFEST_FILE))?)?;
+        if manifest.format_version > FORMAT_VERSION {
+            return Err(Error::Parse(format!(
+                "{}: bundle format version {} is newer than the supported version {}",
+                root.display(),
+                manifest.format_version,
+                FORMAT_VERSION
+            )));
+        }
+        Ok(VmBundle { root, manifest })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn name(&self) -> &str {
+        &self.manifest.name
+    }
+
+    pub fn manifest(&self) -> &BundleManifest {
+        &self.manifest
+    }
+
+    /// manifest to modify, call [`save`](VmBundle::save) afterwards
+    pub fn manifest_mut(&mut self) -> &mut BundleManifest {
+        &mut self.manifest
+    }
+
+    /// write the manifest atomically
+    pub fn save(&self) -> Result<()> {
+        let path = self.root.join(MANIFEST_FILE);
+        let tmp_path = self.root.join(format!("{}.tmp", MANIFEST_FILE));
+        fs::write(&tmp_path, toml::to_string_pretty(&self.manifest)?)?;
+        fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+
+    /// `path` of the manifest resolved against the bundle
+    pub fn resolve(&self, path: &str) -> PathBuf {
+        self.root.join(path)
+    }
+
+    pub fn disks_dir(&self) -> PathBuf {
+        self.root.join(DISKS_DIR)
+    }
+
+    pub fn disk_path(&self, file_name: &str) -> PathBuf {
+        self.disks_dir().join(file_name)
+    }
+
+    pub fn auxiliary_storage_path(&self) -> PathBuf {
+        self.root.join(AUXILIARY_STORAGE_FILE)
+    }
+
+    pub fn hardware_model_path(&self) -> PathBuf {
+        self.root.join(HARDWARE_MODEL_FILE)
+    }
+
+    pub fn machine_identifier_path(&self) -> PathBuf {
+        self.root.join(MACHINE_IDENTIFIER_FILE)
+    }
+
+    pub fn nvram_path(&self) -> PathBuf {
+        self.root.join(NVRAM_FILE)
+    }
+
+    /// specification of the virtual machine with every path resolved against the bundle
+    pub fn spec(&self) -> VmSpec {
+        self.manifest.spec.resolve_paths(&self.root)
+    }
+
+    /// check the resolved specification and that every file it uses is inside the bundle
+    pub fn validate(&self, limits: &ValidationLimits) -> Vec<Diagnostic> {
+        validation::validate_bundle(self, limits)
+    }
+
+    /// lock the bundle and create the configuration of the virtual machine, which holds the lock,
+    /// see [`VmSpec::to_configuration`](crate::spec::VmSpec::to_configuration)
+    pub fn to_configuration(&self) -> Result<VZVirtualMachineConfiguration> {
+        let lock = self.lock()?;
+        let mut conf = self.manifest.spec.to_configuration(&self.root)?;
+        conf.hold_lock(lock);
+        Ok(conf)
+    }
+
+    /// boot the virtual machine with the EFI boot loader, keeping its variable store in `nvram`
+    ///
+    /// The variable store is created the first time the virtual machine is configured.
+    pub fn use_efi_boot_loader(&mut self) -> Result<()> {
+        self.manifest.spec.boot_loader = Some(BootLoaderSpec::Efi {
+            variable_store: NVRAM_FILE.to_string(),
+        });
+        self.save()
+    }
+
+    /// path of `disks/<file_name>` in the manifest, checking that it can be attached
+    fn new_disk_path(&self, file_name: &str) -> Result<String> {
+        let mut components = Path::new(file_name).components();
+        let plain = matches!(
+            (components.next(), components.next()),
+            (Some(Component::Normal(name)), None) if name == file_name
+        );
+        if !plain {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{:?} is not a plain file name", file_name),
+            )
+            .into());
+        }
+        let path = format!("{}/{}", DISKS_DIR, file_name);
+        if self
+            .manifest
+            .spec
+            .storage
+            .iter()
+            .any(|storage| storage.path == path)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} is already attached", path),
+            )
+            .into());
+        }
+        Ok(path)
+    }
+
+    /// attach `disks/<file_name>` to the virtual machine, returns its path
+    ///
+    /// `file_name` must not contain a directory. The disk image itself is not created.
+    pub fn add_disk(&mut self, file_name: &str, read_only: bool) -> Result<PathBuf> {
+        let path = self.new_disk_path(file_name)?;
+        self.attach_disk(path, read_only)?;
+        Ok(self.disk_path(file_name))
+    }
+
+    fn attach_disk(&mut self, path: String, read_only: bool) -> Result<()> {
+        self.manifest.spec.storage.push(StorageSpec {
+            path,
+            read_only,
+            caching_mode: Default::default(),
+            synchronization_mode: Default::default(),
+        });
+        self.save()
+    }
+
+    /// move the disk image at `source` into the bundle and attach it, returns its new path
+    ///
+    /// Nothing is moved when the disk cannot be attached.
+    pub fn import_disk<P: AsRef<Path>>(&mut self, source: P, read_only: bool) -> Result<PathBuf> {
+        let source = source.as_ref();
+        let file_name = source
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} has no file name", source.display()),
+                )
+            })?;
+        let path = self.new_disk_path(file_name)?;
+        let destination = self.disk_path(file_name);
+        if destination.exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already exists", destination.display()),
+            )
+            .into());
+        }
+        move_file(source, &destination)?;
+        self.attach_disk(path, read_only)?;
+        Ok(destination)
+    }
+
+    /// create the platform files of the bundle for `configuration_requirements` and use them
+    pub fn create_platform(
+        &mut self,
+        configuration_requirements: VZMacOsConfigurationRequirements,
+    ) -> Result<VZMacPlatformConfiguration> {
+        let platform = VZMacPlatformConfiguration::create(
+            configuration_requirements,
+            &path_str(&self.auxiliary_storage_path())?,
+            &path_str(&self.hardware_model_path())?,
+            &path_str(&self.machine_identifier_path())?,
+        )?;
+        self.manifest.spec.platform = Some(MacPlatformSpec {
+            auxiliary_storage: AUXILIARY_STORAGE_FILE.to_string(),
+            hardware_model: HARDWARE_MODEL_FILE.to_string(),
+            machine_identifier: MACHINE_IDENTIFIER_FILE.to_string(),
+        });
+        self.save()?;
+        Ok(platform)
+    }
+
+    /// load the platform configuration from the files of the bundle
+    pub fn load_platform(&self) -> Result<VZMacPlatformConfiguration> {
+        VZMacPlatformConfiguration::load(
+            &path_str(&self.auxiliary_storage_path())?,
+            &path_str(&self.hardware_model_path())?,
+            &path_str(&self.machine_identifier_path())?,
+        )
+    }
+
+    /// snapshot the stopped virtual machine as `name`, see [`snapshot::create`]
+    ///
+    /// # Examples
+    /// ```rust,no_run
+    /// use virtualization_rs::bundle::VmBundle;
+    ///
+    /// let mut bundle = VmBundle::open("example.vmbundle").unwrap();
+    /// bundle.create_snapshot("before-upgrade").unwrap();
+    /// for snapshot in bundle.snapshots().unwrap() {
+    ///     println!("{} {}", snapshot.name, snapshot.created);
+    /// }
+    /// bundle.revert_to_snapshot("before-upgrade").unwrap();
+    /// ```
+    pub fn create_snapshot(&self, name: &str) -> Result<Snapshot> {
+        snapshot::create(self, name)
+    }
+
+    /// the snapshots of the bundle, oldest first
+    pub fn snapshots(&self) -> Result<Vec<Snapshot>> {
+        snapshot::list(self)
+    }
+
+    /// restore the virtual machine saved in the snapshot `name`, see [`snapshot::revert`]
+    pub fn revert_to_snapshot(&mut self, name: &str) -> Result<()> {
+        snapshot::revert(self, name)
+    }
+
+    pub fn delete_snapshot(&self, name: &str) -> Result<()> {
+        snapshot::delete(self, name)
+    }
+
+    /// lock the bundle exclusively until the returned lock is dropped
+    ///
+    /// Fails with [`Error::Locked`] naming the holding process while another one uses it.
+    pub fn lock(&self) -> Result<FileLock> {
+        FileLock::acquire(&self.root, LockMode::Exclusive)
+    }
+}
+
+/// whether `path` of a manifest stays inside the bundle
+pub fn is_inside_bundle(path: &str) -> bool {
+    Path::new(path)
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+}
+
+fn path_str(path: &Path) -> Result<String> {
+    path.to_str().map(str::to_string).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{:?} is not valid UTF-8", path),
+        )
+        .into()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bundle(name: &str) -> VmBundle {
+        let root = std::env::temp_dir().join(format!("bundle-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let spec = VmSpec::from_toml("cpu_count = 2\nmemory_size = 2147483648\n").unwrap();
+        VmBundle::create(&root, name, spec).unwrap()
+    }
+
+    #[test]
+    fn disk_names_must_be_plain_file_names() {
+        let mut bundle = bundle("disk-names");
+        for name in &["../x", "a/b", "/etc/passwd", "", ".", "..", "x/"] {
+            match bundle.add_disk(name, false) {
+                Err(Error::Io(err)) => assert_eq!(err.kind(), io::ErrorKind::InvalidInput),
+                other => panic!("{:?} was accepted: {:?}", name, other),
+            }
+        }
+        assert!(bundle.manifest().spec.storage.is_empty());
+
+        let path = bundle.add_disk("root.img", false).unwrap();
+        assert_eq!(path, bundle.disks_dir().join("root.img"));
+        assert!(bundle.add_disk("root.img", true).is_err());
+        assert_eq!(
+            VmBundle::open(bundle.root())
+                .unwrap()
+                .manifest()
+                .spec
+                .storage[0]
+                .path,
+            "disks/root.img"
+        );
+        fs::remove_dir_all(bundle.root()).unwrap();
+    }
+
+    #[test]
+    fn import_disk_leaves_the_source_when_it_cannot_be_attached() {
+        let mut bundle = bundle("import-disk");
+        bundle.add_disk("data.img", false).unwrap();
+
+        let source = bundle.root().join("data.img");
+        fs::write(&source, b"disk").unwrap();
+        assert!(bundle.import_disk(&source, false).is_err());
+        assert!(source.exists());
+        assert!(!bundle.disk_path("data.img").exists());
+
+        let source = bundle.root().join("other.img");
+        fs::write(&source, b"disk").unwrap();
+        let path = bundle.import_disk(&source, true).unwrap();
+        assert!(!source.exists());
+        assert_eq!(fs::read(&path).unwrap(), b"disk");
+        assert!(bundle.manifest().spec.storage[1].read_only);
+        fs::remove_dir_all(bundle.root()).unwrap();
+    }
+
+    #[test]
+    fn efi_variable_store_is_kept_in_the_bundle() {
+        let mut bundle = bundle("efi");
+        bundle.use_efi_boot_loader().unwrap();
+
+        let bundle = VmBundle::open(bundle.root()).unwrap();
+        assert_eq!(
+            bundle.manifest().spec.boot_loader,
+            Some(BootLoaderSpec::Efi {
+                variable_store: NVRAM_FILE.to_string()
+            })
+        );
+        assert_eq!(
+            bundle.spec().boot_loader,
+            Some(BootLoaderSpec::Efi {
+                variable_store: bundle.nvram_path().to_string_lossy().into_owned()
+            })
+        );
+        let limits = ValidationLimits {
+            macos_version: None,
+            ..ValidationLimits::default()
+        };
+        assert!(bundle.validate(&limits).is_empty());
+        fs::remove_dir_all(bundle.root()).unwrap();
+    }
+}
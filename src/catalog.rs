@@ -0,0 +1,423 @@
+//! restore image catalog module
+//!
+//! [`RestoreImageCatalog`] manages a cache directory of restore images. Every image is stored
+//! once, under a name derived from its build and SHA-256 digest, and indexed in `catalog.json`
+//! with the information reported by [`ipsw::inspect`](crate::ipsw::inspect), so an image can be
+//! looked up by version or build instead of by path.
+
+use crate::{
+    disk::clone::clone_file,
+    download::{sha256_file, DownloadBuilder, DownloadProgress},
+    error::{Error, Result},
+    ipsw::{self, RestoreImageInfo},
+    version::compare_versions,
+};
+
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const INDEX: &str = "catalog.json";
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+fn not_found(description: String) -> Error {
+    io::Error::new(io::ErrorKind::NotFound, description).into()
+}
+
+/// restore image stored in the catalog
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CatalogEntry {
+    /// file name inside the catalog directory
+    pub file_name: String,
+    /// hex encoded SHA-256 digest of the image
+    pub sha256: String,
+    /// size in bytes
+    pub size: u64,
+    pub info: RestoreImageInfo,
+    /// URL the image was downloaded from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_url: Option<String>,
+    /// seconds since the Unix epoch
+    pub added: u64,
+    /// seconds since the Unix epoch
+    pub last_used: u64,
+}
+
+impl CatalogEntry {
+    /// whether `query` is the build of this image, or a prefix of its version
+    ///
+    /// `13` matches `13.0.1` and `13.0`, `13.0` does not match `13.1`.
+    pub fn matches(&self, query: &str) -> bool {
+        if self.info.build_version.eq_ignore_ascii_case(query) {
+            return true;
+        }
+        let mut version = self.info.product_version.split('.');
+        query
+            .split('.')
+            .all(|component| version.next() == Some(component))
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Index {
+    #[serde(default)]
+    images: Vec<CatalogEntry>,
+}
+
+/// cache directory of restore images
+pub struct RestoreImageCatalog {
+    root: PathBuf,
+    images: Vec<CatalogEntry>,
+}
+
+impl RestoreImageCatalog {
+    /// open the catalog in `root`, creating the directory if needed
+    ///
+    /// Entries whose image file has disappeared are dropped from the index.
+    pub fn open<P: AsRef<Path>>(root: P) -> Result<RestoreImageCatalog> {
+        let root = root.as_ref().to_path_buf();
+        fs::create_dir_all(&root)?;
+        let index_path = root.join(INDEX);
+        let index = if index_path.exists() {
+            serde_json::from_str(&fs::read_to_string(&index_path)?)?
+        } else {
+            Index::default()
+        };
+
+        let count = index.images.len();
+        let images: Vec<CatalogEntry> = index
+            .images
+            .into_iter()
+            .filter(|image| root.join(&image.file_name).is_file())
+            .collect();
+        let catalog = RestoreImageCatalog { root, images };
+        if catalog.images.len() != count {
+            catalog.save()?;
+        }
+        Ok(catalog)
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// every image of the catalog, newest version first
+    pub fn list(&self) -> Vec<&CatalogEntry> {
+        let mut images: Vec<&CatalogEntry> = self.images.iter().collect();
+        images.sort_by(|a, b| {
+            compare_versions(&b.info.product_version, &a.info.product_version)
+                .then_with(|| b.info.build_version.cmp(&a.info.build_version))
+        });
+        images
+    }
+
+    /// total size of the images in bytes
+    pub fn size(&self) -> u64 {
+        self.images.iter().map(|image| image.size).sum()
+    }
+
+    /// path of the image file of `entry`
+    pub fn path(&self, entry: &CatalogEntry) -> PathBuf {
+        self.root.join(&entry.file_name)
+    }
+
+    /// newest image matching `query`, see [`CatalogEntry::matches`]
+    pub fn find(&self, query: &str) -> Option<&CatalogEntry> {
+        self.list().into_iter().find(|image| image.matches(query))
+    }
+
+    pub fn find_by_sha256(&self, sha256: &str) -> Option<&CatalogEntry> {
+        self.images
+            .iter()
+            .find(|image| image.sha256.eq_ignore_ascii_case(sha256))
+    }
+
+    /// mark the image as used now, the least recently used images are pruned first
+    pub fn touch(&mut self, sha256: &str) -> Result<()> {
+        let image = self
+            .images
+            .iter_mut()
+            .find(|image| image.sha256.eq_ignore_ascii_case(sha256))
+            .ok_or_else(|| not_found(format!("no restore image with digest {}", sha256)))?;
+        image.last_used = now();
+        self.save()
+    }
+
+    /// copy the restore image at `path` into the catalog, cloning it where the file system can
+    ///
+    /// If the catalog already holds an image with the same digest, the existing entry is returned.
+    pub fn import<P: AsRef<Path>>(&mut self, path: P) -> Result<CatalogEntry> {
+        self.import_from(path.as_ref(), None, false)
+    }
+
+    /// move the restore image at `path` into the catalog
+    ///
+    /// If the catalog already holds an image with the same digest, `path` is removed and the
+    /// existing entry is returned.
+    pub fn import_move<P: AsRef<Path>>(&mut self, path: P) -> Result<CatalogEntry> {
+        self.import_from(path.as_ref(), None, true)
+    }
+
+    /// download the restore image at `url` into the catalog, unless it was downloaded before
+    ///
+    /// An interrupted download is resumed on the next call.
+    pub fn download<F: FnMut(DownloadProgress) + 'static>(
+        &mut self,
+        url: &str,
+        progress: F,
+    ) -> Result<CatalogEntry> {
+        if let Some(image) = self
+            .images
+            .iter()
+            .find(|image| image.source_url.as_deref() == Some(url))
+        {
+            return Ok(image.clone());
+        }
+
+        let file_name = url
+            .split(['?', '#'])
+            .next()
+            .unwrap_or(url)
+            .rsplit('/')
+            .next()
+            .filter(|file_name| !file_name.is_empty())
+            .unwrap_or("restore.ipsw");
+        let destination = self.root.join(format!("download-{}", file_name));
+        // a finished download is left behind if importing it failed
+        if !destination.exists() {
+            DownloadBuilder::new()
+                .url(url)
+                .destination(&destination)
+                .progress(progress)
+                .build()
+                .run()?;
+        }
+        self.import_from(&destination, Some(url), true)
+    }
+
+    /// remove the image with digest `sha256` from the catalog and from disk
+    pub fn remove(&mut self, sha256: &str) -> Result<CatalogEntry> {
+        let position = self
+            .images
+            .iter()
+            .position(|image| image.sha256.eq_ignore_ascii_case(sha256))
+            .ok_or_else(|| not_found(format!("no restore image with digest {}", sha256)))?;
+        let image = self.images.remove(position);
+        self.save()?;
+        match fs::remove_file(self.path(&image)) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+            _ => {}
+        }
+        Ok(image)
+    }
+
+    /// remove the least recently used images until the catalog fits in `budget` bytes,
+    /// returns the removed images
+    pub fn prune(&mut self, budget: u64) -> Result<Vec<CatalogEntry>> {
+        let mut candidates: Vec<(u64, u64, String)> = self
+            .images
+            .iter()
+            .map(|image| (image.last_used, image.added, image.sha256.clone()))
+            .collect();
+        candidates.sort();
+
+        let mut removed = Vec::new();
+        for (_, _, sha256) in candidates {
+            if self.size() <= budget {
+                break;
+            }
+            removed.push(self.remove(&sha256)?);
+        }
+        Ok(removed)
+    }
+
+    /// add the image at `path`, which is moved rather than copied if `remove_source` is set
+    fn import_from(
+        &mut self,
+        path: &Path,
+        source_url: Option<&str>,
+        remove_source: bool,
+    ) -> Result<CatalogEntry> {
+        let sha256 = sha256_file(path)?;
+        if let Some(image) = self.find_by_sha256(&sha256) {
+            let image = image.clone();
+            if remove_source && self.path(&image) != path {
+                fs::remove_file(path)?;
+            }
+            return Ok(image);
+        }
+
+        let info = ipsw::inspect(path)?;
+        // the build comes from the image, it must not be able to name a path
+        if info.build_version.is_empty()
+            || !info
+                .build_version
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric())
+        {
+            return Err(Error::Parse(format!(
+                "{}: invalid build version {:?}",
+                path.display(),
+                info.build_version
+            )));
+        }
+        let file_name = format!("{}-{}.ipsw", info.build_version, &sha256[..12]);
+        let destination = self.root.join(&file_name);
+        if remove_source {
+            move_file(path, &destination)?;
+        } else {
+            clone_file(path, &destination)?;
+        }
+
+        let now = now();
+        let image = CatalogEntry {
+            file_name,
+            sha256,
+            size: fs::metadata(&destination)?.len(),
+            info,
+            source_url: source_url.map(str::to_string),
+            added: now,
+            last_used: now,
+        };
+        self.images.push(image.clone());
+        self.save()?;
+        Ok(image)
+    }
+
+    /// write the index atomically
+    fn save(&self) -> Result<()> {
+        let index = Index {
+            images: self.images.clone(),
+        };
+        let path = self.root.join(INDEX);
+        let tmp_path = self.root.join(format!("{}.tmp", INDEX));
+        fs::write(&tmp_path, serde_json::to_string_pretty(&index)?)?;
+        fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+}
+
+/// rename `from` to `to`, copying when they are on different file systems
+pub(crate) fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    if fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    fs::copy(from, to)?;
+    fs::remove_file(from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// stored zip archive holding a `BuildManifest.plist` for `build_version`
+    fn ipsw(build_version: &str) -> Vec<u8> {
+        let name = "BuildManifest.plist";
+        let data = format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <plist version=\"1.0\"><dict>\
+             <key>ProductVersion</key><string>13.0</string>\
+             <key>ProductBuildVersion</key><string>{}</string>\
+             </dict></plist>\n",
+            build_version
+        );
+        let mut crc = flate2::Crc::new();
+        crc.update(data.as_bytes());
+
+        let mut zip = Vec::new();
+        zip.extend_from_slice(&0x0403_4b50u32.to_le_bytes());
+        zip.extend_from_slice(&[20, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        zip.extend_from_slice(&crc.sum().to_le_bytes());
+        zip.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        zip.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        zip.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        zip.extend_from_slice(&0u16.to_le_bytes());
+        zip.extend_from_slice(name.as_bytes());
+        zip.extend_from_slice(data.as_bytes());
+
+        let cd_offset = zip.len() as u32;
+        zip.extend_from_slice(&0x0201_4b50u32.to_le_bytes());
+        zip.extend_from_slice(&[20, 0, 20, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        zip.extend_from_slice(&crc.sum().to_le_bytes());
+        zip.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        zip.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        zip.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        zip.extend_from_slice(&[0; 12]);
+        zip.extend_from_slice(&0u32.to_le_bytes());
+        zip.extend_from_slice(name.as_bytes());
+        let cd_size = zip.len() as u32 - cd_offset;
+
+        zip.extend_from_slice(&0x0605_4b50u32.to_le_bytes());
+        zip.extend_from_slice(&[0, 0, 0, 0, 1, 0, 1, 0]);
+        zip.extend_from_slice(&cd_size.to_le_bytes());
+        zip.extend_from_slice(&cd_offset.to_le_bytes());
+        zip.extend_from_slice(&0u16.to_le_bytes());
+        zip
+    }
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("catalog-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn import_copies_and_import_move_moves() {
+        let dir = scratch("import");
+        let mut catalog = RestoreImageCatalog::open(dir.join("catalog")).unwrap();
+
+        let source = dir.join("a.ipsw");
+        fs::write(&source, ipsw("22A380")).unwrap();
+        let entry = catalog.import(&source).unwrap();
+        assert!(source.exists());
+        assert_eq!(entry.info.build_version, "22A380");
+        assert!(catalog.path(&entry).is_file());
+
+        // a second import of the same image is deduplicated and still leaves the source alone
+        assert_eq!(catalog.import(&source).unwrap(), entry);
+        assert!(source.exists());
+        assert_eq!(catalog.list().len(), 1);
+
+        let moved = dir.join("b.ipsw");
+        fs::write(&moved, ipsw("22A400")).unwrap();
+        let entry = catalog.import_move(&moved).unwrap();
+        assert!(!moved.exists());
+        assert!(catalog.path(&entry).is_file());
+        assert_eq!(catalog.list().len(), 2);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn build_version_must_be_alphanumeric() {
+        let dir = scratch("build-version");
+        let mut catalog = RestoreImageCatalog::open(dir.join("catalog")).unwrap();
+
+        for build_version in &["../x", "22A380/..", ""] {
+            let source = dir.join("hostile.ipsw");
+            fs::write(&source, ipsw(build_version)).unwrap();
+            match catalog.import(&source) {
+                Err(Error::Parse(_)) => {}
+                other => panic!("{:?} was accepted: {:?}", build_version, other),
+            }
+        }
+        assert!(catalog.list().is_empty());
+        // `../x` would have been written next to the catalog directory
+        assert!(fs::read_dir(&dir).unwrap().all(|entry| !entry
+            .unwrap()
+            .file_name()
+            .to_string_lossy()
+            .starts_with("x-")));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,246 @@
+//! disk image cloning module
+//!
+//! [`clone_disk`] copies a disk image, typically a template, in constant time where the file
+//! system supports copy-on-write clones: `clonefile(2)` on APFS and the `FICLONE` ioctl on Btrfs
+//! or XFS. Elsewhere it falls back to a copy that only writes the allocated, non-zero ranges of the
+//! source, so the clone stays sparse.
+
+use crate::{disk::seek, error::Result, lock::FileLock};
+
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+/// how a disk image was cloned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloneMethod {
+    /// copy-on-write clone sharing the blocks of the source
+    Reflink,
+    /// copy of the allocated ranges of the source
+    SparseCopy,
+}
+
+/// clone the disk image `source` to `destination`, which must not exist
+///
+/// The source is locked shared meanwhile, so cloning fails while a virtual machine writes to it.
+/// A source in a read-only directory, where no lock file can be created, is cloned unlocked.
+/// The clone is made under a temporary name and only then linked to `destination`, so it appears
+/// complete or not at all.
+///
+/// # Examples
+/// ```rust
+/// use virtualization_rs::disk::clone::clone_disk;
+/// use virtualization_rs::disk::raw::RawDiskImageBuilder;
+///
+/// let dir = std::env::temp_dir().join(format!("clone-example-{}", std::process::id()));
+/// std::fs::create_dir(&dir).unwrap();
+/// RawDiskImageBuilder::new()
+///     .path(dir.join("template.img"))
+///     .size(16 * 1024 * 1024)
+///     .build()
+///     .unwrap();
+///
+/// clone_disk(dir.join("template.img"), dir.join("vm.img")).unwrap();
+/// assert_eq!(std::fs::metadata(dir.join("vm.img")).unwrap().len(), 16 * 1024 * 1024);
+/// assert!(clone_disk(dir.join("template.img"), dir.join("vm.img")).is_err());
+/// # std::fs::remove_dir_all(&dir).unwrap();
+/// ```
+pub fn clone_disk<P: AsRef<Path>, Q: AsRef<Path>>(
+    source: P,
+    destination: Q,
+) -> Result<CloneMethod> {
+    let source = source.as_ref();
+    let _lock = FileLock::acquire_shared_unless_read_only(source)?;
+    Ok(clone_file(source, destination.as_ref())?)
+}
+
+/// clone `source` to `destination`, which must not exist, without locking `source`
+pub(crate) fn clone_file(source: &Path, destination: &Path) -> io::Result<CloneMethod> {
+    if fs::symlink_metadata(destination).is_ok() {
+        return Err(already_exists(destination));
+    }
+
+    let tmp_path = temporary_path(destination);
+    let _ = fs::remove_file(&tmp_path);
+    let result = clone_or_copy(source, &tmp_path).and_then(|method| {
+        publish(&tmp_path, destination)?;
+        Ok(method)
+    });
+    let _ = fs::remove_file(&tmp_path);
+    result
+}
+
+fn clone_or_copy(source: &Path, destination: &Path) -> io::Result<CloneMethod> {
+    match reflink(source, destination) {
+        Ok(()) => Ok(CloneMethod::Reflink),
+        Err(err) if is_unsupported(&err) => {
+            sparse_copy(source, destination)?;
+            Ok(CloneMethod::SparseCopy)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// link `tmp_path` to `destination` unless it exists meanwhile
+fn publish(tmp_path: &Path, destination: &Path) -> io::Result<()> {
+    match fs::hard_link(tmp_path, destination) {
+        Ok(()) => Ok(()),
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => Err(already_exists(destination)),
+        // file systems without hard links
+        Err(_) if fs::symlink_metadata(destination).is_err() => fs::rename(tmp_path, destination),
+        Err(err) => Err(err),
+    }
+}
+
+/// hidden name next to `path`, unique to this process
+fn temporary_path(path: &Path) -> PathBuf {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    path.with_file_name(format!(".{}.{}.clone", name, std::process::id()))
+}
+
+fn already_exists(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        format!("{} already exists", path.display()),
+    )
+}
+
+/// whether a clone failed because the file system cannot clone
+fn is_unsupported(err: &io::Error) -> bool {
+    if err.kind() == io::ErrorKind::Unsupported {
+        return true;
+    }
+    match err.raw_os_error() {
+        Some(code) => [
+            libc::ENOTSUP,
+            libc::EOPNOTSUPP,
+            libc::EXDEV,
+            libc::EINVAL,
+            libc::ENOTTY,
+        ]
+        .contains(&code),
+        None => false,
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn reflink(source: &Path, destination: &Path) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    const CLONE_NOFOLLOW: u32 = 0x0001;
+    let source = CString::new(source.as_os_str().as_bytes())?;
+    let destination = CString::new(destination.as_os_str().as_bytes())?;
+    if unsafe { libc::clonefile(source.as_ptr(), destination.as_ptr(), CLONE_NOFOLLOW) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn reflink(source: &Path, destination: &Path) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let src = File::open(source)?;
+    let dst = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(destination)?;
+    if unsafe { libc::ioctl(dst.as_raw_fd(), libc::FICLONE, src.as_raw_fd()) } == 0 {
+        dst.set_permissions(src.metadata()?.permissions())?;
+        return Ok(());
+    }
+    let err = io::Error::last_os_error();
+    drop(dst);
+    let _ = fs::remove_file(destination);
+    Err(err)
+}
+
+#[cfg(not(any(target_os = "macos", target_os = "linux")))]
+fn reflink(_source: &Path, _destination: &Path) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// copy `source` to the new file `destination`, leaving holes where the source has holes or zeros
+fn sparse_copy(source: &Path, destination: &Path) -> io::Result<()> {
+    const CHUNK: usize = 1024 * 1024;
+    let src = File::open(source)?;
+    let metadata = src.metadata()?;
+    let size = metadata.len();
+    let dst = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(destination)?;
+    dst.set_permissions(metadata.permissions())?;
+    dst.set_len(size)?;
+
+    let mut buffer = vec![0u8; CHUNK];
+    let mut offset = 0;
+    while offset < size {
+        let data = match seek(&src, offset, libc::SEEK_DATA)? {
+            Some(data) if data < size => data,
+            _ => break,
+        };
+        let hole = seek(&src, data, libc::SEEK_HOLE)?.unwrap_or(size).min(size);
+        let mut position = data;
+        while position < hole {
+            let len = (hole - position).min(CHUNK as u64) as usize;
+            src.read_exact_at(&mut buffer[..len], position)?;
+            if buffer[..len].iter().any(|&byte| byte != 0) {
+                dst.write_all_at(&buffer[..len], position)?;
+            }
+            position += len as u64;
+        }
+        offset = hole;
+    }
+    dst.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+    use crate::lock::{lock_file_path, LockMode};
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn clones_a_source_in_a_read_only_directory() {
+        let dir = std::env::temp_dir().join(format!("clone-read-only-{}", std::process::id()));
+        let templates = dir.join("templates");
+        fs::create_dir_all(&templates).unwrap();
+        let source = templates.join("template.img");
+        fs::write(&source, b"template").unwrap();
+        fs::set_permissions(&templates, fs::Permissions::from_mode(0o555)).unwrap();
+
+        let result = clone_disk(&source, dir.join("vm.img"));
+        fs::set_permissions(&templates, fs::Permissions::from_mode(0o755)).unwrap();
+        result.unwrap();
+        assert_eq!(fs::read(dir.join("vm.img")).unwrap(), b"template");
+        assert!(!lock_file_path(&source).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_a_source_locked_exclusively() {
+        let dir = std::env::temp_dir().join(format!("clone-locked-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("template.img");
+        fs::write(&source, b"template").unwrap();
+
+        let lock = FileLock::acquire(&source, LockMode::Exclusive).unwrap();
+        assert!(matches!(
+            clone_disk(&source, dir.join("vm.img")),
+            Err(Error::Locked { .. })
+        ));
+        assert!(!dir.join("vm.img").exists());
+        drop(lock);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,318 @@
+//! disk image conversion module
+//!
+//! [`Convert`] reads a disk image through a [`DiskImageReader`] and writes it as a sparse raw
+//! image that [`VZDiskImageStorageDeviceAttachmentBuilder`] accepts. Like a download, the image is
+//! written to `<destination>.part`, compared with a second read of the source and only then renamed
+//! to `destination`.
+//!
+//! [`VZDiskImageStorageDeviceAttachmentBuilder`]: crate::virtualization::storage_device::VZDiskImageStorageDeviceAttachmentBuilder
+
+use crate::{
+    disk::{detect_format, open_image_with_depth, DiskFormat},
+    error::{Error, Result},
+};
+
+use sha2::{Digest, Sha256};
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+const BUFFER_SIZE: usize = 1024 * 1024;
+
+/// disk image read as the guest sees it
+pub trait DiskImageReader {
+    /// size of the disk seen by the guest in bytes
+    fn virtual_size(&self) -> u64;
+
+    /// fill `buf` with the bytes of the disk at `offset`, returns `false` when none of them is
+    /// allocated in the image and `buf` was zero-filled
+    ///
+    /// Bytes past the end of the disk read as zeros.
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<bool>;
+}
+
+impl<R: DiskImageReader + ?Sized> DiskImageReader for Box<R> {
+    fn virtual_size(&self) -> u64 {
+        (**self).virtual_size()
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<bool> {
+        (**self).read_at(offset, buf)
+    }
+}
+
+/// progress of a conversion, reported after every chunk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConvertProgress {
+    /// bytes of the disk converted so far
+    pub converted: u64,
+    /// size of the disk
+    pub total: u64,
+}
+
+impl ConvertProgress {
+    /// completed fraction in `0.0..=1.0`
+    pub fn fraction(&self) -> f64 {
+        if self.total == 0 {
+            1.0
+        } else {
+            self.converted as f64 / self.total as f64
+        }
+    }
+}
+
+/// result of a conversion
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConvertReport {
+    /// format of the source image
+    pub format: DiskFormat,
+    /// size of the disk in bytes
+    pub virtual_size: u64,
+    /// bytes written to the raw image, the rest of it is sparse
+    pub written: u64,
+    /// whether the raw image was read back and compared with a second read of the source
+    pub verified: bool,
+}
+
+/// builder for Convert
+/// # Examples
+/// ```rust,no_run
+/// use virtualization_rs::disk::convert::ConvertBuilder;
+///
+/// let report = ConvertBuilder::new()
+///     .source("jammy-server-cloudimg-arm64.img")
+///     .destination("jammy.img")
+///     .progress(|progress| println!("{:.0}%", progress.fraction() * 100.0))
+///     .build()
+///     .run()
+///     .unwrap();
+/// println!("{} of {} bytes allocated", report.written, report.virtual_size);
+/// ```
+pub struct ConvertBuilder<Source, Destination> {
+    source: Source,
+    destination: Destination,
+    verify: bool,
+    allow_backing_files: bool,
+    progress: Option<Box<dyn FnMut(ConvertProgress)>>,
+}
+
+impl ConvertBuilder<(), ()> {
+    pub fn new() -> Self {
+        ConvertBuilder {
+            source: (),
+            destination: (),
+            verify: true,
+            allow_backing_files: false,
+            progress: None,
+        }
+    }
+}
+
+impl Default for ConvertBuilder<(), ()> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<Source, Destination> ConvertBuilder<Source, Destination> {
+    /// image to convert, its format is detected from its contents
+    pub fn source<T: Into<PathBuf>>(self, source: T) -> ConvertBuilder<PathBuf, Destination> {
+        ConvertBuilder {
+            source: source.into(),
+            destination: self.destination,
+            verify: self.verify,
+            allow_backing_files: self.allow_backing_files,
+            progress: self.progress,
+        }
+    }
+
+    /// path of the raw image, which must not exist
+    pub fn destination<T: Into<PathBuf>>(self, destination: T) -> ConvertBuilder<Source, PathBuf> {
+        ConvertBuilder {
+            source: self.source,
+            destination: destination.into(),
+            verify: self.verify,
+            allow_backing_files: self.allow_backing_files,
+            progress: self.progress,
+        }
+    }
+
+    /// read the raw image back and compare it with a second read of the source before renaming,
+    /// enabled by default
+    pub fn verify(mut self, verify: bool) -> Self {
+        self.verify = verify;
+        self
+    }
+
+    /// follow backing files named by an absolute path or through `..`, disabled by default
+    ///
+    /// Only enable it for trusted images: a backing file can name any file readable by the
+    /// process, which is then copied into the raw image.
+    pub fn allow_backing_files(mut self, allow_backing_files: bool) -> Self {
+        self.allow_backing_files = allow_backing_files;
+        self
+    }
+
+    pub fn progress<F: FnMut(ConvertProgress) + 'static>(mut self, progress: F) -> Self {
+        self.progress = Some(Box::new(progress));
+        self
+    }
+}
+
+impl ConvertBuilder<PathBuf, PathBuf> {
+    pub fn build(self) -> Convert {
+        Convert {
+            source: self.source,
+            destination: self.destination,
+            verify: self.verify,
+            allow_backing_files: self.allow_backing_files,
+            progress: self.progress,
+        }
+    }
+}
+
+/// conversion of a disk image to a sparse raw image
+pub struct Convert {
+    source: PathBuf,
+    destination: PathBuf,
+    verify: bool,
+    allow_backing_files: bool,
+    progress: Option<Box<dyn FnMut(ConvertProgress)>>,
+}
+
+impl Convert {
+    /// path the image is written to until it has been verified
+    pub fn part_path(&self) -> PathBuf {
+        let mut part_path = OsString::from(self.destination.as_os_str());
+        part_path.push(".part");
+        PathBuf::from(part_path)
+    }
+
+    /// convert the image, nothing is left at the destination when it fails
+    pub fn run(mut self) -> Result<ConvertReport> {
+        if fs::symlink_metadata(&self.destination).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already exists", self.destination.display()),
+            )
+            .into());
+        }
+        let format = detect_format(&self.source)?;
+        let mut reader = self.open(format)?;
+        let part_path = self.part_path();
+        let result = self.write(reader.as_mut(), &part_path);
+        let report = match result {
+            Ok(written) => {
+                let verified = if self.verify {
+                    // a second reader reads the source again instead of trusting cached data
+                    self.open(format)
+                        .and_then(|mut source| verify(source.as_mut(), &part_path))
+                } else {
+                    Ok(())
+                };
+                verified.map(|()| ConvertReport {
+                    format,
+                    virtual_size: reader.virtual_size(),
+                    written,
+                    verified: self.verify,
+                })
+            }
+            Err(err) => Err(err),
+        };
+        match report {
+            Ok(report) => {
+                fs::rename(&part_path, &self.destination)?;
+                Ok(report)
+            }
+            Err(err) => {
+                let _ = fs::remove_file(&part_path);
+                Err(err)
+            }
+        }
+    }
+
+    fn open(&self, format: DiskFormat) -> Result<Box<dyn DiskImageReader>> {
+        open_image_with_depth(
+            &self.source,
+            Some(format.name()),
+            0,
+            self.allow_backing_files,
+        )
+    }
+
+    /// write the allocated, non-zero chunks of `reader` to `part_path`, returns the bytes written
+    fn write(&mut self, reader: &mut dyn DiskImageReader, part_path: &Path) -> Result<u64> {
+        let total = reader.virtual_size();
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(part_path)?;
+        file.set_len(total)?;
+
+        let mut written = 0;
+        let mut buffer = vec![0; BUFFER_SIZE];
+        let mut offset = 0;
+        self.report(0, total);
+        while offset < total {
+            let len = (total - offset).min(BUFFER_SIZE as u64) as usize;
+            let chunk = &mut buffer[..len];
+            if reader.read_at(offset, chunk)? && chunk.iter().any(|&byte| byte != 0) {
+                file.write_all_at(chunk, offset)?;
+                written += len as u64;
+            }
+            offset += len as u64;
+            self.report(offset, total);
+        }
+        file.sync_all()?;
+        Ok(written)
+    }
+
+    fn report(&mut self, converted: u64, total: u64) {
+        if let Some(progress) = &mut self.progress {
+            progress(ConvertProgress { converted, total });
+        }
+    }
+}
+
+/// compare the raw image at `path` with the disk read from `source`
+fn verify(source: &mut dyn DiskImageReader, path: &Path) -> Result<()> {
+    let file = File::open(path)?;
+    let total = source.virtual_size();
+    if file.metadata()?.len() != total {
+        return Err(Error::Parse(format!(
+            "{}: {} bytes instead of {}",
+            path.display(),
+            file.metadata()?.len(),
+            total
+        )));
+    }
+    let mut expected = Sha256::new();
+    let mut actual = Sha256::new();
+    let mut source_buffer = vec![0; BUFFER_SIZE];
+    let mut buffer = vec![0; BUFFER_SIZE];
+    let mut offset = 0;
+    while offset < total {
+        let len = (total - offset).min(BUFFER_SIZE as u64) as usize;
+        source.read_at(offset, &mut source_buffer[..len])?;
+        file.read_exact_at(&mut buffer[..len], offset)?;
+        expected.update(&source_buffer[..len]);
+        actual.update(&buffer[..len]);
+        offset += len as u64;
+    }
+    let expected = expected.finalize();
+    let actual = actual.finalize();
+    if actual != expected {
+        return Err(Error::ChecksumMismatch {
+            expected: hex(&expected),
+            actual: hex(&actual),
+        });
+    }
+    Ok(())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
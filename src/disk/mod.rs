@@ -0,0 +1,219 @@
+//! disk image module
+//!
+//! Utilities to create and inspect the disk images attached with
+//! [`VZDiskImageStorageDeviceAttachmentBuilder`](crate::virtualization::storage_device::VZDiskImageStorageDeviceAttachmentBuilder).
+
+pub mod clone;
+pub mod convert;
+pub mod partition;
+pub mod qcow2;
+pub mod raw;
+pub mod vhd;
+pub mod vhdx;
+pub mod vmdk;
+
+use crate::{
+    disk::{
+        convert::DiskImageReader, qcow2::Qcow2Image, raw::RawImageReader, vhd::VhdImage,
+        vhdx::VhdxImage, vmdk::VmdkImage,
+    },
+    error::{Error, Result},
+};
+
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+/// size of a sector, the size of a disk image must be a multiple of it
+pub const SECTOR_SIZE: u64 = 512;
+
+/// longest chain of backing files that is followed
+const MAX_BACKING_DEPTH: usize = 16;
+
+/// format of a disk image file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiskFormat {
+    Raw,
+    Qcow2,
+    /// VMware monolithic sparse or stream-optimized image
+    Vmdk,
+    /// Virtual PC / Hyper-V fixed or dynamic image
+    Vhd,
+    /// Hyper-V image
+    Vhdx,
+}
+
+impl DiskFormat {
+    /// name of the format as used by qemu, e.g. in qcow2 backing file format extensions
+    pub fn name(self) -> &'static str {
+        match self {
+            DiskFormat::Raw => "raw",
+            DiskFormat::Qcow2 => "qcow2",
+            DiskFormat::Vmdk => "vmdk",
+            DiskFormat::Vhd => "vpc",
+            DiskFormat::Vhdx => "vhdx",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<DiskFormat> {
+        match name {
+            "raw" => Some(DiskFormat::Raw),
+            "qcow2" => Some(DiskFormat::Qcow2),
+            "vmdk" => Some(DiskFormat::Vmdk),
+            "vpc" | "vhd" => Some(DiskFormat::Vhd),
+            "vhdx" => Some(DiskFormat::Vhdx),
+            _ => None,
+        }
+    }
+}
+
+/// format of the disk image at `path`, detected from its magic bytes whatever its extension
+///
+/// Files in no known format are raw images.
+pub fn detect_format<P: AsRef<Path>>(path: P) -> Result<DiskFormat> {
+    let file = File::open(path)?;
+    let file_len = file.metadata()?.len();
+    let mut magic = [0; 32];
+    let len = file_len.min(magic.len() as u64) as usize;
+    file.read_exact_at(&mut magic[..len], 0)?;
+    if magic.starts_with(&qcow2::QCOW2_MAGIC) {
+        return Ok(DiskFormat::Qcow2);
+    }
+    if magic.starts_with(&vmdk::VMDK_MAGIC) || magic.starts_with(vmdk::DESCRIPTOR_MAGIC) {
+        return Ok(DiskFormat::Vmdk);
+    }
+    if magic.starts_with(&vhdx::VHDX_MAGIC) {
+        return Ok(DiskFormat::Vhdx);
+    }
+    // dynamic images start with a copy of the footer, fixed ones only have it at the end
+    if magic.starts_with(&vhd::VHD_MAGIC) {
+        return Ok(DiskFormat::Vhd);
+    }
+    if file_len >= vhd::FOOTER_SIZE {
+        let mut footer = [0; 8];
+        file.read_exact_at(&mut footer, file_len - vhd::FOOTER_SIZE)?;
+        if footer == vhd::VHD_MAGIC {
+            return Ok(DiskFormat::Vhd);
+        }
+    }
+    Ok(DiskFormat::Raw)
+}
+
+/// open the disk image at `path` in its detected format
+///
+/// Only backing files next to the image or below its directory are opened.
+pub fn open_image<P: AsRef<Path>>(path: P) -> Result<(DiskFormat, Box<dyn DiskImageReader>)> {
+    let path = path.as_ref();
+    let format = detect_format(path)?;
+    Ok((
+        format,
+        open_image_with_depth(path, Some(format.name()), 0, false)?,
+    ))
+}
+
+/// open the disk image at `path`, a backing file `depth` levels below the image converted
+///
+/// Backing files named by an absolute path or through `..` are refused unless
+/// `allow_backing_files` is set.
+pub(crate) fn open_image_with_depth(
+    path: &Path,
+    format: Option<&str>,
+    depth: usize,
+    allow_backing_files: bool,
+) -> Result<Box<dyn DiskImageReader>> {
+    if depth > MAX_BACKING_DEPTH {
+        return Err(Error::Parse(format!(
+            "{}: more than {} backing files",
+            path.display(),
+            MAX_BACKING_DEPTH
+        )));
+    }
+    let format = match format {
+        Some(name) => DiskFormat::from_name(name).ok_or_else(|| {
+            Error::Parse(format!(
+                "{}: unsupported disk image format {}",
+                path.display(),
+                name
+            ))
+        })?,
+        None => detect_format(path)?,
+    };
+    Ok(match format {
+        DiskFormat::Raw => Box::new(RawImageReader::open(path)?),
+        DiskFormat::Qcow2 => Box::new(Qcow2Image::open_with_depth(
+            path,
+            depth,
+            allow_backing_files,
+        )?),
+        DiskFormat::Vmdk => Box::new(VmdkImage::open(path)?),
+        DiskFormat::Vhd => Box::new(VhdImage::open(path)?),
+        DiskFormat::Vhdx => Box::new(VhdxImage::open(path)?),
+    })
+}
+
+fn unsupported(path: &Path, feature: String) -> Error {
+    Error::Parse(format!("{}: {} is not supported", path.display(), feature))
+}
+
+/// reversed polynomial of the CRC-32 of zlib, GPT and Ethernet
+const CRC32: u32 = 0xedb8_8320;
+/// reversed polynomial of the CRC-32C (Castagnoli) of VHDX
+const CRC32C: u32 = 0x82f6_3b78;
+
+/// CRC-32 of `bytes` with the reversed `polynomial`
+fn crc32(polynomial: u32, bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (polynomial & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+fn be_u32(bytes: &[u8], offset: usize) -> u32 {
+    let mut value = [0; 4];
+    value.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_be_bytes(value)
+}
+
+fn be_u64(bytes: &[u8], offset: usize) -> u64 {
+    let mut value = [0; 8];
+    value.copy_from_slice(&bytes[offset..offset + 8]);
+    u64::from_be_bytes(value)
+}
+
+fn le_u16(bytes: &[u8], offset: usize) -> u16 {
+    let mut value = [0; 2];
+    value.copy_from_slice(&bytes[offset..offset + 2]);
+    u16::from_le_bytes(value)
+}
+
+fn le_u32(bytes: &[u8], offset: usize) -> u32 {
+    let mut value = [0; 4];
+    value.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_le_bytes(value)
+}
+
+fn le_u64(bytes: &[u8], offset: usize) -> u64 {
+    let mut value = [0; 8];
+    value.copy_from_slice(&bytes[offset..offset + 8]);
+    u64::from_le_bytes(value)
+}
+
+/// `lseek(2)` with `whence`, `None` when there is no hole or data after `offset`
+pub(crate) fn seek(file: &File, offset: u64, whence: libc::c_int) -> io::Result<Option<u64>> {
+    let position = unsafe { libc::lseek(file.as_raw_fd(), offset as libc::off_t, whence) };
+    if position >= 0 {
+        return Ok(Some(position as u64));
+    }
+    let err = io::Error::last_os_error();
+    if err.raw_os_error() == Some(libc::ENXIO) {
+        Ok(None)
+    } else {
+        Err(err)
+    }
+}
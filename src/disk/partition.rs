@@ -0,0 +1,934 @@
+//! partition table inspection module
+//!
+//! [`DiskLayout`] reads the GPT or MBR partition table of a disk image in any supported format
+//! and detects the file system of each partition from its superblock, so a blank disk can be
+//! rejected or the root partition passed to a Linux kernel before booting.
+
+use crate::{
+    disk::{convert::DiskImageReader, crc32, le_u16, le_u32, le_u64, open_image, CRC32},
+    error::{Error, Result},
+};
+
+use std::fmt;
+use std::path::Path;
+
+const GPT_SIGNATURE: [u8; 8] = *b"EFI PART";
+const GPT_MIN_HEADER_SIZE: u32 = 92;
+const GPT_MIN_ENTRY_SIZE: u32 = 128;
+/// partition entry arrays larger than this are rejected as corrupt
+const GPT_MAX_ENTRIES_SIZE: u64 = 1024 * 1024;
+/// legacy BIOS bootable attribute of a GPT partition
+const GPT_LEGACY_BIOS_BOOTABLE: u64 = 1 << 2;
+
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+const MBR_PROTECTIVE: u8 = 0xee;
+const MBR_EXTENDED: [u8; 3] = [0x05, 0x0f, 0x85];
+const MBR_ESP: u8 = 0xef;
+const MBR_LINUX: u8 = 0x83;
+/// logical partitions followed in an extended partition
+const MBR_MAX_LOGICAL: u32 = 128;
+
+/// bytes read to detect a file system, enough for every superblock except btrfs'
+const PROBE_SIZE: usize = 4096;
+const BTRFS_MAGIC_OFFSET: u64 = 0x1_0040;
+
+/// GUID as stored in GPT, with its first three fields little-endian
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Guid([u8; 16]);
+
+impl Guid {
+    /// the GUID written `data1-data2-data3-data4`
+    pub const fn from_fields(data1: u32, data2: u16, data3: u16, data4: [u8; 8]) -> Guid {
+        let a = data1.to_le_bytes();
+        let b = data2.to_le_bytes();
+        let c = data3.to_le_bytes();
+        Guid([
+            a[0], a[1], a[2], a[3], b[0], b[1], c[0], c[1], data4[0], data4[1], data4[2], data4[3],
+            data4[4], data4[5], data4[6], data4[7],
+        ])
+    }
+
+    /// the GUID stored as `bytes`
+    pub fn from_bytes(bytes: [u8; 16]) -> Guid {
+        Guid(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 16] {
+        &self.0
+    }
+
+    pub fn is_nil(&self) -> bool {
+        self.0 == [0; 16]
+    }
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let b = &self.0;
+        write!(
+            f,
+            "{:08x}-{:04x}-{:04x}-{:02x}{:02x}-{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
+            le_u32(b, 0),
+            le_u16(b, 4),
+            le_u16(b, 6),
+            b[8],
+            b[9],
+            b[10],
+            b[11],
+            b[12],
+            b[13],
+            b[14],
+            b[15]
+        )
+    }
+}
+
+impl fmt::Debug for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Guid({})", self)
+    }
+}
+
+/// GPT partition type of an EFI system partition
+pub const EFI_SYSTEM_PARTITION: Guid = Guid::from_fields(
+    0xc12a_7328,
+    0xf81f,
+    0x11d2,
+    [0xba, 0x4b, 0x00, 0xa0, 0xc9, 0x3e, 0xc9, 0x3b],
+);
+/// GPT partition type of an APFS container
+pub const APFS_CONTAINER: Guid = Guid::from_fields(
+    0x7c34_57ef,
+    0x0000,
+    0x11aa,
+    [0xaa, 0x11, 0x00, 0x30, 0x65, 0x43, 0xec, 0xac],
+);
+/// GPT partition type of Linux file systems
+pub const LINUX_FILESYSTEM: Guid = Guid::from_fields(
+    0x0fc6_3daf,
+    0x8483,
+    0x4772,
+    [0x8e, 0x79, 0x3d, 0x69, 0xd8, 0x47, 0x7d, 0xe4],
+);
+/// GPT partition type of the root file system of arm64 Linux in the Discoverable Partitions
+/// Specification
+pub const LINUX_ROOT_ARM64: Guid = Guid::from_fields(
+    0xb921_b045,
+    0x1df0,
+    0x41c3,
+    [0xaf, 0x44, 0x4c, 0x6f, 0x28, 0x0d, 0x3f, 0xae],
+);
+/// GPT partition type of the root file system of x86-64 Linux in the Discoverable Partitions
+/// Specification
+pub const LINUX_ROOT_X86_64: Guid = Guid::from_fields(
+    0x4f68_bce3,
+    0xe8cd,
+    0x4db1,
+    [0x96, 0xe7, 0xfb, 0xca, 0xf9, 0x84, 0xb7, 0x09],
+);
+/// GPT partition type of Linux swap
+pub const LINUX_SWAP: Guid = Guid::from_fields(
+    0x0657_fd6d,
+    0xa4ab,
+    0x43c4,
+    [0x84, 0xe5, 0x09, 0x33, 0xc8, 0x4b, 0x4f, 0x4f],
+);
+
+/// type of a partition
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionType {
+    Gpt(Guid),
+    /// MBR partition type byte
+    Mbr(u8),
+}
+
+impl PartitionType {
+    /// whether the partition is an EFI system partition, whatever its file system
+    pub fn is_esp(&self) -> bool {
+        match self {
+            PartitionType::Gpt(guid) => *guid == EFI_SYSTEM_PARTITION,
+            PartitionType::Mbr(kind) => *kind == MBR_ESP,
+        }
+    }
+
+    /// description of well-known types
+    pub fn description(&self) -> Option<&'static str> {
+        match self {
+            PartitionType::Gpt(guid) => match *guid {
+                EFI_SYSTEM_PARTITION => Some("EFI system partition"),
+                APFS_CONTAINER => Some("APFS container"),
+                LINUX_FILESYSTEM => Some("Linux file system"),
+                LINUX_ROOT_ARM64 => Some("Linux root (arm64)"),
+                LINUX_ROOT_X86_64 => Some("Linux root (x86-64)"),
+                LINUX_SWAP => Some("Linux swap"),
+                _ => None,
+            },
+            PartitionType::Mbr(kind) => match *kind {
+                0x01 | 0x04 | 0x06 | 0x0b | 0x0c | 0x0e => Some("FAT"),
+                0x07 => Some("NTFS or exFAT"),
+                0x82 => Some("Linux swap"),
+                MBR_LINUX => Some("Linux"),
+                0xaf => Some("HFS+"),
+                MBR_ESP => Some("EFI system partition"),
+                _ => None,
+            },
+        }
+    }
+}
+
+impl fmt::Display for PartitionType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PartitionType::Gpt(guid) => write!(f, "{}", guid),
+            PartitionType::Mbr(kind) => write!(f, "{:#04x}", kind),
+        }
+    }
+}
+
+/// file system detected from its superblock
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Filesystem {
+    /// FAT12, FAT16 or FAT32, the file system of EFI system partitions
+    Fat,
+    Ntfs,
+    /// APFS container
+    Apfs,
+    HfsPlus,
+    Ext2,
+    Ext3,
+    Ext4,
+    Xfs,
+    Btrfs,
+    Swap,
+}
+
+impl Filesystem {
+    /// whether Linux can boot from it as its root file system
+    pub fn is_linux_root(self) -> bool {
+        matches!(
+            self,
+            Filesystem::Ext2
+                | Filesystem::Ext3
+                | Filesystem::Ext4
+                | Filesystem::Xfs
+                | Filesystem::Btrfs
+        )
+    }
+}
+
+/// partitioning scheme of a disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionScheme {
+    Gpt { disk_guid: Guid },
+    Mbr { disk_signature: u32 },
+}
+
+/// partition of a disk
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Partition {
+    /// number of the partition as Linux names it, from 1, logical MBR partitions from 5
+    pub number: u32,
+    pub partition_type: PartitionType,
+    /// unique GUID of a GPT partition
+    pub guid: Option<Guid>,
+    /// name of a GPT partition
+    pub name: String,
+    /// offset on the disk in bytes
+    pub start: u64,
+    /// size in bytes
+    pub size: u64,
+    /// active flag of MBR or legacy BIOS bootable attribute of GPT
+    pub bootable: bool,
+    pub filesystem: Option<Filesystem>,
+}
+
+impl Partition {
+    /// whether the partition is an EFI system partition, see [`PartitionType::is_esp`]
+    pub fn is_esp(&self) -> bool {
+        self.partition_type.is_esp()
+    }
+}
+
+/// partitions and file systems of a disk
+///
+/// # Examples
+/// ```rust,no_run
+/// use virtualization_rs::disk::partition::DiskLayout;
+///
+/// let layout = DiskLayout::read("disk.img").unwrap();
+/// if layout.is_blank() {
+///     panic!("disk.img is blank");
+/// }
+/// for partition in &layout.partitions {
+///     println!(
+///         "{} {} {:?} {} bytes {:?}",
+///         partition.number, partition.partition_type, partition.name, partition.size,
+///         partition.filesystem
+///     );
+/// }
+/// // e.g. root=PARTUUID=6e4a8b2f-...
+/// let command_line = format!("console=hvc0 {}", layout.root_argument().unwrap());
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiskLayout {
+    /// size of the disk in bytes
+    pub size: u64,
+    /// sector size the partition table was found with
+    pub sector_size: u64,
+    pub scheme: Option<PartitionScheme>,
+    /// partitions in the order of their numbers
+    pub partitions: Vec<Partition>,
+    /// file system of the whole disk when it is not partitioned
+    pub filesystem: Option<Filesystem>,
+}
+
+impl DiskLayout {
+    /// read the layout of the disk image at `path`, detecting its format
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<DiskLayout> {
+        let (_, mut reader) = open_image(path)?;
+        DiskLayout::from_reader(reader.as_mut())
+    }
+
+    pub fn from_reader(reader: &mut dyn DiskImageReader) -> Result<DiskLayout> {
+        let size = reader.virtual_size();
+        let mut layout = DiskLayout {
+            size,
+            sector_size: 512,
+            scheme: None,
+            partitions: Vec::new(),
+            filesystem: None,
+        };
+
+        // 4 KiB sector disks keep the GPT header in their second 4 KiB sector
+        for &sector_size in &[512, 4096] {
+            if let Some((disk_guid, partitions)) = read_gpt(reader, sector_size)? {
+                layout.sector_size = sector_size;
+                layout.scheme = Some(PartitionScheme::Gpt { disk_guid });
+                layout.partitions = partitions;
+                break;
+            }
+        }
+        if layout.scheme.is_none() {
+            // a FAT or NTFS boot sector ends with the MBR signature too
+            layout.filesystem = probe(reader, 0, size)?;
+            if layout.filesystem.is_none() {
+                if let Some((disk_signature, partitions)) = read_mbr(reader)? {
+                    layout.scheme = Some(PartitionScheme::Mbr { disk_signature });
+                    layout.partitions = partitions;
+                }
+            }
+        }
+
+        for partition in &mut layout.partitions {
+            partition.filesystem = probe(reader, partition.start, partition.size)?;
+        }
+        Ok(layout)
+    }
+
+    /// whether the disk holds neither partitions nor a file system
+    pub fn is_blank(&self) -> bool {
+        self.partitions.is_empty() && self.filesystem.is_none()
+    }
+
+    pub fn partition(&self, number: u32) -> Option<&Partition> {
+        self.partitions
+            .iter()
+            .find(|partition| partition.number == number)
+    }
+
+    /// the first EFI system partition
+    pub fn esp(&self) -> Option<&Partition> {
+        self.partitions.iter().find(|partition| partition.is_esp())
+    }
+
+    /// the partition Linux most likely boots from
+    ///
+    /// That is the partition typed as an arm64 or x86-64 root file system, or else the largest
+    /// partition holding a file system Linux can boot from.
+    pub fn root_partition(&self) -> Option<&Partition> {
+        for root_type in &[LINUX_ROOT_ARM64, LINUX_ROOT_X86_64] {
+            let root = self
+                .partitions
+                .iter()
+                .find(|partition| partition.partition_type == PartitionType::Gpt(*root_type));
+            if root.is_some() {
+                return root;
+            }
+        }
+        self.partitions
+            .iter()
+            .filter(|partition| {
+                !partition.is_esp()
+                    && partition
+                        .filesystem
+                        .is_some_and(|filesystem| filesystem.is_linux_root())
+            })
+            .max_by_key(|partition| partition.size)
+    }
+
+    /// `PARTUUID` Linux identifies `partition` with
+    pub fn part_uuid(&self, partition: &Partition) -> Option<String> {
+        match (self.scheme?, partition.guid) {
+            (PartitionScheme::Gpt { .. }, Some(guid)) => Some(guid.to_string()),
+            (PartitionScheme::Mbr { disk_signature }, _) => {
+                Some(format!("{:08x}-{:02x}", disk_signature, partition.number))
+            }
+            _ => None,
+        }
+    }
+
+    /// `root=PARTUUID=...` argument of the kernel command line for the root partition
+    pub fn root_argument(&self) -> Option<String> {
+        let partuuid = self.part_uuid(self.root_partition()?)?;
+        Some(format!("root=PARTUUID={}", partuuid))
+    }
+}
+
+fn read(reader: &mut dyn DiskImageReader, offset: u64, len: usize) -> Result<Vec<u8>> {
+    // offsets come from the partition table and may be hostile
+    if offset.checked_add(len as u64).is_none() {
+        return Err(Error::Parse(format!(
+            "{} bytes at offset {} are out of range",
+            len, offset
+        )));
+    }
+    let mut buf = vec![0; len];
+    reader.read_at(offset, &mut buf)?;
+    Ok(buf)
+}
+
+/// disk GUID and partitions of the GPT, from the primary header or else the backup one
+fn read_gpt(
+    reader: &mut dyn DiskImageReader,
+    sector_size: u64,
+) -> Result<Option<(Guid, Vec<Partition>)>> {
+    let sectors = reader.virtual_size() / sector_size;
+    if sectors < 3 {
+        return Ok(None);
+    }
+    let mut found = false;
+    for &lba in &[1, sectors - 1] {
+        let header = read(reader, lba * sector_size, sector_size as usize)?;
+        if header[..8] != GPT_SIGNATURE {
+            continue;
+        }
+        found = true;
+        if let Some(gpt) = parse_gpt(reader, &header, sector_size)? {
+            return Ok(Some(gpt));
+        }
+    }
+    if found {
+        return Err(Error::Parse(
+            "corrupt GPT: no valid header and partition entries".to_string(),
+        ));
+    }
+    Ok(None)
+}
+
+/// the partitions of a GPT header, `None` if a checksum does not match
+fn parse_gpt(
+    reader: &mut dyn DiskImageReader,
+    header: &[u8],
+    sector_size: u64,
+) -> Result<Option<(Guid, Vec<Partition>)>> {
+    let header_size = le_u32(header, 12);
+    if header_size < GPT_MIN_HEADER_SIZE || u64::from(header_size) > sector_size {
+        return Ok(None);
+    }
+    let mut zeroed = header[..header_size as usize].to_vec();
+    zeroed[16..20].fill(0);
+    if crc32(CRC32, &zeroed) != le_u32(header, 16) {
+        return Ok(None);
+    }
+
+    let mut disk_guid = [0; 16];
+    disk_guid.copy_from_slice(&header[56..72]);
+    let entries_lba = le_u64(header, 72);
+    let entry_count = le_u32(header, 80);
+    let entry_size = le_u32(header, 84);
+    let entries_size = u64::from(entry_count) * u64::from(entry_size);
+    if entry_size < GPT_MIN_ENTRY_SIZE
+        || entry_size % 8 != 0
+        || entries_size > GPT_MAX_ENTRIES_SIZE
+    {
+        return Ok(None);
+    }
+    let entries_offset = gpt_offset(entries_lba, sector_size)?;
+    let entries = read(reader, entries_offset, entries_size as usize)?;
+    if crc32(CRC32, &entries) != le_u32(header, 88) {
+        return Ok(None);
+    }
+
+    let mut partitions = Vec::new();
+    for (index, entry) in entries.chunks_exact(entry_size as usize).enumerate() {
+        let mut type_guid = [0; 16];
+        type_guid.copy_from_slice(&entry[..16]);
+        let type_guid = Guid(type_guid);
+        if type_guid.is_nil() {
+            continue;
+        }
+        let mut guid = [0; 16];
+        guid.copy_from_slice(&entry[16..32]);
+        let first_lba = le_u64(entry, 32);
+        let last_lba = le_u64(entry, 40);
+        if last_lba < first_lba {
+            continue;
+        }
+        let sectors = (last_lba - first_lba).checked_add(1).ok_or_else(|| {
+            Error::Parse(format!(
+                "corrupt GPT: partition {} covers every LBA",
+                index + 1
+            ))
+        })?;
+        let name: Vec<u16> = entry[56..128]
+            .chunks_exact(2)
+            .map(|unit| le_u16(unit, 0))
+            .take_while(|&unit| unit != 0)
+            .collect();
+        partitions.push(Partition {
+            number: index as u32 + 1,
+            partition_type: PartitionType::Gpt(type_guid),
+            guid: Some(Guid(guid)),
+            name: String::from_utf16_lossy(&name),
+            start: gpt_offset(first_lba, sector_size)?,
+            size: gpt_offset(sectors, sector_size)?,
+            bootable: le_u64(entry, 48) & GPT_LEGACY_BIOS_BOOTABLE != 0,
+            filesystem: None,
+        });
+    }
+    Ok(Some((Guid(disk_guid), partitions)))
+}
+
+/// byte offset of `lba`, which a corrupt GPT may place past any disk
+fn gpt_offset(lba: u64, sector_size: u64) -> Result<u64> {
+    lba.checked_mul(sector_size)
+        .ok_or_else(|| Error::Parse(format!("corrupt GPT: LBA {} is out of range", lba)))
+}
+
+/// disk signature and partitions of the MBR, including the logical partitions
+fn read_mbr(reader: &mut dyn DiskImageReader) -> Result<Option<(u32, Vec<Partition>)>> {
+    let mbr = read(reader, 0, 512)?;
+    if mbr[510..] != MBR_SIGNATURE {
+        return Ok(None);
+    }
+    let entries: Vec<&[u8]> = mbr[446..510].chunks_exact(16).collect();
+    if entries
+        .iter()
+        .any(|entry| entry[0] != 0 && entry[0] != 0x80)
+        || entries.iter().all(|entry| entry[4] == 0)
+    {
+        return Ok(None);
+    }
+
+    let mut partitions = Vec::new();
+    for (index, entry) in entries.iter().enumerate() {
+        let kind = entry[4];
+        let start = u64::from(le_u32(entry, 8));
+        let sectors = u64::from(le_u32(entry, 12));
+        if kind == 0 || kind == MBR_PROTECTIVE || sectors == 0 {
+            continue;
+        }
+        if MBR_EXTENDED.contains(&kind) {
+            read_logical_partitions(reader, start, &mut partitions)?;
+            continue;
+        }
+        partitions.push(mbr_partition(index as u32 + 1, entry, 0));
+    }
+    partitions.sort_by_key(|partition| partition.number);
+    Ok(Some((le_u32(&mbr, 440), partitions)))
+}
+
+/// follow the chain of extended boot records of the extended partition at `extended_start`
+fn read_logical_partitions(
+    reader: &mut dyn DiskImageReader,
+    extended_start: u64,
+    partitions: &mut Vec<Partition>,
+) -> Result<()> {
+    let mut ebr_lba = extended_start;
+    for number in 5..5 + MBR_MAX_LOGICAL {
+        let ebr = read(reader, ebr_lba * 512, 512)?;
+        if ebr[510..] != MBR_SIGNATURE {
+            break;
+        }
+        let logical = &ebr[446..462];
+        if logical[4] != 0 && le_u32(logical, 12) != 0 {
+            partitions.push(mbr_partition(number, logical, ebr_lba));
+        }
+        let next = &ebr[462..478];
+        if next[4] == 0 || le_u32(next, 8) == 0 {
+            break;
+        }
+        ebr_lba = extended_start + u64::from(le_u32(next, 8));
+    }
+    Ok(())
+}
+
+fn mbr_partition(number: u32, entry: &[u8], base_lba: u64) -> Partition {
+    Partition {
+        number,
+        partition_type: PartitionType::Mbr(entry[4]),
+        guid: None,
+        name: String::new(),
+        start: (base_lba + u64::from(le_u32(entry, 8))) * 512,
+        size: u64::from(le_u32(entry, 12)) * 512,
+        bootable: entry[0] == 0x80,
+        filesystem: None,
+    }
+}
+
+/// the file system starting at `offset`, from the magic numbers of its superblock
+fn probe(reader: &mut dyn DiskImageReader, offset: u64, size: u64) -> Result<Option<Filesystem>> {
+    if size < 1024 {
+        return Ok(None);
+    }
+    let block = read(reader, offset, PROBE_SIZE)?;
+    if &block[32..36] == b"NXSB" {
+        return Ok(Some(Filesystem::Apfs));
+    }
+    if block[1080..1082] == [0x53, 0xef] {
+        return Ok(Some(ext_version(&block[1024..2048])));
+    }
+    if &block[..4] == b"XFSB" {
+        return Ok(Some(Filesystem::Xfs));
+    }
+    if &block[1024..1026] == b"H+" || &block[1024..1026] == b"HX" {
+        return Ok(Some(Filesystem::HfsPlus));
+    }
+    if &block[3..11] == b"NTFS    " {
+        return Ok(Some(Filesystem::Ntfs));
+    }
+    if block[510..512] == MBR_SIGNATURE
+        && (&block[54..59] == b"FAT12" || &block[54..59] == b"FAT16" || &block[82..87] == b"FAT32")
+    {
+        return Ok(Some(Filesystem::Fat));
+    }
+    if size >= PROBE_SIZE as u64 && &block[PROBE_SIZE - 10..] == b"SWAPSPACE2" {
+        return Ok(Some(Filesystem::Swap));
+    }
+    if size >= BTRFS_MAGIC_OFFSET + 8 {
+        let magic_offset = offset.checked_add(BTRFS_MAGIC_OFFSET).ok_or_else(|| {
+            Error::Parse(format!("partition at offset {} is out of range", offset))
+        })?;
+        if read(reader, magic_offset, 8)? == b"_BHRfS_M" {
+            return Ok(Some(Filesystem::Btrfs));
+        }
+    }
+    Ok(None)
+}
+
+/// ext2, ext3 or ext4 from the features of `superblock`
+fn ext_version(superblock: &[u8]) -> Filesystem {
+    const COMPAT_HAS_JOURNAL: u32 = 0x4;
+    // extents, 64bit, flex_bg
+    const INCOMPAT_EXT4: u32 = 0x40 | 0x80 | 0x200;
+    // huge_file, gdt_csum, dir_nlink, extra_isize, metadata_csum
+    const RO_COMPAT_EXT4: u32 = 0x8 | 0x10 | 0x20 | 0x40 | 0x400;
+    let compat = le_u32(superblock, 0x5c);
+    let incompat = le_u32(superblock, 0x60);
+    let ro_compat = le_u32(superblock, 0x64);
+    if incompat & INCOMPAT_EXT4 != 0 || ro_compat & RO_COMPAT_EXT4 != 0 {
+        Filesystem::Ext4
+    } else if compat & COMPAT_HAS_JOURNAL != 0 {
+        Filesystem::Ext3
+    } else {
+        Filesystem::Ext2
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECTOR: usize = 512;
+    const DISK_SECTORS: usize = 4096;
+    const ENTRIES_LBA: u64 = 2;
+    const ENTRY_COUNT: usize = 128;
+
+    const DISK_GUID: Guid =
+        Guid::from_fields(0x1234_5678, 0x9abc, 0xdef0, [1, 2, 3, 4, 5, 6, 7, 8]);
+    const ROOT_GUID: Guid = Guid::from_fields(0x6e4a_8b2f, 0x1111, 0x2222, [3; 8]);
+
+    /// disk image held in memory
+    struct Memory(Vec<u8>);
+
+    impl DiskImageReader for Memory {
+        fn virtual_size(&self) -> u64 {
+            self.0.len() as u64
+        }
+
+        fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<bool> {
+            buf.fill(0);
+            if offset < self.0.len() as u64 {
+                let start = offset as usize;
+                let len = buf.len().min(self.0.len() - start);
+                buf[..len].copy_from_slice(&self.0[start..start + len]);
+            }
+            Ok(true)
+        }
+    }
+
+    fn layout(disk: Vec<u8>) -> Result<DiskLayout> {
+        DiskLayout::from_reader(&mut Memory(disk))
+    }
+
+    fn put_u32(disk: &mut [u8], offset: usize, value: u32) {
+        disk[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn put_u64(disk: &mut [u8], offset: usize, value: u64) {
+        disk[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn mbr_entry(disk: &mut [u8], offset: usize, active: bool, kind: u8, start: u32, sectors: u32) {
+        disk[offset] = if active { 0x80 } else { 0 };
+        disk[offset + 4] = kind;
+        put_u32(disk, offset + 8, start);
+        put_u32(disk, offset + 12, sectors);
+    }
+
+    fn fat(disk: &mut [u8], lba: usize) {
+        let offset = lba * SECTOR;
+        disk[offset + 82..offset + 87].copy_from_slice(b"FAT32");
+        disk[offset + 510..offset + 512].copy_from_slice(&MBR_SIGNATURE);
+    }
+
+    fn ext4(disk: &mut [u8], lba: usize) {
+        let superblock = lba * SECTOR + 1024;
+        disk[superblock + 56..superblock + 58].copy_from_slice(&[0x53, 0xef]);
+        put_u32(disk, superblock + 0x60, 0x40);
+    }
+
+    /// GPT header at `lba` for the partition entries at `entries_lba`
+    fn gpt_header(disk: &mut [u8], lba: u64, entries_lba: u64) {
+        let entries_offset = ENTRIES_LBA as usize * SECTOR;
+        let entries_crc = crc32(
+            CRC32,
+            &disk[entries_offset..entries_offset + ENTRY_COUNT * 128],
+        );
+        let offset = lba as usize * SECTOR;
+        let header = &mut disk[offset..offset + SECTOR];
+        header.fill(0);
+        header[..8].copy_from_slice(&GPT_SIGNATURE);
+        put_u32(header, 12, GPT_MIN_HEADER_SIZE);
+        put_u64(header, 24, lba);
+        header[56..72].copy_from_slice(DISK_GUID.as_bytes());
+        put_u64(header, 72, entries_lba);
+        put_u32(header, 80, ENTRY_COUNT as u32);
+        put_u32(header, 84, 128);
+        put_u32(header, 88, entries_crc);
+        let crc = crc32(CRC32, &header[..GPT_MIN_HEADER_SIZE as usize]);
+        put_u32(header, 16, crc);
+    }
+
+    /// protective MBR, GPT with `partitions` as type, first and last LBA, and its backup header
+    fn gpt_disk(partitions: &[(Guid, u64, u64)]) -> Vec<u8> {
+        let mut disk = vec![0; DISK_SECTORS * SECTOR];
+        mbr_entry(
+            &mut disk,
+            446,
+            false,
+            MBR_PROTECTIVE,
+            1,
+            DISK_SECTORS as u32 - 1,
+        );
+        disk[510..512].copy_from_slice(&MBR_SIGNATURE);
+        for (index, (partition_type, first_lba, last_lba)) in partitions.iter().enumerate() {
+            let offset = ENTRIES_LBA as usize * SECTOR + index * 128;
+            let entry = &mut disk[offset..offset + 128];
+            entry[..16].copy_from_slice(partition_type.as_bytes());
+            let guid = if *partition_type == LINUX_FILESYSTEM {
+                ROOT_GUID
+            } else {
+                Guid::from_fields(index as u32, 0, 0, [0; 8])
+            };
+            entry[16..32].copy_from_slice(guid.as_bytes());
+            put_u64(entry, 32, *first_lba);
+            put_u64(entry, 40, *last_lba);
+            for (unit, c) in "part".encode_utf16().enumerate() {
+                entry[56 + 2 * unit..58 + 2 * unit].copy_from_slice(&c.to_le_bytes());
+            }
+        }
+        gpt_header(&mut disk, 1, ENTRIES_LBA);
+        gpt_header(&mut disk, DISK_SECTORS as u64 - 1, ENTRIES_LBA);
+        disk
+    }
+
+    /// GPT with a FAT ESP and an ext4 Linux partition
+    fn standard_gpt_disk() -> Vec<u8> {
+        let mut disk = gpt_disk(&[
+            (EFI_SYSTEM_PARTITION, 34, 99),
+            (LINUX_FILESYSTEM, 100, 3999),
+        ]);
+        fat(&mut disk, 34);
+        ext4(&mut disk, 100);
+        disk
+    }
+
+    #[test]
+    fn reads_gpt_partitions_and_file_systems() {
+        let disk_layout = layout(standard_gpt_disk()).unwrap();
+        assert_eq!(
+            disk_layout.scheme,
+            Some(PartitionScheme::Gpt {
+                disk_guid: DISK_GUID
+            })
+        );
+        assert_eq!(disk_layout.partitions.len(), 2);
+
+        let esp = disk_layout.esp().unwrap();
+        assert_eq!((esp.number, esp.start, esp.size), (1, 34 * 512, 66 * 512));
+        assert_eq!(esp.filesystem, Some(Filesystem::Fat));
+        assert_eq!(esp.name, "part");
+
+        let root = disk_layout.root_partition().unwrap();
+        assert_eq!(root.number, 2);
+        assert_eq!(root.filesystem, Some(Filesystem::Ext4));
+        assert_eq!(
+            disk_layout.root_argument().unwrap(),
+            format!("root=PARTUUID={}", ROOT_GUID)
+        );
+        assert!(!disk_layout.is_blank());
+    }
+
+    #[test]
+    fn falls_back_to_the_backup_gpt_header() {
+        let mut disk = standard_gpt_disk();
+        disk[SECTOR + 56] ^= 0xff;
+        assert_eq!(layout(disk).unwrap().partitions.len(), 2);
+
+        let mut disk = standard_gpt_disk();
+        disk[SECTOR + 56] ^= 0xff;
+        disk[(DISK_SECTORS - 1) * SECTOR + 56] ^= 0xff;
+        assert!(matches!(layout(disk), Err(Error::Parse(_))));
+    }
+
+    #[test]
+    fn rejects_hostile_gpt() {
+        // partition entries past the end of the address space
+        let mut disk = standard_gpt_disk();
+        gpt_header(&mut disk, 1, 1 << 60);
+        gpt_header(&mut disk, DISK_SECTORS as u64 - 1, 1 << 60);
+        assert!(matches!(layout(disk), Err(Error::Parse(_))));
+
+        let hostile = [
+            (u64::MAX / 256, u64::MAX / 256),
+            (0, u64::MAX),
+            (1, u64::MAX),
+            // the start fits but not the superblocks read from it
+            (u64::MAX / 512 - 1, u64::MAX / 512),
+        ];
+        for (first_lba, last_lba) in hostile.iter() {
+            let disk = gpt_disk(&[(LINUX_FILESYSTEM, *first_lba, *last_lba)]);
+            assert!(
+                matches!(layout(disk), Err(Error::Parse(_))),
+                "LBA {}..={} was accepted",
+                first_lba,
+                last_lba
+            );
+        }
+
+        // reversed partitions are skipped
+        let disk = gpt_disk(&[(LINUX_FILESYSTEM, 200, 100)]);
+        assert!(layout(disk).unwrap().partitions.is_empty());
+    }
+
+    /// MBR with a Linux partition and an extended partition holding two logical ones
+    fn mbr_disk() -> Vec<u8> {
+        let mut disk = vec![0; 2048 * SECTOR];
+        put_u32(&mut disk, 440, 0xdead_beef);
+        mbr_entry(&mut disk, 446, true, MBR_LINUX, 64, 512);
+        mbr_entry(&mut disk, 462, false, MBR_EXTENDED[0], 1024, 1024);
+        disk[510..512].copy_from_slice(&MBR_SIGNATURE);
+        ext4(&mut disk, 64);
+
+        // each extended boot record describes a logical partition relative to itself and the
+        // next record relative to the extended partition
+        for (ebr_lba, next) in [(1024, 512), (1536, 0)].iter() {
+            let offset = ebr_lba * SECTOR;
+            mbr_entry(&mut disk, offset + 446, false, MBR_LINUX, 64, 128);
+            if *next != 0 {
+                mbr_entry(&mut disk, offset + 462, false, MBR_EXTENDED[0], *next, 512);
+            }
+            disk[offset + 510..offset + 512].copy_from_slice(&MBR_SIGNATURE);
+        }
+        fat(&mut disk, 1536 + 64);
+        disk
+    }
+
+    #[test]
+    fn reads_mbr_and_logical_partitions() {
+        let disk_layout = layout(mbr_disk()).unwrap();
+        assert_eq!(
+            disk_layout.scheme,
+            Some(PartitionScheme::Mbr {
+                disk_signature: 0xdead_beef
+            })
+        );
+        let partitions: Vec<_> = disk_layout
+            .partitions
+            .iter()
+            .map(|partition| {
+                (
+                    partition.number,
+                    partition.start / 512,
+                    partition.size / 512,
+                    partition.filesystem,
+                )
+            })
+            .collect();
+        assert_eq!(
+            partitions,
+            [
+                (1, 64, 512, Some(Filesystem::Ext4)),
+                (5, 1088, 128, None),
+                (6, 1600, 128, Some(Filesystem::Fat)),
+            ]
+        );
+        assert!(disk_layout.partitions[0].bootable);
+        assert_eq!(
+            disk_layout.root_argument().unwrap(),
+            "root=PARTUUID=deadbeef-01"
+        );
+    }
+
+    #[test]
+    fn stops_following_hostile_extended_boot_records() {
+        // a record pointing back at itself
+        let mut disk = mbr_disk();
+        mbr_entry(
+            &mut disk,
+            1536 * SECTOR + 462,
+            false,
+            MBR_EXTENDED[0],
+            512,
+            512,
+        );
+        let logical = layout(disk)
+            .unwrap()
+            .partitions
+            .iter()
+            .filter(|partition| partition.number >= 5)
+            .count();
+        assert_eq!(logical, MBR_MAX_LOGICAL as usize);
+
+        // partitions past the end of the disk have no file system
+        let mut disk = mbr_disk();
+        mbr_entry(&mut disk, 462, false, MBR_EXTENDED[0], u32::MAX, u32::MAX);
+        mbr_entry(&mut disk, 478, false, MBR_LINUX, u32::MAX, u32::MAX);
+        let disk_layout = layout(disk).unwrap();
+        let last = disk_layout.partition(3).unwrap();
+        assert_eq!(last.start, u64::from(u32::MAX) * 512);
+        assert_eq!(last.filesystem, None);
+    }
+
+    #[test]
+    fn unpartitioned_disks() {
+        assert!(layout(vec![0; 64 * SECTOR]).unwrap().is_blank());
+
+        let mut disk = vec![0; 64 * SECTOR];
+        ext4(&mut disk, 0);
+        let disk_layout = layout(disk).unwrap();
+        assert_eq!(disk_layout.scheme, None);
+        assert_eq!(disk_layout.filesystem, Some(Filesystem::Ext4));
+    }
+}
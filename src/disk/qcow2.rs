@@ -0,0 +1,649 @@
+//! qcow2 disk image module
+//!
+//! Reads version 2 and 3 qcow2 images, the format of most Linux cloud images, including
+//! zlib-compressed clusters, zero clusters and backing files. Encrypted images, external data
+//! files, zstd compression and extended L2 entries are not supported.
+
+use crate::{
+    disk::{be_u32, be_u64, convert::DiskImageReader, open_image_with_depth, unsupported},
+    error::{Error, Result},
+};
+
+use flate2::read::DeflateDecoder;
+use std::fs::File;
+use std::io::Read;
+use std::os::unix::fs::FileExt;
+use std::path::{Component, Path, PathBuf};
+
+/// first bytes of a qcow2 image
+pub const QCOW2_MAGIC: [u8; 4] = *b"QFI\xfb";
+
+const V2_HEADER_LENGTH: u32 = 72;
+const V3_HEADER_LENGTH: u32 = 104;
+
+const INCOMPATIBLE_DIRTY: u64 = 1 << 0;
+const INCOMPATIBLE_CORRUPT: u64 = 1 << 1;
+const INCOMPATIBLE_EXTERNAL_DATA_FILE: u64 = 1 << 2;
+const INCOMPATIBLE_COMPRESSION_TYPE: u64 = 1 << 3;
+const INCOMPATIBLE_EXTENDED_L2: u64 = 1 << 4;
+
+const EXTENSION_END: u32 = 0;
+const EXTENSION_BACKING_FORMAT: u32 = 0xe279_2aca;
+
+const L1_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+const L2_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+const L2_COMPRESSED: u64 = 1 << 62;
+const L2_ZERO: u64 = 1;
+
+/// L1 tables larger than this are rejected as corrupt
+const MAX_L1_SIZE: u64 = 32 * 1024 * 1024;
+
+/// header fields of a qcow2 image
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Qcow2Header {
+    /// 2 or 3
+    pub version: u32,
+    pub cluster_bits: u32,
+    /// size of the disk seen by the guest in bytes
+    pub size: u64,
+    /// name of the backing file as stored in the image
+    pub backing_file: Option<String>,
+    /// format of the backing file, e.g. `raw` or `qcow2`, when the image records it
+    pub backing_format: Option<String>,
+    pub l1_size: u32,
+    pub l1_table_offset: u64,
+    pub incompatible_features: u64,
+}
+
+impl Qcow2Header {
+    pub fn cluster_size(&self) -> u64 {
+        1 << self.cluster_bits
+    }
+
+    /// whether the image was not closed cleanly, its refcounts may be wrong but its data is intact
+    pub fn is_dirty(&self) -> bool {
+        self.incompatible_features & INCOMPATIBLE_DIRTY != 0
+    }
+}
+
+/// cluster of the disk as described by its L2 entry
+enum Cluster {
+    /// not allocated in this image, read from the backing file
+    Unallocated,
+    Zero,
+    Data(u64),
+    /// host offset and length of the compressed data
+    Compressed(u64, u64),
+}
+
+/// qcow2 disk image
+///
+/// # Examples
+/// ```rust,no_run
+/// use virtualization_rs::disk::convert::DiskImageReader;
+/// use virtualization_rs::disk::qcow2::Qcow2Image;
+///
+/// let mut image = Qcow2Image::open("jammy-server-cloudimg-arm64.img").unwrap();
+/// println!("{} bytes, backing file {:?}", image.virtual_size(), image.backing_file());
+/// let mut mbr = [0; 512];
+/// image.read_at(0, &mut mbr).unwrap();
+/// ```
+pub struct Qcow2Image {
+    file: File,
+    path: PathBuf,
+    header: Qcow2Header,
+    l1_table: Vec<u64>,
+    backing: Option<Box<dyn DiskImageReader>>,
+    /// index in the L1 table and contents of the last L2 table read
+    l2_cache: Option<(usize, Vec<u64>)>,
+    /// host offset and contents of the last compressed cluster read
+    compressed_cache: Option<(u64, Vec<u8>)>,
+}
+
+impl Qcow2Image {
+    /// open the qcow2 image at `path` and its backing files
+    ///
+    /// A backing file must be named relative to the directory of the image without `..`, an
+    /// untrusted image could otherwise read any file of the host into the disk. See
+    /// [`ConvertBuilder::allow_backing_files`](crate::disk::convert::ConvertBuilder::allow_backing_files)
+    /// to follow other backing files.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Qcow2Image> {
+        Self::open_with_depth(path.as_ref(), 0, false)
+    }
+
+    pub(crate) fn open_with_depth(
+        path: &Path,
+        depth: usize,
+        allow_backing_files: bool,
+    ) -> Result<Qcow2Image> {
+        let file = File::open(path)?;
+        let header = read_header(&file, path)?;
+
+        let cluster_size = header.cluster_size();
+        let l2_entries = cluster_size / 8;
+        let needed = header.size.div_ceil(cluster_size).div_ceil(l2_entries);
+        if u64::from(header.l1_size) < needed || u64::from(header.l1_size) * 8 > MAX_L1_SIZE {
+            return Err(corrupt(
+                path,
+                format!("L1 table of {} entries", header.l1_size),
+            ));
+        }
+        let mut l1_table = vec![0; header.l1_size as usize * 8];
+        file.read_exact_at(&mut l1_table, header.l1_table_offset)?;
+        let l1_table = l1_table
+            .chunks_exact(8)
+            .map(|entry| be_u64(entry, 0) & L1_OFFSET_MASK)
+            .collect();
+
+        let backing = match &header.backing_file {
+            Some(name) => {
+                if !allow_backing_files && !is_contained(name) {
+                    return Err(Error::Parse(format!(
+                        "{}: backing file {} is outside the directory of the image",
+                        path.display(),
+                        name
+                    )));
+                }
+                let backing_path = resolve_backing_file(path, name);
+                Some(open_image_with_depth(
+                    &backing_path,
+                    header.backing_format.as_deref(),
+                    depth + 1,
+                    allow_backing_files,
+                )?)
+            }
+            None => None,
+        };
+
+        Ok(Qcow2Image {
+            file,
+            path: path.to_path_buf(),
+            header,
+            l1_table,
+            backing,
+            l2_cache: None,
+            compressed_cache: None,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn header(&self) -> &Qcow2Header {
+        &self.header
+    }
+
+    /// path of the backing file, relative names are resolved against the directory of the image
+    pub fn backing_file(&self) -> Option<PathBuf> {
+        self.header
+            .backing_file
+            .as_ref()
+            .map(|name| resolve_backing_file(&self.path, name))
+    }
+
+    /// the cluster containing the disk offset `offset`
+    fn cluster(&mut self, offset: u64) -> Result<Cluster> {
+        let cluster_bits = self.header.cluster_bits;
+        let l2_entries = 1usize << (cluster_bits - 3);
+        let index = (offset >> cluster_bits) as usize;
+        let l1_index = index / l2_entries;
+        let l2_index = index % l2_entries;
+
+        let l2_offset = match self.l1_table.get(l1_index) {
+            Some(0) | None => return Ok(Cluster::Unallocated),
+            Some(l2_offset) => *l2_offset,
+        };
+        let cached = matches!(&self.l2_cache, Some((cached, _)) if *cached == l1_index);
+        if !cached {
+            let mut table = vec![0; l2_entries * 8];
+            self.file.read_exact_at(&mut table, l2_offset)?;
+            let table = table
+                .chunks_exact(8)
+                .map(|entry| be_u64(entry, 0))
+                .collect();
+            self.l2_cache = Some((l1_index, table));
+        }
+        let entry = match &self.l2_cache {
+            Some((_, table)) => table[l2_index],
+            None => unreachable!(),
+        };
+
+        if entry & L2_COMPRESSED != 0 {
+            let offset_bits = 62 - (cluster_bits - 8);
+            let host_offset = entry & ((1 << offset_bits) - 1);
+            let sectors = (entry & ((1 << 62) - 1)) >> offset_bits;
+            let len = (sectors + 1) * 512 - (host_offset & 511);
+            return Ok(Cluster::Compressed(host_offset, len));
+        }
+        if self.header.version >= 3 && entry & L2_ZERO != 0 {
+            return Ok(Cluster::Zero);
+        }
+        match entry & L2_OFFSET_MASK {
+            0 => Ok(Cluster::Unallocated),
+            host_offset if host_offset & (self.header.cluster_size() - 1) != 0 => Err(corrupt(
+                &self.path,
+                format!("unaligned cluster at offset {}", host_offset),
+            )),
+            host_offset => Ok(Cluster::Data(host_offset)),
+        }
+    }
+
+    /// decompressed contents of the cluster stored at `host_offset`
+    fn decompress(&mut self, host_offset: u64, len: u64) -> Result<&[u8]> {
+        let cached = matches!(&self.compressed_cache, Some((cached, _)) if *cached == host_offset);
+        if !cached {
+            // the length is rounded up to whole sectors and may reach past the end of the file
+            let available = self.file.metadata()?.len().saturating_sub(host_offset);
+            let mut compressed = vec![0; len.min(available) as usize];
+            self.file.read_exact_at(&mut compressed, host_offset)?;
+            let mut cluster = vec![0; self.header.cluster_size() as usize];
+            DeflateDecoder::new(&compressed[..])
+                .read_exact(&mut cluster)
+                .map_err(|err| {
+                    corrupt(
+                        &self.path,
+                        format!("compressed cluster at offset {}: {}", host_offset, err),
+                    )
+                })?;
+            self.compressed_cache = Some((host_offset, cluster));
+        }
+        match &self.compressed_cache {
+            Some((_, cluster)) => Ok(cluster),
+            None => unreachable!(),
+        }
+    }
+
+    fn read_backing(&mut self, offset: u64, buf: &mut [u8]) -> Result<bool> {
+        match &mut self.backing {
+            // a backing file smaller than the image reads as zeros past its end
+            Some(backing) if offset < backing.virtual_size() => backing.read_at(offset, buf),
+            _ => {
+                buf.fill(0);
+                Ok(false)
+            }
+        }
+    }
+}
+
+impl DiskImageReader for Qcow2Image {
+    fn virtual_size(&self) -> u64 {
+        self.header.size
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<bool> {
+        let cluster_size = self.header.cluster_size();
+        let mut allocated = false;
+        let mut position = 0;
+        while position < buf.len() {
+            let offset = offset + position as u64;
+            let in_cluster = offset & (cluster_size - 1);
+            let len = ((cluster_size - in_cluster) as usize).min(buf.len() - position);
+            let part = &mut buf[position..position + len];
+            position += len;
+            if offset >= self.header.size {
+                part.fill(0);
+                continue;
+            }
+            match self.cluster(offset)? {
+                Cluster::Unallocated => allocated |= self.read_backing(offset, part)?,
+                Cluster::Zero => {
+                    part.fill(0);
+                    allocated = true;
+                }
+                Cluster::Data(host_offset) => {
+                    self.file.read_exact_at(part, host_offset + in_cluster)?;
+                    allocated = true;
+                }
+                Cluster::Compressed(host_offset, compressed_len) => {
+                    let cluster = self.decompress(host_offset, compressed_len)?;
+                    let start = in_cluster as usize;
+                    part.copy_from_slice(&cluster[start..start + len]);
+                    allocated = true;
+                }
+            }
+        }
+        Ok(allocated)
+    }
+}
+
+fn read_header(file: &File, path: &Path) -> Result<Qcow2Header> {
+    let mut header = [0; V3_HEADER_LENGTH as usize];
+    let len = file.metadata()?.len().min(header.len() as u64) as usize;
+    file.read_exact_at(&mut header[..len], 0)?;
+    if len < V2_HEADER_LENGTH as usize || header[..4] != QCOW2_MAGIC {
+        return Err(Error::Parse(format!(
+            "{} is not a qcow2 image",
+            path.display()
+        )));
+    }
+
+    let version = be_u32(&header, 4);
+    let backing_file_offset = be_u64(&header, 8);
+    let backing_file_size = be_u32(&header, 16);
+    let cluster_bits = be_u32(&header, 20);
+    let size = be_u64(&header, 24);
+    let crypt_method = be_u32(&header, 32);
+    let l1_size = be_u32(&header, 36);
+    let l1_table_offset = be_u64(&header, 40);
+
+    let (incompatible_features, header_length) = match version {
+        2 => (0, V2_HEADER_LENGTH),
+        3 if len == V3_HEADER_LENGTH as usize => (be_u64(&header, 72), be_u32(&header, 100)),
+        3 => return Err(corrupt(path, "truncated header".to_string())),
+        _ => return Err(unsupported(path, format!("qcow2 version {}", version))),
+    };
+    if !(9..=21).contains(&cluster_bits) {
+        return Err(corrupt(path, format!("cluster bits {}", cluster_bits)));
+    }
+    if crypt_method != 0 {
+        return Err(unsupported(path, "encryption".to_string()));
+    }
+    if incompatible_features & INCOMPATIBLE_CORRUPT != 0 {
+        return Err(corrupt(path, "the image is marked corrupt".to_string()));
+    }
+    if incompatible_features & INCOMPATIBLE_EXTERNAL_DATA_FILE != 0 {
+        return Err(unsupported(path, "external data files".to_string()));
+    }
+    if incompatible_features & INCOMPATIBLE_EXTENDED_L2 != 0 {
+        return Err(unsupported(path, "extended L2 entries".to_string()));
+    }
+    if incompatible_features & INCOMPATIBLE_COMPRESSION_TYPE != 0 {
+        if header_length <= V3_HEADER_LENGTH {
+            return Err(corrupt(path, "missing compression type".to_string()));
+        }
+        let mut compression_type = [0; 1];
+        file.read_exact_at(&mut compression_type, u64::from(V3_HEADER_LENGTH))?;
+        if compression_type[0] != 0 {
+            return Err(unsupported(path, "zstd compression".to_string()));
+        }
+    }
+    let known = INCOMPATIBLE_DIRTY
+        | INCOMPATIBLE_CORRUPT
+        | INCOMPATIBLE_EXTERNAL_DATA_FILE
+        | INCOMPATIBLE_COMPRESSION_TYPE
+        | INCOMPATIBLE_EXTENDED_L2;
+    if incompatible_features & !known != 0 {
+        return Err(unsupported(
+            path,
+            format!(
+                "incompatible features {:#x}",
+                incompatible_features & !known
+            ),
+        ));
+    }
+
+    let backing_file = if backing_file_offset != 0 {
+        if backing_file_size == 0 || backing_file_size > 1023 {
+            return Err(corrupt(
+                path,
+                format!("backing file name of {} bytes", backing_file_size),
+            ));
+        }
+        let mut name = vec![0; backing_file_size as usize];
+        file.read_exact_at(&mut name, backing_file_offset)?;
+        Some(String::from_utf8_lossy(&name).into_owned())
+    } else {
+        None
+    };
+    let backing_format = read_backing_format(file, header_length, backing_file_offset)?;
+
+    Ok(Qcow2Header {
+        version,
+        cluster_bits,
+        size,
+        backing_file,
+        backing_format,
+        l1_size,
+        l1_table_offset,
+        incompatible_features,
+    })
+}
+
+/// the backing file format header extension, which follows the header
+fn read_backing_format(
+    file: &File,
+    header_length: u32,
+    backing_file_offset: u64,
+) -> Result<Option<String>> {
+    let end = if backing_file_offset != 0 {
+        backing_file_offset
+    } else {
+        file.metadata()?.len()
+    };
+    let mut offset = u64::from(header_length);
+    while offset + 8 <= end {
+        let mut extension = [0; 8];
+        file.read_exact_at(&mut extension, offset)?;
+        let kind = be_u32(&extension, 0);
+        let len = u64::from(be_u32(&extension, 4));
+        if kind == EXTENSION_END || offset + 8 + len > end {
+            break;
+        }
+        if kind == EXTENSION_BACKING_FORMAT {
+            let mut format = vec![0; len as usize];
+            file.read_exact_at(&mut format, offset + 8)?;
+            return Ok(Some(String::from_utf8_lossy(&format).into_owned()));
+        }
+        offset += 8 + len.div_ceil(8) * 8;
+    }
+    Ok(None)
+}
+
+/// whether the backing file `name` is relative to the directory of the image and stays below it
+fn is_contained(name: &str) -> bool {
+    let name = Path::new(name);
+    name.components().next().is_some()
+        && name
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+}
+
+fn resolve_backing_file(image: &Path, name: &str) -> PathBuf {
+    let name = Path::new(name);
+    match image.parent() {
+        Some(dir) if name.is_relative() => dir.join(name),
+        _ => name.to_path_buf(),
+    }
+}
+
+fn corrupt(path: &Path, description: String) -> Error {
+    Error::Parse(format!(
+        "{}: corrupt qcow2 image: {}",
+        path.display(),
+        description
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk::convert::ConvertBuilder;
+    use flate2::{write::DeflateEncoder, Compression};
+    use std::fs;
+    use std::io::Write;
+
+    const CLUSTER_BITS: u32 = 9;
+    const CLUSTER_SIZE: usize = 1 << CLUSTER_BITS;
+    const L1_OFFSET: usize = CLUSTER_SIZE;
+    const L2_OFFSET: usize = 2 * CLUSTER_SIZE;
+    const DATA_OFFSET: usize = 3 * CLUSTER_SIZE;
+    const COMPRESSED_OFFSET: usize = 4 * CLUSTER_SIZE;
+    const BACKING_NAME_OFFSET: usize = 200;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// version 3 image of 5 clusters: data, compressed, zero, unallocated and zero on version 2
+    fn image(version: u32, backing_file: Option<&str>) -> Vec<u8> {
+        let mut image = vec![0; 5 * CLUSTER_SIZE];
+        image[..4].copy_from_slice(&QCOW2_MAGIC);
+        image[4..8].copy_from_slice(&version.to_be_bytes());
+        if let Some(name) = backing_file {
+            image[8..16].copy_from_slice(&(BACKING_NAME_OFFSET as u64).to_be_bytes());
+            image[16..20].copy_from_slice(&(name.len() as u32).to_be_bytes());
+            image[BACKING_NAME_OFFSET..BACKING_NAME_OFFSET + name.len()]
+                .copy_from_slice(name.as_bytes());
+        }
+        image[20..24].copy_from_slice(&CLUSTER_BITS.to_be_bytes());
+        image[24..32].copy_from_slice(&(5 * CLUSTER_SIZE as u64).to_be_bytes());
+        image[36..40].copy_from_slice(&1u32.to_be_bytes());
+        image[40..48].copy_from_slice(&(L1_OFFSET as u64).to_be_bytes());
+        if version == 3 {
+            image[100..104].copy_from_slice(&V3_HEADER_LENGTH.to_be_bytes());
+        }
+        image[L1_OFFSET..L1_OFFSET + 8].copy_from_slice(&(L2_OFFSET as u64).to_be_bytes());
+
+        image[DATA_OFFSET..DATA_OFFSET + CLUSTER_SIZE].fill(0xaa);
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&[0xcc; CLUSTER_SIZE]).unwrap();
+        let compressed = encoder.finish().unwrap();
+        assert!(compressed.len() <= CLUSTER_SIZE);
+        image[COMPRESSED_OFFSET..COMPRESSED_OFFSET + compressed.len()].copy_from_slice(&compressed);
+
+        let entries = [
+            DATA_OFFSET as u64,
+            L2_COMPRESSED | COMPRESSED_OFFSET as u64,
+            L2_ZERO,
+            0,
+            L2_ZERO,
+        ];
+        for (i, entry) in entries.iter().enumerate() {
+            let offset = L2_OFFSET + i * 8;
+            image[offset..offset + 8].copy_from_slice(&entry.to_be_bytes());
+        }
+        image
+    }
+
+    fn cluster(image: &mut Qcow2Image, index: usize) -> (bool, Vec<u8>) {
+        let mut buf = vec![0x11; CLUSTER_SIZE];
+        let allocated = image
+            .read_at((index * CLUSTER_SIZE) as u64, &mut buf)
+            .unwrap();
+        (allocated, buf)
+    }
+
+    #[test]
+    fn reads_data_compressed_zero_and_backing_clusters() {
+        let dir = temp_dir("qcow2-clusters");
+        fs::write(dir.join("base.img"), vec![0xbb; 5 * CLUSTER_SIZE]).unwrap();
+        fs::write(dir.join("disk.qcow2"), image(3, Some("base.img"))).unwrap();
+
+        let mut image = Qcow2Image::open(dir.join("disk.qcow2")).unwrap();
+        assert_eq!(image.virtual_size(), 5 * CLUSTER_SIZE as u64);
+        assert_eq!(image.backing_file(), Some(dir.join("base.img")));
+        assert_eq!(cluster(&mut image, 0), (true, vec![0xaa; CLUSTER_SIZE]));
+        assert_eq!(cluster(&mut image, 1), (true, vec![0xcc; CLUSTER_SIZE]));
+        // a zero cluster hides the backing file
+        assert_eq!(cluster(&mut image, 2), (true, vec![0; CLUSTER_SIZE]));
+        assert_eq!(cluster(&mut image, 3).1, vec![0xbb; CLUSTER_SIZE]);
+
+        // a read across clusters combines them
+        let mut buf = vec![0; CLUSTER_SIZE];
+        image.read_at((CLUSTER_SIZE / 2) as u64, &mut buf).unwrap();
+        assert_eq!(&buf[..CLUSTER_SIZE / 2], &[0xaa; CLUSTER_SIZE / 2][..]);
+        assert_eq!(&buf[CLUSTER_SIZE / 2..], &[0xcc; CLUSTER_SIZE / 2][..]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unallocated_clusters_without_backing_file_read_as_zeros() {
+        let dir = temp_dir("qcow2-unallocated");
+        fs::write(dir.join("disk.qcow2"), image(3, None)).unwrap();
+
+        let mut image = Qcow2Image::open(dir.join("disk.qcow2")).unwrap();
+        assert_eq!(cluster(&mut image, 3), (false, vec![0; CLUSTER_SIZE]));
+        // past the end of the disk
+        assert_eq!(cluster(&mut image, 5), (false, vec![0; CLUSTER_SIZE]));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn zero_flag_is_ignored_by_version_2() {
+        let dir = temp_dir("qcow2-version-2");
+        fs::write(dir.join("base.img"), vec![0xbb; 5 * CLUSTER_SIZE]).unwrap();
+        fs::write(dir.join("disk.qcow2"), image(2, Some("base.img"))).unwrap();
+
+        let mut image = Qcow2Image::open(dir.join("disk.qcow2")).unwrap();
+        assert_eq!(image.header().version, 2);
+        assert_eq!(cluster(&mut image, 4).1, vec![0xbb; CLUSTER_SIZE]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_backing_files_outside_the_image_directory() {
+        let dir = temp_dir("qcow2-backing-outside");
+        let images = dir.join("images");
+        fs::create_dir_all(&images).unwrap();
+        fs::write(dir.join("base.img"), vec![0xbb; 5 * CLUSTER_SIZE]).unwrap();
+        let absolute = dir.join("base.img").to_string_lossy().into_owned();
+
+        for name in ["../base.img", absolute.as_str()] {
+            fs::write(images.join("disk.qcow2"), image(3, Some(name))).unwrap();
+            assert!(matches!(
+                Qcow2Image::open(images.join("disk.qcow2")),
+                Err(Error::Parse(_))
+            ));
+
+            let mut image =
+                Qcow2Image::open_with_depth(&images.join("disk.qcow2"), 0, true).unwrap();
+            assert_eq!(cluster(&mut image, 3).1, vec![0xbb; CLUSTER_SIZE]);
+            fs::remove_file(images.join("disk.qcow2")).unwrap();
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn converts_only_with_allowed_backing_files() {
+        let dir = temp_dir("qcow2-convert");
+        let images = dir.join("images");
+        fs::create_dir_all(&images).unwrap();
+        fs::write(dir.join("base.img"), vec![0xbb; 5 * CLUSTER_SIZE]).unwrap();
+        fs::write(images.join("disk.qcow2"), image(3, Some("../base.img"))).unwrap();
+
+        let refused = ConvertBuilder::new()
+            .source(images.join("disk.qcow2"))
+            .destination(dir.join("disk.img"))
+            .build()
+            .run();
+        assert!(matches!(refused, Err(Error::Parse(_))));
+        assert!(!dir.join("disk.img").exists());
+
+        let report = ConvertBuilder::new()
+            .source(images.join("disk.qcow2"))
+            .destination(dir.join("disk.img"))
+            .allow_backing_files(true)
+            .build()
+            .run()
+            .unwrap();
+        assert!(report.verified);
+        let raw = fs::read(dir.join("disk.img")).unwrap();
+        let expected = [
+            vec![0xaa; CLUSTER_SIZE],
+            vec![0xcc; CLUSTER_SIZE],
+            vec![0; CLUSTER_SIZE],
+            vec![0xbb; CLUSTER_SIZE],
+            vec![0; CLUSTER_SIZE],
+        ]
+        .concat();
+        assert_eq!(raw, expected);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn contained_backing_file_names() {
+        assert!(is_contained("base.img"));
+        assert!(is_contained("./bases/base.img"));
+        assert!(!is_contained(""));
+        assert!(!is_contained("/var/lib/base.img"));
+        assert!(!is_contained("bases/../../base.img"));
+    }
+}
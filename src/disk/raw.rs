@@ -0,0 +1,458 @@
+//! raw disk image module
+//!
+//! A raw image stores the disk byte for byte. It is created sparse: the file has the logical size
+//! of the disk, but the host file system only allocates the blocks the guest writes.
+
+use crate::{
+    disk::{convert::DiskImageReader, seek, SECTOR_SIZE},
+    error::Result,
+    lock::{FileLock, LockMode},
+};
+
+use sha2::{Digest, Sha256};
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+/// logical and allocated size of a disk image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DiskUsage {
+    /// size of the disk seen by the guest in bytes
+    pub logical_size: u64,
+    /// bytes allocated on the host file system
+    pub allocated_size: u64,
+}
+
+impl DiskUsage {
+    /// whether fewer bytes are allocated than the disk holds
+    pub fn is_sparse(&self) -> bool {
+        self.allocated_size < self.logical_size
+    }
+}
+
+/// builder for RawDiskImage
+/// # Examples
+/// ```rust
+/// use virtualization_rs::disk::raw::RawDiskImageBuilder;
+/// use virtualization_rs::lock::lock_file_path;
+///
+/// let path = std::env::temp_dir().join(format!("raw-example-{}.img", std::process::id()));
+/// let image = RawDiskImageBuilder::new()
+///     .path(&path)
+///     .size(64 * 1024 * 1024 * 1024)
+///     .build()
+///     .unwrap();
+/// let usage = image.usage().unwrap();
+/// assert_eq!(usage.logical_size, 64 * 1024 * 1024 * 1024);
+/// assert!(usage.is_sparse());
+///
+/// image.grow(128 * 1024 * 1024 * 1024).unwrap();
+/// assert_eq!(image.logical_size().unwrap(), 128 * 1024 * 1024 * 1024);
+/// assert!(!lock_file_path(&path).exists());
+/// # std::fs::remove_file(&path).unwrap();
+/// ```
+pub struct RawDiskImageBuilder<Path, Size> {
+    path: Path,
+    size: Size,
+    preallocate: bool,
+}
+
+impl RawDiskImageBuilder<(), ()> {
+    pub fn new() -> Self {
+        RawDiskImageBuilder {
+            path: (),
+            size: (),
+            preallocate: false,
+        }
+    }
+}
+
+impl Default for RawDiskImageBuilder<(), ()> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<Path, Size> RawDiskImageBuilder<Path, Size> {
+    pub fn path<T: Into<PathBuf>>(self, path: T) -> RawDiskImageBuilder<PathBuf, Size> {
+        RawDiskImageBuilder {
+            path: path.into(),
+            size: self.size,
+            preallocate: self.preallocate,
+        }
+    }
+
+    /// logical size in bytes, a multiple of [`SECTOR_SIZE`]
+    pub fn size(self, size: u64) -> RawDiskImageBuilder<Path, u64> {
+        RawDiskImageBuilder {
+            path: self.path,
+            size,
+            preallocate: self.preallocate,
+        }
+    }
+
+    /// allocate every block up front instead of creating a sparse image, disabled by default
+    pub fn preallocate(mut self, preallocate: bool) -> Self {
+        self.preallocate = preallocate;
+        self
+    }
+}
+
+impl RawDiskImageBuilder<PathBuf, u64> {
+    /// create the image, fails if the file already exists
+    pub fn build(self) -> Result<RawDiskImage> {
+        check_size(self.size)?;
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&self.path)?;
+        let result = file.set_len(self.size).and_then(|()| {
+            if self.preallocate {
+                allocate(&file, self.size)
+            } else {
+                Ok(())
+            }
+        });
+        if let Err(err) = result {
+            let _ = fs::remove_file(&self.path);
+            return Err(err.into());
+        }
+        Ok(RawDiskImage { path: self.path })
+    }
+}
+
+/// raw disk image
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawDiskImage {
+    path: PathBuf,
+}
+
+impl RawDiskImage {
+    /// the existing raw image at `path`
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<RawDiskImage> {
+        let path = path.as_ref().to_path_buf();
+        if !fs::metadata(&path)?.is_file() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not a file", path.display()),
+            )
+            .into());
+        }
+        Ok(RawDiskImage { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// size of the disk seen by the guest in bytes
+    pub fn logical_size(&self) -> Result<u64> {
+        Ok(self.usage()?.logical_size)
+    }
+
+    /// bytes allocated on the host file system
+    pub fn allocated_size(&self) -> Result<u64> {
+        Ok(self.usage()?.allocated_size)
+    }
+
+    pub fn usage(&self) -> Result<DiskUsage> {
+        disk_usage(&self.path)
+    }
+
+    /// grow the disk to `size` bytes, the new space is sparse
+    ///
+    /// The image is locked exclusively meanwhile, so it fails while a virtual machine uses it.
+    /// Shrinking is refused because it would drop the data at the end of the disk.
+    pub fn grow(&self, size: u64) -> Result<()> {
+        check_size(size)?;
+        let _lock = FileLock::acquire(&self.path, LockMode::Exclusive)?;
+        let file = OpenOptions::new().write(true).open(&self.path)?;
+        let current = file.metadata()?.len();
+        if size < current {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "cannot shrink {} from {} to {} bytes",
+                    self.path.display(),
+                    current,
+                    size
+                ),
+            )
+            .into());
+        }
+        file.set_len(size)?;
+        Ok(())
+    }
+
+    /// allocate every block of the disk that is not allocated yet
+    ///
+    /// The image is locked exclusively meanwhile, so it fails while a virtual machine uses it.
+    pub fn preallocate(&self) -> Result<()> {
+        let _lock = FileLock::acquire(&self.path, LockMode::Exclusive)?;
+        let file = OpenOptions::new().read(true).write(true).open(&self.path)?;
+        let size = file.metadata()?.len();
+        allocate(&file, size)?;
+        Ok(())
+    }
+}
+
+/// raw disk image opened for reading
+pub struct RawImageReader {
+    file: File,
+    size: u64,
+}
+
+impl RawImageReader {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<RawImageReader> {
+        let file = File::open(path)?;
+        let size = file.metadata()?.len();
+        Ok(RawImageReader { file, size })
+    }
+}
+
+impl DiskImageReader for RawImageReader {
+    fn virtual_size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<bool> {
+        let end = offset.saturating_add(buf.len() as u64).min(self.size);
+        let allocated = offset < end
+            && matches!(seek(&self.file, offset, libc::SEEK_DATA)?, Some(data) if data < end);
+        buf.fill(0);
+        if allocated {
+            let len = (end - offset) as usize;
+            self.file.read_exact_at(&mut buf[..len], offset)?;
+        }
+        Ok(allocated)
+    }
+}
+
+/// hex encoded SHA-256 digest of the contents of the raw image at `path`
+///
+/// Holes are skipped rather than read and chunks of zeros are left out of the digest, so it does
+/// not depend on how the image is allocated: a sparse copy or a clone of an image has its digest.
+pub fn disk_digest<P: AsRef<Path>>(path: P) -> Result<String> {
+    const CHUNK: usize = 1024 * 1024;
+    let mut reader = RawImageReader::open(path)?;
+    let size = reader.virtual_size();
+    let mut hasher = Sha256::new();
+    hasher.update(size.to_be_bytes());
+    let mut buffer = vec![0u8; CHUNK];
+    let mut offset = 0;
+    while offset < size {
+        if reader.read_at(offset, &mut buffer)? && buffer.iter().any(|&byte| byte != 0) {
+            hasher.update(offset.to_be_bytes());
+            hasher.update(&buffer);
+        }
+        offset += CHUNK as u64;
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+/// logical and allocated size of the file at `path`
+pub fn disk_usage<P: AsRef<Path>>(path: P) -> Result<DiskUsage> {
+    let metadata = fs::metadata(path)?;
+    Ok(DiskUsage {
+        logical_size: metadata.len(),
+        // st_blocks counts 512-byte units whatever the block size of the file system
+        allocated_size: metadata.blocks() * 512,
+    })
+}
+
+fn check_size(size: u64) -> io::Result<()> {
+    if size == 0 || size % SECTOR_SIZE != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "disk size {} is not a positive multiple of {} bytes",
+                size, SECTOR_SIZE
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// allocate the first `size` bytes of `file`
+#[cfg(target_os = "linux")]
+fn allocate(file: &File, size: u64) -> io::Result<()> {
+    match unsafe { libc::posix_fallocate(file.as_raw_fd(), 0, size as libc::off_t) } {
+        0 => Ok(()),
+        // the file system cannot allocate without writing
+        libc::EOPNOTSUPP | libc::EINVAL => fill_holes(file, size),
+        err => Err(io::Error::from_raw_os_error(err)),
+    }
+}
+
+/// allocate the first `size` bytes of `file`
+#[cfg(not(target_os = "linux"))]
+fn allocate(file: &File, size: u64) -> io::Result<()> {
+    fill_holes(file, size)
+}
+
+/// write zeros over every hole in the first `size` bytes of `file`
+fn fill_holes(file: &File, size: u64) -> io::Result<()> {
+    const CHUNK: usize = 1024 * 1024;
+    let zeros = vec![0u8; CHUNK];
+    let mut offset = 0;
+    while offset < size {
+        let hole = seek(file, offset, libc::SEEK_HOLE)?
+            .unwrap_or(size)
+            .min(size);
+        if hole >= size {
+            break;
+        }
+        let data = seek(file, hole, libc::SEEK_DATA)?.unwrap_or(size).min(size);
+        let mut position = hole;
+        while position < data {
+            let len = (data - position).min(CHUNK as u64) as usize;
+            file.write_all_at(&zeros[..len], position)?;
+            position += len as u64;
+        }
+        offset = data;
+    }
+    file.sync_data()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+
+    const MIB: u64 = 1024 * 1024;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("raw-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn is_invalid_input<T>(result: Result<T>) -> bool {
+        matches!(result, Err(Error::Io(err)) if err.kind() == io::ErrorKind::InvalidInput)
+    }
+
+    #[test]
+    fn preallocated_images_are_fully_allocated() {
+        let dir = scratch("preallocate");
+        let image = RawDiskImageBuilder::new()
+            .path(dir.join("dense.img"))
+            .size(4 * MIB)
+            .preallocate(true)
+            .build()
+            .unwrap();
+        let usage = image.usage().unwrap();
+        assert_eq!(usage.allocated_size, usage.logical_size);
+        assert!(!usage.is_sparse());
+
+        let image = RawDiskImageBuilder::new()
+            .path(dir.join("sparse.img"))
+            .size(4 * MIB)
+            .build()
+            .unwrap();
+        assert!(image.usage().unwrap().is_sparse());
+        image.preallocate().unwrap();
+        let usage = image.usage().unwrap();
+        assert_eq!(usage.allocated_size, usage.logical_size);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn grow_refuses_to_shrink() {
+        let dir = scratch("grow");
+        let image = RawDiskImageBuilder::new()
+            .path(dir.join("disk.img"))
+            .size(4 * MIB)
+            .build()
+            .unwrap();
+        assert!(is_invalid_input(image.grow(2 * MIB)));
+        assert!(is_invalid_input(image.grow(4 * MIB + 1)));
+        assert_eq!(image.logical_size().unwrap(), 4 * MIB);
+
+        image.grow(4 * MIB).unwrap();
+        image.grow(8 * MIB).unwrap();
+        assert_eq!(image.logical_size().unwrap(), 8 * MIB);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sizes_are_positive_multiples_of_the_sector_size() {
+        assert!(check_size(0).is_err());
+        assert!(check_size(1000).is_err());
+        assert!(check_size(SECTOR_SIZE + 1).is_err());
+        assert!(check_size(SECTOR_SIZE).is_ok());
+
+        let dir = scratch("size");
+        let path = dir.join("disk.img");
+        let result = RawDiskImageBuilder::new().path(&path).size(1000).build();
+        assert!(is_invalid_input(result));
+        assert!(!path.exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn build_refuses_an_existing_path() {
+        let dir = scratch("existing");
+        let path = dir.join("disk.img");
+        fs::write(&path, b"data").unwrap();
+        let result = RawDiskImageBuilder::new().path(&path).size(MIB).build();
+        assert!(
+            matches!(result, Err(Error::Io(err)) if err.kind() == io::ErrorKind::AlreadyExists)
+        );
+        assert_eq!(fs::read(&path).unwrap(), b"data");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn digest_does_not_depend_on_allocation() {
+        let dir = scratch("digest");
+        let sparse = RawDiskImageBuilder::new()
+            .path(dir.join("sparse.img"))
+            .size(8 * MIB)
+            .build()
+            .unwrap();
+        let file = OpenOptions::new().write(true).open(sparse.path()).unwrap();
+        file.write_all_at(b"boot", 0).unwrap();
+        file.write_all_at(b"data", 3 * MIB + 17).unwrap();
+        assert!(sparse.usage().unwrap().is_sparse());
+
+        let mut contents = vec![0; 8 * MIB as usize];
+        contents[..4].copy_from_slice(b"boot");
+        contents[3 * MIB as usize + 17..3 * MIB as usize + 21].copy_from_slice(b"data");
+        let dense = dir.join("dense.img");
+        fs::write(&dense, &contents).unwrap();
+        assert_eq!(
+            disk_digest(sparse.path()).unwrap(),
+            disk_digest(&dense).unwrap()
+        );
+
+        contents[3 * MIB as usize + 17] = b'D';
+        fs::write(&dense, &contents).unwrap();
+        assert_ne!(
+            disk_digest(sparse.path()).unwrap(),
+            disk_digest(&dense).unwrap()
+        );
+
+        // the size is part of the digest
+        sparse.grow(16 * MIB).unwrap();
+        contents[3 * MIB as usize + 17] = b'd';
+        fs::write(&dense, &contents).unwrap();
+        assert_ne!(
+            disk_digest(sparse.path()).unwrap(),
+            disk_digest(&dense).unwrap()
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,396 @@
+//! VHD disk image module
+//!
+//! Reads fixed and dynamic Virtual PC / Hyper-V images. Every VHD ends with a 512-byte footer;
+//! a dynamic image also starts with a copy of it, followed by a header locating its block
+//! allocation table. Differencing images are not supported.
+
+use crate::{
+    disk::{be_u32, be_u64, convert::DiskImageReader, unsupported, SECTOR_SIZE},
+    error::{Error, Result},
+};
+
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+/// first bytes of the footer
+pub const VHD_MAGIC: [u8; 8] = *b"conectix";
+/// size of the footer at the end of every VHD
+pub const FOOTER_SIZE: u64 = 512;
+
+const DYNAMIC_HEADER_MAGIC: [u8; 8] = *b"cxsparse";
+const DYNAMIC_HEADER_SIZE: usize = 1024;
+
+/// block allocation table entry of a block that is not allocated
+const BLOCK_UNALLOCATED: u32 = u32::MAX;
+
+/// block allocation tables larger than this are rejected as corrupt
+const MAX_BAT_SIZE: u64 = 32 * 1024 * 1024;
+
+/// type of a VHD
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VhdDiskType {
+    Fixed,
+    Dynamic,
+    Differencing,
+}
+
+/// footer fields of a VHD
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VhdFooter {
+    pub disk_type: VhdDiskType,
+    /// size of the disk seen by the guest in bytes
+    pub current_size: u64,
+    /// offset of the dynamic disk header, `u64::MAX` for fixed images
+    pub data_offset: u64,
+}
+
+/// location of the data of a VHD
+enum Layout {
+    /// the disk is stored as is in front of the footer
+    Fixed,
+    Dynamic {
+        block_size: u64,
+        /// size of the sector bitmap in front of the data of every block
+        bitmap_size: u64,
+        /// sector of every block
+        bat: Vec<u32>,
+    },
+}
+
+/// fixed or dynamic VHD
+///
+/// # Examples
+/// ```rust,no_run
+/// use virtualization_rs::disk::convert::DiskImageReader;
+/// use virtualization_rs::disk::vhd::VhdImage;
+///
+/// let image = VhdImage::open("appliance.vhd").unwrap();
+/// println!("{:?} {} bytes", image.footer().disk_type, image.virtual_size());
+/// ```
+pub struct VhdImage {
+    file: File,
+    path: PathBuf,
+    footer: VhdFooter,
+    layout: Layout,
+}
+
+impl VhdImage {
+    /// open the VHD at `path`
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<VhdImage> {
+        let path = path.as_ref();
+        let file = File::open(path)?;
+        let file_len = file.metadata()?.len();
+        let footer = read_footer(&file, path, file_len)?;
+
+        let layout = match footer.disk_type {
+            VhdDiskType::Fixed => {
+                if file_len - FOOTER_SIZE < footer.current_size {
+                    return Err(corrupt(
+                        path,
+                        format!(
+                            "{} bytes of data for a {} byte disk",
+                            file_len, footer.current_size
+                        ),
+                    ));
+                }
+                Layout::Fixed
+            }
+            VhdDiskType::Dynamic => read_dynamic_header(&file, path, &footer)?,
+            VhdDiskType::Differencing => {
+                return Err(unsupported(path, "differencing VHD images".to_string()))
+            }
+        };
+
+        Ok(VhdImage {
+            file,
+            path: path.to_path_buf(),
+            footer,
+            layout,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn footer(&self) -> &VhdFooter {
+        &self.footer
+    }
+}
+
+impl DiskImageReader for VhdImage {
+    fn virtual_size(&self) -> u64 {
+        self.footer.current_size
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<bool> {
+        let size = self.footer.current_size;
+        let (block_size, bitmap_size, bat) = match &self.layout {
+            Layout::Fixed => {
+                let end = offset.saturating_add(buf.len() as u64).min(size);
+                buf.fill(0);
+                if offset >= end {
+                    return Ok(false);
+                }
+                self.file
+                    .read_exact_at(&mut buf[..(end - offset) as usize], offset)?;
+                return Ok(true);
+            }
+            Layout::Dynamic {
+                block_size,
+                bitmap_size,
+                bat,
+            } => (*block_size, *bitmap_size, bat),
+        };
+
+        let mut allocated = false;
+        let mut position = 0;
+        while position < buf.len() {
+            let offset = offset + position as u64;
+            let in_block = offset % block_size;
+            let len = ((block_size - in_block) as usize).min(buf.len() - position);
+            let part = &mut buf[position..position + len];
+            position += len;
+            match bat.get((offset / block_size) as usize) {
+                Some(&sector) if offset < size && sector != BLOCK_UNALLOCATED => {
+                    let host_offset = u64::from(sector) * SECTOR_SIZE + bitmap_size + in_block;
+                    self.file.read_exact_at(part, host_offset)?;
+                    allocated = true;
+                }
+                _ => part.fill(0),
+            }
+        }
+        Ok(allocated)
+    }
+}
+
+/// the footer at the end of the image, or its copy at the start when it is damaged
+fn read_footer(file: &File, path: &Path, file_len: u64) -> Result<VhdFooter> {
+    let mut footer = [0; FOOTER_SIZE as usize];
+    let mut found = false;
+    for offset in [file_len.checked_sub(FOOTER_SIZE), Some(0)] {
+        let offset = match offset {
+            Some(offset) => offset,
+            None => continue,
+        };
+        file.read_exact_at(&mut footer, offset)?;
+        if footer[..8] == VHD_MAGIC && checksum(&footer, 64) == be_u32(&footer, 64) {
+            found = true;
+            break;
+        }
+    }
+    if !found {
+        return Err(Error::Parse(format!(
+            "{} is not a VHD or its footer is corrupt",
+            path.display()
+        )));
+    }
+
+    let disk_type = match be_u32(&footer, 60) {
+        2 => VhdDiskType::Fixed,
+        3 => VhdDiskType::Dynamic,
+        4 => VhdDiskType::Differencing,
+        disk_type => return Err(corrupt(path, format!("disk type {}", disk_type))),
+    };
+    Ok(VhdFooter {
+        disk_type,
+        current_size: be_u64(&footer, 48),
+        data_offset: be_u64(&footer, 16),
+    })
+}
+
+fn read_dynamic_header(file: &File, path: &Path, footer: &VhdFooter) -> Result<Layout> {
+    let mut header = [0; DYNAMIC_HEADER_SIZE];
+    file.read_exact_at(&mut header, footer.data_offset)?;
+    if header[..8] != DYNAMIC_HEADER_MAGIC || checksum(&header, 36) != be_u32(&header, 36) {
+        return Err(corrupt(path, "dynamic disk header".to_string()));
+    }
+    let table_offset = be_u64(&header, 16);
+    let max_table_entries = be_u32(&header, 28);
+    let block_size = u64::from(be_u32(&header, 32));
+    if block_size == 0 || block_size % SECTOR_SIZE != 0 {
+        return Err(corrupt(path, format!("block size {}", block_size)));
+    }
+
+    let entries = footer.current_size.div_ceil(block_size);
+    if u64::from(max_table_entries) < entries || entries * 4 > MAX_BAT_SIZE {
+        return Err(corrupt(
+            path,
+            format!("block allocation table of {} entries", max_table_entries),
+        ));
+    }
+    let mut bat = vec![0; entries as usize * 4];
+    file.read_exact_at(&mut bat, table_offset)?;
+    let bat = bat.chunks_exact(4).map(|entry| be_u32(entry, 0)).collect();
+
+    // one bit per sector of the block, padded to whole sectors
+    let bitmap_size = (block_size / SECTOR_SIZE).div_ceil(8).div_ceil(SECTOR_SIZE) * SECTOR_SIZE;
+    Ok(Layout::Dynamic {
+        block_size,
+        bitmap_size,
+        bat,
+    })
+}
+
+/// one's complement of the sum of the bytes of `structure` except its checksum at `offset`
+fn checksum(structure: &[u8], offset: usize) -> u32 {
+    let sum = structure
+        .iter()
+        .enumerate()
+        .filter(|(index, _)| !(offset..offset + 4).contains(index))
+        .fold(0u32, |sum, (_, &byte)| sum.wrapping_add(u32::from(byte)));
+    !sum
+}
+
+fn corrupt(path: &Path, description: String) -> Error {
+    Error::Parse(format!(
+        "{}: corrupt VHD image: {}",
+        path.display(),
+        description
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    const BLOCK_SIZE: usize = 4096;
+    const DISK_SIZE: usize = 4 * BLOCK_SIZE;
+
+    fn footer(disk_type: u32, current_size: u64, data_offset: u64) -> Vec<u8> {
+        let mut footer = vec![0; FOOTER_SIZE as usize];
+        footer[..8].copy_from_slice(&VHD_MAGIC);
+        footer[16..24].copy_from_slice(&data_offset.to_be_bytes());
+        footer[48..56].copy_from_slice(&current_size.to_be_bytes());
+        footer[60..64].copy_from_slice(&disk_type.to_be_bytes());
+        let sum = checksum(&footer, 64);
+        footer[64..68].copy_from_slice(&sum.to_be_bytes());
+        footer
+    }
+
+    fn fixed(current_size: u64) -> Vec<u8> {
+        let data: Vec<u8> = (0..DISK_SIZE).map(|i| (i / BLOCK_SIZE) as u8 + 1).collect();
+        [data, footer(2, current_size, u64::MAX)].concat()
+    }
+
+    /// blocks 0 and 2 are allocated in sectors 4 and 13, the others are not
+    fn dynamic(current_size: u64, block_size: u32) -> Vec<u8> {
+        let footer = footer(3, current_size, FOOTER_SIZE);
+        let mut header = vec![0; DYNAMIC_HEADER_SIZE];
+        header[..8].copy_from_slice(&DYNAMIC_HEADER_MAGIC);
+        header[8..16].copy_from_slice(&u64::MAX.to_be_bytes());
+        header[16..24].copy_from_slice(&1536u64.to_be_bytes());
+        header[28..32].copy_from_slice(&4u32.to_be_bytes());
+        header[32..36].copy_from_slice(&block_size.to_be_bytes());
+        let sum = checksum(&header, 36);
+        header[36..40].copy_from_slice(&sum.to_be_bytes());
+
+        let mut bat: Vec<u8> = [4, BLOCK_UNALLOCATED, 13, BLOCK_UNALLOCATED]
+            .iter()
+            .flat_map(|entry: &u32| entry.to_be_bytes())
+            .collect();
+        bat.resize(SECTOR_SIZE as usize, 0xff);
+
+        let bitmap = vec![0xff; SECTOR_SIZE as usize];
+        [
+            footer.clone(),
+            header,
+            bat,
+            bitmap.clone(),
+            vec![0xaa; BLOCK_SIZE],
+            bitmap,
+            vec![0xbb; BLOCK_SIZE],
+            footer,
+        ]
+        .concat()
+    }
+
+    fn write(name: &str, image: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("{}-{}.vhd", name, std::process::id()));
+        fs::write(&path, image).unwrap();
+        path
+    }
+
+    fn blocks(image: &mut VhdImage) -> Vec<(bool, Vec<u8>)> {
+        (0..4)
+            .map(|block| {
+                let mut buf = vec![0x11; BLOCK_SIZE];
+                let allocated = image
+                    .read_at((block * BLOCK_SIZE) as u64, &mut buf)
+                    .unwrap();
+                (allocated, buf)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reads_fixed_image() {
+        let path = write("vhd-fixed", &fixed(DISK_SIZE as u64));
+        let mut image = VhdImage::open(&path).unwrap();
+        assert_eq!(image.footer().disk_type, VhdDiskType::Fixed);
+        assert_eq!(image.virtual_size(), DISK_SIZE as u64);
+        for (block, (allocated, data)) in blocks(&mut image).into_iter().enumerate() {
+            assert!(allocated);
+            assert_eq!(data, vec![block as u8 + 1; BLOCK_SIZE]);
+        }
+
+        // past the end of the disk
+        let mut buf = vec![0x11; 1024];
+        assert!(image.read_at(DISK_SIZE as u64 - 512, &mut buf).unwrap());
+        assert_eq!(&buf[..512], &[4; 512][..]);
+        assert_eq!(&buf[512..], &[0; 512][..]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reads_dynamic_image() {
+        let path = write("vhd-dynamic", &dynamic(DISK_SIZE as u64, BLOCK_SIZE as u32));
+        let mut image = VhdImage::open(&path).unwrap();
+        assert_eq!(image.footer().disk_type, VhdDiskType::Dynamic);
+        assert_eq!(
+            blocks(&mut image),
+            vec![
+                (true, vec![0xaa; BLOCK_SIZE]),
+                (false, vec![0; BLOCK_SIZE]),
+                (true, vec![0xbb; BLOCK_SIZE]),
+                (false, vec![0; BLOCK_SIZE]),
+            ]
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reads_the_footer_copy_of_a_damaged_dynamic_image() {
+        let mut image = dynamic(DISK_SIZE as u64, BLOCK_SIZE as u32);
+        let len = image.len();
+        image[len - 1] ^= 0xff;
+        let path = write("vhd-damaged-footer", &image);
+        let mut image = VhdImage::open(&path).unwrap();
+        assert_eq!(blocks(&mut image)[2], (true, vec![0xbb; BLOCK_SIZE]));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rejects_corrupt_images() {
+        let mut bad_checksum = fixed(DISK_SIZE as u64);
+        let len = bad_checksum.len();
+        bad_checksum[len - FOOTER_SIZE as usize + 48] ^= 0xff;
+        let images = [
+            bad_checksum,
+            // more data than the file holds
+            fixed(u64::MAX),
+            dynamic(DISK_SIZE as u64, 0),
+            dynamic(DISK_SIZE as u64, 1000),
+            // more blocks than the allocation table holds
+            dynamic(u64::MAX, BLOCK_SIZE as u32),
+            footer(5, DISK_SIZE as u64, u64::MAX),
+        ];
+        for image in images.iter() {
+            let path = write("vhd-corrupt", image);
+            assert!(matches!(VhdImage::open(&path), Err(Error::Parse(_))));
+            fs::remove_file(&path).unwrap();
+        }
+    }
+}
@@ -0,0 +1,524 @@
+//! VHDX disk image module
+//!
+//! Reads fixed and dynamic Hyper-V images. The file starts with an identifier followed by two
+//! copies of the header and of the region table, which locates the block allocation table and
+//! the metadata describing the disk. Differencing images and images whose log must be replayed,
+//! because Hyper-V did not close them cleanly, are not supported.
+
+use crate::{
+    disk::{convert::DiskImageReader, crc32, le_u16, le_u32, le_u64, unsupported, CRC32C},
+    error::{Error, Result},
+};
+
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+/// first bytes of a VHDX
+pub const VHDX_MAGIC: [u8; 8] = *b"vhdxfile";
+
+const KIB: u64 = 1024;
+const MIB: u64 = 1024 * KIB;
+
+const HEADER_OFFSETS: [u64; 2] = [64 * KIB, 128 * KIB];
+const HEADER_SIZE: usize = 4 * KIB as usize;
+const HEADER_MAGIC: [u8; 4] = *b"head";
+
+const REGION_TABLE_OFFSETS: [u64; 2] = [192 * KIB, 256 * KIB];
+const REGION_TABLE_SIZE: usize = 64 * KIB as usize;
+const REGION_TABLE_MAGIC: [u8; 4] = *b"regi";
+const MAX_REGION_ENTRIES: u32 = 2047;
+
+const METADATA_MAGIC: [u8; 8] = *b"metadata";
+const MAX_METADATA_ENTRIES: u16 = 2047;
+
+const BAT_REGION: [u8; 16] = [
+    0x66, 0x77, 0xc2, 0x2d, 0x23, 0xf6, 0x00, 0x42, 0x9d, 0x64, 0x11, 0x5e, 0x9b, 0xfd, 0x4a, 0x08,
+];
+const METADATA_REGION: [u8; 16] = [
+    0x06, 0xa2, 0x7c, 0x8b, 0x90, 0x47, 0x9a, 0x4b, 0xb8, 0xfe, 0x57, 0x5f, 0x05, 0x0f, 0x88, 0x6e,
+];
+const FILE_PARAMETERS: [u8; 16] = [
+    0x37, 0x67, 0xa1, 0xca, 0x36, 0xfa, 0x43, 0x4d, 0xb3, 0xb6, 0x33, 0xf0, 0xaa, 0x44, 0xe7, 0x6b,
+];
+const VIRTUAL_DISK_SIZE: [u8; 16] = [
+    0x24, 0x42, 0xa5, 0x2f, 0x1b, 0xcd, 0x76, 0x48, 0xb2, 0x11, 0x5d, 0xbe, 0xd8, 0x3b, 0xf4, 0xb8,
+];
+const LOGICAL_SECTOR_SIZE: [u8; 16] = [
+    0x1d, 0xbf, 0x41, 0x81, 0x6f, 0xa9, 0x09, 0x47, 0xba, 0x47, 0xf2, 0x33, 0xa8, 0xfa, 0xab, 0x5f,
+];
+
+const REQUIRED: u32 = 1 << 0;
+const METADATA_REQUIRED: u32 = 1 << 2;
+const HAS_PARENT: u32 = 1 << 1;
+
+const BLOCK_STATE_MASK: u64 = 0x7;
+const BLOCK_FULLY_PRESENT: u64 = 6;
+const BLOCK_PARTIALLY_PRESENT: u64 = 7;
+
+/// block allocation tables larger than this are rejected as corrupt
+const MAX_BAT_SIZE: u64 = 256 * MIB;
+
+/// fields of the metadata region describing the disk
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VhdxMetadata {
+    /// size of a payload block in bytes
+    pub block_size: u32,
+    /// size of the disk seen by the guest in bytes
+    pub virtual_disk_size: u64,
+    pub logical_sector_size: u32,
+}
+
+/// fixed or dynamic VHDX
+///
+/// # Examples
+/// ```rust,no_run
+/// use virtualization_rs::disk::convert::DiskImageReader;
+/// use virtualization_rs::disk::vhdx::VhdxImage;
+///
+/// let image = VhdxImage::open("appliance.vhdx").unwrap();
+/// println!("{} bytes in blocks of {}", image.virtual_size(), image.metadata().block_size);
+/// ```
+pub struct VhdxImage {
+    file: File,
+    path: PathBuf,
+    metadata: VhdxMetadata,
+    /// payload blocks described by each sector bitmap entry of the block allocation table
+    chunk_ratio: u64,
+    bat: Vec<u64>,
+}
+
+impl VhdxImage {
+    /// open the VHDX at `path`
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<VhdxImage> {
+        let path = path.as_ref();
+        let file = File::open(path)?;
+        let mut identifier = [0; 8];
+        let len = file.metadata()?.len().min(identifier.len() as u64) as usize;
+        file.read_exact_at(&mut identifier[..len], 0)?;
+        if identifier != VHDX_MAGIC {
+            return Err(Error::Parse(format!(
+                "{} is not a VHDX image",
+                path.display()
+            )));
+        }
+
+        check_header(&file, path)?;
+        let (bat_region, metadata_region) = read_region_table(&file, path)?;
+        let metadata = read_metadata(&file, path, metadata_region)?;
+
+        let block_size = u64::from(metadata.block_size);
+        let chunk_ratio = (1 << 23) * u64::from(metadata.logical_sector_size) / block_size;
+        let blocks = metadata.virtual_disk_size.div_ceil(block_size);
+        // a sector bitmap entry follows every chunk of payload entries
+        let entries = blocks + (blocks.saturating_sub(1)) / chunk_ratio;
+        let (bat_offset, bat_len) = bat_region;
+        if entries * 8 > u64::from(bat_len) || entries * 8 > MAX_BAT_SIZE {
+            return Err(corrupt(
+                path,
+                format!("block allocation table of {} bytes", bat_len),
+            ));
+        }
+        let mut bat = vec![0; entries as usize * 8];
+        file.read_exact_at(&mut bat, bat_offset)?;
+        let bat = bat.chunks_exact(8).map(|entry| le_u64(entry, 0)).collect();
+
+        Ok(VhdxImage {
+            file,
+            path: path.to_path_buf(),
+            metadata,
+            chunk_ratio,
+            bat,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn metadata(&self) -> &VhdxMetadata {
+        &self.metadata
+    }
+
+    /// host offset of the payload block `block`, `None` when it reads as zeros
+    fn block_offset(&self, block: u64) -> Result<Option<u64>> {
+        let index = block + block / self.chunk_ratio;
+        let entry = match self.bat.get(index as usize) {
+            Some(entry) => *entry,
+            None => return Ok(None),
+        };
+        match entry & BLOCK_STATE_MASK {
+            BLOCK_FULLY_PRESENT => Ok(Some(entry >> 20 << 20)),
+            BLOCK_PARTIALLY_PRESENT => Err(corrupt(
+                &self.path,
+                format!("partially present block {} without a parent", block),
+            )),
+            // not present, undefined, zero or unmapped
+            _ => Ok(None),
+        }
+    }
+}
+
+impl DiskImageReader for VhdxImage {
+    fn virtual_size(&self) -> u64 {
+        self.metadata.virtual_disk_size
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<bool> {
+        let size = self.metadata.virtual_disk_size;
+        let block_size = u64::from(self.metadata.block_size);
+        let mut allocated = false;
+        let mut position = 0;
+        while position < buf.len() {
+            let offset = offset + position as u64;
+            let in_block = offset % block_size;
+            let len = ((block_size - in_block) as usize).min(buf.len() - position);
+            let part = &mut buf[position..position + len];
+            position += len;
+            match self.block_offset(offset / block_size)? {
+                Some(host_offset) if offset < size => {
+                    let host_offset = host_offset.checked_add(in_block).ok_or_else(|| {
+                        corrupt(
+                            &self.path,
+                            format!("block {} at offset {}", offset / block_size, host_offset),
+                        )
+                    })?;
+                    self.file.read_exact_at(part, host_offset)?;
+                    allocated = true;
+                }
+                _ => part.fill(0),
+            }
+        }
+        Ok(allocated)
+    }
+}
+
+/// check that the current header, the valid one with the highest sequence number, has no log
+fn check_header(file: &File, path: &Path) -> Result<()> {
+    let mut current: Option<(u64, Vec<u8>)> = None;
+    for &offset in &HEADER_OFFSETS {
+        let mut header = vec![0; HEADER_SIZE];
+        file.read_exact_at(&mut header, offset)?;
+        if header[..4] != HEADER_MAGIC || !has_valid_checksum(&header) {
+            continue;
+        }
+        let sequence_number = le_u64(&header, 8);
+        if current
+            .as_ref()
+            .map_or(true, |(current, _)| sequence_number > *current)
+        {
+            current = Some((sequence_number, header));
+        }
+    }
+    let header = match current {
+        Some((_, header)) => header,
+        None => return Err(corrupt(path, "no valid header".to_string())),
+    };
+    let version = le_u16(&header, 66);
+    if version != 1 {
+        return Err(unsupported(path, format!("VHDX version {}", version)));
+    }
+    if header[48..64].iter().any(|&byte| byte != 0) {
+        return Err(unsupported(
+            path,
+            "replaying the log of a VHDX image".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// offsets and lengths of the block allocation table and metadata regions
+fn read_region_table(file: &File, path: &Path) -> Result<((u64, u32), (u64, u32))> {
+    let mut table = vec![0; REGION_TABLE_SIZE];
+    let mut found = false;
+    for &offset in &REGION_TABLE_OFFSETS {
+        file.read_exact_at(&mut table, offset)?;
+        if table[..4] == REGION_TABLE_MAGIC && has_valid_checksum(&table) {
+            found = true;
+            break;
+        }
+    }
+    let entry_count = le_u32(&table, 8);
+    if !found || entry_count > MAX_REGION_ENTRIES {
+        return Err(corrupt(path, "no valid region table".to_string()));
+    }
+
+    let mut bat = None;
+    let mut metadata = None;
+    for entry in table[16..].chunks_exact(32).take(entry_count as usize) {
+        let region = (le_u64(entry, 16), le_u32(entry, 24));
+        if entry[..16] == BAT_REGION {
+            bat = Some(region);
+        } else if entry[..16] == METADATA_REGION {
+            metadata = Some(region);
+        } else if le_u32(entry, 28) & REQUIRED != 0 {
+            return Err(unsupported(
+                path,
+                "an unknown required VHDX region".to_string(),
+            ));
+        }
+    }
+    match (bat, metadata) {
+        (Some(bat), Some(metadata)) => Ok((bat, metadata)),
+        _ => Err(corrupt(path, "missing region".to_string())),
+    }
+}
+
+fn read_metadata(file: &File, path: &Path, (offset, len): (u64, u32)) -> Result<VhdxMetadata> {
+    let mut table = [0; 64 * KIB as usize];
+    file.read_exact_at(&mut table, offset)?;
+    let entry_count = le_u16(&table, 10);
+    if table[..8] != METADATA_MAGIC || entry_count > MAX_METADATA_ENTRIES {
+        return Err(corrupt(path, "metadata table".to_string()));
+    }
+
+    let mut file_parameters = None;
+    let mut virtual_disk_size = None;
+    let mut logical_sector_size = None;
+    for entry in table[32..].chunks_exact(32).take(entry_count as usize) {
+        let item_offset = le_u32(entry, 16);
+        let item_len = le_u32(entry, 20);
+        if u64::from(item_offset) + u64::from(item_len) > u64::from(len) {
+            return Err(corrupt(
+                path,
+                "metadata item outside its region".to_string(),
+            ));
+        }
+        let read_item = |item_len: usize| -> Result<Vec<u8>> {
+            let mut item = vec![0; item_len];
+            file.read_exact_at(&mut item, offset + u64::from(item_offset))?;
+            Ok(item)
+        };
+        match &entry[..16] {
+            id if id == FILE_PARAMETERS && item_len >= 8 => {
+                let item = read_item(8)?;
+                file_parameters = Some((le_u32(&item, 0), le_u32(&item, 4)));
+            }
+            id if id == VIRTUAL_DISK_SIZE && item_len >= 8 => {
+                virtual_disk_size = Some(le_u64(&read_item(8)?, 0));
+            }
+            id if id == LOGICAL_SECTOR_SIZE && item_len >= 4 => {
+                logical_sector_size = Some(le_u32(&read_item(4)?, 0));
+            }
+            _ if le_u32(entry, 24) & METADATA_REQUIRED != 0 => {
+                return Err(unsupported(
+                    path,
+                    "an unknown required VHDX metadata item".to_string(),
+                ));
+            }
+            _ => {}
+        }
+    }
+
+    let (block_size, flags, virtual_disk_size, logical_sector_size) =
+        match (file_parameters, virtual_disk_size, logical_sector_size) {
+            (Some((block_size, flags)), Some(size), Some(sector_size)) => {
+                (block_size, flags, size, sector_size)
+            }
+            _ => return Err(corrupt(path, "missing metadata".to_string())),
+        };
+    if flags & HAS_PARENT != 0 {
+        return Err(unsupported(path, "differencing VHDX images".to_string()));
+    }
+    if !(MIB..=256 * MIB).contains(&u64::from(block_size)) || !block_size.is_power_of_two() {
+        return Err(corrupt(path, format!("block size {}", block_size)));
+    }
+    if logical_sector_size != 512 && logical_sector_size != 4096 {
+        return Err(corrupt(
+            path,
+            format!("logical sector size {}", logical_sector_size),
+        ));
+    }
+    Ok(VhdxMetadata {
+        block_size,
+        virtual_disk_size,
+        logical_sector_size,
+    })
+}
+
+/// whether the CRC-32C of `structure`, computed with its checksum field at offset 4 zeroed,
+/// matches that field
+fn has_valid_checksum(structure: &[u8]) -> bool {
+    let mut zeroed = structure.to_vec();
+    zeroed[4..8].fill(0);
+    crc32(CRC32C, &zeroed) == le_u32(structure, 4)
+}
+
+fn corrupt(path: &Path, description: String) -> Error {
+    Error::Parse(format!(
+        "{}: corrupt VHDX image: {}",
+        path.display(),
+        description
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    const METADATA_OFFSET: usize = MIB as usize;
+    const METADATA_LEN: u32 = 128 * KIB as u32;
+    const BAT_OFFSET: usize = 2 * MIB as usize;
+    const BAT_LEN: u32 = MIB as u32;
+    const DATA_OFFSET: u64 = 3 * MIB;
+
+    fn set_checksum(structure: &mut [u8]) {
+        structure[4..8].fill(0);
+        let crc = crc32(CRC32C, structure);
+        structure[4..8].copy_from_slice(&crc.to_le_bytes());
+    }
+
+    fn region(table: &mut [u8], index: usize, id: &[u8; 16], offset: usize, len: u32) {
+        let entry = &mut table[16 + index * 32..16 + (index + 1) * 32];
+        entry[..16].copy_from_slice(id);
+        entry[16..24].copy_from_slice(&(offset as u64).to_le_bytes());
+        entry[24..28].copy_from_slice(&len.to_le_bytes());
+        entry[28..32].copy_from_slice(&REQUIRED.to_le_bytes());
+    }
+
+    fn metadata_item(table: &mut [u8], index: usize, id: &[u8; 16], value: &[u8]) {
+        let item_offset = 64 * KIB as usize + index * 64;
+        let entry = &mut table[32 + index * 32..32 + (index + 1) * 32];
+        entry[..16].copy_from_slice(id);
+        entry[16..20].copy_from_slice(&(item_offset as u32).to_le_bytes());
+        entry[20..24].copy_from_slice(&(value.len() as u32).to_le_bytes());
+        entry[24..28].copy_from_slice(&METADATA_REQUIRED.to_le_bytes());
+        table[item_offset..item_offset + value.len()].copy_from_slice(value);
+    }
+
+    /// image whose block allocation table holds `bat`, followed by `data_blocks` payload blocks
+    /// filled with their index plus one
+    fn image(block_size: u32, disk_size: u64, bat: &[u64], data_blocks: usize) -> Vec<u8> {
+        let block_len = block_size as usize;
+        let mut image = vec![0; DATA_OFFSET as usize + data_blocks * block_len];
+        image[..8].copy_from_slice(&VHDX_MAGIC);
+
+        // only the second header is valid
+        let header = &mut image[128 * KIB as usize..128 * KIB as usize + HEADER_SIZE];
+        header[..4].copy_from_slice(&HEADER_MAGIC);
+        header[8..16].copy_from_slice(&1u64.to_le_bytes());
+        header[66..68].copy_from_slice(&1u16.to_le_bytes());
+        set_checksum(header);
+
+        let table = &mut image[192 * KIB as usize..192 * KIB as usize + REGION_TABLE_SIZE];
+        table[..4].copy_from_slice(&REGION_TABLE_MAGIC);
+        table[8..12].copy_from_slice(&2u32.to_le_bytes());
+        region(table, 0, &BAT_REGION, BAT_OFFSET, BAT_LEN);
+        region(table, 1, &METADATA_REGION, METADATA_OFFSET, METADATA_LEN);
+        set_checksum(table);
+
+        let table = &mut image[METADATA_OFFSET..METADATA_OFFSET + METADATA_LEN as usize];
+        table[..8].copy_from_slice(&METADATA_MAGIC);
+        table[10..12].copy_from_slice(&3u16.to_le_bytes());
+        let file_parameters = [block_size.to_le_bytes(), 0u32.to_le_bytes()].concat();
+        metadata_item(table, 0, &FILE_PARAMETERS, &file_parameters);
+        metadata_item(table, 1, &VIRTUAL_DISK_SIZE, &disk_size.to_le_bytes());
+        metadata_item(table, 2, &LOGICAL_SECTOR_SIZE, &512u32.to_le_bytes());
+
+        for (index, entry) in bat.iter().enumerate() {
+            let offset = BAT_OFFSET + index * 8;
+            image[offset..offset + 8].copy_from_slice(&entry.to_le_bytes());
+        }
+        for block in 0..data_blocks {
+            let offset = DATA_OFFSET as usize + block * block_len;
+            image[offset..offset + block_len].fill(block as u8 + 1);
+        }
+        image
+    }
+
+    fn present(block: u64) -> u64 {
+        (DATA_OFFSET + block * MIB) | BLOCK_FULLY_PRESENT
+    }
+
+    fn write(name: &str, image: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("{}-{}.vhdx", name, std::process::id()));
+        fs::write(&path, image).unwrap();
+        path
+    }
+
+    fn blocks(image: &mut VhdxImage, count: u64) -> Vec<(bool, Vec<u8>)> {
+        (0..count)
+            .map(|block| {
+                let mut buf = vec![0x11; 4096];
+                let allocated = image.read_at(block * MIB + 512, &mut buf).unwrap();
+                (allocated, buf)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reads_fixed_image() {
+        let bat = [present(0), present(1), present(2)];
+        let path = write("vhdx-fixed", &image(MIB as u32, 3 * MIB, &bat, 3));
+        let mut image = VhdxImage::open(&path).unwrap();
+        assert_eq!(image.virtual_size(), 3 * MIB);
+        assert_eq!(image.metadata().block_size, MIB as u32);
+        assert_eq!(
+            blocks(&mut image, 3),
+            vec![
+                (true, vec![1; 4096]),
+                (true, vec![2; 4096]),
+                (true, vec![3; 4096]),
+            ]
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reads_dynamic_image() {
+        let bat = [present(1), 0, present(0)];
+        let path = write("vhdx-dynamic", &image(MIB as u32, 3 * MIB, &bat, 2));
+        let mut image = VhdxImage::open(&path).unwrap();
+        assert_eq!(
+            blocks(&mut image, 4),
+            vec![
+                (true, vec![2; 4096]),
+                (false, vec![0; 4096]),
+                (true, vec![1; 4096]),
+                // past the end of the disk
+                (false, vec![0; 4096]),
+            ]
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rejects_corrupt_images() {
+        let bat = [present(0)];
+        let mut bad_region_table = image(MIB as u32, MIB, &bat, 1);
+        bad_region_table[192 * KIB as usize + 20] ^= 0xff;
+        bad_region_table[256 * KIB as usize..320 * KIB as usize].fill(0);
+        let images = [
+            bad_region_table,
+            image(3 * MIB as u32, MIB, &bat, 1),
+            image(MIB as u32 / 2, MIB, &bat, 1),
+            // more blocks than the allocation table holds
+            image(MIB as u32, u64::MAX, &bat, 1),
+        ];
+        for image in images.iter() {
+            let path = write("vhdx-corrupt", image);
+            assert!(matches!(VhdxImage::open(&path), Err(Error::Parse(_))));
+            fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn rejects_blocks_at_hostile_offsets() {
+        // a block at the end of the address space overflows past its first MiB
+        let bat = [u64::MAX >> 20 << 20 | BLOCK_FULLY_PRESENT, 0, 0, 0];
+        let path = write("vhdx-hostile", &image(2 * MIB as u32, 2 * MIB, &bat, 0));
+        let mut image = VhdxImage::open(&path).unwrap();
+        let mut buf = vec![0; 512];
+        assert!(matches!(
+            image.read_at(MIB + MIB / 2, &mut buf),
+            Err(Error::Parse(_))
+        ));
+
+        let bat = [BLOCK_PARTIALLY_PRESENT | DATA_OFFSET];
+        fs::write(&path, self::image(MIB as u32, MIB, &bat, 1)).unwrap();
+        let mut image = VhdxImage::open(&path).unwrap();
+        assert!(matches!(image.read_at(0, &mut buf), Err(Error::Parse(_))));
+        fs::remove_file(&path).unwrap();
+    }
+}
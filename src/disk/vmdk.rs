@@ -0,0 +1,586 @@
+//! VMDK disk image module
+//!
+//! Reads the single-file VMDK variants appliances are shipped as: monolithic sparse images and the
+//! stream-optimized images found in OVA archives, whose grains are zlib-compressed. Delta links,
+//! split images and descriptor files referencing separate extents are not supported.
+
+use crate::{
+    disk::{convert::DiskImageReader, le_u16, le_u32, le_u64, unsupported, SECTOR_SIZE},
+    error::{Error, Result},
+};
+
+use flate2::read::ZlibDecoder;
+use std::fs::File;
+use std::io::Read;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+/// first bytes of a sparse extent
+pub const VMDK_MAGIC: [u8; 4] = *b"KDMV";
+/// first bytes of a descriptor file
+pub const DESCRIPTOR_MAGIC: &[u8] = b"# Disk DescriptorFile";
+
+const HEADER_SIZE: usize = 512;
+
+const FLAG_COMPRESSED: u32 = 1 << 16;
+const FLAG_MARKERS: u32 = 1 << 17;
+
+const COMPRESSION_NONE: u16 = 0;
+const COMPRESSION_DEFLATE: u16 = 1;
+
+/// grain directory offset of stream-optimized images, whose real header is in the footer
+const GD_AT_END: u64 = u64::MAX;
+
+/// grain table entry of a grain that reads as zeros
+const GRAIN_ZERO: u32 = 1;
+
+/// CID of the parent of an image without one
+const NO_PARENT: &str = "ffffffff";
+
+/// grain directories larger than this are rejected as corrupt
+const MAX_GRAIN_DIRECTORY_SIZE: u64 = 32 * 1024 * 1024;
+
+/// largest descriptor read
+const MAX_DESCRIPTOR_SIZE: u64 = 1024 * 1024;
+
+/// header fields of a sparse extent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VmdkHeader {
+    pub version: u32,
+    pub flags: u32,
+    /// size of the disk in sectors
+    pub capacity: u64,
+    /// size of a grain in sectors
+    pub grain_size: u64,
+    /// offset of the embedded descriptor in sectors, 0 without one
+    pub descriptor_offset: u64,
+    /// size of the embedded descriptor in sectors
+    pub descriptor_size: u64,
+    pub num_gtes_per_gt: u32,
+    /// offset of the grain directory in sectors
+    pub gd_offset: u64,
+    pub compress_algorithm: u16,
+}
+
+impl VmdkHeader {
+    /// whether grains are compressed, as in stream-optimized images
+    pub fn is_compressed(&self) -> bool {
+        self.flags & FLAG_COMPRESSED != 0
+    }
+
+    fn grain_bytes(&self) -> u64 {
+        self.grain_size * SECTOR_SIZE
+    }
+}
+
+/// grain of the disk as described by its grain table entry
+enum Grain {
+    Unallocated,
+    Zero,
+    /// host offset in bytes
+    Data(u64),
+}
+
+/// monolithic sparse or stream-optimized VMDK image
+///
+/// # Examples
+/// ```rust,no_run
+/// use virtualization_rs::disk::convert::DiskImageReader;
+/// use virtualization_rs::disk::vmdk::VmdkImage;
+///
+/// let image = VmdkImage::open("appliance-disk1.vmdk").unwrap();
+/// println!("{} {} bytes", image.create_type().unwrap_or("unknown"), image.virtual_size());
+/// ```
+pub struct VmdkImage {
+    file: File,
+    path: PathBuf,
+    header: VmdkHeader,
+    descriptor: String,
+    grain_directory: Vec<u32>,
+    /// index in the grain directory and contents of the last grain table read
+    gt_cache: Option<(usize, Vec<u32>)>,
+    /// host offset and contents of the last compressed grain read
+    grain_cache: Option<(u64, Vec<u8>)>,
+}
+
+impl VmdkImage {
+    /// open the VMDK image at `path`
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<VmdkImage> {
+        let path = path.as_ref();
+        let file = File::open(path)?;
+        let header = read_header(&file, path)?;
+
+        if header.descriptor_offset == 0 {
+            return Err(unsupported(
+                path,
+                "an extent of a split VMDK image".to_string(),
+            ));
+        }
+        let descriptor = read_descriptor(&file, &header)?;
+        if let Some(parent) = descriptor_value(&descriptor, "parentCID") {
+            if !parent.eq_ignore_ascii_case(NO_PARENT) {
+                return Err(unsupported(path, "VMDK delta links".to_string()));
+            }
+        }
+
+        let gt_coverage = u64::from(header.num_gtes_per_gt) * header.grain_bytes();
+        let entries = (header.capacity * SECTOR_SIZE).div_ceil(gt_coverage);
+        if entries * 4 > MAX_GRAIN_DIRECTORY_SIZE {
+            return Err(corrupt(
+                path,
+                format!("grain directory of {} entries", entries),
+            ));
+        }
+        let mut grain_directory = vec![0; entries as usize * 4];
+        file.read_exact_at(&mut grain_directory, header.gd_offset * SECTOR_SIZE)?;
+        let grain_directory = grain_directory
+            .chunks_exact(4)
+            .map(|entry| le_u32(entry, 0))
+            .collect();
+
+        Ok(VmdkImage {
+            file,
+            path: path.to_path_buf(),
+            header,
+            descriptor,
+            grain_directory,
+            gt_cache: None,
+            grain_cache: None,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn header(&self) -> &VmdkHeader {
+        &self.header
+    }
+
+    /// embedded descriptor
+    pub fn descriptor(&self) -> &str {
+        &self.descriptor
+    }
+
+    /// `createType` of the descriptor, e.g. `monolithicSparse` or `streamOptimized`
+    pub fn create_type(&self) -> Option<&str> {
+        descriptor_value(&self.descriptor, "createType")
+    }
+
+    /// the grain containing the disk offset `offset`
+    fn grain(&mut self, offset: u64) -> Result<Grain> {
+        let num_gtes = self.header.num_gtes_per_gt as usize;
+        let index = (offset / self.header.grain_bytes()) as usize;
+        let gd_index = index / num_gtes;
+        let gt_index = index % num_gtes;
+
+        let gt_offset = match self.grain_directory.get(gd_index) {
+            Some(0) | None => return Ok(Grain::Unallocated),
+            Some(gt_offset) => u64::from(*gt_offset) * SECTOR_SIZE,
+        };
+        let cached = matches!(&self.gt_cache, Some((cached, _)) if *cached == gd_index);
+        if !cached {
+            let mut table = vec![0; num_gtes * 4];
+            self.file.read_exact_at(&mut table, gt_offset)?;
+            let table = table
+                .chunks_exact(4)
+                .map(|entry| le_u32(entry, 0))
+                .collect();
+            self.gt_cache = Some((gd_index, table));
+        }
+        let entry = match &self.gt_cache {
+            Some((_, table)) => table[gt_index],
+            None => unreachable!(),
+        };
+        Ok(match entry {
+            0 => Grain::Unallocated,
+            GRAIN_ZERO => Grain::Zero,
+            sector => Grain::Data(u64::from(sector) * SECTOR_SIZE),
+        })
+    }
+
+    /// decompressed contents of the grain whose marker is at `host_offset`
+    fn decompress(&mut self, host_offset: u64) -> Result<&[u8]> {
+        let cached = matches!(&self.grain_cache, Some((cached, _)) if *cached == host_offset);
+        if !cached {
+            // a grain marker holds the sector of the grain and the size of its compressed data
+            let mut marker = [0; 12];
+            self.file.read_exact_at(&mut marker, host_offset)?;
+            let len = le_u32(&marker, 8);
+            if u64::from(len) > 2 * self.header.grain_bytes() + 1024 {
+                return Err(corrupt(
+                    &self.path,
+                    format!(
+                        "compressed grain of {} bytes at offset {}",
+                        len, host_offset
+                    ),
+                ));
+            }
+            let mut compressed = vec![0; len as usize];
+            self.file.read_exact_at(&mut compressed, host_offset + 12)?;
+            let mut grain = vec![0; self.header.grain_bytes() as usize];
+            ZlibDecoder::new(&compressed[..])
+                .read_exact(&mut grain)
+                .map_err(|err| {
+                    corrupt(
+                        &self.path,
+                        format!("compressed grain at offset {}: {}", host_offset, err),
+                    )
+                })?;
+            self.grain_cache = Some((host_offset, grain));
+        }
+        match &self.grain_cache {
+            Some((_, grain)) => Ok(grain),
+            None => unreachable!(),
+        }
+    }
+}
+
+impl DiskImageReader for VmdkImage {
+    fn virtual_size(&self) -> u64 {
+        self.header.capacity * SECTOR_SIZE
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<bool> {
+        let size = self.virtual_size();
+        let grain_bytes = self.header.grain_bytes();
+        let mut allocated = false;
+        let mut position = 0;
+        while position < buf.len() {
+            let offset = offset + position as u64;
+            let in_grain = offset % grain_bytes;
+            let len = ((grain_bytes - in_grain) as usize).min(buf.len() - position);
+            let part = &mut buf[position..position + len];
+            position += len;
+            if offset >= size {
+                part.fill(0);
+                continue;
+            }
+            match self.grain(offset)? {
+                Grain::Unallocated => part.fill(0),
+                Grain::Zero => {
+                    part.fill(0);
+                    allocated = true;
+                }
+                Grain::Data(host_offset) if self.header.is_compressed() => {
+                    let grain = self.decompress(host_offset)?;
+                    let start = in_grain as usize;
+                    part.copy_from_slice(&grain[start..start + len]);
+                    allocated = true;
+                }
+                Grain::Data(host_offset) => {
+                    self.file.read_exact_at(part, host_offset + in_grain)?;
+                    allocated = true;
+                }
+            }
+        }
+        Ok(allocated)
+    }
+}
+
+fn read_header(file: &File, path: &Path) -> Result<VmdkHeader> {
+    let file_len = file.metadata()?.len();
+    let mut header = [0; HEADER_SIZE];
+    let len = file_len.min(header.len() as u64) as usize;
+    file.read_exact_at(&mut header[..len], 0)?;
+    if header.starts_with(DESCRIPTOR_MAGIC) {
+        return Err(unsupported(
+            path,
+            "a VMDK descriptor file with separate extents".to_string(),
+        ));
+    }
+    if len < HEADER_SIZE || header[..4] != VMDK_MAGIC {
+        return Err(Error::Parse(format!(
+            "{} is not a VMDK image",
+            path.display()
+        )));
+    }
+    let mut header = parse_header(&header);
+
+    // stream-optimized images are written in one pass, their header is repeated with the grain
+    // directory offset in the footer: a footer marker, the header and an end-of-stream marker
+    if header.gd_offset == GD_AT_END {
+        if header.flags & FLAG_MARKERS == 0 || file_len < 3 * SECTOR_SIZE {
+            return Err(corrupt(path, "missing footer".to_string()));
+        }
+        let mut footer = [0; HEADER_SIZE];
+        file.read_exact_at(&mut footer, file_len - 2 * SECTOR_SIZE)?;
+        if footer[..4] != VMDK_MAGIC {
+            return Err(corrupt(path, "missing footer".to_string()));
+        }
+        header = parse_header(&footer);
+        if header.gd_offset == GD_AT_END {
+            return Err(corrupt(
+                path,
+                "no grain directory in the footer".to_string(),
+            ));
+        }
+    }
+
+    if !(1..=3).contains(&header.version) {
+        return Err(unsupported(
+            path,
+            format!("VMDK version {}", header.version),
+        ));
+    }
+    if header.grain_size == 0
+        || !header.grain_size.is_power_of_two()
+        || header.grain_size > 2048
+        || header.num_gtes_per_gt == 0
+        || header.num_gtes_per_gt > 4096
+    {
+        return Err(corrupt(
+            path,
+            format!(
+                "grain size {} with {} entries per grain table",
+                header.grain_size, header.num_gtes_per_gt
+            ),
+        ));
+    }
+    // sizes and offsets in sectors are read in bytes, they must not overflow
+    if header.capacity.checked_mul(SECTOR_SIZE).is_none() {
+        return Err(corrupt(
+            path,
+            format!("capacity of {} sectors", header.capacity),
+        ));
+    }
+    if header.gd_offset.checked_mul(SECTOR_SIZE).is_none() {
+        return Err(corrupt(
+            path,
+            format!("grain directory at sector {}", header.gd_offset),
+        ));
+    }
+    if header.descriptor_offset.checked_mul(SECTOR_SIZE).is_none()
+        || header.descriptor_size > MAX_DESCRIPTOR_SIZE / SECTOR_SIZE
+    {
+        return Err(corrupt(
+            path,
+            format!(
+                "descriptor of {} sectors at sector {}",
+                header.descriptor_size, header.descriptor_offset
+            ),
+        ));
+    }
+    match header.compress_algorithm {
+        COMPRESSION_NONE if !header.is_compressed() => {}
+        COMPRESSION_DEFLATE if header.is_compressed() => {}
+        algorithm => {
+            return Err(unsupported(
+                path,
+                format!("VMDK compression algorithm {}", algorithm),
+            ))
+        }
+    }
+    Ok(header)
+}
+
+fn parse_header(header: &[u8]) -> VmdkHeader {
+    VmdkHeader {
+        version: le_u32(header, 4),
+        flags: le_u32(header, 8),
+        capacity: le_u64(header, 12),
+        grain_size: le_u64(header, 20),
+        descriptor_offset: le_u64(header, 28),
+        descriptor_size: le_u64(header, 36),
+        num_gtes_per_gt: le_u32(header, 44),
+        gd_offset: le_u64(header, 56),
+        compress_algorithm: le_u16(header, 77),
+    }
+}
+
+/// the embedded descriptor, whose size and offset `read_header` checked
+fn read_descriptor(file: &File, header: &VmdkHeader) -> Result<String> {
+    let mut descriptor = vec![0; (header.descriptor_size * SECTOR_SIZE) as usize];
+    file.read_exact_at(&mut descriptor, header.descriptor_offset * SECTOR_SIZE)?;
+    let end = descriptor
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(descriptor.len());
+    Ok(String::from_utf8_lossy(&descriptor[..end]).into_owned())
+}
+
+/// value of `key = "value"` in a descriptor
+fn descriptor_value<'a>(descriptor: &'a str, key: &str) -> Option<&'a str> {
+    descriptor.lines().find_map(|line| {
+        let (name, value) = line.split_once('=')?;
+        if name.trim() == key {
+            Some(value.trim().trim_matches('"'))
+        } else {
+            None
+        }
+    })
+}
+
+fn corrupt(path: &Path, description: String) -> Error {
+    Error::Parse(format!(
+        "{}: corrupt VMDK image: {}",
+        path.display(),
+        description
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{write::ZlibEncoder, Compression};
+    use std::fs;
+    use std::io::Write;
+
+    const SECTOR: usize = SECTOR_SIZE as usize;
+
+    /// header of a disk of 8 one-sector grains in grain tables of 4 entries, its descriptor in
+    /// sector 1 and its grain directory in sector 2
+    fn header(flags: u32, compress_algorithm: u16, gd_offset: u64) -> Vec<u8> {
+        let mut header = vec![0; SECTOR];
+        header[..4].copy_from_slice(&VMDK_MAGIC);
+        header[4..8].copy_from_slice(&1u32.to_le_bytes());
+        header[8..12].copy_from_slice(&flags.to_le_bytes());
+        header[12..20].copy_from_slice(&8u64.to_le_bytes());
+        header[20..28].copy_from_slice(&1u64.to_le_bytes());
+        header[28..36].copy_from_slice(&1u64.to_le_bytes());
+        header[36..44].copy_from_slice(&1u64.to_le_bytes());
+        header[44..48].copy_from_slice(&4u32.to_le_bytes());
+        header[56..64].copy_from_slice(&gd_offset.to_le_bytes());
+        header[77..79].copy_from_slice(&compress_algorithm.to_le_bytes());
+        header
+    }
+
+    fn descriptor(create_type: &str) -> Vec<u8> {
+        let mut descriptor = format!(
+            "# Disk DescriptorFile\nversion=1\nparentCID=ffffffff\ncreateType=\"{}\"\n",
+            create_type
+        )
+        .into_bytes();
+        descriptor.resize(SECTOR, 0);
+        descriptor
+    }
+
+    /// sector of little-endian `u32` entries
+    fn table(entries: &[u32]) -> Vec<u8> {
+        let mut table: Vec<u8> = entries
+            .iter()
+            .flat_map(|entry| entry.to_le_bytes())
+            .collect();
+        table.resize(SECTOR, 0);
+        table
+    }
+
+    /// grains 0 and 3 hold data, grain 1 is zero and the others are not allocated
+    fn sparse() -> Vec<u8> {
+        [
+            header(0, COMPRESSION_NONE, 2),
+            descriptor("monolithicSparse"),
+            table(&[3, 0]),
+            table(&[4, GRAIN_ZERO, 0, 5]),
+            vec![0xaa; SECTOR],
+            vec![0xbb; SECTOR],
+        ]
+        .concat()
+    }
+
+    /// grain 0 is compressed, grain 1 is zero and the others are not allocated
+    fn stream_optimized() -> Vec<u8> {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&[0xcc; SECTOR]).unwrap();
+        let compressed = encoder.finish().unwrap();
+        // grain marker: sector of the grain and size of its compressed data
+        let mut grain = 0u64.to_le_bytes().to_vec();
+        grain.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+        grain.extend_from_slice(&compressed);
+        grain.resize(2 * SECTOR, 0);
+
+        let flags = FLAG_COMPRESSED | FLAG_MARKERS;
+        [
+            header(flags, COMPRESSION_DEFLATE, GD_AT_END),
+            descriptor("streamOptimized"),
+            table(&[3, 0]),
+            table(&[4, GRAIN_ZERO, 0, 0]),
+            grain,
+            // footer marker, footer and end-of-stream marker
+            vec![0; SECTOR],
+            header(flags, COMPRESSION_DEFLATE, 2),
+            vec![0; SECTOR],
+        ]
+        .concat()
+    }
+
+    fn write(name: &str, image: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("{}-{}.vmdk", name, std::process::id()));
+        fs::write(&path, image).unwrap();
+        path
+    }
+
+    fn grains(image: &mut VmdkImage) -> Vec<(bool, Vec<u8>)> {
+        (0..8)
+            .map(|grain| {
+                let mut buf = vec![0x11; SECTOR];
+                let allocated = image.read_at(grain * SECTOR_SIZE, &mut buf).unwrap();
+                (allocated, buf)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reads_monolithic_sparse_image() {
+        let path = write("vmdk-sparse", &sparse());
+        let mut image = VmdkImage::open(&path).unwrap();
+        assert_eq!(image.create_type(), Some("monolithicSparse"));
+        assert_eq!(image.virtual_size(), 8 * SECTOR_SIZE);
+
+        let grains = grains(&mut image);
+        assert_eq!(grains[0], (true, vec![0xaa; SECTOR]));
+        assert_eq!(grains[1], (true, vec![0; SECTOR]));
+        assert_eq!(grains[2], (false, vec![0; SECTOR]));
+        assert_eq!(grains[3], (true, vec![0xbb; SECTOR]));
+        assert!(grains[4..]
+            .iter()
+            .all(|grain| *grain == (false, vec![0; SECTOR])));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reads_stream_optimized_image() {
+        let path = write("vmdk-stream", &stream_optimized());
+        let mut image = VmdkImage::open(&path).unwrap();
+        assert_eq!(image.create_type(), Some("streamOptimized"));
+        assert_eq!(image.header().gd_offset, 2);
+
+        let grains = grains(&mut image);
+        assert_eq!(grains[0], (true, vec![0xcc; SECTOR]));
+        assert_eq!(grains[1], (true, vec![0; SECTOR]));
+        assert_eq!(grains[2], (false, vec![0; SECTOR]));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rejects_corrupt_images() {
+        let corruptions: [(usize, &[u8]); 6] = [
+            // capacity, grain directory, descriptor offset and size overflowing in bytes
+            (12, &u64::MAX.to_le_bytes()),
+            (56, &(u64::MAX / 2).to_le_bytes()),
+            (28, &(u64::MAX / 2).to_le_bytes()),
+            (36, &(u64::MAX / 2).to_le_bytes()),
+            // grain size
+            (20, &3u64.to_le_bytes()),
+            // grain table entries
+            (44, &0u32.to_le_bytes()),
+        ];
+        for (offset, value) in corruptions.iter() {
+            let mut image = sparse();
+            image[*offset..*offset + value.len()].copy_from_slice(value);
+            let path = write("vmdk-corrupt", &image);
+            assert!(matches!(VmdkImage::open(&path), Err(Error::Parse(_))));
+            fs::remove_file(&path).unwrap();
+        }
+
+        // a stream-optimized image without its footer
+        let image = stream_optimized();
+        let path = write("vmdk-no-footer", &image[..6 * SECTOR]);
+        assert!(matches!(VmdkImage::open(&path), Err(Error::Parse(_))));
+        fs::remove_file(&path).unwrap();
+
+        let path = write("vmdk-truncated", &sparse()[..100]);
+        assert!(matches!(VmdkImage::open(&path), Err(Error::Parse(_))));
+        fs::remove_file(&path).unwrap();
+    }
+}
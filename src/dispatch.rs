@@ -0,0 +1,953 @@
+//! dispatch queue module
+//!
+//! [`Queue`] is what the crate needs from a queue: run work asynchronously, after a delay, or run
+//! it and wait for its result. [`SerialQueue`] marks the queues running their work one item at a
+//! time in submission order. [`DispatchQueue`] implements them with Grand Central Dispatch,
+//! [`ThreadQueue`] with threads on any host, and [`ManualQueue`] is a fake queue that only runs
+//! its work when asked, so code marshalling onto a queue can be tested deterministically.
+
+use crate::{base::Id, sys::StrongPtr};
+
+use std::any::Any;
+use std::cell::Cell;
+use std::collections::VecDeque;
+#[cfg(target_os = "macos")]
+use std::ffi::c_void;
+use std::ffi::CString;
+use std::marker::PhantomData;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, sync_channel, Sender};
+#[cfg(not(target_os = "macos"))]
+use std::sync::OnceLock;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// queue running work on other threads
+pub trait Queue: Send + Sync {
+    /// run `work` without waiting
+    fn exec_async<F>(&self, work: F)
+    where
+        F: FnOnce() + Send + 'static;
+
+    /// run `work` and return its result, directly when already running on the queue
+    fn exec_sync<R, F>(&self, work: F) -> R
+    where
+        F: FnOnce() -> R + Send,
+        R: Send;
+
+    /// run `work` once `delay` has passed, without waiting
+    fn after<F>(&self, delay: Duration, work: F)
+    where
+        F: FnOnce() + Send + 'static;
+}
+
+/// queue running its work one item at a time, in submission order
+///
+/// # Safety
+/// Implementations must never run two work items at once, must run work in the order it was
+/// submitted, and `exec_sync` must not return before its work has run. Code relying on a queue to
+/// serialize access to data, such as
+/// [`VirtualMachineHandle`](crate::virtualization::handle::VirtualMachineHandle), depends on it.
+pub unsafe trait SerialQueue: Queue {}
+
+/// kind of queue, [`Serial`] or [`Concurrent`]
+pub trait QueueKind: Send + Sync + 'static {
+    const CONCURRENT: bool;
+}
+
+/// queue running one work item at a time, in submission order
+pub struct Serial;
+
+/// queue running work items in parallel
+pub struct Concurrent;
+
+impl QueueKind for Serial {
+    const CONCURRENT: bool = false;
+}
+
+impl QueueKind for Concurrent {
+    const CONCURRENT: bool = true;
+}
+
+#[cfg(target_os = "macos")]
+mod ffi {
+    use crate::base::Id;
+    use std::ffi::c_void;
+
+    pub type Function = extern "C" fn(*mut c_void);
+
+    pub const DISPATCH_TIME_NOW: u64 = 0;
+
+    // libdispatch is part of libSystem
+    #[link(name = "System", kind = "dylib")]
+    extern "C" {
+        pub fn dispatch_queue_create(label: *const libc::c_char, attr: Id) -> Id;
+        pub fn dispatch_sync_f(queue: Id, context: *mut c_void, work: Function);
+        pub fn dispatch_async_f(queue: Id, context: *mut c_void, work: Function);
+        pub fn dispatch_after_f(when: u64, queue: Id, context: *mut c_void, work: Function);
+        pub fn dispatch_time(when: u64, delta: i64) -> u64;
+        pub fn dispatch_queue_set_specific(
+            queue: Id,
+            key: *const c_void,
+            context: *mut c_void,
+            destructor: Option<Function>,
+        );
+        pub fn dispatch_get_specific(key: *const c_void) -> *mut c_void;
+        static _dispatch_main_q: c_void;
+        static _dispatch_queue_attr_concurrent: c_void;
+    }
+
+    pub fn main_queue() -> Id {
+        unsafe { &_dispatch_main_q as *const c_void as Id }
+    }
+
+    pub fn concurrent_attribute() -> Id {
+        unsafe { &_dispatch_queue_attr_concurrent as *const c_void as Id }
+    }
+}
+
+/// key marking dispatch queues with `dispatch_queue_set_specific`
+#[cfg(target_os = "macos")]
+static QUEUE_KEY: u8 = 0;
+
+#[cfg(target_os = "macos")]
+fn queue_key() -> *const c_void {
+    &QUEUE_KEY as *const u8 as *const c_void
+}
+
+/// mark `queue` so that it can recognize its own work
+#[cfg(target_os = "macos")]
+unsafe fn mark_queue(queue: Id) {
+    ffi::dispatch_queue_set_specific(queue, queue_key(), queue as *mut c_void, None);
+}
+
+#[cfg(not(target_os = "macos"))]
+unsafe fn mark_queue(_queue: Id) {}
+
+// a panic must not unwind into libdispatch, so like a ThreadQueue the trampolines catch it:
+// asynchronous work discards it and `exec_sync` resumes it on the calling thread
+
+#[cfg(target_os = "macos")]
+extern "C" fn run_boxed(context: *mut c_void) {
+    let work = unsafe { Box::from_raw(context as *mut Box<dyn FnOnce() + Send>) };
+    drop(panic::catch_unwind(AssertUnwindSafe(work)));
+}
+
+/// work run by `dispatch_sync_f`, and the panic it raised
+#[cfg(target_os = "macos")]
+struct Borrowed<F> {
+    work: Option<F>,
+    panic: Option<Box<dyn Any + Send>>,
+}
+
+#[cfg(target_os = "macos")]
+extern "C" fn run_borrowed<F: FnOnce()>(context: *mut c_void) {
+    let borrowed = unsafe { &mut *(context as *mut Borrowed<F>) };
+    if let Some(work) = borrowed.work.take() {
+        borrowed.panic = panic::catch_unwind(AssertUnwindSafe(work)).err();
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn boxed<F: FnOnce() + Send + 'static>(work: F) -> *mut c_void {
+    let work: Box<dyn FnOnce() + Send> = Box::new(work);
+    Box::into_raw(Box::new(work)) as *mut c_void
+}
+
+/// owned dispatch queue, released when dropped
+///
+/// Without Virtualization.framework the queue is backed by a [`ThreadQueue`]. There are no raw
+/// queues to tell apart there, so [`main`](Self::main) and [`from_raw`](Self::from_raw) share one.
+///
+/// Panics are handled like on a [`ThreadQueue`]: one in work given to `exec_async` or `after` is
+/// caught and discarded, one in work given to `exec_sync` is resumed on the calling thread.
+///
+/// # Examples
+/// ```rust,ignore
+/// use virtualization_rs::dispatch::{DispatchQueue, Queue, Serial};
+///
+/// let queue = DispatchQueue::new("vm", Serial);
+/// let answer = queue.exec_sync(|| 42);
+/// queue.exec_async(move || println!("{}", answer));
+/// ```
+pub struct DispatchQueue<K: QueueKind = Serial> {
+    queue: StrongPtr,
+    #[cfg(not(target_os = "macos"))]
+    threads: Arc<ThreadQueue<K>>,
+    _kind: PhantomData<K>,
+}
+
+// dispatch queues can be used from any thread
+unsafe impl<K: QueueKind> Send for DispatchQueue<K> {}
+unsafe impl<K: QueueKind> Sync for DispatchQueue<K> {}
+
+impl<K: QueueKind> DispatchQueue<K> {
+    /// queue named `label`
+    pub fn new(label: &str, _kind: K) -> Self {
+        let label = CString::new(label).unwrap_or_default();
+        unsafe {
+            let queue = create_queue(&label, K::CONCURRENT);
+            mark_queue(queue);
+            DispatchQueue {
+                queue: StrongPtr::new(queue),
+                #[cfg(not(target_os = "macos"))]
+                threads: Arc::new(ThreadQueue::new(&label.to_string_lossy(), _kind)),
+                _kind: PhantomData,
+            }
+        }
+    }
+
+    /// the `dispatch_queue_t`, valid while the queue is alive
+    pub fn as_raw(&self) -> Id {
+        *self.queue
+    }
+
+    /// whether the current thread is running work of the queue
+    #[cfg(target_os = "macos")]
+    pub fn is_current(&self) -> bool {
+        unsafe { ffi::dispatch_get_specific(queue_key()) == *self.queue as *mut c_void }
+    }
+
+    /// whether the current thread is running work of the queue
+    #[cfg(not(target_os = "macos"))]
+    pub fn is_current(&self) -> bool {
+        self.threads.is_current()
+    }
+}
+
+impl DispatchQueue<Serial> {
+    /// the queue of the main thread
+    pub fn main() -> Self {
+        unsafe { Self::from_raw(main_queue()) }
+    }
+
+    /// retain the serial queue `queue`
+    ///
+    /// # Safety
+    /// `queue` must be a serial dispatch queue.
+    pub unsafe fn from_raw(queue: Id) -> Self {
+        mark_queue(queue);
+        DispatchQueue {
+            queue: StrongPtr::retain(queue),
+            #[cfg(not(target_os = "macos"))]
+            threads: stub_main_queue(),
+            _kind: PhantomData,
+        }
+    }
+}
+
+impl<K: QueueKind> Clone for DispatchQueue<K> {
+    fn clone(&self) -> Self {
+        DispatchQueue {
+            queue: unsafe { StrongPtr::retain(*self.queue) },
+            #[cfg(not(target_os = "macos"))]
+            threads: self.threads.clone(),
+            _kind: PhantomData,
+        }
+    }
+}
+
+#[cfg(target_os = "macos")]
+unsafe fn create_queue(label: &CString, concurrent: bool) -> Id {
+    let attr = if concurrent {
+        ffi::concurrent_attribute()
+    } else {
+        crate::base::NIL
+    };
+    ffi::dispatch_queue_create(label.as_ptr(), attr)
+}
+
+#[cfg(not(target_os = "macos"))]
+unsafe fn create_queue(_label: &CString, _concurrent: bool) -> Id {
+    crate::base::NIL
+}
+
+#[cfg(target_os = "macos")]
+fn main_queue() -> Id {
+    ffi::main_queue()
+}
+
+#[cfg(not(target_os = "macos"))]
+fn main_queue() -> Id {
+    crate::base::NIL
+}
+
+/// thread queue standing in for every raw dispatch queue
+#[cfg(not(target_os = "macos"))]
+fn stub_main_queue() -> Arc<ThreadQueue<Serial>> {
+    static MAIN: OnceLock<Arc<ThreadQueue<Serial>>> = OnceLock::new();
+    MAIN.get_or_init(|| Arc::new(ThreadQueue::new("main", Serial)))
+        .clone()
+}
+
+#[cfg(target_os = "macos")]
+impl<K: QueueKind> Queue for DispatchQueue<K> {
+    fn exec_async<F>(&self, work: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        unsafe { ffi::dispatch_async_f(*self.queue, boxed(work), run_boxed) };
+    }
+
+    fn exec_sync<R, F>(&self, work: F) -> R
+    where
+        F: FnOnce() -> R + Send,
+        R: Send,
+    {
+        if self.is_current() {
+            return work();
+        }
+        let mut result = None;
+        let mut borrowed = Borrowed {
+            work: Some(|| result = Some(work())),
+            panic: None,
+        };
+        unsafe {
+            ffi::dispatch_sync_f(
+                *self.queue,
+                &mut borrowed as *mut Borrowed<_> as *mut c_void,
+                run_borrowed_of(&borrowed),
+            )
+        };
+        let panic = borrowed.panic.take();
+        drop(borrowed);
+        if let Some(panic) = panic {
+            panic::resume_unwind(panic);
+        }
+        result.expect("dispatch_sync returned without running the work")
+    }
+
+    fn after<F>(&self, delay: Duration, work: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let delta = i64::try_from(delay.as_nanos()).unwrap_or(i64::MAX);
+        unsafe {
+            let when = ffi::dispatch_time(ffi::DISPATCH_TIME_NOW, delta);
+            ffi::dispatch_after_f(when, *self.queue, boxed(work), run_boxed);
+        }
+    }
+}
+
+/// trampoline running the closure stored in `_borrowed`
+#[cfg(target_os = "macos")]
+fn run_borrowed_of<F: FnOnce()>(_borrowed: &Borrowed<F>) -> ffi::Function {
+    run_borrowed::<F>
+}
+
+#[cfg(not(target_os = "macos"))]
+impl<K: QueueKind> Queue for DispatchQueue<K> {
+    fn exec_async<F>(&self, work: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.threads.exec_async(work);
+    }
+
+    fn exec_sync<R, F>(&self, work: F) -> R
+    where
+        F: FnOnce() -> R + Send,
+        R: Send,
+    {
+        self.threads.exec_sync(work)
+    }
+
+    fn after<F>(&self, delay: Duration, work: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.threads.after(delay, work);
+    }
+}
+
+unsafe impl SerialQueue for DispatchQueue<Serial> {}
+
+/// value moved to the queue it is only used on
+pub(crate) struct AssertSend<T>(pub T);
+
+unsafe impl<T> Send for AssertSend<T> {}
+
+impl<T> AssertSend<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+type Work = Box<dyn FnOnce() + Send + 'static>;
+
+struct Job {
+    work: Work,
+    /// signalled once the work has run and been dropped
+    done: Option<std::sync::mpsc::SyncSender<Option<Box<dyn Any + Send>>>>,
+}
+
+impl Job {
+    fn run(self, queue: usize) {
+        let panic = {
+            let _current = Current::enter(queue);
+            panic::catch_unwind(AssertUnwindSafe(self.work)).err()
+        };
+        match self.done {
+            Some(done) => {
+                let _ = done.send(panic);
+            }
+            None => drop(panic),
+        }
+    }
+}
+
+thread_local! {
+    /// id of the ThreadQueue or ManualQueue running work on this thread
+    static CURRENT: Cell<usize> = const { Cell::new(0) };
+}
+
+/// marks the current thread as running work of a queue until dropped, also when the work panics
+struct Current(usize);
+
+impl Current {
+    fn enter(queue: usize) -> Current {
+        Current(CURRENT.with(|current| current.replace(queue)))
+    }
+}
+
+impl Drop for Current {
+    fn drop(&mut self) {
+        CURRENT.with(|current| current.set(self.0));
+    }
+}
+
+fn next_queue_id() -> usize {
+    static NEXT: AtomicUsize = AtomicUsize::new(1);
+    NEXT.fetch_add(1, Ordering::Relaxed)
+}
+
+/// queue backed by threads, available on every host
+///
+/// A serial queue runs its work on one worker thread, a concurrent queue on a new thread per work
+/// item. A panic in work given to `exec_async` or `after` is caught and discarded, one in work
+/// given to `exec_sync` is resumed on the calling thread. Dropping the queue waits for the work
+/// already submitted, on a concurrent queue too, unless it is dropped by its own work; delayed
+/// work that is not due yet is dropped.
+///
+/// # Examples
+/// ```rust
+/// use std::sync::mpsc::channel;
+/// use std::time::Duration;
+/// use virtualization_rs::dispatch::{Queue, Serial, ThreadQueue};
+///
+/// let queue = ThreadQueue::new("worker", Serial);
+/// let (sender, receiver) = channel();
+/// let s = sender.clone();
+/// queue.after(Duration::from_millis(10), move || s.send(2).unwrap());
+/// queue.exec_async(move || sender.send(1).unwrap());
+/// assert!(!queue.is_current());
+/// assert!(queue.exec_sync(|| true));
+/// assert_eq!(receiver.recv().unwrap(), 1);
+/// assert_eq!(receiver.recv().unwrap(), 2);
+/// ```
+pub struct ThreadQueue<K: QueueKind = Serial> {
+    id: usize,
+    sender: Arc<Mutex<Option<Sender<Job>>>>,
+    worker: Option<JoinHandle<()>>,
+    _kind: PhantomData<K>,
+}
+
+impl<K: QueueKind> ThreadQueue<K> {
+    /// queue named `label`, its threads carry that name
+    pub fn new(label: &str, _kind: K) -> Self {
+        let id = next_queue_id();
+        let (sender, receiver) = channel::<Job>();
+        let name = label.to_string();
+        let worker = thread::Builder::new()
+            .name(name.clone())
+            .spawn(move || {
+                let mut running: Vec<JoinHandle<()>> = Vec::new();
+                for job in receiver {
+                    if K::CONCURRENT {
+                        running.retain(|thread| !thread.is_finished());
+                        let spawned = thread::Builder::new()
+                            .name(name.clone())
+                            .spawn(move || job.run(id));
+                        // a job that could not be spawned is dropped, waking exec_sync
+                        running.extend(spawned);
+                    } else {
+                        job.run(id);
+                    }
+                }
+                for thread in running {
+                    let _ = thread.join();
+                }
+            })
+            .expect("failed to spawn the queue thread");
+        ThreadQueue {
+            id,
+            sender: Arc::new(Mutex::new(Some(sender))),
+            worker: Some(worker),
+            _kind: PhantomData,
+        }
+    }
+
+    /// whether the current thread is running work of the queue
+    pub fn is_current(&self) -> bool {
+        CURRENT.with(|current| current.get() == self.id)
+    }
+
+    fn submit(&self, job: Job) {
+        if let Some(sender) = self.sender.lock().unwrap().as_ref() {
+            let _ = sender.send(job);
+        }
+    }
+}
+
+impl<K: QueueKind> Queue for ThreadQueue<K> {
+    fn exec_async<F>(&self, work: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.submit(Job {
+            work: Box::new(work),
+            done: None,
+        });
+    }
+
+    fn exec_sync<R, F>(&self, work: F) -> R
+    where
+        F: FnOnce() -> R + Send,
+        R: Send,
+    {
+        if self.is_current() {
+            return work();
+        }
+        let result = Mutex::new(None);
+        let work: Box<dyn FnOnce() + Send + '_> = Box::new(|| {
+            let value = work();
+            *result.lock().unwrap() = Some(value);
+        });
+        // SAFETY: the queue needs `'static` work, but this closure borrows `work` and `result`
+        // from this frame. The borrows outlive every use of the closure because:
+        // - the closure only travels inside the Job, whose `done` sender is declared after `work`
+        //   and so is dropped after it, or used by `Job::run` after the closure has been consumed
+        // - a Job that never runs, because the queue is gone or a thread could not be spawned, is
+        //   dropped, dropping the closure before `done`
+        // - `recv` only returns once `done` has been sent to or dropped, and a panic in `submit`
+        //   drops the Job before unwinding this frame, so this frame outlives the closure
+        let work: Work = unsafe { std::mem::transmute(work) };
+        let (done, finished) = sync_channel(1);
+        self.submit(Job {
+            work,
+            done: Some(done),
+        });
+        if let Ok(Some(panic)) = finished.recv() {
+            panic::resume_unwind(panic);
+        }
+        result
+            .into_inner()
+            .unwrap()
+            .expect("the queue dropped the work without running it")
+    }
+
+    fn after<F>(&self, delay: Duration, work: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let sender = self.sender.clone();
+        thread::spawn(move || {
+            thread::sleep(delay);
+            if let Some(sender) = sender.lock().unwrap().as_ref() {
+                let _ = sender.send(Job {
+                    work: Box::new(work),
+                    done: None,
+                });
+            }
+        });
+    }
+}
+
+unsafe impl SerialQueue for ThreadQueue<Serial> {}
+
+impl<K: QueueKind> Drop for ThreadQueue<K> {
+    fn drop(&mut self) {
+        self.sender.lock().unwrap().take();
+        if let Some(worker) = self.worker.take() {
+            if !self.is_current() {
+                let _ = worker.join();
+            }
+        }
+    }
+}
+
+/// fake serial queue running its work on the thread calling [`run_pending`](ManualQueue::run_pending)
+///
+/// `exec_sync` first runs the work submitted before it, then its own, on the calling thread.
+/// Delayed work becomes pending once the clock of the queue has been moved past its delay with
+/// [`advance`](ManualQueue::advance).
+///
+/// # Examples
+/// ```rust
+/// use std::sync::{Arc, Mutex};
+/// use std::time::Duration;
+/// use virtualization_rs::dispatch::{ManualQueue, Queue};
+///
+/// let queue = ManualQueue::new();
+/// let log = Arc::new(Mutex::new(Vec::new()));
+/// let l = log.clone();
+/// queue.after(Duration::from_secs(1), move || l.lock().unwrap().push(3));
+/// let l = log.clone();
+/// queue.exec_async(move || l.lock().unwrap().push(1));
+/// assert_eq!(queue.pending(), 1);
+/// let n = queue.exec_sync(|| {
+///     log.lock().unwrap().push(2);
+///     log.lock().unwrap().len()
+/// });
+/// assert_eq!(n, 2);
+/// queue.advance(Duration::from_secs(1));
+/// assert_eq!(queue.run_pending(), 1);
+/// assert_eq!(*log.lock().unwrap(), vec![1, 2, 3]);
+/// ```
+pub struct ManualQueue {
+    id: usize,
+    work: Mutex<VecDeque<Work>>,
+    delayed: Mutex<Vec<(Duration, Work)>>,
+    now: Mutex<Duration>,
+    run: Mutex<()>,
+}
+
+impl ManualQueue {
+    pub fn new() -> Self {
+        ManualQueue {
+            id: next_queue_id(),
+            work: Mutex::new(VecDeque::new()),
+            delayed: Mutex::new(Vec::new()),
+            now: Mutex::new(Duration::ZERO),
+            run: Mutex::new(()),
+        }
+    }
+}
+
+impl Default for ManualQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ManualQueue {
+    /// number of work items waiting to run
+    pub fn pending(&self) -> usize {
+        self.work.lock().unwrap().len()
+    }
+
+    /// time elapsed on the clock of the queue
+    pub fn now(&self) -> Duration {
+        *self.now.lock().unwrap()
+    }
+
+    /// move the clock forward, making the delayed work that is due pending in the order it is due
+    pub fn advance(&self, duration: Duration) {
+        let now = {
+            let mut now = self.now.lock().unwrap();
+            *now += duration;
+            *now
+        };
+        let mut delayed = self.delayed.lock().unwrap();
+        let mut due = Vec::new();
+        let mut i = 0;
+        while i < delayed.len() {
+            if delayed[i].0 <= now {
+                due.push(delayed.remove(i));
+            } else {
+                i += 1;
+            }
+        }
+        due.sort_by_key(|(at, _)| *at);
+        self.work
+            .lock()
+            .unwrap()
+            .extend(due.into_iter().map(|(_, work)| work));
+    }
+
+    /// whether the current thread is running work of the queue
+    pub fn is_current(&self) -> bool {
+        CURRENT.with(|current| current.get() == self.id)
+    }
+
+    /// run the waiting work, including work submitted while running, returns how many items ran
+    ///
+    /// A panic in the work is resumed on the calling thread; the work after it stays pending.
+    pub fn run_pending(&self) -> usize {
+        if self.is_current() {
+            return 0;
+        }
+        let _running = self.lock_run();
+        self.drain()
+    }
+
+    /// serialize the callers running work, a panic in earlier work does not poison the queue
+    fn lock_run(&self) -> MutexGuard<'_, ()> {
+        self.run
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn drain(&self) -> usize {
+        let _current = Current::enter(self.id);
+        let mut count = 0;
+        loop {
+            let work = self.work.lock().unwrap().pop_front();
+            match work {
+                Some(work) => {
+                    work();
+                    count += 1;
+                }
+                None => break,
+            }
+        }
+        count
+    }
+}
+
+impl Queue for ManualQueue {
+    fn exec_async<F>(&self, work: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.work.lock().unwrap().push_back(Box::new(work));
+    }
+
+    fn exec_sync<R, F>(&self, work: F) -> R
+    where
+        F: FnOnce() -> R + Send,
+        R: Send,
+    {
+        if self.is_current() {
+            return work();
+        }
+        let _running = self.lock_run();
+        self.drain();
+        let _current = Current::enter(self.id);
+        work()
+    }
+
+    fn after<F>(&self, delay: Duration, work: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let at = self.now() + delay;
+        self.delayed.lock().unwrap().push((at, Box::new(work)));
+    }
+}
+
+unsafe impl SerialQueue for ManualQueue {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Log = Arc<Mutex<Vec<u32>>>;
+
+    /// shared log, and a function making work that appends a number to it
+    fn log() -> (Log, impl Fn(u32) -> Work) {
+        let log = Log::default();
+        let l = log.clone();
+        let push = move |n: u32| -> Work {
+            let l = l.clone();
+            Box::new(move || l.lock().unwrap().push(n))
+        };
+        (log, push)
+    }
+
+    #[test]
+    fn manual_queue_runs_work_in_submission_order() {
+        let queue = ManualQueue::new();
+        let (log, push) = log();
+        for n in 0..5 {
+            queue.exec_async(push(n));
+        }
+        assert!(log.lock().unwrap().is_empty());
+        assert_eq!(queue.pending(), 5);
+        assert_eq!(queue.run_pending(), 5);
+        assert_eq!(*log.lock().unwrap(), vec![0, 1, 2, 3, 4]);
+        assert_eq!(queue.run_pending(), 0);
+    }
+
+    #[test]
+    fn manual_queue_exec_sync_completes_earlier_work_first() {
+        let queue = ManualQueue::new();
+        let (log, push) = log();
+        queue.exec_async(push(1));
+        queue.exec_async(push(2));
+        queue.exec_sync(push(3));
+        assert_eq!(*log.lock().unwrap(), vec![1, 2, 3]);
+        assert_eq!(queue.pending(), 0);
+
+        // work submitted from inside the queue runs after the current item, nested exec_sync
+        // runs directly
+        let q = Arc::new(queue);
+        let inner = q.clone();
+        let l = log.clone();
+        q.exec_async(move || {
+            inner.exec_async(push(5));
+            assert!(inner.is_current());
+            inner.exec_sync(|| l.lock().unwrap().push(4));
+        });
+        assert_eq!(q.run_pending(), 2);
+        assert_eq!(*log.lock().unwrap(), vec![1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn manual_queue_delayed_work_runs_in_due_order() {
+        let queue = ManualQueue::new();
+        let (log, push) = log();
+        queue.after(Duration::from_secs(3), push(3));
+        queue.after(Duration::from_secs(1), push(1));
+        queue.after(Duration::from_secs(2), push(2));
+        queue.advance(Duration::from_millis(1500));
+        assert_eq!(queue.run_pending(), 1);
+        queue.advance(Duration::from_secs(10));
+        assert_eq!(queue.run_pending(), 2);
+        assert_eq!(*log.lock().unwrap(), vec![1, 2, 3]);
+        assert_eq!(queue.now(), Duration::from_millis(11500));
+    }
+
+    #[test]
+    fn serial_thread_queue_runs_work_in_order_on_one_thread() {
+        let queue = ThreadQueue::new("serial-test", Serial);
+        let threads = Arc::new(Mutex::new(Vec::new()));
+        let (log, push) = log();
+        for n in 0..100 {
+            let threads = threads.clone();
+            let push = push(n);
+            queue.exec_async(move || {
+                threads.lock().unwrap().push(thread::current().id());
+                push();
+            });
+        }
+        assert!(queue.exec_sync(|| true));
+        assert_eq!(*log.lock().unwrap(), (0..100).collect::<Vec<_>>());
+        let threads = threads.lock().unwrap();
+        assert!(threads.iter().all(|id| *id == threads[0]));
+        assert_ne!(threads[0], thread::current().id());
+    }
+
+    #[test]
+    fn thread_queue_exec_sync_sees_earlier_work_and_borrows() {
+        let queue = ThreadQueue::new("sync-test", Serial);
+        let (log, push) = log();
+        queue.exec_async(push(1));
+        let mut local = 0;
+        let len = queue.exec_sync(|| {
+            local += 1;
+            log.lock().unwrap().len()
+        });
+        assert_eq!(len, 1);
+        assert_eq!(local, 1);
+    }
+
+    #[test]
+    fn thread_queue_panics_are_contained() {
+        let queue = ThreadQueue::new("panic-test", Serial);
+        queue.exec_async(|| panic!("async work panicked"));
+        let caught = panic::catch_unwind(AssertUnwindSafe(|| {
+            queue.exec_sync(|| panic!("sync work panicked"))
+        }));
+        assert!(caught.is_err());
+        // the queue keeps running work after both panics
+        assert_eq!(queue.exec_sync(|| 42), 42);
+    }
+
+    #[test]
+    fn dropping_a_thread_queue_waits_for_submitted_work() {
+        let (log, push) = log();
+        {
+            let queue = ThreadQueue::new("drop-test", Serial);
+            for n in 0..10 {
+                queue.exec_async(push(n));
+            }
+        }
+        assert_eq!(log.lock().unwrap().len(), 10);
+    }
+
+    #[test]
+    fn thread_queue_delayed_work_runs_after_its_delay() {
+        let queue = ThreadQueue::new("after-test", Serial);
+        let (sender, receiver) = channel();
+        let start = std::time::Instant::now();
+        queue.after(Duration::from_millis(50), move || {
+            sender.send(start.elapsed()).unwrap();
+        });
+        let elapsed = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(elapsed >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn concurrent_thread_queue_completes_every_item() {
+        let queue = ThreadQueue::new("concurrent-test", Concurrent);
+        let (sender, receiver) = channel();
+        for n in 0..10 {
+            let sender = sender.clone();
+            queue.exec_async(move || sender.send(n).unwrap());
+        }
+        let mut received: Vec<u32> = (0..10)
+            .map(|_| receiver.recv_timeout(Duration::from_secs(5)).unwrap())
+            .collect();
+        received.sort_unstable();
+        assert_eq!(received, (0..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn dropping_a_concurrent_thread_queue_waits_for_submitted_work() {
+        let (log, push) = log();
+        {
+            let queue = ThreadQueue::new("concurrent-drop-test", Concurrent);
+            for n in 0..10 {
+                let push = push(n);
+                queue.exec_async(move || {
+                    thread::sleep(Duration::from_millis(20));
+                    push();
+                });
+            }
+        }
+        assert_eq!(log.lock().unwrap().len(), 10);
+    }
+
+    #[test]
+    fn manual_queue_survives_panicking_work() {
+        let queue = ManualQueue::new();
+        let (log, push) = log();
+        queue.exec_async(|| panic!("manual work panicked"));
+        queue.exec_async(push(1));
+        let caught = panic::catch_unwind(AssertUnwindSafe(|| queue.run_pending()));
+        assert!(caught.is_err());
+        assert!(!queue.is_current());
+        assert_eq!(queue.pending(), 1);
+
+        let caught = panic::catch_unwind(AssertUnwindSafe(|| {
+            queue.exec_sync(|| panic!("manual sync work panicked"))
+        }));
+        assert!(caught.is_err());
+        assert!(!queue.is_current());
+        assert_eq!(*log.lock().unwrap(), vec![1]);
+        assert_eq!(queue.exec_sync(|| 42), 42);
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    #[test]
+    fn stub_dispatch_queue_runs_work_on_threads() {
+        let queue = DispatchQueue::new("stub", Serial);
+        assert!(!queue.is_current());
+        assert!(queue.exec_sync(|| queue.is_current()));
+        let (sender, receiver) = channel();
+        let s = sender.clone();
+        queue.after(Duration::from_millis(10), move || s.send(2).unwrap());
+        queue.exec_async(move || sender.send(1).unwrap());
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(5)).unwrap(), 1);
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(5)).unwrap(), 2);
+
+        // every raw queue is the same queue on the stub
+        let main = DispatchQueue::main();
+        let raw = unsafe { DispatchQueue::from_raw(main.as_raw()) };
+        assert!(main.exec_sync(|| raw.is_current()));
+        assert!(!queue.exec_sync(|| main.is_current()));
+    }
+}
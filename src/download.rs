@@ -0,0 +1,886 @@
+//! download module
+//!
+//! [`Download`] streams a file into `<destination>.part`, resumes an interrupted transfer with an
+//! HTTP Range request, verifies the size and an optional SHA-256 digest and only then renames
+//! the file to `destination`, so `destination` never holds a partial restore image. A transfer
+//! resumed within the same run sends `If-Range`, so the server restarts it from the beginning
+//! if the file has changed in the meantime.
+
+use crate::error::{Error, Result};
+
+use reqwest::blocking::{Client, Response};
+use reqwest::header::{HeaderValue, CONTENT_RANGE, ETAG, IF_RANGE, LAST_MODIFIED, RANGE};
+use reqwest::StatusCode;
+use sha2::{Digest, Sha256};
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+const BUFFER_SIZE: usize = 1024 * 1024;
+
+/// longest wait between two attempts
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// interval of the TCP keepalive probes detecting a dead peer
+const TCP_KEEPALIVE: Duration = Duration::from_secs(30);
+
+/// progress of a download, reported after every chunk written to disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DownloadProgress {
+    /// bytes on disk, including those of a resumed transfer
+    pub downloaded: u64,
+    /// size of the file, `None` if the server did not report it
+    pub total: Option<u64>,
+}
+
+impl DownloadProgress {
+    /// completed fraction in `0.0..=1.0`
+    pub fn fraction(&self) -> Option<f64> {
+        match self.total {
+            Some(0) => Some(1.0),
+            Some(total) => Some(self.downloaded as f64 / total as f64),
+            None => None,
+        }
+    }
+}
+
+/// builder for Download
+/// # Examples
+/// ```rust,no_run
+/// use virtualization_rs::download::DownloadBuilder;
+///
+/// DownloadBuilder::new()
+///     .url("http://127.0.0.1:8000/restore.ipsw")
+///     .destination("restore.ipsw")
+///     .sha256("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855")
+///     .progress(|progress| println!("{} bytes", progress.downloaded))
+///     .build()
+///     .run()
+///     .unwrap();
+/// ```
+pub struct DownloadBuilder<Url, Destination> {
+    url: Url,
+    destination: Destination,
+    expected_size: Option<u64>,
+    sha256: Option<String>,
+    connect_timeout: Duration,
+    read_timeout: Duration,
+    retries: usize,
+    retry_delay: Duration,
+    progress: Option<Box<dyn FnMut(DownloadProgress)>>,
+}
+
+impl DownloadBuilder<(), ()> {
+    pub fn new() -> Self {
+        DownloadBuilder {
+            url: (),
+            destination: (),
+            expected_size: None,
+            sha256: None,
+            connect_timeout: Duration::from_secs(30),
+            read_timeout: Duration::from_secs(60),
+            retries: 3,
+            retry_delay: Duration::from_secs(1),
+            progress: None,
+        }
+    }
+}
+
+impl Default for DownloadBuilder<(), ()> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<Url, Destination> DownloadBuilder<Url, Destination> {
+    pub fn url<T: Into<String>>(self, url: T) -> DownloadBuilder<String, Destination> {
+        DownloadBuilder {
+            url: url.into(),
+            destination: self.destination,
+            expected_size: self.expected_size,
+            sha256: self.sha256,
+            connect_timeout: self.connect_timeout,
+            read_timeout: self.read_timeout,
+            retries: self.retries,
+            retry_delay: self.retry_delay,
+            progress: self.progress,
+        }
+    }
+
+    pub fn destination<T: Into<PathBuf>>(self, destination: T) -> DownloadBuilder<Url, PathBuf> {
+        DownloadBuilder {
+            url: self.url,
+            destination: destination.into(),
+            expected_size: self.expected_size,
+            sha256: self.sha256,
+            connect_timeout: self.connect_timeout,
+            read_timeout: self.read_timeout,
+            retries: self.retries,
+            retry_delay: self.retry_delay,
+            progress: self.progress,
+        }
+    }
+
+    /// size the downloaded file must have, checked in addition to the size reported by the server
+    pub fn expected_size(mut self, expected_size: u64) -> Self {
+        self.expected_size = Some(expected_size);
+        self
+    }
+
+    /// hex encoded SHA-256 digest the downloaded file must have
+    pub fn sha256<T: Into<String>>(mut self, sha256: T) -> Self {
+        self.sha256 = Some(sha256.into());
+        self
+    }
+
+    pub fn connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.connect_timeout = connect_timeout;
+        self
+    }
+
+    /// longest wait for the response headers or for the next chunk of the body
+    ///
+    /// A transfer stalled for longer fails with a timeout, which is retried like a dropped
+    /// connection.
+    pub fn read_timeout(mut self, read_timeout: Duration) -> Self {
+        self.read_timeout = read_timeout;
+        self
+    }
+
+    /// number of times a transfer interrupted by a transient error is resumed before giving up
+    ///
+    /// Transient errors are server errors, dropped connections and timeouts; other HTTP statuses
+    /// and local I/O errors fail the download at once.
+    pub fn retries(mut self, retries: usize) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// time to wait before the first retry, doubled before each following one
+    pub fn retry_delay(mut self, retry_delay: Duration) -> Self {
+        self.retry_delay = retry_delay;
+        self
+    }
+
+    pub fn progress<F: FnMut(DownloadProgress) + 'static>(mut self, progress: F) -> Self {
+        self.progress = Some(Box::new(progress));
+        self
+    }
+}
+
+impl DownloadBuilder<String, PathBuf> {
+    pub fn build(self) -> Download {
+        Download {
+            url: self.url,
+            destination: self.destination,
+            expected_size: self.expected_size,
+            sha256: self.sha256.map(|sha256| sha256.to_ascii_lowercase()),
+            connect_timeout: self.connect_timeout,
+            read_timeout: self.read_timeout,
+            retries: self.retries,
+            retry_delay: self.retry_delay,
+            progress: self.progress,
+            validator: None,
+        }
+    }
+}
+
+/// resumable download of a single file
+pub struct Download {
+    url: String,
+    destination: PathBuf,
+    expected_size: Option<u64>,
+    sha256: Option<String>,
+    connect_timeout: Duration,
+    read_timeout: Duration,
+    retries: usize,
+    retry_delay: Duration,
+    progress: Option<Box<dyn FnMut(DownloadProgress)>>,
+    /// strong ETag or Last-Modified of the response the part file was started from
+    validator: Option<HeaderValue>,
+}
+
+impl Download {
+    /// path the file is written to until it has been verified
+    pub fn part_path(&self) -> PathBuf {
+        part_path(&self.destination)
+    }
+
+    /// download the file, resuming from a `.part` file left by an earlier run
+    ///
+    /// A partial file is kept when the transfer fails, so calling this again continues where
+    /// it stopped. A file that fails verification is removed. A part file left by an earlier
+    /// run is resumed without `If-Range`, so pass [`sha256`](DownloadBuilder::sha256) to detect a
+    /// file that changed on the server.
+    pub fn run(mut self) -> Result<()> {
+        let part_path = self.part_path();
+        let client = Client::builder()
+            .connect_timeout(self.connect_timeout)
+            // the blocking client applies this to the response and to every read of the body,
+            // not to the whole transfer
+            .timeout(self.read_timeout)
+            .tcp_keepalive(TCP_KEEPALIVE)
+            .build()?;
+
+        let mut attempt = 0;
+        let mut delay = self.retry_delay;
+        let total = loop {
+            match self.transfer(&client, &part_path) {
+                Ok(total) => break total,
+                Err(err) if attempt < self.retries && is_transient(&err) => {
+                    attempt += 1;
+                    thread::sleep(delay);
+                    delay = (delay * 2).min(MAX_RETRY_DELAY);
+                }
+                Err(err) => return Err(err),
+            }
+        };
+
+        if let Err(err) = self.verify(&part_path, total) {
+            let _ = fs::remove_file(&part_path);
+            return Err(err);
+        }
+        fs::rename(&part_path, &self.destination)?;
+        Ok(())
+    }
+
+    /// append the rest of the file to `part_path`, returns the size reported by the server
+    fn transfer(&mut self, client: &Client, part_path: &Path) -> Result<Option<u64>> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(part_path)?;
+        let mut offset = file.metadata()?.len();
+        if let Some(expected_size) = self.expected_size {
+            if offset == expected_size {
+                return Ok(None);
+            }
+            if offset > expected_size {
+                file.set_len(0)?;
+                offset = 0;
+            }
+        }
+
+        let mut response = self.request(client, offset)?;
+        if offset > 0 && response.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+            // the part file is either complete or longer than the file on the server
+            let total = content_range_total(&response);
+            if total == Some(offset) {
+                return Ok(total);
+            }
+            file.set_len(0)?;
+            offset = 0;
+            response = self.request(client, offset)?;
+        }
+        let mut response = response.error_for_status()?;
+
+        let total = if response.status() == StatusCode::PARTIAL_CONTENT {
+            if content_range_start(&response) != Some(offset) {
+                return Err(Error::Download(format!(
+                    "{}: server resumed at an unexpected offset",
+                    self.url
+                )));
+            }
+            content_range_total(&response)
+        } else {
+            // the server ignored the range or the file changed, it sends the whole file
+            file.set_len(0)?;
+            offset = 0;
+            self.validator = validator(&response);
+            response.content_length()
+        };
+        file.seek(SeekFrom::Start(offset))?;
+
+        let mut downloaded = offset;
+        self.report(downloaded, total);
+        let mut buffer = vec![0; BUFFER_SIZE];
+        loop {
+            let n = match response.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => {
+                    file.sync_all()?;
+                    return Err(err.into());
+                }
+            };
+            file.write_all(&buffer[..n])?;
+            downloaded += n as u64;
+            self.report(downloaded, total);
+        }
+        file.sync_all()?;
+
+        if let Some(total) = total {
+            if downloaded < total {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!(
+                        "{}: connection closed after {} of {} bytes",
+                        self.url, downloaded, total
+                    ),
+                )
+                .into());
+            }
+        }
+        Ok(total)
+    }
+
+    fn request(&self, client: &Client, offset: u64) -> Result<Response> {
+        let mut request = client.get(self.url.as_str());
+        if offset > 0 {
+            request = request.header(RANGE, format!("bytes={}-", offset));
+            if let Some(validator) = &self.validator {
+                request = request.header(IF_RANGE, validator.clone());
+            }
+        }
+        Ok(request.send()?)
+    }
+
+    fn report(&mut self, downloaded: u64, total: Option<u64>) {
+        let total = total.or(self.expected_size);
+        if let Some(progress) = &mut self.progress {
+            progress(DownloadProgress { downloaded, total });
+        }
+    }
+
+    fn verify(&self, part_path: &Path, total: Option<u64>) -> Result<()> {
+        let size = fs::metadata(part_path)?.len();
+        for expected_size in self.expected_size.iter().chain(total.iter()) {
+            if size != *expected_size {
+                return Err(Error::Download(format!(
+                    "{}: downloaded {} bytes, expected {}",
+                    self.url, size, expected_size
+                )));
+            }
+        }
+
+        if let Some(expected) = &self.sha256 {
+            let actual = sha256_file(part_path)?;
+            if *expected != actual {
+                return Err(Error::ChecksumMismatch {
+                    expected: expected.clone(),
+                    actual,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// whether a later attempt may succeed: server errors, dropped connections and timeouts
+fn is_transient(err: &Error) -> bool {
+    match err {
+        Error::Http(err) => is_transient_http(err),
+        Error::Io(err) => match err.kind() {
+            io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::UnexpectedEof => true,
+            // errors reading the body are reqwest errors wrapped in an io::Error
+            _ => err
+                .get_ref()
+                .and_then(|err| err.downcast_ref::<reqwest::Error>())
+                .is_some_and(is_transient_http),
+        },
+        _ => false,
+    }
+}
+
+fn is_transient_http(err: &reqwest::Error) -> bool {
+    match err.status() {
+        Some(status) => {
+            status.is_server_error()
+                || status == StatusCode::REQUEST_TIMEOUT
+                || status == StatusCode::TOO_MANY_REQUESTS
+        }
+        None => err.is_timeout() || err.is_connect() || err.is_body() || err.is_request(),
+    }
+}
+
+/// value for `If-Range`: the ETag of `response` unless it is weak, otherwise its Last-Modified
+fn validator(response: &Response) -> Option<HeaderValue> {
+    let headers = response.headers();
+    headers
+        .get(ETAG)
+        .filter(|etag| !etag.as_bytes().starts_with(b"W/"))
+        .or_else(|| headers.get(LAST_MODIFIED))
+        .cloned()
+}
+
+fn part_path(destination: &Path) -> PathBuf {
+    let mut part_path = OsString::from(destination.as_os_str());
+    part_path.push(".part");
+    PathBuf::from(part_path)
+}
+
+/// hex encoded SHA-256 digest of the file at `path`
+pub fn sha256_file<P: AsRef<Path>>(path: P) -> Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; BUFFER_SIZE];
+    loop {
+        let n = match file.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err.into()),
+        };
+        hasher.update(&buffer[..n]);
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+/// `(start, total)` of a `Content-Range: bytes start-end/total` header
+fn content_range(response: &Response) -> Option<(Option<u64>, Option<u64>)> {
+    let value = response.headers().get(CONTENT_RANGE)?.to_str().ok()?;
+    let value = value.trim().strip_prefix("bytes")?.trim_start();
+    let (range, total) = value.split_once('/')?;
+    let start = range
+        .split_once('-')
+        .and_then(|(start, _)| start.parse().ok());
+    Some((start, total.parse().ok()))
+}
+
+fn content_range_start(response: &Response) -> Option<u64> {
+    content_range(response).and_then(|(start, _)| start)
+}
+
+fn content_range_total(response: &Response) -> Option<u64> {
+    content_range(response).and_then(|(_, total)| total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::BufRead;
+    use std::io::BufReader;
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+
+    const BODY: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyz";
+
+    /// HTTP server on localhost answering the `n`th request with `reply(n, range_start)`
+    struct Server {
+        url: String,
+        /// start of the Range header of every request received
+        ranges: Arc<Mutex<Vec<Option<u64>>>>,
+        /// If-Range header of every request received
+        if_ranges: Arc<Mutex<Vec<Option<String>>>>,
+    }
+
+    impl Server {
+        fn start<F>(reply: F) -> Server
+        where
+            F: Fn(usize, Option<u64>) -> Vec<u8> + Send + 'static,
+        {
+            Server::serve(reply, false)
+        }
+
+        /// like `start`, but never closes a connection, so a short reply stalls the client
+        fn stalling<F>(reply: F) -> Server
+        where
+            F: Fn(usize, Option<u64>) -> Vec<u8> + Send + 'static,
+        {
+            Server::serve(reply, true)
+        }
+
+        fn serve<F>(reply: F, hold: bool) -> Server
+        where
+            F: Fn(usize, Option<u64>) -> Vec<u8> + Send + 'static,
+        {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let url = format!("http://{}/restore.ipsw", listener.local_addr().unwrap());
+            let ranges = Arc::new(Mutex::new(Vec::new()));
+            let if_ranges = Arc::new(Mutex::new(Vec::new()));
+            let received = (ranges.clone(), if_ranges.clone());
+            thread::spawn(move || {
+                let mut held = Vec::new();
+                for stream in listener.incoming() {
+                    let mut stream = stream.unwrap();
+                    let mut reader = BufReader::new(stream.try_clone().unwrap());
+                    let mut range = None;
+                    let mut if_range = None;
+                    loop {
+                        let mut line = String::new();
+                        if reader.read_line(&mut line).unwrap() == 0 || line == "\r\n" {
+                            break;
+                        }
+                        let line = line.to_ascii_lowercase();
+                        if let Some(value) = line.strip_prefix("range: bytes=") {
+                            range = value.trim().trim_end_matches('-').parse().ok();
+                        }
+                        if let Some(value) = line.strip_prefix("if-range: ") {
+                            if_range = Some(value.trim().to_string());
+                        }
+                    }
+                    received.1.lock().unwrap().push(if_range);
+                    let n = {
+                        let mut ranges = received.0.lock().unwrap();
+                        ranges.push(range);
+                        ranges.len() - 1
+                    };
+                    let _ = stream.write_all(&reply(n, range));
+                    if hold {
+                        held.push(stream);
+                    }
+                }
+            });
+            Server {
+                url,
+                ranges,
+                if_ranges,
+            }
+        }
+
+        fn ranges(&self) -> Vec<Option<u64>> {
+            self.ranges.lock().unwrap().clone()
+        }
+
+        fn if_ranges(&self) -> Vec<Option<String>> {
+            self.if_ranges.lock().unwrap().clone()
+        }
+    }
+
+    fn response(status: &str, headers: &[String], body: &[u8]) -> Vec<u8> {
+        let mut response = format!("HTTP/1.1 {}\r\nConnection: close\r\n", status);
+        for header in headers {
+            response.push_str(header);
+            response.push_str("\r\n");
+        }
+        response.push_str("\r\n");
+        let mut response = response.into_bytes();
+        response.extend_from_slice(body);
+        response
+    }
+
+    fn ok(body: &[u8]) -> Vec<u8> {
+        response("200 OK", &[format!("Content-Length: {}", body.len())], body)
+    }
+
+    /// the whole body from `start`, as an answer to a Range request
+    fn partial(start: u64) -> Vec<u8> {
+        let body = &BODY[start as usize..];
+        response(
+            "206 Partial Content",
+            &[
+                format!("Content-Length: {}", body.len()),
+                format!(
+                    "Content-Range: bytes {}-{}/{}",
+                    start,
+                    BODY.len() - 1,
+                    BODY.len()
+                ),
+            ],
+            body,
+        )
+    }
+
+    /// announces the whole body but closes the connection after `len` bytes
+    fn truncated(len: usize) -> Vec<u8> {
+        truncated_with(len, &[])
+    }
+
+    fn truncated_with(len: usize, headers: &[&str]) -> Vec<u8> {
+        let mut headers: Vec<String> = headers.iter().map(|header| header.to_string()).collect();
+        headers.push(format!("Content-Length: {}", BODY.len()));
+        response("200 OK", &headers, &BODY[..len])
+    }
+
+    fn status(status: &str) -> Vec<u8> {
+        response(status, &["Content-Length: 0".to_string()], b"")
+    }
+
+    fn destination(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("download-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let destination = dir.join(name);
+        let _ = fs::remove_file(&destination);
+        let _ = fs::remove_file(part_path(&destination));
+        destination
+    }
+
+    fn download(server: &Server, destination: &Path) -> DownloadBuilder<String, PathBuf> {
+        DownloadBuilder::new()
+            .url(server.url.as_str())
+            .destination(destination)
+            .retry_delay(Duration::from_millis(1))
+    }
+
+    fn sha256(bytes: &[u8]) -> String {
+        Sha256::digest(bytes)
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    #[test]
+    fn downloads_and_verifies() {
+        let server = Server::start(|_, _| ok(BODY));
+        let destination = destination("complete");
+        let reported = Arc::new(Mutex::new(Vec::new()));
+        let progress = reported.clone();
+        download(&server, &destination)
+            .expected_size(BODY.len() as u64)
+            .sha256(sha256(BODY).to_ascii_uppercase())
+            .progress(move |p| progress.lock().unwrap().push(p))
+            .build()
+            .run()
+            .unwrap();
+        assert_eq!(fs::read(&destination).unwrap(), BODY);
+        assert!(!part_path(&destination).exists());
+        assert_eq!(server.ranges(), vec![None]);
+        let last = *reported.lock().unwrap().last().unwrap();
+        assert_eq!(last.fraction(), Some(1.0));
+    }
+
+    #[test]
+    fn resumes_a_part_file_with_a_range_request() {
+        let server = Server::start(|_, range| partial(range.unwrap()));
+        let destination = destination("resume");
+        fs::write(part_path(&destination), &BODY[..10]).unwrap();
+        download(&server, &destination).build().run().unwrap();
+        assert_eq!(fs::read(&destination).unwrap(), BODY);
+        assert_eq!(server.ranges(), vec![Some(10)]);
+    }
+
+    #[test]
+    fn restarts_when_the_server_ignores_the_range() {
+        let server = Server::start(|_, _| ok(BODY));
+        let destination = destination("ignored-range");
+        fs::write(part_path(&destination), b"stale data").unwrap();
+        download(&server, &destination).build().run().unwrap();
+        assert_eq!(fs::read(&destination).unwrap(), BODY);
+        assert_eq!(server.ranges(), vec![Some(10)]);
+    }
+
+    #[test]
+    fn complete_part_file_is_accepted_on_range_not_satisfiable() {
+        let server = Server::start(|_, _| {
+            response(
+                "416 Range Not Satisfiable",
+                &[
+                    "Content-Length: 0".to_string(),
+                    format!("Content-Range: bytes */{}", BODY.len()),
+                ],
+                b"",
+            )
+        });
+        let destination = destination("already-complete");
+        fs::write(part_path(&destination), BODY).unwrap();
+        download(&server, &destination).build().run().unwrap();
+        assert_eq!(fs::read(&destination).unwrap(), BODY);
+        assert_eq!(server.ranges(), vec![Some(BODY.len() as u64)]);
+    }
+
+    #[test]
+    fn longer_part_file_is_downloaded_again_on_range_not_satisfiable() {
+        let server = Server::start(|n, _| match n {
+            0 => response(
+                "416 Range Not Satisfiable",
+                &[
+                    "Content-Length: 0".to_string(),
+                    format!("Content-Range: bytes */{}", BODY.len()),
+                ],
+                b"",
+            ),
+            _ => ok(BODY),
+        });
+        let destination = destination("too-long");
+        let mut stale = BODY.to_vec();
+        stale.extend_from_slice(b"trailing");
+        fs::write(part_path(&destination), stale).unwrap();
+        download(&server, &destination).build().run().unwrap();
+        assert_eq!(fs::read(&destination).unwrap(), BODY);
+        assert_eq!(server.ranges(), vec![Some(BODY.len() as u64 + 8), None]);
+    }
+
+    #[test]
+    fn interrupted_transfer_is_resumed() {
+        let server = Server::start(|n, range| match n {
+            0 => truncated(12),
+            _ => partial(range.unwrap()),
+        });
+        let destination = destination("interrupted");
+        download(&server, &destination).build().run().unwrap();
+        assert_eq!(fs::read(&destination).unwrap(), BODY);
+        assert_eq!(server.ranges(), vec![None, Some(12)]);
+    }
+
+    #[test]
+    fn resumed_requests_carry_the_strong_etag() {
+        let server = Server::start(|n, range| match n {
+            0 => truncated_with(12, &["ETag: \"v1\"", "Last-Modified: Sat, 17 Oct 2026"]),
+            _ => partial(range.unwrap()),
+        });
+        let destination = destination("if-range-etag");
+        download(&server, &destination).build().run().unwrap();
+        assert_eq!(fs::read(&destination).unwrap(), BODY);
+        assert_eq!(server.if_ranges(), vec![None, Some("\"v1\"".to_string())]);
+    }
+
+    #[test]
+    fn weak_etags_fall_back_to_last_modified() {
+        let server = Server::start(|n, range| match n {
+            0 => truncated_with(12, &["ETag: W/\"v1\"", "Last-Modified: Sat, 17 Oct 2026"]),
+            _ => partial(range.unwrap()),
+        });
+        let destination = destination("if-range-last-modified");
+        download(&server, &destination).build().run().unwrap();
+        // the test server lowercases the headers it receives
+        assert_eq!(
+            server.if_ranges(),
+            vec![None, Some("sat, 17 oct 2026".to_string())]
+        );
+    }
+
+    #[test]
+    fn changed_file_is_downloaded_again() {
+        // a server whose file changed ignores the Range of a request with a stale If-Range
+        let server = Server::start(|n, _| match n {
+            0 => truncated_with(12, &["ETag: \"v1\""]),
+            _ => ok(BODY),
+        });
+        let destination = destination("if-range-changed");
+        download(&server, &destination).build().run().unwrap();
+        assert_eq!(fs::read(&destination).unwrap(), BODY);
+        assert_eq!(server.ranges(), vec![None, Some(12)]);
+    }
+
+    #[test]
+    fn stalled_transfer_times_out_and_is_resumed() {
+        let server = Server::stalling(|n, range| match n {
+            0 => truncated(12),
+            _ => partial(range.unwrap()),
+        });
+        let destination = destination("stalled");
+        download(&server, &destination)
+            .read_timeout(Duration::from_millis(200))
+            .build()
+            .run()
+            .unwrap();
+        assert_eq!(fs::read(&destination).unwrap(), BODY);
+        assert_eq!(server.ranges(), vec![None, Some(12)]);
+    }
+
+    #[test]
+    fn stalled_transfer_fails_with_a_timeout_once_retries_are_exhausted() {
+        let server = Server::stalling(|_, _| truncated(12));
+        let destination = destination("stalled-exhausted");
+        let err = download(&server, &destination)
+            .read_timeout(Duration::from_millis(100))
+            .retries(0)
+            .build()
+            .run()
+            .unwrap_err();
+        assert!(is_transient(&err), "{:?}", err);
+        assert_eq!(fs::metadata(part_path(&destination)).unwrap().len(), 12);
+    }
+
+    #[test]
+    fn size_mismatch_removes_the_part_file() {
+        let server = Server::start(|_, _| ok(BODY));
+        let destination = destination("size-mismatch");
+        let err = download(&server, &destination)
+            .expected_size(BODY.len() as u64 + 1)
+            .build()
+            .run()
+            .unwrap_err();
+        assert!(matches!(err, Error::Download(_)), "{:?}", err);
+        assert!(!destination.exists());
+        assert!(!part_path(&destination).exists());
+    }
+
+    #[test]
+    fn checksum_mismatch_removes_the_part_file() {
+        let server = Server::start(|_, _| ok(BODY));
+        let destination = destination("checksum-mismatch");
+        let err = download(&server, &destination)
+            .sha256(sha256(b"something else"))
+            .build()
+            .run()
+            .unwrap_err();
+        match err {
+            Error::ChecksumMismatch { expected, actual } => {
+                assert_eq!(expected, sha256(b"something else"));
+                assert_eq!(actual, sha256(BODY));
+            }
+            err => panic!("unexpected error {:?}", err),
+        }
+        assert!(!destination.exists());
+        assert!(!part_path(&destination).exists());
+    }
+
+    #[test]
+    fn client_errors_are_not_retried() {
+        let server = Server::start(|_, _| status("404 Not Found"));
+        let destination = destination("not-found");
+        let err = download(&server, &destination).build().run().unwrap_err();
+        match err {
+            Error::Http(err) => assert_eq!(err.status(), Some(StatusCode::NOT_FOUND)),
+            err => panic!("unexpected error {:?}", err),
+        }
+        assert_eq!(server.ranges().len(), 1);
+        assert!(!destination.exists());
+    }
+
+    #[test]
+    fn server_errors_are_retried_until_exhausted() {
+        let server = Server::start(|_, _| status("503 Service Unavailable"));
+        let destination = destination("unavailable");
+        let err = download(&server, &destination)
+            .retries(2)
+            .build()
+            .run()
+            .unwrap_err();
+        match err {
+            Error::Http(err) => {
+                assert_eq!(err.status(), Some(StatusCode::SERVICE_UNAVAILABLE))
+            }
+            err => panic!("unexpected error {:?}", err),
+        }
+        assert_eq!(server.ranges().len(), 3);
+    }
+
+    #[test]
+    fn server_error_followed_by_success() {
+        let server = Server::start(|n, _| match n {
+            0 => status("500 Internal Server Error"),
+            _ => ok(BODY),
+        });
+        let destination = destination("recovered");
+        download(&server, &destination).build().run().unwrap();
+        assert_eq!(fs::read(&destination).unwrap(), BODY);
+        assert_eq!(server.ranges().len(), 2);
+    }
+
+    #[test]
+    fn only_dropped_connections_are_transient_io_errors() {
+        for kind in &[
+            io::ErrorKind::ConnectionReset,
+            io::ErrorKind::TimedOut,
+            io::ErrorKind::UnexpectedEof,
+        ] {
+            assert!(is_transient(&io::Error::from(*kind).into()), "{:?}", kind);
+        }
+        for kind in &[
+            io::ErrorKind::PermissionDenied,
+            io::ErrorKind::NotFound,
+            io::ErrorKind::Other,
+        ] {
+            assert!(!is_transient(&io::Error::from(*kind).into()), "{:?}", kind);
+        }
+        // ENOSPC
+        assert!(!is_transient(&io::Error::from_raw_os_error(28).into()));
+    }
+}
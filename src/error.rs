@@ -0,0 +1,390 @@
+//! error module
+
+use crate::{
+    base::{NSError, NSString, NIL},
+    validation::Diagnostic,
+};
+
+use std::error;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+/// error domain of Virtualization.framework
+pub const VZ_ERROR_DOMAIN: &str = "VZErrorDomain";
+
+/// result of fallible operations of this crate
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// error codes of `VZErrorDomain`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum VZErrorCode {
+    Internal,
+    InvalidVirtualMachineConfiguration,
+    InvalidVirtualMachineState,
+    InvalidVirtualMachineStateTransition,
+    InvalidDiskImage,
+    VirtualMachineLimitExceeded,
+    NetworkError,
+    OutOfDiskSpace,
+    OperationCancelled,
+    NotSupported,
+    RestoreImageCatalogLoadFailed,
+    InvalidRestoreImageCatalog,
+    NoSupportedRestoreImagesInCatalog,
+    RestoreImageLoadFailed,
+    InvalidRestoreImage,
+    InstallationRequiresUpdate,
+    InstallationFailed,
+    /// code unknown to this crate
+    Other(isize),
+}
+
+impl VZErrorCode {
+    pub fn from_code(code: isize) -> VZErrorCode {
+        match code {
+            1 => VZErrorCode::Internal,
+            2 => VZErrorCode::InvalidVirtualMachineConfiguration,
+            3 => VZErrorCode::InvalidVirtualMachineState,
+            4 => VZErrorCode::InvalidVirtualMachineStateTransition,
+            5 => VZErrorCode::InvalidDiskImage,
+            6 => VZErrorCode::VirtualMachineLimitExceeded,
+            7 => VZErrorCode::NetworkError,
+            8 => VZErrorCode::OutOfDiskSpace,
+            9 => VZErrorCode::OperationCancelled,
+            10 => VZErrorCode::NotSupported,
+            10001 => VZErrorCode::RestoreImageCatalogLoadFailed,
+            10002 => VZErrorCode::InvalidRestoreImageCatalog,
+            10003 => VZErrorCode::NoSupportedRestoreImagesInCatalog,
+            10004 => VZErrorCode::RestoreImageLoadFailed,
+            10005 => VZErrorCode::InvalidRestoreImage,
+            10006 => VZErrorCode::InstallationRequiresUpdate,
+            10007 => VZErrorCode::InstallationFailed,
+            _ => VZErrorCode::Other(code),
+        }
+    }
+
+    pub fn code(self) -> isize {
+        match self {
+            VZErrorCode::Internal => 1,
+            VZErrorCode::InvalidVirtualMachineConfiguration => 2,
+            VZErrorCode::InvalidVirtualMachineState => 3,
+            VZErrorCode::InvalidVirtualMachineStateTransition => 4,
+            VZErrorCode::InvalidDiskImage => 5,
+            VZErrorCode::VirtualMachineLimitExceeded => 6,
+            VZErrorCode::NetworkError => 7,
+            VZErrorCode::OutOfDiskSpace => 8,
+            VZErrorCode::OperationCancelled => 9,
+            VZErrorCode::NotSupported => 10,
+            VZErrorCode::RestoreImageCatalogLoadFailed => 10001,
+            VZErrorCode::InvalidRestoreImageCatalog => 10002,
+            VZErrorCode::NoSupportedRestoreImagesInCatalog => 10003,
+            VZErrorCode::RestoreImageLoadFailed => 10004,
+            VZErrorCode::InvalidRestoreImage => 10005,
+            VZErrorCode::InstallationRequiresUpdate => 10006,
+            VZErrorCode::InstallationFailed => 10007,
+            VZErrorCode::Other(code) => code,
+        }
+    }
+}
+
+/// NSError copied into owned Rust strings
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameworkError {
+    pub domain: String,
+    pub code: isize,
+    pub description: String,
+    pub failure_reason: Option<String>,
+    pub recovery_suggestion: Option<String>,
+}
+
+impl FrameworkError {
+    /// error of `VZErrorDomain`
+    pub fn new<T: Into<String>>(code: VZErrorCode, description: T) -> FrameworkError {
+        FrameworkError {
+            domain: VZ_ERROR_DOMAIN.to_string(),
+            code: code.code(),
+            description: description.into(),
+            failure_reason: None,
+            recovery_suggestion: None,
+        }
+    }
+
+    /// the `VZErrorDomain` code, `None` for errors of other domains
+    pub fn vz_code(&self) -> Option<VZErrorCode> {
+        if self.domain == VZ_ERROR_DOMAIN {
+            Some(VZErrorCode::from_code(self.code))
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for FrameworkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.description)?;
+        if let Some(failure_reason) = &self.failure_reason {
+            write!(f, ": {}", failure_reason)?;
+        }
+        write!(f, " ({} code {})", self.domain, self.code)
+    }
+}
+
+impl error::Error for FrameworkError {}
+
+fn optional_string(s: NSString) -> Option<String> {
+    if *s.0 == NIL {
+        None
+    } else {
+        Some(s.as_str().to_string())
+    }
+}
+
+impl From<NSError> for FrameworkError {
+    fn from(err: NSError) -> Self {
+        FrameworkError {
+            domain: err.domain().as_str().to_string(),
+            code: err.code(),
+            description: err.localized_description().as_str().to_string(),
+            failure_reason: optional_string(err.localized_failure_reason()),
+            recovery_suggestion: optional_string(err.localized_recovery_suggestion()),
+        }
+    }
+}
+
+/// error of this crate
+#[derive(Debug)]
+pub enum Error {
+    /// error reported by Virtualization.framework or Foundation
+    Framework(FrameworkError),
+    Io(io::Error),
+    /// a download failed
+    Http(reqwest::Error),
+    /// a download was incomplete or the server answered unexpectedly
+    Download(String),
+    /// a downloaded file does not have the expected SHA-256 digest
+    ChecksumMismatch {
+        expected: String,
+        actual: String,
+    },
+    /// the configuration was rejected before reaching the framework
+    Validation(Vec<Diagnostic>),
+    Toml(toml::de::Error),
+    TomlSerialize(toml::ser::Error),
+    Json(serde_json::Error),
+    /// a file has an unexpected format
+    Parse(String),
+    /// the mac platform configuration could not be loaded or created
+    Platform(String),
+    /// a disk image or bundle is locked by another user, the process `pid` if known
+    Locked {
+        path: PathBuf,
+        pid: Option<u32>,
+    },
+    /// no restore image of macOS `version` is cached and `latest` is the newest one available
+    RestoreImageUnavailable {
+        version: String,
+        latest: String,
+    },
+}
+
+impl Error {
+    /// the `VZErrorDomain` code of a framework error
+    pub fn vz_code(&self) -> Option<VZErrorCode> {
+        match self {
+            Error::Framework(err) => err.vz_code(),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Framework(err) => write!(f, "{}", err),
+            Error::Io(err) => write!(f, "{}", err),
+            Error::Http(err) => write!(f, "{}", err),
+            Error::Download(description) => write!(f, "{}", description),
+            Error::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "SHA-256 digest mismatch: expected {}, got {}",
+                expected, actual
+            ),
+            Error::Validation(diagnostics) => {
+                write!(f, "invalid configuration")?;
+                for diagnostic in diagnostics {
+                    write!(f, "\n  {}", diagnostic)?;
+                }
+                Ok(())
+            }
+            Error::Toml(err) => write!(f, "invalid TOML: {}", err),
+            Error::TomlSerialize(err) => write!(f, "cannot write TOML: {}", err),
+            Error::Json(err) => write!(f, "invalid JSON: {}", err),
+            Error::Parse(description) => write!(f, "{}", description),
+            Error::Platform(description) => write!(f, "{}", description),
+            Error::Locked {
+                path,
+                pid: Some(pid),
+            } => write!(f, "{} is in use by process {}", path.display(), pid),
+            Error::Locked { path, pid: None } => {
+                write!(f, "{} is in use by another process", path.display())
+            }
+            Error::RestoreImageUnavailable { version, latest } => write!(
+                f,
+                "no restore image for macOS {}, the latest supported is {}",
+                version, latest
+            ),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Framework(err) => Some(err),
+            Error::Io(err) => Some(err),
+            Error::Http(err) => Some(err),
+            Error::Toml(err) => Some(err),
+            Error::TomlSerialize(err) => Some(err),
+            Error::Json(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<FrameworkError> for Error {
+    fn from(err: FrameworkError) -> Self {
+        Error::Framework(err)
+    }
+}
+
+impl From<NSError> for Error {
+    fn from(err: NSError) -> Self {
+        Error::Framework(err.into())
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(err: reqwest::Error) -> Self {
+        Error::Http(err)
+    }
+}
+
+impl From<toml::de::Error> for Error {
+    fn from(err: toml::de::Error) -> Self {
+        Error::Toml(err)
+    }
+}
+
+impl From<toml::ser::Error> for Error {
+    fn from(err: toml::ser::Error) -> Self {
+        Error::TomlSerialize(err)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Self {
+        Error::Json(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::validation::{DiagnosticKind, Severity};
+
+    #[test]
+    fn error_codes_round_trip() {
+        for code in (1..=10).chain(10001..=10007) {
+            let vz_code = VZErrorCode::from_code(code);
+            assert!(!matches!(vz_code, VZErrorCode::Other(_)), "{}", code);
+            assert_eq!(vz_code.code(), code);
+        }
+        for &code in &[-1, 0, 11, 10000, 10008] {
+            assert_eq!(VZErrorCode::from_code(code), VZErrorCode::Other(code));
+            assert_eq!(VZErrorCode::Other(code).code(), code);
+        }
+        assert_eq!(VZErrorCode::from_code(5), VZErrorCode::InvalidDiskImage);
+    }
+
+    #[test]
+    fn framework_errors_display_their_failure_reason() {
+        let mut err = FrameworkError::new(VZErrorCode::InvalidDiskImage, "The disk is invalid.");
+        assert_eq!(
+            err.to_string(),
+            "The disk is invalid. (VZErrorDomain code 5)"
+        );
+        assert_eq!(err.vz_code(), Some(VZErrorCode::InvalidDiskImage));
+
+        err.failure_reason = Some("The file is truncated.".to_string());
+        assert_eq!(
+            Error::from(err.clone()).to_string(),
+            "The disk is invalid.: The file is truncated. (VZErrorDomain code 5)"
+        );
+
+        err.domain = "NSCocoaErrorDomain".to_string();
+        assert_eq!(err.vz_code(), None);
+        assert_eq!(Error::from(err).vz_code(), None);
+    }
+
+    #[test]
+    fn errors_display_their_details() {
+        let locked = Error::Locked {
+            path: PathBuf::from("/vm/disk.img"),
+            pid: Some(42),
+        };
+        assert_eq!(locked.to_string(), "/vm/disk.img is in use by process 42");
+        let locked = Error::Locked {
+            path: PathBuf::from("/vm/disk.img"),
+            pid: None,
+        };
+        assert_eq!(
+            locked.to_string(),
+            "/vm/disk.img is in use by another process"
+        );
+
+        let invalid = Error::Validation(vec![Diagnostic {
+            field: "cpu_count".to_string(),
+            severity: Severity::Error,
+            kind: DiagnosticKind::CpuCountTooLow { count: 0, min: 1 },
+        }]);
+        let text = invalid.to_string();
+        assert!(text.starts_with("invalid configuration\n  error: cpu_count: "));
+        assert_eq!(text.lines().count(), 2);
+        assert!(error::Error::source(&invalid).is_none());
+    }
+
+    #[test]
+    fn sources_are_the_wrapped_errors() {
+        let err = Error::from(io::Error::new(io::ErrorKind::NotFound, "missing disk"));
+        let source = error::Error::source(&err).unwrap();
+        assert_eq!(
+            source.downcast_ref::<io::Error>().unwrap().kind(),
+            io::ErrorKind::NotFound
+        );
+        assert_eq!(err.to_string(), "missing disk");
+
+        let err = Error::from(FrameworkError::new(VZErrorCode::Internal, "internal"));
+        let source = error::Error::source(&err).unwrap();
+        assert_eq!(
+            source.downcast_ref::<FrameworkError>().unwrap().vz_code(),
+            Some(VZErrorCode::Internal)
+        );
+
+        // a relative URL fails while building the request, without any network access
+        let err = Error::from(reqwest::blocking::get("restore.ipsw").unwrap_err());
+        let source = error::Error::source(&err).unwrap();
+        assert!(source
+            .downcast_ref::<reqwest::Error>()
+            .unwrap()
+            .is_builder());
+
+        assert!(error::Error::source(&Error::Parse("bad".to_string())).is_none());
+    }
+}
@@ -0,0 +1,676 @@
+//! restore image inspection module
+//!
+//! An `.ipsw` restore image is a zip archive. [`IpswArchive`] reads its central directory,
+//! including the ZIP64 records needed for archives larger than 4 GiB, and extracts single
+//! entries, so [`inspect`] can report what an image contains without Virtualization.framework.
+
+use crate::error::{Error, Result};
+
+use flate2::read::DeflateDecoder;
+use flate2::Crc;
+use plist::Value;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufReader, Cursor, Read, Seek, SeekFrom};
+use std::path::Path;
+
+const END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x0605_4b50;
+const ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR_SIGNATURE: u32 = 0x0706_4b50;
+const ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x0606_4b50;
+const CENTRAL_DIRECTORY_HEADER_SIGNATURE: u32 = 0x0201_4b50;
+const LOCAL_FILE_HEADER_SIGNATURE: u32 = 0x0403_4b50;
+const ZIP64_EXTRA_FIELD_ID: u16 = 0x0001;
+
+const END_OF_CENTRAL_DIRECTORY_SIZE: u64 = 22;
+const ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR_SIZE: u64 = 20;
+const MAX_COMMENT_SIZE: u64 = 0xffff;
+
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATED: u16 = 8;
+
+/// entries larger than this are not extracted into memory
+const MAX_ENTRY_SIZE: u64 = 64 * 1024 * 1024;
+
+const BUILD_MANIFEST: &str = "BuildManifest.plist";
+const RESTORE: &str = "Restore.plist";
+
+fn malformed<T: AsRef<str>>(description: T) -> Error {
+    Error::Parse(format!("malformed zip archive: {}", description.as_ref()))
+}
+
+fn u16_at(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([buf[offset], buf[offset + 1]])
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&buf[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn u64_at(buf: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&buf[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+/// file stored in a zip archive
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ZipEntry {
+    pub name: String,
+    /// uncompressed size in bytes
+    pub size: u64,
+    pub compressed_size: u64,
+    pub crc32: u32,
+    method: u16,
+    local_header_offset: u64,
+}
+
+/// zip archive of a restore image
+pub struct IpswArchive<R> {
+    reader: R,
+    entries: Vec<ZipEntry>,
+}
+
+impl IpswArchive<BufReader<File>> {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        IpswArchive::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read + Seek> IpswArchive<R> {
+    /// read the central directory of the archive
+    pub fn new(mut reader: R) -> Result<Self> {
+        let (cd_offset, cd_size, count) = find_central_directory(&mut reader)?;
+        reader.seek(SeekFrom::Start(cd_offset))?;
+        let mut cd = Vec::new();
+        (&mut reader).take(cd_size).read_to_end(&mut cd)?;
+        if (cd.len() as u64) < cd_size {
+            return Err(malformed("truncated central directory"));
+        }
+
+        let mut entries = Vec::with_capacity(count.min(0x10000) as usize);
+        let mut pos = 0;
+        for _ in 0..count {
+            let (entry, next) = parse_central_directory_header(&cd, pos)?;
+            entries.push(entry);
+            pos = next;
+        }
+        Ok(IpswArchive { reader, entries })
+    }
+
+    pub fn entries(&self) -> &[ZipEntry] {
+        &self.entries
+    }
+
+    pub fn entry(&self, name: &str) -> Option<&ZipEntry> {
+        self.entries.iter().find(|entry| entry.name == name)
+    }
+
+    /// extract the entry named `name`, its CRC-32 is verified
+    pub fn read_entry(&mut self, name: &str) -> Result<Vec<u8>> {
+        let entry = match self.entry(name) {
+            Some(entry) => entry.clone(),
+            None => return Err(Error::Parse(format!("{} not found in archive", name))),
+        };
+        if entry.size > MAX_ENTRY_SIZE {
+            return Err(Error::Parse(format!(
+                "{} is too large to extract ({} bytes)",
+                name, entry.size
+            )));
+        }
+
+        let mut header = [0; 30];
+        self.reader
+            .seek(SeekFrom::Start(entry.local_header_offset))?;
+        self.reader.read_exact(&mut header)?;
+        if u32_at(&header, 0) != LOCAL_FILE_HEADER_SIGNATURE {
+            return Err(malformed(format!("bad local header of {}", name)));
+        }
+        let data_offset = entry.local_header_offset
+            + 30
+            + u16_at(&header, 26) as u64
+            + u16_at(&header, 28) as u64;
+        self.reader.seek(SeekFrom::Start(data_offset))?;
+
+        let compressed = (&mut self.reader).take(entry.compressed_size);
+        let mut data = Vec::with_capacity(entry.size as usize);
+        match entry.method {
+            METHOD_STORED => compressed.take(entry.size).read_to_end(&mut data)?,
+            METHOD_DEFLATED => DeflateDecoder::new(compressed)
+                .take(entry.size)
+                .read_to_end(&mut data)?,
+            method => {
+                return Err(Error::Parse(format!(
+                    "{} uses unsupported compression method {}",
+                    name, method
+                )))
+            }
+        };
+
+        let mut crc = Crc::new();
+        crc.update(&data);
+        if data.len() as u64 != entry.size || crc.sum() != entry.crc32 {
+            return Err(malformed(format!("{} is corrupted", name)));
+        }
+        Ok(data)
+    }
+}
+
+/// `(offset, size, number of entries)` of the central directory
+fn find_central_directory<R: Read + Seek>(reader: &mut R) -> Result<(u64, u64, u64)> {
+    let file_size = reader.seek(SeekFrom::End(0))?;
+    if file_size < END_OF_CENTRAL_DIRECTORY_SIZE {
+        return Err(malformed("file is too small"));
+    }
+    let tail_size = file_size.min(END_OF_CENTRAL_DIRECTORY_SIZE + MAX_COMMENT_SIZE);
+    let tail_offset = file_size - tail_size;
+    let mut tail = vec![0; tail_size as usize];
+    reader.seek(SeekFrom::Start(tail_offset))?;
+    reader.read_exact(&mut tail)?;
+
+    let eocd = (0..=tail.len() - END_OF_CENTRAL_DIRECTORY_SIZE as usize)
+        .rev()
+        .find(|&pos| u32_at(&tail, pos) == END_OF_CENTRAL_DIRECTORY_SIGNATURE)
+        .ok_or_else(|| malformed("end of central directory not found"))?;
+    let count = u16_at(&tail, eocd + 10) as u64;
+    let cd_size = u32_at(&tail, eocd + 12) as u64;
+    let cd_offset = u32_at(&tail, eocd + 16) as u64;
+    if count != 0xffff && cd_size != 0xffff_ffff && cd_offset != 0xffff_ffff {
+        return Ok((cd_offset, cd_size, count));
+    }
+
+    // ZIP64, the locator immediately precedes the end of central directory record
+    let locator_offset = (tail_offset + eocd as u64)
+        .checked_sub(ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR_SIZE)
+        .ok_or_else(|| malformed("ZIP64 locator not found"))?;
+    let mut locator = [0; 20];
+    reader.seek(SeekFrom::Start(locator_offset))?;
+    reader.read_exact(&mut locator)?;
+    if u32_at(&locator, 0) != ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR_SIGNATURE {
+        return Err(malformed("ZIP64 locator not found"));
+    }
+
+    let mut record = [0; 56];
+    reader.seek(SeekFrom::Start(u64_at(&locator, 8)))?;
+    reader.read_exact(&mut record)?;
+    if u32_at(&record, 0) != ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE {
+        return Err(malformed("ZIP64 end of central directory not found"));
+    }
+    Ok((
+        u64_at(&record, 48),
+        u64_at(&record, 40),
+        u64_at(&record, 32),
+    ))
+}
+
+/// parse the header at `pos`, returns the entry and the position of the next header
+fn parse_central_directory_header(cd: &[u8], pos: usize) -> Result<(ZipEntry, usize)> {
+    if cd.len() < pos + 46 || u32_at(cd, pos) != CENTRAL_DIRECTORY_HEADER_SIGNATURE {
+        return Err(malformed("bad central directory header"));
+    }
+    let name_len = u16_at(cd, pos + 28) as usize;
+    let extra_len = u16_at(cd, pos + 30) as usize;
+    let comment_len = u16_at(cd, pos + 32) as usize;
+    let name_start = pos + 46;
+    let extra_start = name_start + name_len;
+    let next = extra_start + extra_len + comment_len;
+    if cd.len() < next {
+        return Err(malformed("truncated central directory header"));
+    }
+
+    let mut entry = ZipEntry {
+        name: String::from_utf8_lossy(&cd[name_start..extra_start]).into_owned(),
+        size: u32_at(cd, pos + 24) as u64,
+        compressed_size: u32_at(cd, pos + 20) as u64,
+        crc32: u32_at(cd, pos + 16),
+        method: u16_at(cd, pos + 10),
+        local_header_offset: u32_at(cd, pos + 42) as u64,
+    };
+
+    // the ZIP64 extra field holds, in order, only the values saturated in the header
+    let extra = &cd[extra_start..extra_start + extra_len];
+    let mut field = 0;
+    while field + 4 <= extra.len() {
+        let id = u16_at(extra, field);
+        let len = u16_at(extra, field + 2) as usize;
+        let data = &extra[field + 4..(field + 4 + len).min(extra.len())];
+        if id == ZIP64_EXTRA_FIELD_ID {
+            let mut values = data.chunks_exact(8).map(|value| u64_at(value, 0));
+            for saturated in [
+                &mut entry.size,
+                &mut entry.compressed_size,
+                &mut entry.local_header_offset,
+            ] {
+                if *saturated == 0xffff_ffff {
+                    *saturated = values
+                        .next()
+                        .ok_or_else(|| malformed("truncated ZIP64 extra field"))?;
+                }
+            }
+        }
+        field += 4 + len;
+    }
+    Ok((entry, next))
+}
+
+/// what a restore image contains, read from its `BuildManifest.plist` and `Restore.plist`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RestoreImageInfo {
+    /// macOS version, e.g. `13.0`
+    pub product_version: String,
+    /// build number, e.g. `22A380`
+    pub build_version: String,
+    /// device models the image can be installed on, e.g. `VirtualMac2,1`
+    pub supported_product_types: Vec<String>,
+    /// hardware models (board configurations) of the build identities, e.g. `vma2macosap`
+    pub device_classes: Vec<String>,
+    /// minimum size of the system partition in MiB
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub minimum_system_partition: Option<u64>,
+}
+
+impl RestoreImageInfo {
+    /// whether the image can be installed on a virtual machine
+    pub fn supports_virtual_machines(&self) -> bool {
+        self.supported_product_types
+            .iter()
+            .any(|product_type| product_type.starts_with("VirtualMac"))
+    }
+}
+
+fn string_array(value: Option<&Value>) -> Vec<String> {
+    value
+        .and_then(Value::as_array)
+        .map(|values| {
+            values
+                .iter()
+                .filter_map(Value::as_string)
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn read_plist<R: Read + Seek>(archive: &mut IpswArchive<R>, name: &str) -> Result<Value> {
+    let data = archive.read_entry(name)?;
+    Value::from_reader(Cursor::new(data)).map_err(|err| Error::Parse(format!("{}: {}", name, err)))
+}
+
+/// report the versions and requirements of the restore image at `path`
+///
+/// Works on any host, `BuildManifest.plist` is required and `Restore.plist` is used when present.
+pub fn inspect<P: AsRef<Path>>(path: P) -> Result<RestoreImageInfo> {
+    let path = path.as_ref();
+    IpswArchive::open(path)
+        .and_then(|mut archive| inspect_archive(&mut archive))
+        .map_err(|err| match err {
+            Error::Parse(description) => {
+                Error::Parse(format!("{}: {}", path.display(), description))
+            }
+            err => err,
+        })
+}
+
+/// report the versions and requirements of a restore image that is already open
+pub fn inspect_archive<R: Read + Seek>(archive: &mut IpswArchive<R>) -> Result<RestoreImageInfo> {
+    let manifest = read_plist(archive, BUILD_MANIFEST)?;
+    let restore = if archive.entry(RESTORE).is_some() {
+        Some(read_plist(archive, RESTORE)?)
+    } else {
+        None
+    };
+    let manifest = manifest
+        .as_dictionary()
+        .ok_or_else(|| Error::Parse(format!("{} is not a dictionary", BUILD_MANIFEST)))?;
+    let restore = restore.as_ref().and_then(Value::as_dictionary);
+
+    let string = |key: &str| {
+        manifest
+            .get(key)
+            .or_else(|| restore.and_then(|restore| restore.get(key)))
+            .and_then(Value::as_string)
+            .map(str::to_string)
+            .ok_or_else(|| Error::Parse(format!("{} has no {}", BUILD_MANIFEST, key)))
+    };
+    let product_version = string("ProductVersion")?;
+    let build_version = string("ProductBuildVersion")?;
+
+    let mut supported_product_types = string_array(manifest.get("SupportedProductTypes"));
+    if let Some(restore) = restore {
+        supported_product_types.extend(string_array(restore.get("SupportedProductTypes")));
+    }
+    supported_product_types.sort();
+    supported_product_types.dedup();
+
+    let identities = manifest
+        .get("BuildIdentities")
+        .and_then(Value::as_array)
+        .map(|identities| identities.as_slice())
+        .unwrap_or_default();
+    let identity_info = identities
+        .iter()
+        .filter_map(Value::as_dictionary)
+        .filter_map(|identity| identity.get("Info").and_then(Value::as_dictionary));
+
+    let mut device_classes: Vec<String> = identity_info
+        .clone()
+        .filter_map(|info| info.get("DeviceClass").and_then(Value::as_string))
+        .map(str::to_string)
+        .collect();
+    if let Some(device_map) = restore
+        .and_then(|restore| restore.get("DeviceMap"))
+        .and_then(Value::as_array)
+    {
+        device_classes.extend(
+            device_map
+                .iter()
+                .filter_map(Value::as_dictionary)
+                .filter_map(|device| device.get("BoardConfig").and_then(Value::as_string))
+                .map(str::to_string),
+        );
+    }
+    for device_class in device_classes.iter_mut() {
+        device_class.make_ascii_lowercase();
+    }
+    device_classes.sort();
+    device_classes.dedup();
+
+    let minimum_system_partition = identity_info
+        .filter_map(|info| info.get("MinimumSystemPartition"))
+        .chain(restore.and_then(|restore| restore.get("MinimumSystemPartition")))
+        .filter_map(Value::as_unsigned_integer)
+        .max();
+
+    Ok(RestoreImageInfo {
+        product_version,
+        build_version,
+        supported_product_types,
+        device_classes,
+        minimum_system_partition,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::DeflateEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    /// zip archive of `entries`, with the ZIP64 records and extra fields when `zip64` is set
+    fn zip(entries: &[(&str, &[u8], u16)], zip64: bool) -> Vec<u8> {
+        let mut zip = Vec::new();
+        let mut cd = Vec::new();
+        for (name, data, method) in entries {
+            let compressed = match *method {
+                METHOD_DEFLATED => {
+                    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+                    encoder.write_all(data).unwrap();
+                    encoder.finish().unwrap()
+                }
+                _ => data.to_vec(),
+            };
+            let mut crc = Crc::new();
+            crc.update(data);
+            let offset = zip.len() as u64;
+
+            zip.extend_from_slice(&LOCAL_FILE_HEADER_SIGNATURE.to_le_bytes());
+            zip.extend_from_slice(&[20, 0, 0, 0]);
+            zip.extend_from_slice(&method.to_le_bytes());
+            zip.extend_from_slice(&[0; 4]);
+            zip.extend_from_slice(&crc.sum().to_le_bytes());
+            zip.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+            zip.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            zip.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            zip.extend_from_slice(&0u16.to_le_bytes());
+            zip.extend_from_slice(name.as_bytes());
+            zip.extend_from_slice(&compressed);
+
+            let (size, compressed_size, local_header_offset) = if zip64 {
+                (0xffff_ffff, 0xffff_ffff, 0xffff_ffff)
+            } else {
+                (data.len() as u32, compressed.len() as u32, offset as u32)
+            };
+            // an unrelated extra field precedes the ZIP64 one
+            let mut extra = vec![0x55, 0x54, 1, 0, 0];
+            if zip64 {
+                extra.extend_from_slice(&ZIP64_EXTRA_FIELD_ID.to_le_bytes());
+                extra.extend_from_slice(&24u16.to_le_bytes());
+                extra.extend_from_slice(&(data.len() as u64).to_le_bytes());
+                extra.extend_from_slice(&(compressed.len() as u64).to_le_bytes());
+                extra.extend_from_slice(&offset.to_le_bytes());
+            }
+            cd.extend_from_slice(&CENTRAL_DIRECTORY_HEADER_SIGNATURE.to_le_bytes());
+            cd.extend_from_slice(&[45, 0, 45, 0, 0, 0]);
+            cd.extend_from_slice(&method.to_le_bytes());
+            cd.extend_from_slice(&[0; 4]);
+            cd.extend_from_slice(&crc.sum().to_le_bytes());
+            cd.extend_from_slice(&compressed_size.to_le_bytes());
+            cd.extend_from_slice(&size.to_le_bytes());
+            cd.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            cd.extend_from_slice(&(extra.len() as u16).to_le_bytes());
+            cd.extend_from_slice(&[0; 10]);
+            cd.extend_from_slice(&local_header_offset.to_le_bytes());
+            cd.extend_from_slice(name.as_bytes());
+            cd.extend_from_slice(&extra);
+        }
+
+        let cd_offset = zip.len() as u64;
+        zip.extend_from_slice(&cd);
+        let count = entries.len() as u64;
+        let (count16, cd_size32, cd_offset32) = if zip64 {
+            let record_offset = zip.len() as u64;
+            zip.extend_from_slice(&ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE.to_le_bytes());
+            zip.extend_from_slice(&44u64.to_le_bytes());
+            zip.extend_from_slice(&[45, 0, 45, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+            zip.extend_from_slice(&count.to_le_bytes());
+            zip.extend_from_slice(&count.to_le_bytes());
+            zip.extend_from_slice(&(cd.len() as u64).to_le_bytes());
+            zip.extend_from_slice(&cd_offset.to_le_bytes());
+
+            zip.extend_from_slice(&ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR_SIGNATURE.to_le_bytes());
+            zip.extend_from_slice(&0u32.to_le_bytes());
+            zip.extend_from_slice(&record_offset.to_le_bytes());
+            zip.extend_from_slice(&1u32.to_le_bytes());
+            (0xffff, 0xffff_ffff, 0xffff_ffff)
+        } else {
+            (count as u16, cd.len() as u32, cd_offset as u32)
+        };
+        zip.extend_from_slice(&END_OF_CENTRAL_DIRECTORY_SIGNATURE.to_le_bytes());
+        zip.extend_from_slice(&[0; 4]);
+        zip.extend_from_slice(&count16.to_le_bytes());
+        zip.extend_from_slice(&count16.to_le_bytes());
+        zip.extend_from_slice(&cd_size32.to_le_bytes());
+        zip.extend_from_slice(&cd_offset32.to_le_bytes());
+        zip.extend_from_slice(&0u16.to_le_bytes());
+        zip
+    }
+
+    fn archive(zip: Vec<u8>) -> Result<IpswArchive<Cursor<Vec<u8>>>> {
+        IpswArchive::new(Cursor::new(zip))
+    }
+
+    fn is_parse_error<T>(result: Result<T>) -> bool {
+        matches!(result, Err(Error::Parse(_)))
+    }
+
+    const TEXT: &[u8] = b"restore image restore image restore image restore image";
+
+    fn entries() -> Vec<(&'static str, &'static [u8], u16)> {
+        vec![
+            ("stored.txt", TEXT, METHOD_STORED),
+            ("deflated.txt", TEXT, METHOD_DEFLATED),
+            ("empty", b"", METHOD_STORED),
+        ]
+    }
+
+    #[test]
+    fn reads_stored_and_deflated_entries() {
+        for zip64 in &[false, true] {
+            let mut archive = archive(zip(&entries(), *zip64)).unwrap();
+            let names: Vec<_> = archive.entries().iter().map(|entry| &entry.name).collect();
+            assert_eq!(names, ["stored.txt", "deflated.txt", "empty"]);
+
+            let deflated = archive.entry("deflated.txt").unwrap();
+            assert_eq!(deflated.size, TEXT.len() as u64);
+            assert!(deflated.compressed_size < deflated.size);
+
+            assert_eq!(archive.read_entry("stored.txt").unwrap(), TEXT);
+            assert_eq!(archive.read_entry("deflated.txt").unwrap(), TEXT);
+            assert!(archive.read_entry("empty").unwrap().is_empty());
+            assert!(is_parse_error(archive.read_entry("missing")));
+        }
+    }
+
+    #[test]
+    fn finds_the_end_of_central_directory_before_a_comment() {
+        let mut zip = zip(&entries(), false);
+        let len = zip.len();
+        zip[len - 2..].copy_from_slice(&5u16.to_le_bytes());
+        zip.extend_from_slice(b"hello");
+        assert_eq!(archive(zip).unwrap().entries().len(), 3);
+    }
+
+    #[test]
+    fn rejects_a_missing_end_of_central_directory() {
+        assert!(is_parse_error(archive(Vec::new())));
+        assert!(is_parse_error(archive(vec![0; 1024])));
+
+        let mut zip = zip(&entries(), false);
+        zip.truncate(zip.len() - 1);
+        assert!(is_parse_error(archive(zip)));
+    }
+
+    #[test]
+    fn rejects_a_truncated_central_directory() {
+        let plain = zip(&entries(), false);
+        let eocd = plain.len() - END_OF_CENTRAL_DIRECTORY_SIZE as usize;
+
+        // a central directory running past the end of the file
+        let mut oversized = plain.clone();
+        oversized[eocd + 12..eocd + 16].copy_from_slice(&0xffff_0000u32.to_le_bytes());
+        assert!(is_parse_error(archive(oversized)));
+
+        // more entries than headers
+        let mut miscounted = plain;
+        miscounted[eocd + 10..eocd + 12].copy_from_slice(&4u16.to_le_bytes());
+        assert!(is_parse_error(archive(miscounted)));
+
+        // a ZIP64 extra field without the saturated offset
+        let mut zip64 = zip(&entries()[..1], true);
+        let cd_offset = zip64
+            .windows(4)
+            .position(|bytes| bytes == CENTRAL_DIRECTORY_HEADER_SIGNATURE.to_le_bytes())
+            .unwrap();
+        let extra = cd_offset + 46 + "stored.txt".len() + 5;
+        zip64[extra + 2..extra + 4].copy_from_slice(&16u16.to_le_bytes());
+        assert!(is_parse_error(archive(zip64)));
+    }
+
+    #[test]
+    fn rejects_a_missing_zip64_locator() {
+        let mut zip = zip(&entries(), true);
+        let locator = zip.len()
+            - END_OF_CENTRAL_DIRECTORY_SIZE as usize
+            - ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR_SIZE as usize;
+        zip[locator] ^= 0xff;
+        assert!(is_parse_error(archive(zip)));
+    }
+
+    #[test]
+    fn rejects_corrupted_entries() {
+        let mut zip = zip(&entries(), false);
+        let data = zip
+            .windows(TEXT.len())
+            .position(|bytes| bytes == TEXT)
+            .unwrap();
+        zip[data] ^= 0xff;
+        let mut archive = archive(zip).unwrap();
+        assert!(is_parse_error(archive.read_entry("stored.txt")));
+        assert_eq!(archive.read_entry("deflated.txt").unwrap(), TEXT);
+    }
+
+    #[test]
+    fn refuses_to_extract_oversized_entries() {
+        let mut zip = zip(&entries()[..1], true);
+        let size = zip
+            .windows(8)
+            .position(|bytes| bytes == (TEXT.len() as u64).to_le_bytes())
+            .unwrap();
+        zip[size..size + 8].copy_from_slice(&(MAX_ENTRY_SIZE + 1).to_le_bytes());
+        let mut archive = archive(zip).unwrap();
+        assert_eq!(
+            archive.entry("stored.txt").unwrap().size,
+            MAX_ENTRY_SIZE + 1
+        );
+        assert!(is_parse_error(archive.read_entry("stored.txt")));
+    }
+
+    fn plist(body: &str) -> String {
+        format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <plist version=\"1.0\"><dict>{}</dict></plist>\n",
+            body
+        )
+    }
+
+    #[test]
+    fn inspects_build_manifest_and_restore_plists() {
+        let manifest = plist(
+            "<key>ProductVersion</key><string>13.0</string>\
+             <key>ProductBuildVersion</key><string>22A380</string>\
+             <key>BuildIdentities</key><array><dict><key>Info</key><dict>\
+             <key>DeviceClass</key><string>VMA2MACOSAP</string>\
+             <key>MinimumSystemPartition</key><integer>5000</integer>\
+             </dict></dict></array>",
+        );
+        let restore = plist(
+            "<key>SupportedProductTypes</key><array><string>VirtualMac2,1</string></array>\
+             <key>DeviceMap</key><array><dict>\
+             <key>BoardConfig</key><string>vma2macosap</string>\
+             </dict><dict><key>BoardConfig</key><string>j314sap</string></dict></array>\
+             <key>MinimumSystemPartition</key><integer>6000</integer>",
+        );
+        let path = std::env::temp_dir().join(format!("ipsw-inspect-{}.ipsw", std::process::id()));
+        std::fs::write(
+            &path,
+            zip(
+                &[
+                    (BUILD_MANIFEST, manifest.as_bytes(), METHOD_DEFLATED),
+                    (RESTORE, restore.as_bytes(), METHOD_STORED),
+                ],
+                false,
+            ),
+        )
+        .unwrap();
+        let info = inspect(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            info,
+            RestoreImageInfo {
+                product_version: "13.0".to_string(),
+                build_version: "22A380".to_string(),
+                supported_product_types: vec!["VirtualMac2,1".to_string()],
+                device_classes: vec!["j314sap".to_string(), "vma2macosap".to_string()],
+                minimum_system_partition: Some(6000),
+            }
+        );
+        assert!(info.supports_virtual_machines());
+    }
+
+    #[test]
+    fn inspect_requires_the_build_versions() {
+        let manifest = plist("<key>ProductVersion</key><string>13.0</string>");
+        let mut incomplete = archive(zip(
+            &[(BUILD_MANIFEST, manifest.as_bytes(), METHOD_STORED)],
+            false,
+        ))
+        .unwrap();
+        assert!(is_parse_error(inspect_archive(&mut incomplete)));
+
+        let mut no_manifest = archive(zip(&[(RESTORE, b"", METHOD_STORED)], false)).unwrap();
+        assert!(is_parse_error(inspect_archive(&mut no_manifest)));
+    }
+}
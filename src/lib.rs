@@ -6,9 +6,32 @@
 //! See the [simplevm](https://github.com/suzusuzu/virtualization-rs/blob/main/examples/simplevm.rs) for more details.
 //!
 //! The example is inspired from [SimpleVM](https://github.com/KhaosT/SimpleVM).
+//!
+//! # Platforms
+//! The Objective-C backend is only compiled on macOS. On other hosts the [`sys`] module provides a
+//! stub backend with the same API, so configuration code builds and can be unit-tested elsewhere;
+//! [`sys::FRAMEWORK_AVAILABLE`] tells which backend is in use.
+//!
+//! The host must be a Unix: disk images and bundles are locked with `flock(2)` and read with
+//! positioned I/O, see [`lock`] and [`disk`]. Windows is not supported.
 
+#[cfg(target_os = "macos")]
 extern crate block;
+#[cfg(target_os = "macos")]
 extern crate objc;
 
 pub mod base;
+pub mod bundle;
+pub mod catalog;
+pub mod disk;
+pub mod dispatch;
+pub mod download;
+pub mod error;
+pub mod ipsw;
+pub mod lock;
+pub mod snapshot;
+pub mod spec;
+pub mod sys;
+pub mod validation;
+pub mod version;
 pub mod virtualization;
@@ -0,0 +1,363 @@
+//! advisory file locking module
+//!
+//! Virtualization.framework does not stop two processes from attaching the same disk image
+//! read-write, which corrupts it. A [`FileLock`] takes a `flock(2)` lock on a lock file next to the
+//! disk image, or inside a bundle directory, so cooperating processes refuse to use it twice:
+//! read-only users share the lock, a read-write user holds it alone and records its PID there.
+//!
+//! The kernel releases the lock when its holder exits, even after a crash, so a lock file that
+//! still names a PID but is not locked is stale and is taken over. The last holder removes the
+//! lock file when it releases the lock.
+//!
+//! ```text
+//! vm/disk.img            locked through vm/.disk.img.lock
+//! vm/example.vmbundle/   locked through vm/example.vmbundle/.lock
+//! ```
+
+use crate::error::{Error, Result};
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+/// name of the lock file inside a locked directory
+pub const DIRECTORY_LOCK_FILE: &str = ".lock";
+
+/// how a path is locked
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockMode {
+    /// any number of read-only users
+    Shared,
+    /// a single read-write user
+    Exclusive,
+}
+
+/// state of the lock of a path
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockStatus {
+    Unlocked,
+    /// not locked, but the previous exclusive holder exited without releasing the lock
+    Stale {
+        pid: u32,
+    },
+    /// locked exclusively by the process `pid`, `None` when its holder is unknown
+    Locked {
+        pid: Option<u32>,
+    },
+}
+
+/// lock file guarding `path`
+pub fn lock_file_path(path: &Path) -> PathBuf {
+    if path.is_dir() {
+        return path.join(DIRECTORY_LOCK_FILE);
+    }
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    path.with_file_name(format!(".{}.lock", name))
+}
+
+/// advisory lock of a disk image or directory, released when dropped
+///
+/// # Examples
+/// ```rust
+/// use virtualization_rs::lock::{lock_file_path, lock_status, FileLock, LockMode, LockStatus};
+///
+/// let disk = std::env::temp_dir().join(format!("lock-example-{}.img", std::process::id()));
+/// std::fs::write(&disk, b"").unwrap();
+///
+/// let lock = FileLock::acquire(&disk, LockMode::Exclusive).unwrap();
+/// assert!(FileLock::acquire(&disk, LockMode::Shared).is_err());
+/// assert_eq!(
+///     lock_status(&disk).unwrap(),
+///     LockStatus::Locked { pid: Some(std::process::id()) }
+/// );
+/// drop(lock);
+/// assert_eq!(lock_status(&disk).unwrap(), LockStatus::Unlocked);
+/// assert!(!lock_file_path(&disk).exists());
+/// # std::fs::remove_file(&disk).unwrap();
+/// ```
+#[derive(Debug)]
+pub struct FileLock {
+    file: File,
+    path: PathBuf,
+    lock_path: PathBuf,
+    mode: LockMode,
+    stale_pid: Option<u32>,
+}
+
+impl FileLock {
+    /// lock `path` without waiting, fails with [`Error::Locked`] when it is in use
+    pub fn acquire<P: AsRef<Path>>(path: P, mode: LockMode) -> Result<FileLock> {
+        let path = path.as_ref().to_path_buf();
+        let lock_path = lock_file_path(&path);
+        let operation = match mode {
+            LockMode::Shared => libc::LOCK_SH,
+            LockMode::Exclusive => libc::LOCK_EX,
+        };
+        let mut file = loop {
+            let mut file = open_lock_file(&lock_path)?;
+            if !try_flock(&file, operation)? {
+                let pid = read_pid(&mut file)?.filter(|pid| is_alive(*pid));
+                return Err(Error::Locked { path, pid });
+            }
+            // the last holder removes the lock file before releasing it, a lock taken on a
+            // removed file guards nothing and is taken again on the current one
+            if is_current(&file, &lock_path)? {
+                break file;
+            }
+        };
+        // while any lock is held nobody else holds it exclusively, so a recorded PID is stale
+        let stale_pid = read_pid(&mut file)?;
+        if mode == LockMode::Exclusive {
+            file.set_len(0)?;
+            file.seek(SeekFrom::Start(0))?;
+            writeln!(file, "{}", std::process::id())?;
+            file.sync_data()?;
+        }
+        Ok(FileLock {
+            file,
+            path,
+            lock_path,
+            mode,
+            stale_pid,
+        })
+    }
+
+    /// lock `path` shared, `None` when its directory cannot hold a lock file
+    ///
+    /// A file on read-only storage cannot be written to by a virtual machine either, so it is
+    /// used without a lock.
+    pub(crate) fn acquire_shared_unless_read_only(path: &Path) -> Result<Option<FileLock>> {
+        match FileLock::acquire(path, LockMode::Shared) {
+            Ok(lock) => Ok(Some(lock)),
+            Err(Error::Io(err)) if is_read_only(&err) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// the locked path
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn mode(&self) -> LockMode {
+        self.mode
+    }
+
+    /// PID of a previous holder that exited without releasing the lock
+    pub fn stale_pid(&self) -> Option<u32> {
+        self.stale_pid
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        // the lock file is removed while it is still locked exclusively, so a shared holder only
+        // removes it when no other holder is left
+        let last = self.mode == LockMode::Exclusive
+            || try_flock(&self.file, libc::LOCK_EX).unwrap_or(false);
+        if last && fs::remove_file(&self.lock_path).is_err() {
+            let _ = self.file.set_len(0);
+        }
+        // closing the file releases the lock
+    }
+}
+
+/// state of the lock of `path`
+pub fn lock_status<P: AsRef<Path>>(path: P) -> Result<LockStatus> {
+    let lock_path = lock_file_path(path.as_ref());
+    let mut file = match OpenOptions::new().read(true).open(&lock_path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(LockStatus::Unlocked),
+        Err(err) => return Err(err.into()),
+    };
+    let pid = read_pid(&mut file)?;
+    // a shared probe does not turn away read-only users, it only finds an exclusive holder
+    if !try_flock(&file, libc::LOCK_SH)? {
+        return Ok(LockStatus::Locked {
+            pid: pid.filter(|pid| is_alive(*pid)),
+        });
+    }
+    try_flock(&file, libc::LOCK_UN)?;
+    Ok(match pid {
+        Some(pid) => LockStatus::Stale { pid },
+        None => LockStatus::Unlocked,
+    })
+}
+
+/// whether a lock file could not be created because its directory is read-only
+fn is_read_only(err: &io::Error) -> bool {
+    err.kind() == io::ErrorKind::PermissionDenied || err.raw_os_error() == Some(libc::EROFS)
+}
+
+/// whether `file` is still the lock file at `path`
+fn is_current(file: &File, path: &Path) -> io::Result<bool> {
+    let current = match fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(err) => return Err(err),
+    };
+    let locked = file.metadata()?;
+    Ok(current.dev() == locked.dev() && current.ino() == locked.ino())
+}
+
+fn open_lock_file(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+}
+
+/// `flock(2)` without blocking, returns whether the lock was taken
+fn try_flock(file: &File, operation: libc::c_int) -> io::Result<bool> {
+    loop {
+        if unsafe { libc::flock(file.as_raw_fd(), operation | libc::LOCK_NB) } == 0 {
+            return Ok(true);
+        }
+        let err = io::Error::last_os_error();
+        match err.kind() {
+            io::ErrorKind::WouldBlock => return Ok(false),
+            io::ErrorKind::Interrupted => continue,
+            _ => return Err(err),
+        }
+    }
+}
+
+fn read_pid(file: &mut File) -> io::Result<Option<u32>> {
+    let mut contents = String::new();
+    file.seek(SeekFrom::Start(0))?;
+    file.read_to_string(&mut contents)?;
+    Ok(contents.trim().parse().ok())
+}
+
+/// whether a process `pid` exists
+fn is_alive(pid: u32) -> bool {
+    if unsafe { libc::kill(pid as libc::pid_t, 0) } == 0 {
+        return true;
+    }
+    io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_file(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("{}-{}.img", name, std::process::id()));
+        fs::write(&path, b"").unwrap();
+        path
+    }
+
+    #[test]
+    fn shared_lock_keeps_recorded_pid() {
+        let path = temp_file("lock-shared-pid");
+        fs::write(lock_file_path(&path), b"4194304\n").unwrap();
+
+        let lock = FileLock::acquire(&path, LockMode::Shared).unwrap();
+        assert_eq!(lock.stale_pid(), Some(4194304));
+        let contents = fs::read_to_string(lock_file_path(&path)).unwrap();
+        assert_eq!(contents, "4194304\n");
+        drop(lock);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn status_probe_does_not_turn_away_shared_users() {
+        let path = temp_file("lock-status-probe");
+        let first = FileLock::acquire(&path, LockMode::Shared).unwrap();
+
+        let lock_path = lock_file_path(&path);
+        let probe = File::open(&lock_path).unwrap();
+        assert!(try_flock(&probe, libc::LOCK_SH).unwrap());
+        // a reader arriving while the status is probed still gets the lock
+        let second = FileLock::acquire(&path, LockMode::Shared).unwrap();
+        drop(probe);
+
+        assert_eq!(lock_status(&path).unwrap(), LockStatus::Unlocked);
+        drop(first);
+        drop(second);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn status_reports_exclusive_holder() {
+        let path = temp_file("lock-status-exclusive");
+        let lock = FileLock::acquire(&path, LockMode::Exclusive).unwrap();
+        assert_eq!(
+            lock_status(&path).unwrap(),
+            LockStatus::Locked {
+                pid: Some(std::process::id())
+            }
+        );
+        drop(lock);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn stale_pid_is_reported_and_taken_over() {
+        let path = temp_file("lock-stale");
+        fs::write(lock_file_path(&path), b"4194304\n").unwrap();
+        assert_eq!(
+            lock_status(&path).unwrap(),
+            LockStatus::Stale { pid: 4194304 }
+        );
+
+        let lock = FileLock::acquire(&path, LockMode::Exclusive).unwrap();
+        assert_eq!(lock.stale_pid(), Some(4194304));
+        drop(lock);
+        assert_eq!(lock_status(&path).unwrap(), LockStatus::Unlocked);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn last_holder_removes_lock_file() {
+        let path = temp_file("lock-remove");
+        let lock_path = lock_file_path(&path);
+
+        let exclusive = FileLock::acquire(&path, LockMode::Exclusive).unwrap();
+        assert!(lock_path.exists());
+        drop(exclusive);
+        assert!(!lock_path.exists());
+
+        let first = FileLock::acquire(&path, LockMode::Shared).unwrap();
+        let second = FileLock::acquire(&path, LockMode::Shared).unwrap();
+        drop(first);
+        assert!(lock_path.exists());
+        drop(second);
+        assert!(!lock_path.exists());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn lock_on_removed_file_is_taken_again() {
+        let path = temp_file("lock-removed");
+        let lock_path = lock_file_path(&path);
+        let old = open_lock_file(&lock_path).unwrap();
+        fs::remove_file(&lock_path).unwrap();
+        assert!(!is_current(&old, &lock_path).unwrap());
+
+        let lock = FileLock::acquire(&path, LockMode::Exclusive).unwrap();
+        assert!(is_current(&lock.file, &lock_path).unwrap());
+        // the exclusive lock is held on the current lock file, not on the removed one
+        assert!(try_flock(&old, libc::LOCK_EX).unwrap());
+        assert!(FileLock::acquire(&path, LockMode::Shared).is_err());
+        drop(lock);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn read_only_errors() {
+        assert!(is_read_only(&io::ErrorKind::PermissionDenied.into()));
+        assert!(is_read_only(&io::Error::from_raw_os_error(libc::EROFS)));
+        assert!(!is_read_only(&io::ErrorKind::NotFound.into()));
+    }
+}
@@ -0,0 +1,438 @@
+//! virtual machine snapshot module
+//!
+//! Virtualization.framework has no snapshots, so they are made of file clones inside the
+//! [`VmBundle`]: a snapshot is a directory under `snapshots/` holding the manifest and clones of
+//! the disk images and the variable stores of the virtual machine. Clones share their blocks with
+//! the originals on APFS, so taking a snapshot is cheap; see
+//! [`clone_disk`](crate::disk::clone::clone_disk).
+//!
+//! `snapshots/index.toml` records for each snapshot the SHA-256 digest of its manifest and of
+//! every file it holds, which are checked before reverting to it.
+//!
+//! ```text
+//! example.vmbundle/
+//!     snapshots/
+//!         index.toml
+//!         before-upgrade/
+//!             bundle.toml
+//!             disks/root.img
+//!             auxiliary_storage
+//! ```
+
+use crate::{
+    bundle::{self, BundleManifest, VmBundle, AUXILIARY_STORAGE_FILE, MANIFEST_FILE, NVRAM_FILE},
+    disk::{clone::clone_file, raw::disk_digest},
+    download::sha256_file,
+    error::{Error, Result},
+    lock::{FileLock, LockMode},
+};
+
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const SNAPSHOTS_DIR: &str = "snapshots";
+pub const INDEX_FILE: &str = "index.toml";
+
+/// file of the bundle saved in a snapshot
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SnapshotFile {
+    /// path relative to the bundle and to the snapshot directory
+    pub path: String,
+    /// hex encoded SHA-256 digest, see [`disk_digest`] for disk images
+    pub sha256: String,
+    pub disk: bool,
+}
+
+/// entry of the snapshot index
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Snapshot {
+    pub name: String,
+    /// seconds since the Unix epoch
+    pub created: u64,
+    /// hex encoded SHA-256 digest of the saved manifest
+    pub config_sha256: String,
+    pub files: Vec<SnapshotFile>,
+}
+
+/// contents of `snapshots/index.toml`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SnapshotIndex {
+    #[serde(default)]
+    pub snapshots: Vec<Snapshot>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+pub fn snapshots_dir(bundle: &VmBundle) -> PathBuf {
+    bundle.root().join(SNAPSHOTS_DIR)
+}
+
+pub fn snapshot_dir(bundle: &VmBundle, name: &str) -> PathBuf {
+    snapshots_dir(bundle).join(name)
+}
+
+/// the snapshots of `bundle`, oldest first
+pub fn list(bundle: &VmBundle) -> Result<Vec<Snapshot>> {
+    Ok(read_index(bundle)?.snapshots)
+}
+
+/// snapshot the stopped virtual machine of `bundle` as `name`
+///
+/// The bundle and its disks are locked exclusively meanwhile, so it fails while the virtual
+/// machine runs or another process uses one of its disks. Disks outside the bundle cannot be saved
+/// and make it fail.
+pub fn create(bundle: &VmBundle, name: &str) -> Result<Snapshot> {
+    check_name(name)?;
+    let _lock = bundle.lock()?;
+    let mut index = read_index(bundle)?;
+    if index.snapshots.iter().any(|snapshot| snapshot.name == name) {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("snapshot {} already exists", name),
+        )
+        .into());
+    }
+
+    let dir = snapshot_dir(bundle, name);
+    let tmp_dir = snapshots_dir(bundle).join(format!(".{}.tmp", name));
+    let _ = fs::remove_dir_all(&tmp_dir);
+    fs::create_dir_all(&tmp_dir)?;
+    let result = save_files(bundle, &tmp_dir).and_then(|files| {
+        let config_sha256 = sha256_file(tmp_dir.join(MANIFEST_FILE))?;
+        fs::rename(&tmp_dir, &dir)?;
+        Ok(Snapshot {
+            name: name.to_string(),
+            created: now(),
+            config_sha256,
+            files,
+        })
+    });
+    let snapshot = match result {
+        Ok(snapshot) => snapshot,
+        Err(err) => {
+            let _ = fs::remove_dir_all(&tmp_dir);
+            return Err(err);
+        }
+    };
+
+    index.snapshots.push(snapshot.clone());
+    if let Err(err) = write_index(bundle, &index) {
+        let _ = fs::remove_dir_all(&dir);
+        return Err(err);
+    }
+    Ok(snapshot)
+}
+
+/// restore the manifest, disks and variable stores of `bundle` saved in the snapshot `name`
+///
+/// The bundle and the disks to restore are locked exclusively meanwhile, so it fails while the
+/// virtual machine runs or another process uses one of its disks. The saved files are checked
+/// against the digests of the index first and it fails with [`Error::ChecksumMismatch`] if any of
+/// them changed. The snapshot is kept, and disks attached after it was taken are detached but not
+/// deleted.
+pub fn revert(bundle: &mut VmBundle, name: &str) -> Result<()> {
+    let _lock = bundle.lock()?;
+    let snapshot = find(bundle, name)?;
+    let dir = snapshot_dir(bundle, name);
+
+    check_digest(
+        &sha256_file(dir.join(MANIFEST_FILE))?,
+        &snapshot.config_sha256,
+    )?;
+    for file in &snapshot.files {
+        let path = dir.join(&file.path);
+        let actual = if file.disk {
+            disk_digest(&path)?
+        } else {
+            sha256_file(&path)?
+        };
+        check_digest(&actual, &file.sha256)?;
+    }
+    let manifest: BundleManifest = toml::from_str(&fs::read_to_string(dir.join(MANIFEST_FILE))?)?;
+
+    let mut locks = Vec::new();
+    for file in snapshot.files.iter().filter(|file| file.disk) {
+        let destination = bundle.resolve(&file.path);
+        if let Some(parent) = destination.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        locks.push(FileLock::acquire(&destination, LockMode::Exclusive)?);
+    }
+
+    // clone every file next to its destination before replacing any of them
+    let mut restored = Vec::new();
+    for file in &snapshot.files {
+        let destination = bundle.resolve(&file.path);
+        if let Some(parent) = destination.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let tmp_path = revert_path(&destination);
+        let _ = fs::remove_file(&tmp_path);
+        let result = clone_file(&dir.join(&file.path), &tmp_path);
+        restored.push((tmp_path, destination));
+        if let Err(err) = result {
+            for (tmp_path, _) in &restored {
+                let _ = fs::remove_file(tmp_path);
+            }
+            return Err(err.into());
+        }
+    }
+    for (tmp_path, destination) in &restored {
+        fs::rename(tmp_path, destination)?;
+    }
+
+    bundle.manifest_mut().spec = manifest.spec;
+    bundle.save()
+}
+
+/// delete the snapshot `name` and its files
+pub fn delete(bundle: &VmBundle, name: &str) -> Result<()> {
+    let _lock = bundle.lock()?;
+    let mut index = read_index(bundle)?;
+    let len = index.snapshots.len();
+    index.snapshots.retain(|snapshot| snapshot.name != name);
+    if index.snapshots.len() == len {
+        return Err(not_found(name).into());
+    }
+    write_index(bundle, &index)?;
+    fs::remove_dir_all(snapshot_dir(bundle, name))?;
+    Ok(())
+}
+
+fn find(bundle: &VmBundle, name: &str) -> Result<Snapshot> {
+    read_index(bundle)?
+        .snapshots
+        .into_iter()
+        .find(|snapshot| snapshot.name == name)
+        .ok_or_else(|| not_found(name).into())
+}
+
+/// clone the manifest, disks and variable stores of `bundle` into `dir`
+fn save_files(bundle: &VmBundle, dir: &Path) -> Result<Vec<SnapshotFile>> {
+    fs::copy(bundle.root().join(MANIFEST_FILE), dir.join(MANIFEST_FILE))?;
+
+    let mut files = Vec::new();
+    for storage in &bundle.manifest().spec.storage {
+        if !bundle::is_inside_bundle(&storage.path) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("disk {} is outside the bundle", storage.path),
+            )
+            .into());
+        }
+        files.push((storage.path.clone(), true));
+    }
+    // the hardware model and machine identifier never change and are not saved
+    for path in &[AUXILIARY_STORAGE_FILE, NVRAM_FILE] {
+        if bundle.resolve(path).exists() {
+            files.push((path.to_string(), false));
+        }
+    }
+
+    // every disk is locked before the first one is saved, so they are saved at the same point
+    let _locks = files
+        .iter()
+        .filter(|(_, disk)| *disk)
+        .map(|(path, _)| FileLock::acquire(bundle.resolve(path), LockMode::Exclusive))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut saved = Vec::new();
+    for (path, disk) in files {
+        let destination = dir.join(&path);
+        if let Some(parent) = destination.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        clone_file(&bundle.resolve(&path), &destination)?;
+        let sha256 = if disk {
+            disk_digest(&destination)?
+        } else {
+            sha256_file(&destination)?
+        };
+        saved.push(SnapshotFile { path, sha256, disk });
+    }
+    Ok(saved)
+}
+
+fn read_index(bundle: &VmBundle) -> Result<SnapshotIndex> {
+    match fs::read_to_string(snapshots_dir(bundle).join(INDEX_FILE)) {
+        Ok(index) => Ok(toml::from_str(&index)?),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(SnapshotIndex::default()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// write the index atomically
+fn write_index(bundle: &VmBundle, index: &SnapshotIndex) -> Result<()> {
+    let dir = snapshots_dir(bundle);
+    fs::create_dir_all(&dir)?;
+    let tmp_path = dir.join(format!("{}.tmp", INDEX_FILE));
+    fs::write(&tmp_path, toml::to_string_pretty(index)?)?;
+    fs::rename(&tmp_path, dir.join(INDEX_FILE))?;
+    Ok(())
+}
+
+/// names are used as directory names and must be plain file names
+fn check_name(name: &str) -> io::Result<()> {
+    let valid = !name.is_empty()
+        && !name.starts_with('.')
+        && name != INDEX_FILE
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.');
+    if !valid {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid snapshot name {:?}", name),
+        ));
+    }
+    Ok(())
+}
+
+fn check_digest(actual: &str, expected: &str) -> Result<()> {
+    if actual != expected {
+        return Err(Error::ChecksumMismatch {
+            expected: expected.to_string(),
+            actual: actual.to_string(),
+        });
+    }
+    Ok(())
+}
+
+/// hidden name next to `path` a saved file is cloned to before replacing it
+fn revert_path(path: &Path) -> PathBuf {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    path.with_file_name(format!(".{}.revert", name))
+}
+
+fn not_found(name: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("no snapshot named {}", name),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spec::VmSpec;
+
+    fn bundle(name: &str) -> VmBundle {
+        let root = std::env::temp_dir().join(format!("snapshot-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let spec = VmSpec::from_toml("cpu_count = 2\nmemory_size = 2147483648\n").unwrap();
+        let mut bundle = VmBundle::create(&root, name, spec).unwrap();
+        let disk = bundle.add_disk("root.img", false).unwrap();
+        fs::write(disk, b"before").unwrap();
+        fs::write(bundle.nvram_path(), b"variables").unwrap();
+        bundle
+    }
+
+    #[test]
+    fn reverts_disks_files_and_spec() {
+        let mut bundle = bundle("revert");
+        let snapshot = create(&bundle, "before-upgrade").unwrap();
+        assert_eq!(list(&bundle).unwrap(), vec![snapshot.clone()]);
+        let paths: Vec<&str> = snapshot
+            .files
+            .iter()
+            .map(|file| file.path.as_str())
+            .collect();
+        assert_eq!(paths, ["disks/root.img", NVRAM_FILE]);
+
+        fs::write(bundle.disk_path("root.img"), b"after").unwrap();
+        fs::write(bundle.nvram_path(), b"changed").unwrap();
+        bundle.manifest_mut().spec.cpu_count = 4;
+        bundle.add_disk("data.img", false).unwrap();
+
+        revert(&mut bundle, "before-upgrade").unwrap();
+        assert_eq!(fs::read(bundle.disk_path("root.img")).unwrap(), b"before");
+        assert_eq!(fs::read(bundle.nvram_path()).unwrap(), b"variables");
+        let reopened = VmBundle::open(bundle.root()).unwrap();
+        assert_eq!(reopened.manifest().spec.cpu_count, 2);
+        assert_eq!(reopened.manifest().spec.storage.len(), 1);
+        // no lock or temporary file is left behind
+        let mut names: Vec<_> = fs::read_dir(bundle.disks_dir())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["root.img"]);
+        fs::remove_dir_all(bundle.root()).unwrap();
+    }
+
+    #[test]
+    fn refuses_disks_in_use() {
+        let mut bundle = bundle("in-use");
+        let disk = bundle.disk_path("root.img");
+
+        let lock = FileLock::acquire(&disk, LockMode::Shared).unwrap();
+        assert!(matches!(
+            create(&bundle, "running"),
+            Err(Error::Locked { .. })
+        ));
+        assert!(list(&bundle).unwrap().is_empty());
+        assert!(!snapshot_dir(&bundle, "running").exists());
+        drop(lock);
+
+        create(&bundle, "stopped").unwrap();
+        fs::write(&disk, b"after").unwrap();
+        let lock = FileLock::acquire(&disk, LockMode::Shared).unwrap();
+        assert!(matches!(
+            revert(&mut bundle, "stopped"),
+            Err(Error::Locked { .. })
+        ));
+        assert_eq!(fs::read(&disk).unwrap(), b"after");
+        drop(lock);
+
+        revert(&mut bundle, "stopped").unwrap();
+        assert_eq!(fs::read(&disk).unwrap(), b"before");
+        fs::remove_dir_all(bundle.root()).unwrap();
+    }
+
+    #[test]
+    fn refuses_to_revert_to_a_modified_snapshot() {
+        let mut bundle = bundle("modified");
+        create(&bundle, "base").unwrap();
+        fs::write(
+            snapshot_dir(&bundle, "base").join("disks/root.img"),
+            b"evil",
+        )
+        .unwrap();
+        fs::write(bundle.disk_path("root.img"), b"after").unwrap();
+
+        assert!(matches!(
+            revert(&mut bundle, "base"),
+            Err(Error::ChecksumMismatch { .. })
+        ));
+        assert_eq!(fs::read(bundle.disk_path("root.img")).unwrap(), b"after");
+        fs::remove_dir_all(bundle.root()).unwrap();
+    }
+
+    #[test]
+    fn snapshot_names_must_be_unique_plain_names() {
+        let bundle = bundle("names");
+        for name in &["", ".hidden", "a/b", "..", INDEX_FILE] {
+            assert!(create(&bundle, name).is_err(), "{:?} was accepted", name);
+        }
+        create(&bundle, "base").unwrap();
+        assert!(create(&bundle, "base").is_err());
+        delete(&bundle, "base").unwrap();
+        assert!(list(&bundle).unwrap().is_empty());
+        assert!(!snapshot_dir(&bundle, "base").exists());
+        fs::remove_dir_all(bundle.root()).unwrap();
+    }
+}
@@ -0,0 +1,659 @@
+//! declarative virtual machine specification module
+//!
+//! A [`VmSpec`] describes a virtual machine as plain data. It can be read from and written to
+//! TOML or JSON, and converted into a VZVirtualMachineConfiguration.
+//!
+//! # Examples
+//! ```toml
+//! cpu_count = 4
+//! memory_size = 2147483648
+//! entropy = true
+//! memory_balloon = true
+//!
+//! [boot_loader]
+//! type = "linux"
+//! kernel = "ubuntu/vmlinuz"
+//! initial_ramdisk = "ubuntu/initrd"
+//! command_line = "console=hvc0"
+//!
+//! [[storage]]
+//! path = "ubuntu/ubuntu.iso"
+//! read_only = true
+//!
+//! [[storage]]
+//! path = "ubuntu/scratch.img"
+//! caching_mode = "cached"
+//! synchronization_mode = "none"
+//!
+//! [[network]]
+//! attachment = "nat"
+//!
+//! [[serial_ports]]
+//! attachment = "stdio"
+//! ```
+
+use crate::{
+    base::NSFileHandle,
+    error::{Error, Result},
+    validation::{has_errors, validate, ValidationLimits},
+    virtualization::{
+        boot_loader::VZLinuxBootLoaderBuilder,
+        efi_boot_loader::{VZEFIBootLoader, VZEFIVariableStore},
+        entropy_device::VZVirtioEntropyDeviceConfiguration,
+        graphics_device::VZMacGraphicsDeviceConfiguration,
+        mac_platform_configuration::VZMacPlatformConfiguration,
+        macos_boot_loader::VZMacOSBootLoader,
+        memory_device::VZVirtioTraditionalMemoryBalloonDeviceConfiguration,
+        network_device::{
+            VZMACAddress, VZNATNetworkDeviceAttachment, VZVirtioNetworkDeviceConfiguration,
+        },
+        serial_port::{
+            VZFileHandleSerialPortAttachmentBuilder, VZVirtioConsoleDeviceSerialPortConfiguration,
+        },
+        storage_device::{
+            VZDiskImageCachingMode, VZDiskImageStorageDeviceAttachmentBuilder,
+            VZDiskImageSynchronizationMode, VZVirtioBlockDeviceConfiguration,
+        },
+        virtual_machine::{VZVirtualMachineConfiguration, VZVirtualMachineConfigurationBuilder},
+    },
+};
+
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// specification of a virtual machine
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VmSpec {
+    pub cpu_count: usize,
+    /// memory size in bytes
+    pub memory_size: u64,
+    /// attach a Virtio entropy device
+    #[serde(default)]
+    pub entropy: bool,
+    /// attach a Virtio traditional memory balloon device
+    #[serde(default)]
+    pub memory_balloon: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub boot_loader: Option<BootLoaderSpec>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub platform: Option<MacPlatformSpec>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub storage: Vec<StorageSpec>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub network: Vec<NetworkSpec>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub serial_ports: Vec<SerialPortSpec>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub graphics: Vec<GraphicsSpec>,
+}
+
+/// specification of the boot loader
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum BootLoaderSpec {
+    /// VZLinuxBootLoader
+    Linux {
+        kernel: String,
+        initial_ramdisk: String,
+        #[serde(default)]
+        command_line: String,
+    },
+    /// VZMacOSBootLoader
+    #[serde(rename = "macos")]
+    MacOS,
+    /// VZEFIBootLoader, requires macOS 13
+    Efi {
+        /// EFI variable store, created on first use
+        variable_store: String,
+    },
+}
+
+/// specification of VZMacPlatformConfiguration, given as paths to its stored data
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MacPlatformSpec {
+    pub auxiliary_storage: String,
+    pub hardware_model: String,
+    pub machine_identifier: String,
+}
+
+/// specification of a disk image attached through the Virtio interface
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StorageSpec {
+    pub path: String,
+    #[serde(default)]
+    pub read_only: bool,
+    /// requires macOS 12 unless automatic
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub caching_mode: CachingModeSpec,
+    /// requires macOS 12 unless full
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub synchronization_mode: SynchronizationModeSpec,
+}
+
+/// caching mode of a disk image, see [`VZDiskImageCachingMode`]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CachingModeSpec {
+    #[default]
+    Automatic,
+    Cached,
+    Uncached,
+}
+
+/// synchronization mode of a disk image, see [`VZDiskImageSynchronizationMode`]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SynchronizationModeSpec {
+    #[default]
+    Full,
+    Fsync,
+    None,
+}
+
+/// specification of a Virtio network device
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NetworkSpec {
+    pub attachment: NetworkAttachmentSpec,
+    /// random locally administered address when omitted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mac_address: Option<String>,
+}
+
+/// attachment of a network device
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NetworkAttachmentSpec {
+    Nat,
+}
+
+/// specification of a Virtio console serial port
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SerialPortSpec {
+    pub attachment: SerialPortAttachmentSpec,
+}
+
+/// attachment of a serial port
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SerialPortAttachmentSpec {
+    /// standard input and standard output of this process
+    Stdio,
+}
+
+/// specification of a mac graphics device with a single display
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GraphicsSpec {
+    pub width_in_pixels: i32,
+    pub height_in_pixels: i32,
+    pub pixels_per_inch: i32,
+}
+
+impl VmSpec {
+    pub fn from_toml(s: &str) -> Result<VmSpec> {
+        Ok(toml::from_str(s)?)
+    }
+
+    pub fn to_toml(&self) -> Result<String> {
+        Ok(toml::to_string_pretty(self)?)
+    }
+
+    pub fn from_json(s: &str) -> Result<VmSpec> {
+        Ok(serde_json::from_str(s)?)
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /// read a specification, the format is chosen by the `toml` or `json` extension
+    ///
+    /// Paths are kept as written, so saving the specification again writes the same paths.
+    /// Relative paths of the kernel, initial ramdisk, platform files and disk images are relative
+    /// to the directory of the file, see [`base_directory`] and
+    /// [`to_configuration`](Self::to_configuration).
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<VmSpec> {
+        let path = path.as_ref();
+        let s = fs::read_to_string(path)?;
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => VmSpec::from_toml(&s),
+            Some("json") => VmSpec::from_json(&s),
+            _ => Err(unknown_format(path)),
+        }
+    }
+
+    /// the specification with every relative path joined to `base`, absolute paths are kept
+    pub fn resolve_paths<P: AsRef<Path>>(&self, base: P) -> VmSpec {
+        let base = base.as_ref();
+        let resolve = |path: &mut String| {
+            *path = base.join(&*path).to_string_lossy().into_owned();
+        };
+        let mut spec = self.clone();
+        if let Some(BootLoaderSpec::Linux {
+            kernel,
+            initial_ramdisk,
+            ..
+        }) = &mut spec.boot_loader
+        {
+            resolve(kernel);
+            resolve(initial_ramdisk);
+        }
+        if let Some(BootLoaderSpec::Efi { variable_store }) = &mut spec.boot_loader {
+            resolve(variable_store);
+        }
+        if let Some(platform) = &mut spec.platform {
+            resolve(&mut platform.auxiliary_storage);
+            resolve(&mut platform.hardware_model);
+            resolve(&mut platform.machine_identifier);
+        }
+        for storage in &mut spec.storage {
+            resolve(&mut storage.path);
+        }
+        spec
+    }
+
+    /// write a specification, the format is chosen by the `toml` or `json` extension
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let s = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => self.to_toml()?,
+            Some("json") => self.to_json()?,
+            _ => return Err(unknown_format(path)),
+        };
+        fs::write(path, s)?;
+        Ok(())
+    }
+
+    /// create the VZVirtualMachineConfiguration described by this specification, with relative
+    /// paths resolved against `base`
+    ///
+    /// The specification is checked with [`validate`] and the default limits first, which fails
+    /// with [`Error::Validation`] holding every diagnostic when any of them is an error. Call
+    /// `validate_with_error` on the result for the checks of the framework.
+    pub fn to_configuration<P: AsRef<Path>>(
+        &self,
+        base: P,
+    ) -> Result<VZVirtualMachineConfiguration> {
+        let base = base.as_ref();
+        let diagnostics = validate(self, base, &ValidationLimits::default());
+        if has_errors(&diagnostics) {
+            return Err(Error::Validation(diagnostics));
+        }
+        self.resolve_paths(base).build_configuration()
+    }
+
+    fn build_configuration(&self) -> Result<VZVirtualMachineConfiguration> {
+        let mut builder = VZVirtualMachineConfigurationBuilder::new()
+            .cpu_count(self.cpu_count)
+            .memory_size(self.memory_size as usize);
+
+        builder = match &self.boot_loader {
+            None => builder,
+            Some(BootLoaderSpec::Linux {
+                kernel,
+                initial_ramdisk,
+                command_line,
+            }) => builder.boot_loader(
+                VZLinuxBootLoaderBuilder::new()
+                    .kernel_url(kernel.as_str())
+                    .initial_ramdisk_url(initial_ramdisk.as_str())
+                    .command_line(command_line.as_str())
+                    .build(),
+            ),
+            Some(BootLoaderSpec::MacOS) => builder.boot_loader(VZMacOSBootLoader::new()),
+            Some(BootLoaderSpec::Efi { variable_store }) => builder.boot_loader(
+                VZEFIBootLoader::new(VZEFIVariableStore::open_or_create(variable_store)?)?,
+            ),
+        };
+
+        if let Some(platform) = &self.platform {
+            let platform = VZMacPlatformConfiguration::load(
+                &platform.auxiliary_storage,
+                &platform.hardware_model,
+                &platform.machine_identifier,
+            )?;
+            builder = builder.platform(platform);
+        }
+
+        if self.entropy {
+            builder = builder.entropy_devices(vec![VZVirtioEntropyDeviceConfiguration::new()]);
+        }
+        if self.memory_balloon {
+            builder = builder.memory_balloon_devices(vec![
+                VZVirtioTraditionalMemoryBalloonDeviceConfiguration::new(),
+            ]);
+        }
+
+        let mut storage_devices = Vec::with_capacity(self.storage.len());
+        for storage in &self.storage {
+            let caching_mode = match storage.caching_mode {
+                CachingModeSpec::Automatic => VZDiskImageCachingMode::Automatic,
+                CachingModeSpec::Cached => VZDiskImageCachingMode::Cached,
+                CachingModeSpec::Uncached => VZDiskImageCachingMode::Uncached,
+            };
+            let synchronization_mode = match storage.synchronization_mode {
+                SynchronizationModeSpec::Full => VZDiskImageSynchronizationMode::Full,
+                SynchronizationModeSpec::Fsync => VZDiskImageSynchronizationMode::Fsync,
+                SynchronizationModeSpec::None => VZDiskImageSynchronizationMode::None,
+            };
+            let attachment = VZDiskImageStorageDeviceAttachmentBuilder::new()
+                .path(storage.path.as_str())
+                .read_only(storage.read_only)
+                .caching_mode(caching_mode)
+                .synchronization_mode(synchronization_mode)
+                .build()?;
+            storage_devices.push(VZVirtioBlockDeviceConfiguration::new(attachment));
+        }
+        builder = builder.storage_devices(storage_devices);
+
+        let network_devices = self
+            .network
+            .iter()
+            .map(|network| {
+                let attachment = match network.attachment {
+                    NetworkAttachmentSpec::Nat => VZNATNetworkDeviceAttachment::new(),
+                };
+                let mut device = VZVirtioNetworkDeviceConfiguration::new(attachment);
+                device.set_mac_address(match &network.mac_address {
+                    Some(mac_address) => VZMACAddress::init_with_string(mac_address),
+                    None => VZMACAddress::random_locally_administered_address(),
+                });
+                device
+            })
+            .collect();
+        builder = builder.network_devices(network_devices);
+
+        let serial_ports =
+            self.serial_ports
+                .iter()
+                .map(|serial_port| {
+                    let attachment = match serial_port.attachment {
+                        SerialPortAttachmentSpec::Stdio => {
+                            VZFileHandleSerialPortAttachmentBuilder::new()
+                                .file_handle_for_reading(
+                                    NSFileHandle::file_handle_with_standard_input(),
+                                )
+                                .file_handle_for_writing(
+                                    NSFileHandle::file_handle_with_standard_output(),
+                                )
+                                .build()
+                        }
+                    };
+                    VZVirtioConsoleDeviceSerialPortConfiguration::new(attachment)
+                })
+                .collect();
+        builder = builder.serial_ports(serial_ports);
+
+        if !self.graphics.is_empty() {
+            builder = builder.graphics_devices(
+                self.graphics
+                    .iter()
+                    .map(|graphics| {
+                        VZMacGraphicsDeviceConfiguration::new(
+                            graphics.width_in_pixels,
+                            graphics.height_in_pixels,
+                            graphics.pixels_per_inch,
+                        )
+                    })
+                    .collect(),
+            );
+        }
+
+        Ok(builder.build())
+    }
+}
+
+/// directory the relative paths of the specification file at `path` are relative to
+pub fn base_directory<P: AsRef<Path>>(path: P) -> PathBuf {
+    path.as_ref()
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_default()
+}
+
+fn unknown_format(path: &Path) -> Error {
+    Error::Parse(format!(
+        "{}: specification must be a .toml or .json file",
+        path.display()
+    ))
+}
+
+fn is_default<T: Default + PartialEq>(value: &T) -> bool {
+    *value == T::default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn linux_spec() -> VmSpec {
+        VmSpec {
+            cpu_count: 4,
+            memory_size: 2 * 1024 * 1024 * 1024,
+            entropy: true,
+            memory_balloon: true,
+            boot_loader: Some(BootLoaderSpec::Linux {
+                kernel: "ubuntu/vmlinuz".to_string(),
+                initial_ramdisk: "ubuntu/initrd".to_string(),
+                command_line: "console=hvc0".to_string(),
+            }),
+            platform: None,
+            storage: vec![
+                StorageSpec {
+                    path: "ubuntu/ubuntu.iso".to_string(),
+                    read_only: true,
+                    caching_mode: CachingModeSpec::Automatic,
+                    synchronization_mode: SynchronizationModeSpec::Full,
+                },
+                StorageSpec {
+                    path: "/var/vm/scratch.img".to_string(),
+                    read_only: false,
+                    caching_mode: CachingModeSpec::Cached,
+                    synchronization_mode: SynchronizationModeSpec::None,
+                },
+            ],
+            network: vec![NetworkSpec {
+                attachment: NetworkAttachmentSpec::Nat,
+                mac_address: Some("02:00:00:00:00:01".to_string()),
+            }],
+            serial_ports: vec![SerialPortSpec {
+                attachment: SerialPortAttachmentSpec::Stdio,
+            }],
+            graphics: Vec::new(),
+        }
+    }
+
+    fn macos_spec() -> VmSpec {
+        VmSpec {
+            cpu_count: 2,
+            memory_size: 4 * 1024 * 1024 * 1024,
+            entropy: false,
+            memory_balloon: false,
+            boot_loader: Some(BootLoaderSpec::MacOS),
+            platform: Some(MacPlatformSpec {
+                auxiliary_storage: "auxiliary_storage".to_string(),
+                hardware_model: "hardware_model".to_string(),
+                machine_identifier: "machine_identifier".to_string(),
+            }),
+            storage: Vec::new(),
+            network: Vec::new(),
+            serial_ports: Vec::new(),
+            graphics: vec![GraphicsSpec {
+                width_in_pixels: 1920,
+                height_in_pixels: 1200,
+                pixels_per_inch: 80,
+            }],
+        }
+    }
+
+    #[test]
+    fn toml_round_trip() {
+        for spec in &[linux_spec(), macos_spec()] {
+            let toml = spec.to_toml().unwrap();
+            assert_eq!(&VmSpec::from_toml(&toml).unwrap(), spec, "{}", toml);
+        }
+    }
+
+    #[test]
+    fn json_round_trip() {
+        for spec in &[linux_spec(), macos_spec()] {
+            let json = spec.to_json().unwrap();
+            assert_eq!(&VmSpec::from_json(&json).unwrap(), spec, "{}", json);
+        }
+    }
+
+    #[test]
+    fn default_storage_modes_are_omitted() {
+        let toml = linux_spec().to_toml().unwrap();
+        assert_eq!(toml.matches("caching_mode").count(), 1);
+        assert_eq!(toml.matches("synchronization_mode").count(), 1);
+    }
+
+    #[test]
+    fn storage_modes_are_snake_case() {
+        let toml = linux_spec().to_toml().unwrap();
+        assert!(toml.contains("caching_mode = 'cached'"), "{}", toml);
+        assert!(toml.contains("synchronization_mode = 'none'"), "{}", toml);
+        let spec = VmSpec::from_toml(
+            "cpu_count = 1\nmemory_size = 1\n\n[[storage]]\npath = \"disk.img\"\n\
+             caching_mode = \"uncached\"\nsynchronization_mode = \"fsync\"\n",
+        )
+        .unwrap();
+        assert_eq!(spec.storage[0].caching_mode, CachingModeSpec::Uncached);
+        assert_eq!(
+            spec.storage[0].synchronization_mode,
+            SynchronizationModeSpec::Fsync
+        );
+    }
+
+    #[test]
+    fn module_example_parses() {
+        let spec = VmSpec::from_toml(
+            r#"
+            cpu_count = 4
+            memory_size = 2147483648
+
+            [boot_loader]
+            type = "linux"
+            kernel = "ubuntu/vmlinuz"
+            initial_ramdisk = "ubuntu/initrd"
+
+            [[storage]]
+            path = "ubuntu/ubuntu.iso"
+            read_only = true
+            "#,
+        )
+        .unwrap();
+        assert!(!spec.entropy);
+        assert_eq!(spec.storage[0].caching_mode, CachingModeSpec::Automatic);
+    }
+
+    #[test]
+    fn efi_boot_loader_round_trips_and_resolves_its_variable_store() {
+        let spec = VmSpec::from_toml(
+            r#"
+            cpu_count = 2
+            memory_size = 2147483648
+
+            [boot_loader]
+            type = "efi"
+            variable_store = "nvram"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            spec.boot_loader,
+            Some(BootLoaderSpec::Efi {
+                variable_store: "nvram".to_string()
+            })
+        );
+        assert_eq!(VmSpec::from_toml(&spec.to_toml().unwrap()).unwrap(), spec);
+        assert_eq!(VmSpec::from_json(&spec.to_json().unwrap()).unwrap(), spec);
+        match spec.resolve_paths("/var/vm").boot_loader {
+            Some(BootLoaderSpec::Efi { variable_store }) => {
+                assert_eq!(Path::new(&variable_store), Path::new("/var/vm/nvram"))
+            }
+            other => panic!("unexpected boot loader {:?}", other),
+        }
+    }
+
+    #[test]
+    fn unknown_fields_are_rejected() {
+        assert!(VmSpec::from_toml("cpu_count = 1\nmemory_size = 1\ncpus = 2\n").is_err());
+        assert!(VmSpec::from_json(r#"{"cpu_count": 1, "memory_size": 1, "cpus": 2}"#).is_err());
+    }
+
+    #[test]
+    fn load_keeps_paths_as_written() {
+        let dir = std::env::temp_dir().join(format!("vmspec-load-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let spec = linux_spec();
+        for name in &["vm.toml", "vm.json"] {
+            let path = dir.join(name);
+            spec.save(&path).unwrap();
+            let loaded = VmSpec::load(&path).unwrap();
+            assert_eq!(loaded, spec);
+
+            let resolved = loaded.resolve_paths(base_directory(&path));
+            match &resolved.boot_loader {
+                Some(BootLoaderSpec::Linux { kernel, .. }) => {
+                    assert_eq!(Path::new(kernel), dir.join("ubuntu/vmlinuz"))
+                }
+                other => panic!("unexpected boot loader {:?}", other),
+            }
+            assert_eq!(resolved.storage[1].path, "/var/vm/scratch.img");
+        }
+        assert!(spec.save(dir.join("vm.yaml")).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn relative_paths_survive_load_and_save() {
+        let dir = std::env::temp_dir().join(format!("vmspec-round-trip-{}", std::process::id()));
+        fs::create_dir_all(dir.join("vms")).unwrap();
+        let path = dir.join("vms/a.toml");
+        linux_spec().save(&path).unwrap();
+        let written = fs::read_to_string(&path).unwrap();
+
+        VmSpec::load(&path).unwrap().save(&path).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), written);
+        assert_eq!(VmSpec::load(&path).unwrap(), linux_spec());
+        assert_eq!(base_directory(&path), dir.join("vms"));
+        assert_eq!(base_directory("a.toml"), PathBuf::new());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn to_configuration_rejects_invalid_specs() {
+        let mut spec = linux_spec();
+        spec.cpu_count = 0;
+        match spec.to_configuration("/nonexistent") {
+            Err(Error::Validation(diagnostics)) => {
+                let fields: Vec<_> = diagnostics
+                    .iter()
+                    .filter(|diagnostic| diagnostic.is_error())
+                    .map(|diagnostic| diagnostic.field.as_str())
+                    .collect();
+                assert_eq!(
+                    fields,
+                    [
+                        "cpu_count",
+                        "boot_loader.kernel",
+                        "boot_loader.initial_ramdisk",
+                        "storage[0].path",
+                        "storage[1].path",
+                    ]
+                );
+            }
+            Err(err) => panic!("unexpected error {}", err),
+            Ok(_) => panic!("invalid specification was accepted"),
+        }
+    }
+}
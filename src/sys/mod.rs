@@ -0,0 +1,52 @@
+//! platform abstraction module
+//!
+//! On macOS this re-exports the Objective-C runtime (`objc`) and block (`block`) items used by the
+//! framework bindings. On every other host a stub backend with the same surface is used instead,
+//! so code depending on this crate can be built and unit-tested without Virtualization.framework.
+//! The stub behaves like messaging `nil` in Objective-C: every message send returns a zero value.
+
+#[cfg(target_os = "macos")]
+pub use block::{Block, ConcreteBlock, RcBlock};
+#[cfg(target_os = "macos")]
+pub use objc::rc::StrongPtr;
+#[cfg(target_os = "macos")]
+pub use objc::runtime::{Object, BOOL, NO, YES};
+#[cfg(target_os = "macos")]
+pub use objc::{class, msg_send, sel, sel_impl};
+
+#[cfg(not(target_os = "macos"))]
+pub mod stub;
+#[cfg(not(target_os = "macos"))]
+pub(crate) use stub::{class, msg_send, sel, sel_impl};
+#[cfg(not(target_os = "macos"))]
+pub use stub::{Block, ConcreteBlock, Object, RcBlock, StrongPtr, BOOL, NO, YES};
+
+/// whether the Virtualization.framework backend is compiled in
+pub const FRAMEWORK_AVAILABLE: bool = cfg!(target_os = "macos");
+
+/// version of the running macOS, e.g. `13.4.1`, `None` on other hosts
+#[cfg(target_os = "macos")]
+pub fn macos_version() -> Option<String> {
+    let mut version = [0u8; 32];
+    let mut len = version.len();
+    let ret = unsafe {
+        libc::sysctlbyname(
+            b"kern.osproductversion\0".as_ptr() as *const libc::c_char,
+            version.as_mut_ptr() as *mut libc::c_void,
+            &mut len,
+            std::ptr::null_mut(),
+            0,
+        )
+    };
+    if ret != 0 {
+        return None;
+    }
+    let version = std::str::from_utf8(&version[..len]).ok()?;
+    Some(version.trim_end_matches('\0').to_string())
+}
+
+/// version of the running macOS, e.g. `13.4.1`, `None` on other hosts
+#[cfg(not(target_os = "macos"))]
+pub fn macos_version() -> Option<String> {
+    None
+}
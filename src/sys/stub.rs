@@ -0,0 +1,213 @@
+//! stub backend for hosts without Virtualization.framework
+//!
+//! Mirrors the parts of `objc` and `block` used by this crate. Message sends evaluate their
+//! receiver and arguments and return the zero value of the expected type, which is what
+//! Objective-C returns when messaging `nil`. Blocks are never invoked.
+
+use std::marker::PhantomData;
+use std::ops::Deref;
+
+/// opaque Objective-C object
+pub enum Object {}
+
+/// Objective-C boolean
+pub type BOOL = i8;
+pub const YES: BOOL = 1;
+pub const NO: BOOL = 0;
+
+type Id = *mut Object;
+
+/// strong reference to an Objective-C object
+///
+/// The stub never owns a real object, so retaining and releasing are no-ops.
+#[derive(Clone)]
+pub struct StrongPtr(Id);
+
+impl StrongPtr {
+    /// # Safety
+    /// Matches `objc::rc::StrongPtr::new`; the stub never dereferences `ptr`.
+    pub unsafe fn new(ptr: Id) -> StrongPtr {
+        StrongPtr(ptr)
+    }
+
+    /// # Safety
+    /// Matches `objc::rc::StrongPtr::retain`; the stub never dereferences `ptr`.
+    pub unsafe fn retain(ptr: Id) -> StrongPtr {
+        StrongPtr(ptr)
+    }
+}
+
+impl Deref for StrongPtr {
+    type Target = Id;
+
+    fn deref(&self) -> &Id {
+        &self.0
+    }
+}
+
+/// value returned by a message sent to `nil`
+pub trait Nil {
+    fn nil() -> Self;
+}
+
+macro_rules! impl_nil {
+    ($($t:ty => $v:expr),* $(,)?) => {
+        $(impl Nil for $t {
+            fn nil() -> Self {
+                $v
+            }
+        })*
+    };
+}
+
+impl_nil! {
+    () => (),
+    bool => false,
+    i8 => 0,
+    i32 => 0,
+    i64 => 0,
+    isize => 0,
+    u32 => 0,
+    u64 => 0,
+    usize => 0,
+    f64 => 0.0,
+}
+
+impl<T> Nil for *mut T {
+    fn nil() -> Self {
+        std::ptr::null_mut()
+    }
+}
+
+impl<T> Nil for *const T {
+    fn nil() -> Self {
+        std::ptr::null()
+    }
+}
+
+/// # Safety
+/// Always safe to call; it is `unsafe` so that stub message sends need an `unsafe` block like real
+/// ones do.
+#[doc(hidden)]
+pub unsafe fn __nil<T: Nil>() -> T {
+    T::nil()
+}
+
+/// Objective-C block type
+pub struct Block<A, R> {
+    _phantom: PhantomData<(A, R)>,
+}
+
+/// closures that can be wrapped in a block taking the arguments `A`
+pub trait IntoConcreteBlock<A> {
+    type Ret;
+}
+
+macro_rules! impl_into_concrete_block {
+    ($($a:ident),*) => {
+        impl<$($a,)* R, X> IntoConcreteBlock<($($a,)*)> for X
+        where
+            X: Fn($($a),*) -> R,
+        {
+            type Ret = R;
+        }
+    };
+}
+
+impl_into_concrete_block!();
+impl_into_concrete_block!(A);
+impl_into_concrete_block!(A, B);
+impl_into_concrete_block!(A, B, C);
+
+/// block backed by a Rust closure
+pub struct ConcreteBlock<A, R, F> {
+    block: Block<A, R>,
+    _closure: F,
+}
+
+impl<A, R, F> ConcreteBlock<A, R, F>
+where
+    F: IntoConcreteBlock<A, Ret = R>,
+{
+    pub fn new(closure: F) -> Self {
+        ConcreteBlock {
+            block: Block {
+                _phantom: PhantomData,
+            },
+            _closure: closure,
+        }
+    }
+}
+
+impl<A, R, F> ConcreteBlock<A, R, F>
+where
+    F: 'static,
+{
+    pub fn copy(self) -> RcBlock<A, R> {
+        RcBlock { block: self.block }
+    }
+}
+
+impl<A, R, F> Deref for ConcreteBlock<A, R, F> {
+    type Target = Block<A, R>;
+
+    fn deref(&self) -> &Block<A, R> {
+        &self.block
+    }
+}
+
+/// reference counted block
+pub struct RcBlock<A, R> {
+    block: Block<A, R>,
+}
+
+impl<A, R> Deref for RcBlock<A, R> {
+    type Target = Block<A, R>;
+
+    fn deref(&self) -> &Block<A, R> {
+        &self.block
+    }
+}
+
+macro_rules! __stub_class {
+    ($name:ident) => {{
+        let _ = stringify!($name);
+        ::std::ptr::null_mut::<$crate::sys::Object>()
+    }};
+}
+
+macro_rules! __stub_sel_impl {
+    ($name:expr) => {{
+        $name
+    }};
+}
+
+macro_rules! __stub_sel {
+    ($name:ident) => {{
+        sel_impl!(concat!(stringify!($name), '\0'))
+    }};
+    ($($name:ident :)+) => {{
+        sel_impl!(concat!($(stringify!($name), ':'),+, '\0'))
+    }};
+}
+
+macro_rules! __stub_msg_send {
+    ($obj:expr, $name:ident) => {{
+        let _ = sel!($name);
+        let _ = $obj;
+        $crate::sys::stub::__nil()
+    }};
+    ($obj:expr, $($name:ident : $arg:expr)+) => {{
+        let _ = sel!($($name:)+);
+        let _ = $obj;
+        $(let _ = $arg;)+
+        $crate::sys::stub::__nil()
+    }};
+}
+
+// like in `objc`, `msg_send!` and `sel!` expect `sel!` and `sel_impl!` to be in scope; the macros
+// are re-exported to the crate only, so they do not appear in its public root
+pub(crate) use __stub_class as class;
+pub(crate) use __stub_msg_send as msg_send;
+pub(crate) use __stub_sel as sel;
+pub(crate) use __stub_sel_impl as sel_impl;
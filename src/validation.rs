@@ -0,0 +1,868 @@
+//! virtual machine configuration validation module
+//!
+//! Checks a [`VmSpec`] in Rust before any Objective-C object is created. Unlike
+//! `validate_with_error`, every problem is reported, each one tied to the field it comes from.
+//!
+//! # Examples
+//! ```rust,ignore
+//! let diagnostics = validate(&spec, base_directory(&path), &ValidationLimits::default());
+//! for diagnostic in &diagnostics {
+//!     println!("{}", diagnostic);
+//! }
+//! if has_errors(&diagnostics) {
+//!     return;
+//! }
+//! ```
+
+use crate::{
+    bundle::{self, VmBundle},
+    spec::{BootLoaderSpec, VmSpec},
+    sys,
+    virtualization::efi_boot_loader::{supports_efi_boot_loader, EFI_BOOT_LOADER_MACOS_VERSION},
+    virtualization::storage_device::{supports_disk_image_modes, DISK_IMAGE_MODES_MACOS_VERSION},
+};
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+
+/// one mebibyte, the granularity of the memory size
+pub const MIB: u64 = 1024 * 1024;
+
+/// bounds of the CPU count and memory size and the macOS version a specification is checked against
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationLimits {
+    pub min_cpu_count: usize,
+    pub max_cpu_count: usize,
+    /// in bytes
+    pub min_memory_size: u64,
+    /// in bytes
+    pub max_memory_size: u64,
+    /// e.g. `12.6`, options requiring a newer macOS are not checked when `None`
+    pub macos_version: Option<String>,
+}
+
+impl Default for ValidationLimits {
+    /// one to 64 CPUs, 128 MiB to 1 TiB of memory and the running macOS
+    fn default() -> Self {
+        ValidationLimits {
+            min_cpu_count: 1,
+            max_cpu_count: 64,
+            min_memory_size: 128 * MIB,
+            max_memory_size: 1024 * 1024 * MIB,
+            macos_version: sys::macos_version(),
+        }
+    }
+}
+
+/// how serious a diagnostic is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// the configuration cannot work
+    Error,
+    /// the configuration works but is likely a mistake
+    Warning,
+}
+
+/// problem found in a specification
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiagnosticKind {
+    CpuCountTooLow {
+        count: usize,
+        min: usize,
+    },
+    CpuCountTooHigh {
+        count: usize,
+        max: usize,
+    },
+    MemorySizeTooSmall {
+        size: u64,
+        min: u64,
+    },
+    MemorySizeTooLarge {
+        size: u64,
+        max: u64,
+    },
+    /// the memory size is not a multiple of 1 MiB
+    MemorySizeMisaligned {
+        size: u64,
+    },
+    MissingBootLoader,
+    /// a kernel, initial ramdisk, disk image or platform file does not exist
+    UnreachablePath {
+        path: String,
+    },
+    InvalidMacAddress {
+        mac_address: String,
+    },
+    /// the same MAC address is already used by the device at `first`
+    DuplicateMacAddress {
+        mac_address: String,
+        first: String,
+    },
+    /// the same disk image is already attached at `first`
+    DuplicateDiskImage {
+        path: String,
+        first: String,
+    },
+    /// VZMacOSBootLoader requires a VZMacPlatformConfiguration
+    MacOSBootLoaderWithoutPlatform,
+    /// VZLinuxBootLoader cannot boot on a VZMacPlatformConfiguration
+    LinuxBootLoaderWithMacPlatform,
+    /// VZEFIBootLoader cannot boot on a VZMacPlatformConfiguration
+    EfiBootLoaderWithMacPlatform,
+    /// VZMacGraphicsDeviceConfiguration requires a VZMacPlatformConfiguration
+    MacGraphicsWithoutPlatform,
+    /// a path of a bundle manifest is absolute or leaves the bundle
+    OutsideBundle {
+        path: String,
+    },
+    /// an option is not available on the macOS version checked against
+    RequiresNewerMacOS {
+        feature: String,
+        required: String,
+        running: String,
+    },
+}
+
+/// diagnostic for a single field of a specification
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// path of the field, e.g. `network[1].mac_address`
+    pub field: String,
+    pub severity: Severity,
+    pub kind: DiagnosticKind,
+}
+
+impl Diagnostic {
+    fn error<T: Into<String>>(field: T, kind: DiagnosticKind) -> Diagnostic {
+        Diagnostic {
+            field: field.into(),
+            severity: Severity::Error,
+            kind,
+        }
+    }
+
+    fn warning<T: Into<String>>(field: T, kind: DiagnosticKind) -> Diagnostic {
+        Diagnostic {
+            field: field.into(),
+            severity: Severity::Warning,
+            kind,
+        }
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+
+impl fmt::Display for DiagnosticKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiagnosticKind::CpuCountTooLow { count, min } => {
+                write!(f, "CPU count {} is less than the minimum of {}", count, min)
+            }
+            DiagnosticKind::CpuCountTooHigh { count, max } => {
+                write!(f, "CPU count {} is more than the maximum of {}", count, max)
+            }
+            DiagnosticKind::MemorySizeTooSmall { size, min } => write!(
+                f,
+                "memory size of {} bytes is less than the minimum of {} bytes",
+                size, min
+            ),
+            DiagnosticKind::MemorySizeTooLarge { size, max } => write!(
+                f,
+                "memory size of {} bytes is more than the maximum of {} bytes",
+                size, max
+            ),
+            DiagnosticKind::MemorySizeMisaligned { size } => write!(
+                f,
+                "memory size of {} bytes is not a multiple of 1 MiB",
+                size
+            ),
+            DiagnosticKind::MissingBootLoader => write!(f, "no boot loader is configured"),
+            DiagnosticKind::UnreachablePath { path } => write!(f, "{} does not exist", path),
+            DiagnosticKind::InvalidMacAddress { mac_address } => {
+                write!(f, "{} is not a valid MAC address", mac_address)
+            }
+            DiagnosticKind::DuplicateMacAddress { mac_address, first } => {
+                write!(
+                    f,
+                    "MAC address {} is already used by {}",
+                    mac_address, first
+                )
+            }
+            DiagnosticKind::DuplicateDiskImage { path, first } => {
+                write!(f, "disk image {} is already attached by {}", path, first)
+            }
+            DiagnosticKind::MacOSBootLoaderWithoutPlatform => write!(
+                f,
+                "the macOS boot loader requires a mac platform configuration"
+            ),
+            DiagnosticKind::LinuxBootLoaderWithMacPlatform => write!(
+                f,
+                "the Linux boot loader cannot be used with a mac platform configuration"
+            ),
+            DiagnosticKind::EfiBootLoaderWithMacPlatform => write!(
+                f,
+                "the EFI boot loader cannot be used with a mac platform configuration"
+            ),
+            DiagnosticKind::MacGraphicsWithoutPlatform => write!(
+                f,
+                "mac graphics devices require a mac platform configuration"
+            ),
+            DiagnosticKind::OutsideBundle { path } => {
+                write!(f, "{} is outside the bundle", path)
+            }
+            DiagnosticKind::RequiresNewerMacOS {
+                feature,
+                required,
+                running,
+            } => write!(
+                f,
+                "{} requires macOS {}, running {}",
+                feature, required, running
+            ),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(f, "{}: {}: {}", severity, self.field, self.kind)
+    }
+}
+
+/// whether any of `diagnostics` is an error
+pub fn has_errors(diagnostics: &[Diagnostic]) -> bool {
+    diagnostics.iter().any(Diagnostic::is_error)
+}
+
+/// check `spec` with its relative paths resolved against `base` against `limits`, returns every
+/// problem found
+pub fn validate<P: AsRef<Path>>(
+    spec: &VmSpec,
+    base: P,
+    limits: &ValidationLimits,
+) -> Vec<Diagnostic> {
+    let spec = &spec.resolve_paths(base);
+    let mut diagnostics = Vec::new();
+    validate_cpu_count(spec, limits, &mut diagnostics);
+    validate_memory_size(spec, limits, &mut diagnostics);
+    validate_boot_loader(spec, limits, &mut diagnostics);
+    validate_platform(spec, &mut diagnostics);
+    validate_storage(spec, limits, &mut diagnostics);
+    validate_network(spec, &mut diagnostics);
+    diagnostics
+}
+
+/// check the specification of `bundle` with its paths resolved, and warn about every path of the
+/// manifest that is not inside the bundle
+pub fn validate_bundle(bundle: &VmBundle, limits: &ValidationLimits) -> Vec<Diagnostic> {
+    let mut diagnostics = validate(&bundle.manifest().spec, bundle.root(), limits);
+    let spec = &bundle.manifest().spec;
+    let mut paths = Vec::new();
+    if let Some(BootLoaderSpec::Linux {
+        kernel,
+        initial_ramdisk,
+        ..
+    }) = &spec.boot_loader
+    {
+        paths.push(("boot_loader.kernel".to_string(), kernel));
+        paths.push(("boot_loader.initial_ramdisk".to_string(), initial_ramdisk));
+    }
+    if let Some(BootLoaderSpec::Efi { variable_store }) = &spec.boot_loader {
+        paths.push(("boot_loader.variable_store".to_string(), variable_store));
+    }
+    if let Some(platform) = &spec.platform {
+        paths.push((
+            "platform.auxiliary_storage".to_string(),
+            &platform.auxiliary_storage,
+        ));
+        paths.push((
+            "platform.hardware_model".to_string(),
+            &platform.hardware_model,
+        ));
+        paths.push((
+            "platform.machine_identifier".to_string(),
+            &platform.machine_identifier,
+        ));
+    }
+    for (i, storage) in spec.storage.iter().enumerate() {
+        paths.push((format!("storage[{}].path", i), &storage.path));
+    }
+    for (field, path) in paths {
+        if !bundle::is_inside_bundle(path) {
+            diagnostics.push(Diagnostic::warning(
+                field,
+                DiagnosticKind::OutsideBundle { path: path.clone() },
+            ));
+        }
+    }
+    diagnostics
+}
+
+fn validate_cpu_count(spec: &VmSpec, limits: &ValidationLimits, diagnostics: &mut Vec<Diagnostic>) {
+    if spec.cpu_count < limits.min_cpu_count {
+        diagnostics.push(Diagnostic::error(
+            "cpu_count",
+            DiagnosticKind::CpuCountTooLow {
+                count: spec.cpu_count,
+                min: limits.min_cpu_count,
+            },
+        ));
+    } else if spec.cpu_count > limits.max_cpu_count {
+        diagnostics.push(Diagnostic::error(
+            "cpu_count",
+            DiagnosticKind::CpuCountTooHigh {
+                count: spec.cpu_count,
+                max: limits.max_cpu_count,
+            },
+        ));
+    }
+}
+
+fn validate_memory_size(
+    spec: &VmSpec,
+    limits: &ValidationLimits,
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    if spec.memory_size < limits.min_memory_size {
+        diagnostics.push(Diagnostic::error(
+            "memory_size",
+            DiagnosticKind::MemorySizeTooSmall {
+                size: spec.memory_size,
+                min: limits.min_memory_size,
+            },
+        ));
+    } else if spec.memory_size > limits.max_memory_size {
+        diagnostics.push(Diagnostic::error(
+            "memory_size",
+            DiagnosticKind::MemorySizeTooLarge {
+                size: spec.memory_size,
+                max: limits.max_memory_size,
+            },
+        ));
+    }
+    if spec.memory_size % MIB != 0 {
+        diagnostics.push(Diagnostic::error(
+            "memory_size",
+            DiagnosticKind::MemorySizeMisaligned {
+                size: spec.memory_size,
+            },
+        ));
+    }
+}
+
+fn validate_boot_loader(
+    spec: &VmSpec,
+    limits: &ValidationLimits,
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    match &spec.boot_loader {
+        None => diagnostics.push(Diagnostic::error(
+            "boot_loader",
+            DiagnosticKind::MissingBootLoader,
+        )),
+        Some(BootLoaderSpec::Linux {
+            kernel,
+            initial_ramdisk,
+            ..
+        }) => {
+            check_path("boot_loader.kernel", kernel, diagnostics);
+            check_path("boot_loader.initial_ramdisk", initial_ramdisk, diagnostics);
+            if spec.platform.is_some() {
+                diagnostics.push(Diagnostic::error(
+                    "boot_loader",
+                    DiagnosticKind::LinuxBootLoaderWithMacPlatform,
+                ));
+            }
+        }
+        Some(BootLoaderSpec::MacOS) => {
+            if spec.platform.is_none() {
+                diagnostics.push(Diagnostic::error(
+                    "boot_loader",
+                    DiagnosticKind::MacOSBootLoaderWithoutPlatform,
+                ));
+            }
+        }
+        // the variable store is created on first use, so it need not exist
+        Some(BootLoaderSpec::Efi { .. }) => {
+            if let Some(running) = limits
+                .macos_version
+                .as_ref()
+                .filter(|version| !supports_efi_boot_loader(version))
+            {
+                diagnostics.push(Diagnostic::error(
+                    "boot_loader",
+                    DiagnosticKind::RequiresNewerMacOS {
+                        feature: "the EFI boot loader".to_string(),
+                        required: EFI_BOOT_LOADER_MACOS_VERSION.to_string(),
+                        running: running.clone(),
+                    },
+                ));
+            }
+            if spec.platform.is_some() {
+                diagnostics.push(Diagnostic::error(
+                    "boot_loader",
+                    DiagnosticKind::EfiBootLoaderWithMacPlatform,
+                ));
+            }
+        }
+    }
+}
+
+fn validate_platform(spec: &VmSpec, diagnostics: &mut Vec<Diagnostic>) {
+    match &spec.platform {
+        Some(platform) => {
+            check_path(
+                "platform.auxiliary_storage",
+                &platform.auxiliary_storage,
+                diagnostics,
+            );
+            check_path(
+                "platform.hardware_model",
+                &platform.hardware_model,
+                diagnostics,
+            );
+            check_path(
+                "platform.machine_identifier",
+                &platform.machine_identifier,
+                diagnostics,
+            );
+        }
+        None => {
+            if !spec.graphics.is_empty() {
+                diagnostics.push(Diagnostic::error(
+                    "graphics",
+                    DiagnosticKind::MacGraphicsWithoutPlatform,
+                ));
+            }
+        }
+    }
+}
+
+fn validate_storage(spec: &VmSpec, limits: &ValidationLimits, diagnostics: &mut Vec<Diagnostic>) {
+    let mut attached: HashMap<&str, (String, bool)> = HashMap::new();
+    for (i, storage) in spec.storage.iter().enumerate() {
+        if let Some(running) = limits
+            .macos_version
+            .as_ref()
+            .filter(|version| !supports_disk_image_modes(version))
+        {
+            let modes = [
+                (
+                    "caching_mode",
+                    "the caching mode of a disk image",
+                    storage.caching_mode != Default::default(),
+                ),
+                (
+                    "synchronization_mode",
+                    "the synchronization mode of a disk image",
+                    storage.synchronization_mode != Default::default(),
+                ),
+            ];
+            for (name, feature, used) in &modes {
+                if *used {
+                    diagnostics.push(Diagnostic::error(
+                        format!("storage[{}].{}", i, name),
+                        DiagnosticKind::RequiresNewerMacOS {
+                            feature: feature.to_string(),
+                            required: DISK_IMAGE_MODES_MACOS_VERSION.to_string(),
+                            running: running.clone(),
+                        },
+                    ));
+                }
+            }
+        }
+
+        let field = format!("storage[{}].path", i);
+        check_path(&field, &storage.path, diagnostics);
+        match attached.get(storage.path.as_str()) {
+            Some((first, first_read_only)) => {
+                let kind = DiagnosticKind::DuplicateDiskImage {
+                    path: storage.path.clone(),
+                    first: first.clone(),
+                };
+                // attaching a disk read-write twice corrupts it
+                if storage.read_only && *first_read_only {
+                    diagnostics.push(Diagnostic::warning(field, kind));
+                } else {
+                    diagnostics.push(Diagnostic::error(field, kind));
+                }
+            }
+            None => {
+                attached.insert(&storage.path, (field, storage.read_only));
+            }
+        }
+    }
+}
+
+fn validate_network(spec: &VmSpec, diagnostics: &mut Vec<Diagnostic>) {
+    let mut used: HashMap<[u8; 6], String> = HashMap::new();
+    for (i, network) in spec.network.iter().enumerate() {
+        let mac_address = match &network.mac_address {
+            Some(mac_address) => mac_address,
+            None => continue,
+        };
+        let field = format!("network[{}].mac_address", i);
+        match parse_mac_address(mac_address) {
+            None => diagnostics.push(Diagnostic::error(
+                field,
+                DiagnosticKind::InvalidMacAddress {
+                    mac_address: mac_address.clone(),
+                },
+            )),
+            Some(octets) => match used.get(&octets) {
+                Some(first) => diagnostics.push(Diagnostic::error(
+                    field,
+                    DiagnosticKind::DuplicateMacAddress {
+                        mac_address: mac_address.clone(),
+                        first: first.clone(),
+                    },
+                )),
+                None => {
+                    used.insert(octets, field);
+                }
+            },
+        }
+    }
+}
+
+fn check_path(field: &str, path: &str, diagnostics: &mut Vec<Diagnostic>) {
+    if !Path::new(path).exists() {
+        diagnostics.push(Diagnostic::error(
+            field,
+            DiagnosticKind::UnreachablePath {
+                path: path.to_string(),
+            },
+        ));
+    }
+}
+
+/// parse a MAC address written as six colon separated octets of two hexadecimal digits
+pub fn parse_mac_address(s: &str) -> Option<[u8; 6]> {
+    let mut octets = [0u8; 6];
+    let mut parts = s.split(':');
+    for octet in octets.iter_mut() {
+        let part = parts.next()?;
+        if part.len() != 2 || !part.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return None;
+        }
+        *octet = u8::from_str_radix(part, 16).ok()?;
+    }
+    if parts.next().is_some() {
+        return None;
+    }
+    Some(octets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spec::{MacPlatformSpec, NetworkAttachmentSpec, NetworkSpec, StorageSpec};
+
+    use std::fs;
+    use std::path::PathBuf;
+
+    /// file that exists, for the paths of the specifications
+    fn existing_file() -> PathBuf {
+        let path = std::env::temp_dir().join(format!("validation-{}", std::process::id()));
+        fs::write(&path, b"").unwrap();
+        path
+    }
+
+    fn linux_spec() -> VmSpec {
+        let path = existing_file().to_string_lossy().into_owned();
+        VmSpec {
+            cpu_count: 2,
+            memory_size: 1024 * MIB,
+            entropy: false,
+            memory_balloon: false,
+            boot_loader: Some(BootLoaderSpec::Linux {
+                kernel: path.clone(),
+                initial_ramdisk: path,
+                command_line: String::new(),
+            }),
+            platform: None,
+            storage: Vec::new(),
+            network: Vec::new(),
+            serial_ports: Vec::new(),
+            graphics: Vec::new(),
+        }
+    }
+
+    fn storage(path: &str, read_only: bool) -> StorageSpec {
+        StorageSpec {
+            path: path.to_string(),
+            read_only,
+            caching_mode: Default::default(),
+            synchronization_mode: Default::default(),
+        }
+    }
+
+    fn network(mac_address: &str) -> NetworkSpec {
+        NetworkSpec {
+            attachment: NetworkAttachmentSpec::Nat,
+            mac_address: Some(mac_address.to_string()),
+        }
+    }
+
+    fn limits() -> ValidationLimits {
+        ValidationLimits {
+            macos_version: None,
+            ..ValidationLimits::default()
+        }
+    }
+
+    fn kinds(diagnostics: &[Diagnostic]) -> Vec<&DiagnosticKind> {
+        diagnostics
+            .iter()
+            .map(|diagnostic| &diagnostic.kind)
+            .collect()
+    }
+
+    #[test]
+    fn valid_spec_has_no_diagnostics() {
+        assert_eq!(validate(&linux_spec(), "", &limits()), Vec::new());
+    }
+
+    #[test]
+    fn cpu_count_and_memory_size_are_bounded() {
+        let mut spec = linux_spec();
+        spec.cpu_count = 0;
+        spec.memory_size = 64 * MIB;
+        let diagnostics = validate(&spec, "", &limits());
+        assert_eq!(
+            kinds(&diagnostics),
+            vec![
+                &DiagnosticKind::CpuCountTooLow { count: 0, min: 1 },
+                &DiagnosticKind::MemorySizeTooSmall {
+                    size: 64 * MIB,
+                    min: 128 * MIB
+                },
+            ]
+        );
+        assert!(has_errors(&diagnostics));
+
+        spec.cpu_count = 65;
+        spec.memory_size = 2048 * 1024 * MIB;
+        let diagnostics = validate(&spec, "", &limits());
+        assert_eq!(diagnostics[0].field, "cpu_count");
+        assert!(matches!(
+            diagnostics[0].kind,
+            DiagnosticKind::CpuCountTooHigh { .. }
+        ));
+        assert!(matches!(
+            diagnostics[1].kind,
+            DiagnosticKind::MemorySizeTooLarge { .. }
+        ));
+    }
+
+    #[test]
+    fn memory_size_must_be_whole_mebibytes() {
+        let mut spec = linux_spec();
+        spec.memory_size = 1024 * MIB + 1;
+        let diagnostics = validate(&spec, "", &limits());
+        assert_eq!(
+            kinds(&diagnostics),
+            vec![&DiagnosticKind::MemorySizeMisaligned {
+                size: 1024 * MIB + 1
+            }]
+        );
+    }
+
+    #[test]
+    fn boot_loader_must_match_the_platform() {
+        let mut spec = linux_spec();
+        spec.boot_loader = None;
+        assert_eq!(
+            kinds(&validate(&spec, "", &limits())),
+            vec![&DiagnosticKind::MissingBootLoader]
+        );
+
+        spec.boot_loader = Some(BootLoaderSpec::MacOS);
+        spec.graphics.push(crate::spec::GraphicsSpec {
+            width_in_pixels: 1920,
+            height_in_pixels: 1200,
+            pixels_per_inch: 80,
+        });
+        assert_eq!(
+            kinds(&validate(&spec, "", &limits())),
+            vec![
+                &DiagnosticKind::MacOSBootLoaderWithoutPlatform,
+                &DiagnosticKind::MacGraphicsWithoutPlatform,
+            ]
+        );
+
+        let path = existing_file().to_string_lossy().into_owned();
+        let mut spec = linux_spec();
+        spec.platform = Some(MacPlatformSpec {
+            auxiliary_storage: path.clone(),
+            hardware_model: path.clone(),
+            machine_identifier: path,
+        });
+        assert_eq!(
+            kinds(&validate(&spec, "", &limits())),
+            vec![&DiagnosticKind::LinuxBootLoaderWithMacPlatform]
+        );
+    }
+
+    #[test]
+    fn missing_paths_are_reported_with_their_field() {
+        let mut spec = linux_spec();
+        spec.storage.push(storage("/nonexistent/disk.img", false));
+        let diagnostics = validate(&spec, "", &limits());
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].field, "storage[0].path");
+        assert_eq!(
+            diagnostics[0].to_string(),
+            "error: storage[0].path: /nonexistent/disk.img does not exist"
+        );
+    }
+
+    #[test]
+    fn relative_paths_are_resolved_against_the_base() {
+        let path = existing_file();
+        let mut spec = linux_spec();
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        spec.storage.push(storage(&name, false));
+        assert!(validate(&spec, path.parent().unwrap(), &limits()).is_empty());
+        assert_eq!(
+            kinds(&validate(&spec, "/nonexistent", &limits())),
+            vec![&DiagnosticKind::UnreachablePath {
+                path: format!("/nonexistent/{}", name)
+            }]
+        );
+    }
+
+    #[test]
+    fn disk_attached_twice_is_an_error_unless_read_only() {
+        let path = existing_file().to_string_lossy().into_owned();
+        let mut spec = linux_spec();
+        spec.storage.push(storage(&path, true));
+        spec.storage.push(storage(&path, true));
+        let diagnostics = validate(&spec, "", &limits());
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].severity, Severity::Warning);
+        assert_eq!(
+            diagnostics[0].kind,
+            DiagnosticKind::DuplicateDiskImage {
+                path: path.clone(),
+                first: "storage[0].path".to_string(),
+            }
+        );
+        assert!(!has_errors(&diagnostics));
+
+        spec.storage[1].read_only = false;
+        assert!(has_errors(&validate(&spec, "", &limits())));
+    }
+
+    #[test]
+    fn mac_addresses_must_be_valid_and_unique() {
+        let mut spec = linux_spec();
+        spec.network.push(network("02:00:00:00:00:01"));
+        spec.network.push(network("02:00:00:00:00:01"));
+        spec.network.push(network("02:00:00:00:00"));
+        let diagnostics = validate(&spec, "", &limits());
+        assert_eq!(
+            diagnostics
+                .iter()
+                .map(|diagnostic| diagnostic.field.as_str())
+                .collect::<Vec<_>>(),
+            vec!["network[1].mac_address", "network[2].mac_address"]
+        );
+        assert_eq!(
+            diagnostics[0].kind,
+            DiagnosticKind::DuplicateMacAddress {
+                mac_address: "02:00:00:00:00:01".to_string(),
+                first: "network[0].mac_address".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn parse_mac_address_requires_two_hex_digits_per_octet() {
+        assert_eq!(
+            parse_mac_address("02:AB:cd:00:ff:10"),
+            Some([0x02, 0xab, 0xcd, 0x00, 0xff, 0x10])
+        );
+        for invalid in &[
+            "",
+            "2:00:00:00:00:01",
+            "+a:00:00:00:00:01",
+            "0x:00:00:00:00:01",
+            "02:00:00:00:00",
+            "02:00:00:00:00:01:02",
+            "002:00:00:00:00:01",
+            "02-00-00-00-00-01",
+        ] {
+            assert_eq!(parse_mac_address(invalid), None, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn storage_modes_require_macos_12() {
+        let path = existing_file().to_string_lossy().into_owned();
+        let mut spec = linux_spec();
+        let mut disk = storage(&path, false);
+        disk.caching_mode = crate::spec::CachingModeSpec::Uncached;
+        spec.storage.push(disk);
+
+        let old = ValidationLimits {
+            macos_version: Some("11.6".to_string()),
+            ..limits()
+        };
+        let diagnostics = validate(&spec, "", &old);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].field, "storage[0].caching_mode");
+        assert!(matches!(
+            diagnostics[0].kind,
+            DiagnosticKind::RequiresNewerMacOS { .. }
+        ));
+
+        let new = ValidationLimits {
+            macos_version: Some("12.0.1".to_string()),
+            ..limits()
+        };
+        assert!(validate(&spec, "", &new).is_empty());
+        assert!(validate(&spec, "", &limits()).is_empty());
+    }
+
+    #[test]
+    fn efi_boot_loader_requires_macos_13_and_no_mac_platform() {
+        let mut spec = linux_spec();
+        // the variable store does not exist until the virtual machine is configured
+        spec.boot_loader = Some(BootLoaderSpec::Efi {
+            variable_store: "/nonexistent/nvram".to_string(),
+        });
+        assert!(validate(&spec, "", &limits()).is_empty());
+
+        let old = ValidationLimits {
+            macos_version: Some("12.6".to_string()),
+            ..limits()
+        };
+        let diagnostics = validate(&spec, "", &old);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].field, "boot_loader");
+        assert!(matches!(
+            diagnostics[0].kind,
+            DiagnosticKind::RequiresNewerMacOS { .. }
+        ));
+
+        let path = existing_file().to_string_lossy().into_owned();
+        spec.platform = Some(MacPlatformSpec {
+            auxiliary_storage: path.clone(),
+            hardware_model: path.clone(),
+            machine_identifier: path,
+        });
+        assert_eq!(
+            kinds(&validate(&spec, "", &limits())),
+            vec![&DiagnosticKind::EfiBootLoaderWithMacPlatform]
+        );
+    }
+}
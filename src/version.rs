@@ -0,0 +1,50 @@
+//! version string module
+
+use std::cmp::Ordering;
+
+/// compare dotted version strings numerically, `13.10` is newer than `13.9`
+///
+/// Missing components count as zero, so `12` and `12.0` are equal.
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    let mut a = a.split('.');
+    let mut b = b.split('.');
+    loop {
+        match (a.next(), b.next()) {
+            (None, None) => return Ordering::Equal,
+            (a, b) => {
+                let (a, b) = (a.unwrap_or("0"), b.unwrap_or("0"));
+                let ordering = match (a.parse::<u64>(), b.parse::<u64>()) {
+                    (Ok(a), Ok(b)) => a.cmp(&b),
+                    _ => a.cmp(b),
+                };
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn components_are_compared_numerically() {
+        assert_eq!(compare_versions("13.10", "13.9"), Ordering::Greater);
+        assert_eq!(compare_versions("12.6.1", "13"), Ordering::Less);
+        assert_eq!(compare_versions("11.6", "12.0"), Ordering::Less);
+    }
+
+    #[test]
+    fn missing_components_are_zero() {
+        assert_eq!(compare_versions("12", "12.0"), Ordering::Equal);
+        assert_eq!(compare_versions("12.0.0", "12"), Ordering::Equal);
+        assert_eq!(compare_versions("12.0.1", "12"), Ordering::Greater);
+    }
+
+    #[test]
+    fn non_numeric_components_are_compared_as_text() {
+        assert_eq!(compare_versions("13.0b", "13.0a"), Ordering::Greater);
+    }
+}
@@ -0,0 +1,149 @@
+//! virtual machine backend module
+
+use crate::{
+    base::{Id, NSError, NIL},
+    error::Result,
+    sys::{Block, ConcreteBlock, RcBlock, StrongPtr},
+    virtualization::{
+        lifecycle::LifecycleOperation,
+        virtual_machine::{
+            check_stop_supported, VZVirtualMachine, VZVirtualMachineState, VirtualMachineOnQueue,
+        },
+    },
+};
+
+use std::cell::RefCell;
+
+/// handler called once an asynchronous operation has finished
+pub type CompletionHandler = Box<dyn FnOnce(Result<()>) + Send + 'static>;
+
+/// common behaviors of a virtual machine, implemented by VZVirtualMachine and by
+/// [`MockVirtualMachine`](crate::virtualization::mock::MockVirtualMachine)
+///
+/// Lifecycle code written against this trait can be exercised with the mock on any host.
+/// Implementations backed by Virtualization.framework must be driven from the queue the
+/// virtual machine was created with.
+pub trait VirtualMachineBackend {
+    /// start the virtual machine, `completion_handler` is called once it is running or has failed
+    fn start(&mut self, completion_handler: CompletionHandler);
+
+    /// stop the virtual machine without giving the guest a chance to shut down
+    fn stop(&mut self, completion_handler: CompletionHandler);
+
+    /// pause a running virtual machine
+    fn pause(&mut self, completion_handler: CompletionHandler);
+
+    /// resume a paused virtual machine
+    fn resume(&mut self, completion_handler: CompletionHandler);
+
+    /// ask the guest to stop, returns whether the request was delivered
+    fn request_stop(&mut self) -> Result<bool>;
+
+    /// current state of the virtual machine
+    fn state(&self) -> VZVirtualMachineState;
+
+    /// whether `operation` can be started in the current state
+    fn can(&self, operation: LifecycleOperation) -> bool {
+        operation.is_allowed(self.state())
+    }
+}
+
+/// block calling `completion_handler` with the NSError it receives
+fn completion_block(completion_handler: CompletionHandler) -> RcBlock<(Id,), ()> {
+    let completion_handler = RefCell::new(Some(completion_handler));
+    let block = ConcreteBlock::new(move |err: Id| {
+        if let Some(completion_handler) = completion_handler.borrow_mut().take() {
+            if err == NIL {
+                completion_handler(Ok(()));
+            } else {
+                completion_handler(Err(NSError(unsafe { StrongPtr::retain(err) }).into()));
+            }
+        }
+    });
+    block.copy()
+}
+
+impl VZVirtualMachine {
+    /// check that `operation` can be started and call `f` with the completion block, both on the
+    /// queue of the virtual machine
+    ///
+    /// `completion_handler` is called with an error instead of raising an Objective-C exception
+    /// when `operation` cannot be started.
+    fn on_queue_checked<F>(
+        &self,
+        operation: LifecycleOperation,
+        completion_handler: CompletionHandler,
+        f: F,
+    ) where
+        F: FnOnce(&VirtualMachineOnQueue<'_>, &Block<(Id,), ()>) + Send,
+    {
+        let rejected = self.on_queue(move |vm| match operation.check(vm.state()) {
+            Ok(()) => {
+                f(vm, &completion_block(completion_handler));
+                None
+            }
+            Err(err) => Some((completion_handler, err)),
+        });
+        if let Some((completion_handler, err)) = rejected {
+            completion_handler(Err(err));
+        }
+    }
+}
+
+impl VirtualMachineBackend for VZVirtualMachine {
+    fn start(&mut self, completion_handler: CompletionHandler) {
+        self.on_queue_checked(
+            LifecycleOperation::Start,
+            completion_handler,
+            |vm, block| vm.start_with_completion_handler(block),
+        );
+    }
+
+    fn stop(&mut self, completion_handler: CompletionHandler) {
+        if let Err(err) = check_stop_supported() {
+            completion_handler(Err(err));
+            return;
+        }
+        self.on_queue_checked(LifecycleOperation::Stop, completion_handler, |vm, block| {
+            // the macOS version was checked above, so the handler is always called
+            let _ = vm.stop_with_completion_handler(block);
+        });
+    }
+
+    fn pause(&mut self, completion_handler: CompletionHandler) {
+        self.on_queue_checked(
+            LifecycleOperation::Pause,
+            completion_handler,
+            |vm, block| vm.pause_with_completion_handler(block),
+        );
+    }
+
+    fn resume(&mut self, completion_handler: CompletionHandler) {
+        self.on_queue_checked(
+            LifecycleOperation::Resume,
+            completion_handler,
+            |vm, block| vm.resume_with_completion_handler(block),
+        );
+    }
+
+    fn request_stop(&mut self) -> Result<bool> {
+        self.on_queue(|vm| {
+            LifecycleOperation::RequestStop.check(vm.state())?;
+            vm.request_stop()
+        })
+    }
+
+    fn state(&self) -> VZVirtualMachineState {
+        VZVirtualMachine::state(self)
+    }
+
+    fn can(&self, operation: LifecycleOperation) -> bool {
+        self.on_queue(|vm| match operation {
+            LifecycleOperation::Start => vm.can_start(),
+            LifecycleOperation::Stop => vm.can_stop(),
+            LifecycleOperation::RequestStop => vm.can_request_stop(),
+            LifecycleOperation::Pause => vm.can_pause(),
+            LifecycleOperation::Resume => vm.can_resume(),
+        })
+    }
+}
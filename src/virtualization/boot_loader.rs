@@ -1,8 +1,8 @@
 //! boot loader module
 use crate::base::{Id, NSString, NSURL};
 
-use objc::rc::StrongPtr;
-use objc::{class, msg_send, sel, sel_impl};
+use crate::sys::StrongPtr;
+use crate::sys::{class, msg_send, sel, sel_impl};
 
 /// common behaviors for booting
 pub trait VZBootLoader {
@@ -11,7 +11,7 @@ pub trait VZBootLoader {
 
 /// builder for VZLinuxBootLoader
 /// # Examples
-/// ```rust
+/// ```rust,ignore
 /// let boot_loader = VZLinuxBootLoaderBuilder::new()
 ///     .kernel_url(kernel_url)
 ///     .initial_ramdisk_url(initial_ramdisk_url)
@@ -34,6 +34,12 @@ impl VZLinuxBootLoaderBuilder<(), (), ()> {
     }
 }
 
+impl Default for VZLinuxBootLoaderBuilder<(), (), ()> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<KernelURL, InitialRamdiskURL, CommandLine>
     VZLinuxBootLoaderBuilder<KernelURL, InitialRamdiskURL, CommandLine>
 {
@@ -0,0 +1,195 @@
+//! virtual machine delegate module
+//!
+//! [`VirtualMachineObserver`] installs a VZVirtualMachineDelegate and a key-value observer of
+//! `state` on a VZVirtualMachine and publishes what they report on an
+//! [`EventBus`](crate::virtualization::events::EventBus). On hosts without
+//! Virtualization.framework nothing is ever published.
+
+#[cfg(target_os = "macos")]
+use crate::base::Id;
+use crate::{
+    base::{NSString, NIL},
+    sys::{msg_send, sel, sel_impl, StrongPtr},
+    virtualization::{events::EventBus, virtual_machine::VZVirtualMachine},
+};
+
+use std::ffi::c_void;
+
+const STATE_KEY_PATH: &str = "state";
+const NS_KEY_VALUE_OBSERVING_OPTION_NEW: usize = 1;
+
+/// publishes the events of a VZVirtualMachine until it is dropped
+pub struct VirtualMachineObserver {
+    vm: StrongPtr,
+    delegate: StrongPtr,
+}
+
+impl VirtualMachineObserver {
+    /// become the delegate of `vm` and observe its state, publishing on `bus`
+    ///
+    /// # Safety
+    /// Must be called on the queue the virtual machine was created with, and the observer must
+    /// be dropped on that queue too.
+    pub unsafe fn new(vm: &VZVirtualMachine, bus: EventBus) -> VirtualMachineObserver {
+        let delegate = new_delegate(bus);
+        let key_path = NSString::new(STATE_KEY_PATH);
+        let _: () = msg_send![*vm.0, setDelegate: *delegate];
+        let _: () = msg_send![*vm.0, addObserver:*delegate forKeyPath:*key_path.0 options:NS_KEY_VALUE_OBSERVING_OPTION_NEW context:std::ptr::null_mut::<c_void>()];
+        VirtualMachineObserver {
+            vm: vm.0.clone(),
+            delegate,
+        }
+    }
+}
+
+impl Drop for VirtualMachineObserver {
+    fn drop(&mut self) {
+        unsafe {
+            let key_path = NSString::new(STATE_KEY_PATH);
+            let _: () = msg_send![*self.vm, removeObserver:*self.delegate forKeyPath:*key_path.0];
+            let _: () = msg_send![*self.vm, setDelegate: NIL];
+        }
+    }
+}
+
+#[cfg(not(target_os = "macos"))]
+unsafe fn new_delegate(_bus: EventBus) -> StrongPtr {
+    StrongPtr::new(NIL)
+}
+
+#[cfg(target_os = "macos")]
+unsafe fn new_delegate(bus: EventBus) -> StrongPtr {
+    let delegate: Id = msg_send![delegate_class::class(), new];
+    let bus = Box::into_raw(Box::new(bus)) as *mut c_void;
+    (*delegate).set_ivar::<*mut c_void>(delegate_class::BUS_IVAR, bus);
+    StrongPtr::new(delegate)
+}
+
+#[cfg(target_os = "macos")]
+mod delegate_class {
+    use crate::{
+        base::{Id, NSError},
+        error::FrameworkError,
+        sys::{class, msg_send, sel, sel_impl, StrongPtr},
+        virtualization::{
+            events::{EventBus, VirtualMachineEvent},
+            virtual_machine::VZVirtualMachineState,
+        },
+    };
+
+    use objc::declare::ClassDecl;
+    use objc::runtime::{Class, Object, Sel};
+    use std::ffi::c_void;
+    use std::sync::Once;
+
+    pub const BUS_IVAR: &str = "_eventBus";
+    const CLASS_NAME: &str = "VirtualizationRsVirtualMachineDelegate";
+    const NS_NOT_FOUND: usize = isize::MAX as usize;
+
+    /// the delegate class, registered with the Objective-C runtime on first use
+    pub fn class() -> &'static Class {
+        static REGISTER: Once = Once::new();
+        REGISTER.call_once(|| unsafe {
+            let mut decl = ClassDecl::new(CLASS_NAME, class!(NSObject)).unwrap();
+            decl.add_ivar::<*mut c_void>(BUS_IVAR);
+            decl.add_method(
+                sel!(guestDidStopVirtualMachine:),
+                guest_did_stop as extern "C" fn(&Object, Sel, Id),
+            );
+            decl.add_method(
+                sel!(virtualMachine:didStopWithError:),
+                did_stop_with_error as extern "C" fn(&Object, Sel, Id, Id),
+            );
+            decl.add_method(
+                sel!(virtualMachine:networkDevice:attachmentWasDisconnectedWithError:),
+                attachment_was_disconnected as extern "C" fn(&Object, Sel, Id, Id, Id),
+            );
+            decl.add_method(
+                sel!(observeValueForKeyPath:ofObject:change:context:),
+                observe_value as extern "C" fn(&Object, Sel, Id, Id, Id, *mut c_void),
+            );
+            decl.add_method(sel!(dealloc), dealloc as extern "C" fn(&mut Object, Sel));
+            decl.register();
+        });
+        Class::get(CLASS_NAME).unwrap()
+    }
+
+    unsafe fn bus(this: &Object) -> &EventBus {
+        &*(*this.get_ivar::<*mut c_void>(BUS_IVAR) as *const EventBus)
+    }
+
+    unsafe fn framework_error(error: Id) -> FrameworkError {
+        NSError(StrongPtr::retain(error)).into()
+    }
+
+    extern "C" fn guest_did_stop(this: &Object, _: Sel, _vm: Id) {
+        unsafe { bus(this) }.publish(VirtualMachineEvent::GuestDidStop);
+    }
+
+    extern "C" fn did_stop_with_error(this: &Object, _: Sel, _vm: Id, error: Id) {
+        let error = unsafe { framework_error(error) };
+        unsafe { bus(this) }.publish(VirtualMachineEvent::DidStopWithError(error));
+    }
+
+    extern "C" fn attachment_was_disconnected(
+        this: &Object,
+        _: Sel,
+        vm: Id,
+        device: Id,
+        error: Id,
+    ) {
+        let index: usize = unsafe {
+            let devices: Id = msg_send![vm, networkDevices];
+            msg_send![devices, indexOfObject: device]
+        };
+        let event = VirtualMachineEvent::NetworkAttachmentWasDisconnected {
+            device_index: if index == NS_NOT_FOUND {
+                None
+            } else {
+                Some(index)
+            },
+            error: unsafe { framework_error(error) },
+        };
+        unsafe { bus(this) }.publish(event);
+    }
+
+    extern "C" fn observe_value(
+        this: &Object,
+        _: Sel,
+        _key_path: Id,
+        object: Id,
+        _change: Id,
+        _context: *mut c_void,
+    ) {
+        let n: isize = unsafe { msg_send![object, state] };
+        unsafe { bus(this) }.publish(VirtualMachineEvent::StateChanged(
+            VZVirtualMachineState::from_raw(n),
+        ));
+    }
+
+    extern "C" fn dealloc(this: &mut Object, _: Sel) {
+        unsafe {
+            let bus = *this.get_ivar::<*mut c_void>(BUS_IVAR);
+            if !bus.is_null() {
+                drop(Box::from_raw(bus as *mut EventBus));
+            }
+            let _: () = msg_send![super(this, class!(NSObject)), dealloc];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(not(target_os = "macos"))]
+    fn streams_end_without_the_framework() {
+        let bus = EventBus::new();
+        let stream = bus.subscribe();
+        let delegate = unsafe { new_delegate(bus) };
+        assert!(*delegate == NIL);
+        // nothing is ever published, so the stream must not wait forever
+        assert_eq!(stream.recv(), None);
+    }
+}
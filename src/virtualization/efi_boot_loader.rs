@@ -0,0 +1,88 @@
+//! EFI boot loader module
+use crate::base::{Id, NSError, NIL, NSURL};
+use crate::error::{Error, Result};
+use crate::sys;
+use crate::version::compare_versions;
+use crate::virtualization::boot_loader::VZBootLoader;
+
+use crate::sys::StrongPtr;
+use crate::sys::{class, msg_send, sel, sel_impl};
+
+use std::cmp::Ordering;
+use std::path::Path;
+
+/// first macOS version with the EFI boot loader
+pub const EFI_BOOT_LOADER_MACOS_VERSION: &str = "13.0";
+
+/// whether macOS `version` supports the EFI boot loader
+pub fn supports_efi_boot_loader(version: &str) -> bool {
+    compare_versions(version, EFI_BOOT_LOADER_MACOS_VERSION) != Ordering::Less
+}
+
+fn check_supported() -> Result<()> {
+    if let Some(version) = sys::macos_version() {
+        if !supports_efi_boot_loader(&version) {
+            return Err(Error::Platform(format!(
+                "the EFI boot loader requires macOS {}, running {}",
+                EFI_BOOT_LOADER_MACOS_VERSION, version
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// EFI variable store, the NVRAM of a virtual machine booted with VZEFIBootLoader
+pub struct VZEFIVariableStore(StrongPtr);
+
+impl VZEFIVariableStore {
+    /// variable store kept in the file at `path`, which is created when it does not exist
+    pub fn open_or_create(path: &str) -> Result<VZEFIVariableStore> {
+        check_supported()?;
+        let url = NSURL::file_url_with_path(path, false);
+        unsafe {
+            let i: Id = msg_send![class!(VZEFIVariableStore), alloc];
+            if Path::new(path).exists() {
+                let p: Id = msg_send![i, initWithURL: *url.0];
+                return Ok(VZEFIVariableStore(StrongPtr::new(p)));
+            }
+            let mut error: Id = NIL;
+            let p: Id = msg_send![i, initCreatingVariableStoreAtURL: *url.0 options: 0usize error: &mut error];
+            if error != NIL {
+                return Err(NSError(StrongPtr::retain(error)).into());
+            }
+            Ok(VZEFIVariableStore(StrongPtr::new(p)))
+        }
+    }
+}
+
+/// boot loader starting the EFI firmware of the guest, requires macOS 13
+pub struct VZEFIBootLoader(StrongPtr);
+
+impl VZEFIBootLoader {
+    pub fn new(variable_store: VZEFIVariableStore) -> Result<VZEFIBootLoader> {
+        check_supported()?;
+        unsafe {
+            let p = StrongPtr::new(msg_send![class!(VZEFIBootLoader), new]);
+            let _: Id = msg_send![*p, setVariableStore: *variable_store.0];
+            Ok(VZEFIBootLoader(p))
+        }
+    }
+}
+
+impl VZBootLoader for VZEFIBootLoader {
+    fn id(&self) -> Id {
+        *self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn efi_boot_loader_requires_macos_13() {
+        assert!(!supports_efi_boot_loader("12.6.1"));
+        assert!(supports_efi_boot_loader("13.0"));
+        assert!(supports_efi_boot_loader("14.2"));
+    }
+}
@@ -2,8 +2,8 @@
 
 use crate::base::Id;
 
-use objc::rc::StrongPtr;
-use objc::{class, msg_send, sel, sel_impl};
+use crate::sys::StrongPtr;
+use crate::sys::{class, msg_send, sel, sel_impl};
 
 /// common configure of entropy device
 pub trait VZEntropyDeviceConfiguration {
@@ -22,6 +22,12 @@ impl VZVirtioEntropyDeviceConfiguration {
     }
 }
 
+impl Default for VZVirtioEntropyDeviceConfiguration {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl VZEntropyDeviceConfiguration for VZVirtioEntropyDeviceConfiguration {
     fn id(&self) -> Id {
         *self.0
@@ -0,0 +1,339 @@
+//! virtual machine events module
+//!
+//! Events of a virtual machine are published on an [`EventBus`] and received through any number
+//! of [`EventStream`]s, either blocking, as an iterator, or by awaiting [`EventStream::next_event`].
+//! The types are platform-neutral: on macOS the events come from the delegate bridge in
+//! [`delegate`](crate::virtualization::delegate), in tests from
+//! [`MockVirtualMachine`](crate::virtualization::mock::MockVirtualMachine).
+//!
+//! # Examples
+//! ```rust
+//! use virtualization_rs::virtualization::backend::VirtualMachineBackend;
+//! use virtualization_rs::virtualization::events::VirtualMachineEvent;
+//! use virtualization_rs::virtualization::mock::MockVirtualMachineBuilder;
+//! use virtualization_rs::virtualization::virtual_machine::VZVirtualMachineState;
+//!
+//! let mut vm = MockVirtualMachineBuilder::new().build();
+//! let events = vm.subscribe();
+//! vm.start(Box::new(|result| result.unwrap()));
+//! vm.request_stop().unwrap();
+//!
+//! let events: Vec<VirtualMachineEvent> = std::iter::from_fn(|| events.try_recv()).collect();
+//! assert_eq!(
+//!     events[events.len() - 2],
+//!     VirtualMachineEvent::StateChanged(VZVirtualMachineState::VZVirtualMachineStateStopped)
+//! );
+//! assert_eq!(events[events.len() - 1], VirtualMachineEvent::GuestDidStop);
+//! ```
+
+use crate::{error::FrameworkError, virtualization::virtual_machine::VZVirtualMachineState};
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+/// event of a virtual machine
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VirtualMachineEvent {
+    /// the state of the virtual machine changed
+    StateChanged(VZVirtualMachineState),
+    /// the guest operating system stopped the virtual machine
+    GuestDidStop,
+    /// the virtual machine stopped because of an error
+    DidStopWithError(FrameworkError),
+    /// the attachment of a network device was disconnected, requires macOS 12
+    NetworkAttachmentWasDisconnected {
+        /// index of the device in the configuration, `None` if it could not be found
+        device_index: Option<usize>,
+        error: FrameworkError,
+    },
+}
+
+struct Queue {
+    events: VecDeque<VirtualMachineEvent>,
+    waker: Option<Waker>,
+    closed: bool,
+}
+
+struct Subscriber {
+    queue: Mutex<Queue>,
+    ready: Condvar,
+}
+
+impl Subscriber {
+    fn push(&self, event: VirtualMachineEvent) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.events.push_back(event);
+        self.notify(&mut queue);
+    }
+
+    fn close(&self) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.closed = true;
+        self.notify(&mut queue);
+    }
+
+    fn notify(&self, queue: &mut Queue) {
+        if let Some(waker) = queue.waker.take() {
+            waker.wake();
+        }
+        self.ready.notify_all();
+    }
+}
+
+struct Subscribers(Mutex<Vec<Weak<Subscriber>>>);
+
+impl Drop for Subscribers {
+    fn drop(&mut self) {
+        for subscriber in self.0.lock().unwrap().iter() {
+            if let Some(subscriber) = subscriber.upgrade() {
+                subscriber.close();
+            }
+        }
+    }
+}
+
+/// publisher of virtual machine events, streams end once every clone has been dropped
+#[derive(Clone)]
+pub struct EventBus {
+    subscribers: Arc<Subscribers>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        EventBus {
+            subscribers: Arc::new(Subscribers(Mutex::new(Vec::new()))),
+        }
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EventBus {
+    /// stream receiving every event published from now on
+    pub fn subscribe(&self) -> EventStream {
+        let subscriber = Arc::new(Subscriber {
+            queue: Mutex::new(Queue {
+                events: VecDeque::new(),
+                waker: None,
+                closed: false,
+            }),
+            ready: Condvar::new(),
+        });
+        self.subscribers
+            .0
+            .lock()
+            .unwrap()
+            .push(Arc::downgrade(&subscriber));
+        EventStream { subscriber }
+    }
+
+    /// deliver `event` to every stream
+    pub fn publish(&self, event: VirtualMachineEvent) {
+        let mut subscribers = self.subscribers.0.lock().unwrap();
+        subscribers.retain(|subscriber| match subscriber.upgrade() {
+            Some(subscriber) => {
+                subscriber.push(event.clone());
+                true
+            }
+            None => false,
+        });
+    }
+}
+
+/// receiver of the events published on an EventBus
+pub struct EventStream {
+    subscriber: Arc<Subscriber>,
+}
+
+impl EventStream {
+    /// next event without waiting
+    pub fn try_recv(&self) -> Option<VirtualMachineEvent> {
+        self.subscriber.queue.lock().unwrap().events.pop_front()
+    }
+
+    /// wait for the next event, `None` once the bus is gone
+    pub fn recv(&self) -> Option<VirtualMachineEvent> {
+        let mut queue = self.subscriber.queue.lock().unwrap();
+        loop {
+            if let Some(event) = queue.events.pop_front() {
+                return Some(event);
+            }
+            if queue.closed {
+                return None;
+            }
+            queue = self.subscriber.ready.wait(queue).unwrap();
+        }
+    }
+
+    /// wait at most `timeout` for the next event
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<VirtualMachineEvent> {
+        let deadline = Instant::now() + timeout;
+        let mut queue = self.subscriber.queue.lock().unwrap();
+        loop {
+            if let Some(event) = queue.events.pop_front() {
+                return Some(event);
+            }
+            let now = Instant::now();
+            if queue.closed || now >= deadline {
+                return None;
+            }
+            queue = self
+                .subscriber
+                .ready
+                .wait_timeout(queue, deadline - now)
+                .unwrap()
+                .0;
+        }
+    }
+
+    /// future resolving to the next event, `None` once the bus is gone
+    pub fn next_event(&mut self) -> NextEvent<'_> {
+        NextEvent { stream: self }
+    }
+}
+
+impl Iterator for EventStream {
+    type Item = VirtualMachineEvent;
+
+    fn next(&mut self) -> Option<VirtualMachineEvent> {
+        self.recv()
+    }
+}
+
+/// future returned by [`EventStream::next_event`]
+pub struct NextEvent<'a> {
+    stream: &'a mut EventStream,
+}
+
+impl Future for NextEvent<'_> {
+    type Output = Option<VirtualMachineEvent>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut queue = self.stream.subscriber.queue.lock().unwrap();
+        if let Some(event) = queue.events.pop_front() {
+            return Poll::Ready(Some(event));
+        }
+        if queue.closed {
+            return Poll::Ready(None);
+        }
+        queue.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::virtualization::future::block_on;
+    use std::thread;
+
+    fn state(state: VZVirtualMachineState) -> VirtualMachineEvent {
+        VirtualMachineEvent::StateChanged(state)
+    }
+
+    #[test]
+    fn every_subscriber_receives_every_event() {
+        let bus = EventBus::new();
+        let first = bus.subscribe();
+        let second = bus.subscribe();
+        bus.publish(state(VZVirtualMachineState::VZVirtualMachineStateStarting));
+        let late = bus.subscribe();
+        bus.publish(VirtualMachineEvent::GuestDidStop);
+
+        for stream in &[&first, &second] {
+            assert_eq!(
+                stream.try_recv(),
+                Some(state(VZVirtualMachineState::VZVirtualMachineStateStarting))
+            );
+            assert_eq!(stream.try_recv(), Some(VirtualMachineEvent::GuestDidStop));
+            assert_eq!(stream.try_recv(), None);
+        }
+        // a stream only receives the events published after it subscribed
+        assert_eq!(late.try_recv(), Some(VirtualMachineEvent::GuestDidStop));
+
+        // dropped streams are forgotten by the next publish
+        drop(second);
+        drop(late);
+        bus.publish(VirtualMachineEvent::GuestDidStop);
+        assert_eq!(bus.subscribers.0.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn streams_end_once_the_bus_is_dropped() {
+        let bus = EventBus::new();
+        let clone = bus.clone();
+        let stream = bus.subscribe();
+        bus.publish(VirtualMachineEvent::GuestDidStop);
+        drop(bus);
+        clone.publish(state(VZVirtualMachineState::VZVirtualMachineStateStopped));
+        drop(clone);
+
+        // pending events are still delivered
+        let events: Vec<_> = stream.collect();
+        assert_eq!(
+            events,
+            [
+                VirtualMachineEvent::GuestDidStop,
+                state(VZVirtualMachineState::VZVirtualMachineStateStopped)
+            ]
+        );
+
+        let bus = EventBus::new();
+        let stream = bus.subscribe();
+        let publisher = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            drop(bus);
+        });
+        assert_eq!(stream.recv(), None);
+        assert_eq!(stream.recv_timeout(Duration::from_secs(10)), None);
+        publisher.join().unwrap();
+    }
+
+    #[test]
+    fn recv_timeout_expires() {
+        let bus = EventBus::new();
+        let stream = bus.subscribe();
+        let start = Instant::now();
+        assert_eq!(stream.recv_timeout(Duration::from_millis(50)), None);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        let publisher = bus.clone();
+        let publisher = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            publisher.publish(VirtualMachineEvent::GuestDidStop);
+        });
+        assert_eq!(
+            stream.recv_timeout(Duration::from_secs(10)),
+            Some(VirtualMachineEvent::GuestDidStop)
+        );
+        publisher.join().unwrap();
+    }
+
+    #[test]
+    fn publishing_wakes_a_pending_next_event() {
+        let bus = EventBus::new();
+        let mut stream = bus.subscribe();
+        let publisher = bus.clone();
+        let publisher = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            publisher.publish(state(VZVirtualMachineState::VZVirtualMachineStateRunning));
+            thread::sleep(Duration::from_millis(50));
+            drop(publisher);
+        });
+        assert_eq!(
+            block_on(stream.next_event()),
+            Some(state(VZVirtualMachineState::VZVirtualMachineStateRunning))
+        );
+        drop(bus);
+        assert_eq!(block_on(stream.next_event()), None);
+        publisher.join().unwrap();
+    }
+}
@@ -0,0 +1,211 @@
+//! asynchronous lifecycle module
+//!
+//! [`AsyncVirtualMachine`] turns the completion handlers of a [`VirtualMachineBackend`] into
+//! futures. The futures only rely on `std::task`, so they can be awaited on any executor, and
+//! [`block_on`] drives one on the current thread for synchronous callers.
+
+use crate::{
+    error::{FrameworkError, Result, VZErrorCode},
+    virtualization::{
+        backend::{CompletionHandler, VirtualMachineBackend},
+        lifecycle::LifecycleOperation,
+        virtual_machine::VZVirtualMachineState,
+    },
+};
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+
+struct Shared {
+    result: Option<Result<()>>,
+    done: bool,
+    waker: Option<Waker>,
+}
+
+/// completes the shared state, or cancels it when dropped without being called
+struct Completer(Arc<Mutex<Shared>>);
+
+impl Completer {
+    fn complete(self, result: Result<()>) {
+        let mut shared = self.0.lock().unwrap();
+        shared.result = Some(result);
+        shared.done = true;
+        if let Some(waker) = shared.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl Drop for Completer {
+    fn drop(&mut self) {
+        let mut shared = self.0.lock().unwrap();
+        if shared.done {
+            return;
+        }
+        shared.result = Some(Err(FrameworkError::new(
+            VZErrorCode::OperationCancelled,
+            "the completion handler was released without being called",
+        )
+        .into()));
+        shared.done = true;
+        if let Some(waker) = shared.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// future resolved by the completion handler it was created with
+///
+/// It resolves to an `OperationCancelled` error if the handler is dropped without being called.
+pub struct CompletionFuture {
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl CompletionFuture {
+    /// a completion handler and the future it resolves
+    pub fn new() -> (CompletionHandler, CompletionFuture) {
+        let shared = Arc::new(Mutex::new(Shared {
+            result: None,
+            done: false,
+            waker: None,
+        }));
+        let completer = Completer(shared.clone());
+        let completion_handler: CompletionHandler =
+            Box::new(move |result| completer.complete(result));
+        (completion_handler, CompletionFuture { shared })
+    }
+}
+
+impl Future for CompletionFuture {
+    type Output = Result<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut shared = self.shared.lock().unwrap();
+        if !shared.done {
+            shared.waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        match shared.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => panic!("CompletionFuture polled after completion"),
+        }
+    }
+}
+
+/// virtual machine whose lifecycle operations return futures
+///
+/// Each operation begins when the method is called, not when the future is first polled, so the
+/// future does not borrow the virtual machine. With VZVirtualMachine the methods must be called
+/// on the queue of the virtual machine, the futures can be awaited anywhere else;
+/// [`VirtualMachineHandle`](crate::virtualization::handle::VirtualMachineHandle) takes care of it.
+///
+/// # Examples
+/// ```rust
+/// use virtualization_rs::virtualization::future::{block_on, AsyncVirtualMachine};
+/// use virtualization_rs::virtualization::mock::MockVirtualMachineBuilder;
+/// use virtualization_rs::virtualization::virtual_machine::VZVirtualMachineState;
+///
+/// let mut vm = AsyncVirtualMachine::new(MockVirtualMachineBuilder::new().build());
+/// block_on(async {
+///     vm.start().await?;
+///     vm.pause().await?;
+///     vm.resume().await?;
+///     vm.stop().await
+/// })
+/// .unwrap();
+/// assert_eq!(vm.state(), VZVirtualMachineState::VZVirtualMachineStateStopped);
+/// ```
+pub struct AsyncVirtualMachine<B> {
+    backend: B,
+}
+
+impl<B: VirtualMachineBackend> AsyncVirtualMachine<B> {
+    pub fn new(backend: B) -> Self {
+        AsyncVirtualMachine { backend }
+    }
+
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    pub fn backend_mut(&mut self) -> &mut B {
+        &mut self.backend
+    }
+
+    pub fn into_inner(self) -> B {
+        self.backend
+    }
+
+    pub fn state(&self) -> VZVirtualMachineState {
+        self.backend.state()
+    }
+
+    /// whether `operation` can be started in the current state
+    pub fn can(&self, operation: LifecycleOperation) -> bool {
+        self.backend.can(operation)
+    }
+
+    /// start the virtual machine
+    pub fn start(&mut self) -> CompletionFuture {
+        let (completion_handler, future) = CompletionFuture::new();
+        self.backend.start(completion_handler);
+        future
+    }
+
+    /// stop the virtual machine without giving the guest a chance to shut down
+    pub fn stop(&mut self) -> CompletionFuture {
+        let (completion_handler, future) = CompletionFuture::new();
+        self.backend.stop(completion_handler);
+        future
+    }
+
+    /// pause a running virtual machine
+    pub fn pause(&mut self) -> CompletionFuture {
+        let (completion_handler, future) = CompletionFuture::new();
+        self.backend.pause(completion_handler);
+        future
+    }
+
+    /// resume a paused virtual machine
+    pub fn resume(&mut self) -> CompletionFuture {
+        let (completion_handler, future) = CompletionFuture::new();
+        self.backend.resume(completion_handler);
+        future
+    }
+
+    /// ask the guest to stop, returns whether the request was delivered
+    pub fn request_stop(&mut self) -> Result<bool> {
+        self.backend.request_stop()
+    }
+}
+
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// run `future` to completion on the current thread
+///
+/// Must not be called on the queue of a VZVirtualMachine the future waits for, its completion
+/// handler would never run.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = Box::pin(future);
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        thread::park();
+    }
+}
@@ -1,5 +1,5 @@
-use objc::rc::StrongPtr;
-use objc::{class, msg_send, sel, sel_impl};
+use crate::sys::StrongPtr;
+use crate::sys::{class, msg_send, sel, sel_impl};
 use crate::base::{Id, NSArray};
 
 pub struct VZMacGraphicsDeviceConfiguration(pub StrongPtr);
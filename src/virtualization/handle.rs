@@ -0,0 +1,349 @@
+//! queue-affine virtual machine module
+//!
+//! A VZVirtualMachine must only be touched on the queue it was created with. A
+//! [`VirtualMachineHandle`] keeps the virtual machine on its [`SerialQueue`] and marshals every call
+//! there, so the handle itself can be cloned and used from any thread.
+
+use crate::{
+    dispatch::{AssertSend, DispatchQueue, SerialQueue},
+    error::Result,
+    virtualization::{
+        backend::VirtualMachineBackend,
+        future::CompletionFuture,
+        lifecycle::LifecycleOperation,
+        virtual_machine::{VZVirtualMachine, VZVirtualMachineConfiguration, VZVirtualMachineState},
+    },
+};
+
+use confined::Confined;
+use std::sync::Arc;
+
+mod confined {
+    use crate::dispatch::SerialQueue;
+
+    use std::cell::RefCell;
+    use std::sync::Arc;
+
+    /// value created, used and dropped only by work of one serial queue
+    ///
+    /// The value is never touched outside that work, so `B` need not be `Send` or `Sync`. It can
+    /// only be made by [`Confined::create`] and reached through methods taking the queue, which
+    /// callers must always pass the queue the value was created on.
+    pub(super) struct Confined<B>(RefCell<Option<B>>);
+
+    // the value only moves between threads inside work of one serial queue, which never runs two
+    // items at once and orders them, and the RefCell catches a handle called again from inside
+    // its own work
+    unsafe impl<B> Send for Confined<B> {}
+    unsafe impl<B> Sync for Confined<B> {}
+
+    impl<B: 'static> Confined<B> {
+        /// value made by `create` on `queue`
+        pub(super) fn create<Q, F>(queue: &Q, create: F) -> Arc<Self>
+        where
+            Q: SerialQueue,
+            F: FnOnce() -> B + Send,
+        {
+            let confined = Arc::new(Confined(RefCell::new(None)));
+            let c = confined.clone();
+            queue.exec_sync(move || {
+                c.0.replace(Some(create()));
+            });
+            confined
+        }
+
+        /// run `f` with the value on `queue` and return its result
+        pub(super) fn exec<Q, R, F>(&self, queue: &Q, f: F) -> R
+        where
+            Q: SerialQueue,
+            F: FnOnce(&mut B) -> R + Send,
+            R: Send,
+        {
+            queue.exec_sync(move || self.with(f))
+        }
+
+        /// run `f` with the value on `queue` without waiting
+        pub(super) fn exec_async<Q, F>(self: &Arc<Self>, queue: &Q, f: F)
+        where
+            Q: SerialQueue,
+            F: FnOnce(&mut B) + Send + 'static,
+        {
+            let confined = self.clone();
+            queue.exec_async(move || confined.with(f));
+        }
+
+        /// drop the value on `queue` once the work submitted before has run
+        pub(super) fn drop_on<Q: SerialQueue>(self: &Arc<Self>, queue: &Q) {
+            let confined = self.clone();
+            queue.exec_async(move || {
+                confined.0.borrow_mut().take();
+            });
+        }
+
+        fn with<R>(&self, f: impl FnOnce(&mut B) -> R) -> R {
+            let mut value = self.0.borrow_mut();
+            f(value
+                .as_mut()
+                .expect("the virtual machine was dropped before its handle"))
+        }
+    }
+}
+
+struct Inner<B: 'static, Q: SerialQueue> {
+    queue: Q,
+    backend: Arc<Confined<B>>,
+}
+
+impl<B: 'static, Q: SerialQueue> Drop for Inner<B, Q> {
+    fn drop(&mut self) {
+        self.backend.drop_on(&self.queue);
+    }
+}
+
+/// virtual machine living on a serial queue, whose methods run there and return their results
+///
+/// Calls run in the order they are made: a method waiting for its result, such as
+/// [`state`](VirtualMachineHandle::state), sees the effect of every call made before it, and the
+/// futures of lifecycle operations resolve once the queue has run them. The virtual machine is
+/// dropped on the queue after the last clone of the handle.
+///
+/// # Examples
+/// ```rust
+/// use virtualization_rs::dispatch::ManualQueue;
+/// use virtualization_rs::virtualization::future::block_on;
+/// use virtualization_rs::virtualization::handle::VirtualMachineHandle;
+/// use virtualization_rs::virtualization::mock::MockVirtualMachineBuilder;
+/// use virtualization_rs::virtualization::virtual_machine::VZVirtualMachineState;
+///
+/// let vm = VirtualMachineHandle::new(ManualQueue::new(), || MockVirtualMachineBuilder::new().build());
+/// let started = vm.start();
+/// assert_eq!(vm.queue().pending(), 1);
+/// assert_eq!(vm.state(), VZVirtualMachineState::VZVirtualMachineStateRunning);
+/// block_on(started).unwrap();
+/// ```
+pub struct VirtualMachineHandle<B: 'static, Q: SerialQueue = DispatchQueue> {
+    inner: Arc<Inner<B, Q>>,
+}
+
+impl<B: 'static, Q: SerialQueue> Clone for VirtualMachineHandle<B, Q> {
+    fn clone(&self) -> Self {
+        VirtualMachineHandle {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<B: 'static, Q: SerialQueue> VirtualMachineHandle<B, Q> {
+    /// virtual machine made by `create` on `queue`
+    pub fn new<F>(queue: Q, create: F) -> Self
+    where
+        F: FnOnce() -> B + Send,
+    {
+        let backend = Confined::create(&queue, create);
+        VirtualMachineHandle {
+            inner: Arc::new(Inner { queue, backend }),
+        }
+    }
+
+    pub fn queue(&self) -> &Q {
+        &self.inner.queue
+    }
+
+    /// run `f` with the virtual machine on its queue and return its result
+    ///
+    /// # Panics
+    /// Panics when called from inside `f` or [`exec_async`](VirtualMachineHandle::exec_async).
+    pub fn exec<R, F>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut B) -> R + Send,
+        R: Send,
+    {
+        self.inner.backend.exec(&self.inner.queue, f)
+    }
+
+    /// run `f` with the virtual machine on its queue without waiting
+    pub fn exec_async<F>(&self, f: F)
+    where
+        F: FnOnce(&mut B) + Send + 'static,
+    {
+        self.inner.backend.exec_async(&self.inner.queue, f);
+    }
+}
+
+impl<B: VirtualMachineBackend + 'static, Q: SerialQueue> VirtualMachineHandle<B, Q> {
+    pub fn state(&self) -> VZVirtualMachineState {
+        self.exec(|vm| vm.state())
+    }
+
+    /// whether `operation` can be started in the current state
+    pub fn can(&self, operation: LifecycleOperation) -> bool {
+        self.exec(move |vm| vm.can(operation))
+    }
+
+    /// ask the guest to stop, returns whether the request was delivered
+    pub fn request_stop(&self) -> Result<bool> {
+        self.exec(|vm| vm.request_stop())
+    }
+
+    /// start the virtual machine
+    pub fn start(&self) -> CompletionFuture {
+        let (completion_handler, future) = CompletionFuture::new();
+        self.exec_async(move |vm| vm.start(completion_handler));
+        future
+    }
+
+    /// stop the virtual machine without giving the guest a chance to shut down
+    pub fn stop(&self) -> CompletionFuture {
+        let (completion_handler, future) = CompletionFuture::new();
+        self.exec_async(move |vm| vm.stop(completion_handler));
+        future
+    }
+
+    /// pause a running virtual machine
+    pub fn pause(&self) -> CompletionFuture {
+        let (completion_handler, future) = CompletionFuture::new();
+        self.exec_async(move |vm| vm.pause(completion_handler));
+        future
+    }
+
+    /// resume a paused virtual machine
+    pub fn resume(&self) -> CompletionFuture {
+        let (completion_handler, future) = CompletionFuture::new();
+        self.exec_async(move |vm| vm.resume(completion_handler));
+        future
+    }
+}
+
+impl VirtualMachineHandle<VZVirtualMachine, DispatchQueue> {
+    /// virtual machine with the configuration `conf`, created on and bound to `queue`
+    pub fn with_configuration(conf: VZVirtualMachineConfiguration, queue: DispatchQueue) -> Self {
+        let conf = AssertSend(conf);
+        let q = queue.clone();
+        VirtualMachineHandle::new(queue, move || {
+            VZVirtualMachine::with_queue(conf.into_inner(), &q)
+        })
+    }
+
+    /// handle owning `vm`, bound to the queue it was created with
+    pub fn from_virtual_machine(vm: VZVirtualMachine) -> Self {
+        let queue = vm.queue().clone();
+        let vm = AssertSend(vm);
+        VirtualMachineHandle::new(queue, move || vm.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dispatch::{ManualQueue, Serial, ThreadQueue};
+    use crate::virtualization::{future::block_on, mock::MockVirtualMachineBuilder};
+
+    use std::sync::mpsc::{channel, Sender};
+    use std::thread::{self, ThreadId};
+    use std::time::Duration;
+
+    use VZVirtualMachineState::*;
+
+    #[test]
+    fn calls_run_on_the_queue_in_the_order_they_are_made() {
+        let vm = VirtualMachineHandle::new(ManualQueue::new(), || {
+            MockVirtualMachineBuilder::new().build()
+        });
+        let started = vm.start();
+        let paused = vm.pause();
+        assert_eq!(vm.queue().pending(), 2);
+
+        // waiting for a result runs the earlier calls first
+        assert_eq!(vm.state(), VZVirtualMachineStatePaused);
+        assert_eq!(vm.queue().pending(), 0);
+        block_on(started).unwrap();
+        block_on(paused).unwrap();
+
+        let resumed = vm.resume();
+        let stopped = vm.stop();
+        assert_eq!(vm.queue().run_pending(), 2);
+        block_on(resumed).unwrap();
+        block_on(stopped).unwrap();
+        assert_eq!(vm.state(), VZVirtualMachineStateStopped);
+    }
+
+    #[test]
+    fn futures_complete_with_the_result_of_the_operation() {
+        let vm = VirtualMachineHandle::new(ManualQueue::new(), || {
+            MockVirtualMachineBuilder::new()
+                .start_delay(Duration::from_secs(1))
+                .build()
+        });
+        let started = vm.start();
+        vm.queue().run_pending();
+        assert_eq!(vm.state(), VZVirtualMachineStateStarting);
+        // the mock is still starting, so pausing is refused
+        let refused = vm.pause();
+        vm.queue().run_pending();
+        assert_eq!(
+            block_on(refused).unwrap_err().vz_code(),
+            Some(crate::error::VZErrorCode::InvalidVirtualMachineStateTransition)
+        );
+
+        vm.exec(|vm| vm.advance(Duration::from_secs(1)));
+        block_on(started).unwrap();
+        assert!(vm.can(LifecycleOperation::Pause));
+    }
+
+    #[test]
+    fn clones_share_the_virtual_machine_across_threads() {
+        let vm = VirtualMachineHandle::new(ThreadQueue::new("handle-test", Serial), || 0u32);
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let vm = vm.clone();
+                thread::spawn(move || {
+                    for _ in 0..100 {
+                        vm.exec(|count| *count += 1);
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(vm.exec(|count| *count), 400);
+    }
+
+    /// reports whether it was dropped on the thread it was created on
+    struct Probe {
+        created_on: ThreadId,
+        dropped: Sender<bool>,
+    }
+
+    impl Drop for Probe {
+        fn drop(&mut self) {
+            let _ = self.dropped.send(thread::current().id() == self.created_on);
+        }
+    }
+
+    #[test]
+    fn the_virtual_machine_is_dropped_on_its_queue_after_the_last_handle() {
+        let (dropped, receiver) = channel();
+        let vm = VirtualMachineHandle::new(ThreadQueue::new("drop-test", Serial), move || Probe {
+            created_on: thread::current().id(),
+            dropped,
+        });
+        assert_ne!(vm.exec(|probe| probe.created_on), thread::current().id());
+
+        let clone = vm.clone();
+        drop(vm);
+        clone.exec(|_| {});
+        assert!(receiver.try_recv().is_err());
+
+        drop(clone);
+        assert!(receiver.recv_timeout(Duration::from_secs(5)).unwrap());
+    }
+
+    #[test]
+    #[should_panic]
+    fn calling_the_handle_from_its_own_work_panics() {
+        let vm = VirtualMachineHandle::new(ManualQueue::new(), || 0u32);
+        let inner = vm.clone();
+        vm.exec(move |_| inner.exec(|_| {}));
+    }
+}
@@ -1,13 +1,19 @@
-use objc::{rc::{StrongPtr, WeakPtr}, runtime::Object};
-use objc::{class, msg_send, sel, sel_impl};
-use crate::base::{NSError, Id, NIL, NSString, NSURL, NSArray};
-use block::{Block, ConcreteBlock};
-use std::sync::mpsc::channel;
+use crate::sys::StrongPtr;
+use crate::sys::{class, msg_send, sel, sel_impl};
+use crate::base::{NSError, Id, NIL, NSString, NSURL};
+use crate::sys::{Block, ConcreteBlock};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
 use crate::{
-    base::{dispatch_async, dispatch_queue_create, NSFileHandle},
+    base::NSFileHandle,
+    catalog::RestoreImageCatalog,
+    dispatch::{AssertSend, DispatchQueue, Serial},
+    download::{DownloadBuilder, DownloadProgress},
+    error::{Error, FrameworkError, Result, VZErrorCode},
+    ipsw,
     virtualization::{
         entropy_device::VZVirtioEntropyDeviceConfiguration,
         graphics_device::VZMacGraphicsDeviceConfiguration,
+        handle::VirtualMachineHandle,
         mac_platform_configuration::VZMacPlatformConfiguration,
         macos_boot_loader::VZMacOSBootLoader,
         memory_device::VZVirtioTraditionalMemoryBalloonDeviceConfiguration,
@@ -20,84 +26,198 @@ use crate::{
         storage_device::{
             VZDiskImageStorageDeviceAttachmentBuilder, VZVirtioBlockDeviceConfiguration,
         },
-        virtual_machine::{VZVirtualMachine, VZVirtualMachineConfigurationBuilder, VZVirtualMachineConfiguration},
+        virtual_machine::{VZVirtualMachine, VZVirtualMachineConfigurationBuilder},
     },
 };
-use std::path::PathBuf;
+use std::cell::RefCell;
 use std::fs::canonicalize;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::Duration;
 
 pub struct VZMacOsConfigurationRequirements(pub StrongPtr);
 
+/// step of a macOS installation, reported to the progress callback
+#[derive(Debug, Clone, PartialEq)]
+pub enum InstallProgress {
+    /// the restore image is downloaded from `url`
+    DownloadStarted { url: String },
+    Downloading(DownloadProgress),
+    DownloadFinished,
+    /// the restore image already exists and is not downloaded again
+    DownloadSkipped,
+    /// macOS is being installed from the restore image
+    InstallStarted { product_version: String, build_version: String },
+    /// `fraction` of the installation is completed, between 0 and 1
+    Installing { fraction: f64 },
+}
+
+/// how often the progress of the installation is reported
+const INSTALL_PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// message of the installer running on the queue of the virtual machine
+enum InstallerMessage {
+    /// the installation has started and reports to the `NSProgress`
+    Started(AssertSend<StrongPtr>),
+    /// the installation has finished, the installer is released by the receiver
+    Finished(AssertSend<StrongPtr>, Result<()>),
+}
+
+/// progress callback shared by the download and installation steps
+type SharedProgress = Rc<RefCell<dyn FnMut(InstallProgress)>>;
+
 
 // TODO: Remove
 //const CPU_COUNT: u32 = 4;
 // TODO: Remove
 //const MEMORY_SIZE: u32 = 2147483648;
 
-pub fn install_macos_image(image_url: &'static str, cpu_count: usize, memory_size: usize, disks: Vec<PathBuf>, pixel_height: i32, pixel_width: i32, pixel_per_inch: i32, auxiliary_storage_url: &str, hardware_model_url: &str, machine_identifier_url: &str) {
+/// Download the latest supported restore image to `image_url` unless it already exists, then
+/// create the platform files, set up a virtual machine and install macOS on `disks`.
+///
+/// Blocks until the installation has finished, `progress` is called at every step and every
+/// second while macOS is installed.
+#[allow(clippy::too_many_arguments)]
+pub fn install_macos_image<F: FnMut(InstallProgress) + 'static>(image_url: &str, cpu_count: usize, memory_size: usize, disks: Vec<PathBuf>, pixel_height: i32, pixel_width: i32, pixel_per_inch: i32, auxiliary_storage_url: &str, hardware_model_url: &str, machine_identifier_url: &str, progress: F) -> Result<()> {
+    let progress: SharedProgress = Rc::new(RefCell::new(progress));
+    install_image(image_url, cpu_count, memory_size, disks, pixel_height, pixel_width, pixel_per_inch, auxiliary_storage_url, hardware_model_url, machine_identifier_url, progress)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn install_image(image_url: &str, cpu_count: usize, memory_size: usize, disks: Vec<PathBuf>, pixel_height: i32, pixel_width: i32, pixel_per_inch: i32, auxiliary_storage_url: &str, hardware_model_url: &str, machine_identifier_url: &str, progress: SharedProgress) -> Result<()> {
+    if !VZVirtualMachine::supported() {
+        return Err(FrameworkError::new(VZErrorCode::NotSupported, "virtualization is not supported on this host").into());
+    }
+
     // Download image if there is none
-    if !std::path::Path::new(image_url).exists() {
-        download_new_macos_image(image_url.to_string());
+    if !Path::new(image_url).exists() {
+        download_image(image_url, progress.clone())?;
     } else {
-        println!("Skipping download because file already exists");
+        (progress.borrow_mut())(InstallProgress::DownloadSkipped);
+    }
+
+    let info = ipsw::inspect(image_url)?;
+    if !info.supports_virtual_machines() {
+        return Err(Error::Platform(format!("{} does not support virtual machines", image_url)));
     }
+    (progress.borrow_mut())(InstallProgress::InstallStarted { product_version: info.product_version.clone(), build_version: info.build_version.clone() });
 
-    let config = load_configuration_requirements_from_disk(image_url);
-    let platform = VZMacPlatformConfiguration::create(config, auxiliary_storage_url, hardware_model_url, machine_identifier_url);
+    let config = load_configuration_requirements_from_disk(image_url)?;
+    let platform = VZMacPlatformConfiguration::create(config, auxiliary_storage_url, hardware_model_url, machine_identifier_url)?;
 
     // FIXME: Three differences from apple code. They use the main thread for doing all of this, they use
     // a "delegate" and they do the setup_virtual_machine_with_mac_os_configuration_requirements on
     // the main thread. This shouldn't matter but they also create the platform inside of
     // setup_virtual_machine_with_mac_os_configuration_requirements so we can do that too in order
     // to get around borrow issue
-    let label = std::ffi::CString::new("second").unwrap();
-    let queue = unsafe { dispatch_queue_create(label.as_ptr(), NIL) };
+    let queue = DispatchQueue::new("second", Serial);
 
-    let vm = setup_virtual_machine_with_mac_os_configuration_requirements(cpu_count, memory_size, disks, platform, pixel_height, pixel_width, pixel_per_inch, queue);
-    let dispatch_block = ConcreteBlock::new(move || {
-        start_installation_with_restore_image_file_url(&vm, image_url);
+    let vm = setup_virtual_machine_with_mac_os_configuration_requirements(cpu_count, memory_size, disks, platform, pixel_height, pixel_width, pixel_per_inch, &queue)?;
+    let vm = VirtualMachineHandle::from_virtual_machine(vm);
+    let (installer_sender, installer_listener) = channel();
+    let image_url = image_url.to_string();
+    vm.exec_async(move |vm| {
+        start_installation_with_restore_image_file_url(vm, &image_url, installer_sender);
     });
-    let dispatch_block = dispatch_block.copy();
-    let dispatch_block: &Block<(), ()> = &dispatch_block;
-    unsafe {dispatch_async(queue, dispatch_block) }
+    let mut installer_progress = None;
+    loop {
+        match installer_listener.recv_timeout(INSTALL_PROGRESS_INTERVAL) {
+            Ok(InstallerMessage::Started(ns_progress)) => installer_progress = Some(ns_progress.into_inner()),
+            Ok(InstallerMessage::Finished(installer, result)) => {
+                // the installer holds the completion handler, which may still be running, so it
+                // is released on the queue of the virtual machine once the handler has returned
+                vm.exec_async(move |_| {
+                    let _ = installer.into_inner();
+                });
+                return result;
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                if let Some(ns_progress) = &installer_progress {
+                    let fraction: f64 = unsafe { msg_send![**ns_progress, fractionCompleted] };
+                    (progress.borrow_mut())(InstallProgress::Installing { fraction });
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => return Err(completion_handler_dropped("installation")),
+        }
+    }
+}
+
+/// Install macOS from the newest restore image of `catalog` matching `version`, a version
+/// prefix such as `13` or `13.0.1`, or a build such as `22A380`.
+///
+/// When no cached image matches, or `version` is `None`, the latest restore image supported by
+/// this host is downloaded into the catalog first. It fails with
+/// [`Error::RestoreImageUnavailable`] if that image does not match `version` either.
+#[allow(clippy::too_many_arguments)]
+pub fn install_macos_image_version<F: FnMut(InstallProgress) + 'static>(catalog: &mut RestoreImageCatalog, version: Option<&str>, cpu_count: usize, memory_size: usize, disks: Vec<PathBuf>, pixel_height: i32, pixel_width: i32, pixel_per_inch: i32, auxiliary_storage_url: &str, hardware_model_url: &str, machine_identifier_url: &str, progress: F) -> Result<()> {
+    let progress: SharedProgress = Rc::new(RefCell::new(progress));
+    let cached = version.and_then(|version| catalog.find(version)).cloned();
+    let image = match cached {
+        Some(image) => image,
+        None => {
+            let url = fetch_latest_supported_image_url()?;
+            (progress.borrow_mut())(InstallProgress::DownloadStarted { url: url.clone() });
+            let download_progress = progress.clone();
+            let image = catalog.download(&url, move |downloaded| (download_progress.borrow_mut())(InstallProgress::Downloading(downloaded)))?;
+            (progress.borrow_mut())(InstallProgress::DownloadFinished);
+            match version {
+                Some(version) if !image.matches(version) => {
+                    return Err(Error::RestoreImageUnavailable { version: version.to_string(), latest: format!("{} ({})", image.info.product_version, image.info.build_version) });
+                }
+                _ => image,
+            }
+        }
+    };
+    catalog.touch(&image.sha256)?;
+    let image_path = path_to_string(&catalog.path(&image))?;
+    install_image(&image_path, cpu_count, memory_size, disks, pixel_height, pixel_width, pixel_per_inch, auxiliary_storage_url, hardware_model_url, machine_identifier_url, progress)
 }
 
-fn start_installation_with_restore_image_file_url(vm: &VZVirtualMachine, restore_image_file_url: &str) {
-    println!("{}", restore_image_file_url);
+/// error for a completion handler that was released without being called
+fn completion_handler_dropped(operation: &str) -> Error {
+    FrameworkError::new(VZErrorCode::OperationCancelled, format!("{} did not complete", operation)).into()
+}
+
+fn path_to_string(path: &Path) -> Result<String> {
+    canonicalize(path)?
+        .into_os_string()
+        .into_string()
+        .map_err(|path| io::Error::new(io::ErrorKind::InvalidInput, format!("{:?} is not valid UTF-8", path)).into())
+}
+
+/// This must run on the VMs queue, `installer_sender` receives the progress of the installation
+/// and then the installer with its result
+fn start_installation_with_restore_image_file_url(vm: &VZVirtualMachine, restore_image_file_url: &str, installer_sender: Sender<InstallerMessage>) {
     let restore_image_url = NSURL::file_url_with_path(restore_image_file_url, false);
     let macos_installer: Id = unsafe { msg_send![class!(VZMacOSInstaller), alloc] };
-    // This must run on the VMs queue
-    let macos_installer: Id = unsafe { msg_send![macos_installer, initWithVirtualMachine:*vm.0 restoreImageURL:*restore_image_url.0] };
+    let macos_installer = unsafe { StrongPtr::new(msg_send![macos_installer, initWithVirtualMachine:*vm.0 restoreImageURL:*restore_image_url.0]) };
+    let installer = *macos_installer;
 
-    println!("lol");
-    //let (install_macos_sender, install_macos_listener) = channel();
+    // the installer is handed to the receiver once it has completed, releasing it here could
+    // free this block while it is running
+    let macos_installer = RefCell::new(Some(macos_installer));
+    let finished_sender = installer_sender.clone();
     let install_macos_block = ConcreteBlock::new(move |err: Id| {
-        println!("Callback");
-        if err != NIL {
-            let error = unsafe { NSError(StrongPtr::retain(err)) };
-            error.dump();
-            panic!("Installation of macOS failed");
-        } else {
-            println!("Succeeded in installing macos");
+        if let Some(macos_installer) = macos_installer.borrow_mut().take() {
+            let result = if err != NIL {
+                Err(unsafe { NSError(StrongPtr::retain(err)) }.into())
+            } else {
+                Ok(())
+            };
+            // the receiver is gone if install_macos_image has returned
+            let _ = finished_sender.send(InstallerMessage::Finished(AssertSend(macos_installer), result));
         }
-        //install_macos_sender.send(()).unwrap();
     });
     let install_macos_block = install_macos_block.copy();
     let install_macos_block: &Block<(Id,), ()> = &install_macos_block;
-    println!("Installing while the VM is in the {:?} state", unsafe { vm.state() });
-    let _: Id = unsafe { msg_send![macos_installer, installWithCompletionHandler:install_macos_block] };
-    loop {
-        let progress: Id = unsafe {msg_send![macos_installer, progress]};
-        let total: i64 = unsafe { msg_send![progress, totalUnitCount] };
-        let completed: i64 = unsafe { msg_send![progress, totalUnitCount] };
-        println!("Fraction: {}, Completed: {}, Total: {}", completed/total, completed, total);
-        println!("Current VM state is {:?}", unsafe { vm.state() });
-        println!("Sleeping..");
-        std::thread::sleep(std::time::Duration::from_secs(1));
-    }
+    let _: Id = unsafe { msg_send![installer, installWithCompletionHandler:install_macos_block] };
+    let ns_progress = unsafe { StrongPtr::retain(msg_send![installer, progress]) };
+    let _ = installer_sender.send(InstallerMessage::Started(AssertSend(ns_progress)));
 }
 
-fn setup_virtual_machine_with_mac_os_configuration_requirements(cpu_count: usize, memory_size: usize, disks: Vec<PathBuf>, platform: VZMacPlatformConfiguration, pixel_height: i32, pixel_width: i32, pixel_per_inch: i32, queue: Id) -> VZVirtualMachine {
+#[allow(clippy::too_many_arguments)]
+fn setup_virtual_machine_with_mac_os_configuration_requirements(cpu_count: usize, memory_size: usize, disks: Vec<PathBuf>, platform: VZMacPlatformConfiguration, pixel_height: i32, pixel_width: i32, pixel_per_inch: i32, queue: &DispatchQueue) -> Result<VZVirtualMachine> {
     let boot_loader = VZMacOSBootLoader::new();
     let file_handle_for_reading = NSFileHandle::file_handle_with_standard_input();
     let file_handle_for_writing = NSFileHandle::file_handle_with_standard_output();
@@ -111,24 +231,10 @@ fn setup_virtual_machine_with_mac_os_configuration_requirements(cpu_count: usize
 
     let mut block_devices = Vec::with_capacity(disks.len());
     for disk in &disks {
-        let block_attachment = match VZDiskImageStorageDeviceAttachmentBuilder::new()
-            .path(
-                canonicalize(disk)
-                    .unwrap()
-                    .into_os_string()
-                    .into_string()
-                    .unwrap(),
-            )
+        let block_attachment = VZDiskImageStorageDeviceAttachmentBuilder::new()
+            .path(path_to_string(disk)?)
             .read_only(false)
-            .build()
-        {
-            Ok(x) => x,
-            Err(err) => {
-                err.dump();
-                // TODO: Fix
-                panic!();
-            }
-        };
+            .build()?;
         let block_device = VZVirtioBlockDeviceConfiguration::new(block_attachment);
         block_devices.push(block_device);
     }
@@ -154,31 +260,34 @@ fn setup_virtual_machine_with_mac_os_configuration_requirements(cpu_count: usize
         .platform(platform)
         .build();
 
-    if conf.validate_with_error().is_err() {
-        unimplemented!();
-    }
+    conf.validate_with_error()?;
 
-    // TODO: the macos SC uses VMDelegate here, what is that and do we need it?
-    VZVirtualMachine::new(conf, queue)
+    // the installer reports through its completion handler, callers that need the guest stop
+    // and error events of the delegate can attach a VirtualMachineObserver
+    Ok(VZVirtualMachine::with_queue(conf, queue))
 }
 
-fn load_configuration_requirements_from_disk(image_path: &str) -> VZMacOsConfigurationRequirements {
+/// Load the restore image at `image_path` and return its most featureful configuration
+/// supported by this host
+pub fn load_configuration_requirements_from_disk(image_path: &str) -> Result<VZMacOsConfigurationRequirements> {
     let (loaded_image_sender, loaded_image_listener) = channel();
     let load_image_block = ConcreteBlock::new(move |image: Id, err: Id| {
-        if err != NIL {
-            let error = unsafe { NSError(StrongPtr::retain(err)) };
-            error.dump();
-            panic!("Could not load image file from disk");
-        }
-        let macos_configuration_requirements = unsafe { VZMacOsConfigurationRequirements(StrongPtr::retain(msg_send![image, mostFeaturefulSupportedConfiguration] )) };
-        let hardware_model: Id = unsafe { msg_send![*macos_configuration_requirements.0, hardwareModel] };
-        let supported: bool = unsafe { msg_send![hardware_model, isSupported] };
-
-        if *macos_configuration_requirements.0 == NIL || !supported {
-            // TODO: Abort installation
-            panic!("No supported Mac configuration");
-        }
-        loaded_image_sender.send(macos_configuration_requirements).unwrap();
+        let result = if err != NIL {
+            Err(unsafe { NSError(StrongPtr::retain(err)) }.into())
+        } else {
+            let macos_configuration_requirements = unsafe { VZMacOsConfigurationRequirements(StrongPtr::retain(msg_send![image, mostFeaturefulSupportedConfiguration] )) };
+            let supported = *macos_configuration_requirements.0 != NIL && {
+                let hardware_model: Id = unsafe { msg_send![*macos_configuration_requirements.0, hardwareModel] };
+                let supported: bool = unsafe { msg_send![hardware_model, isSupported] };
+                supported
+            };
+            if supported {
+                Ok(macos_configuration_requirements)
+            } else {
+                Err(Error::Platform(String::from("No supported Mac configuration")))
+            }
+        };
+        let _ = loaded_image_sender.send(result);
     });
 
     let load_image_block = load_image_block.copy();
@@ -186,32 +295,49 @@ fn load_configuration_requirements_from_disk(image_path: &str) -> VZMacOsConfigu
     let image_location = NSURL::file_url_with_path(image_path, false);
 
     let _: () = unsafe { msg_send![class!(VZMacOSRestoreImage), loadFileURL:image_location completionHandler:load_image_block] };
-    loaded_image_listener.recv().unwrap()
+    loaded_image_listener.recv().map_err(|_| completion_handler_dropped("loading the restore image"))?
 }
 
-fn download_new_macos_image(image_location: String) {
+/// URL of the latest restore image supported by this host
+pub fn fetch_latest_supported_image_url() -> Result<String> {
     let (sender, listener) = channel();
     let fetch_latest_image_url_block = ConcreteBlock::new(move |image: Id, err: Id| {
-        if err != NIL {
-            let error = unsafe { NSError(StrongPtr::retain(err)) };
-            error.dump();
-            panic!("Could not initialize image download");
-        }
-        let url: Id = unsafe { msg_send![image, URL] };
-        let url_string: NSString = unsafe { NSString(StrongPtr::retain(msg_send![url, absoluteString])) };
-        println!("URL IS: {}", url_string.as_str());
-        println!("Downloading");
-        let bytes = reqwest::blocking::Client::builder().timeout(Some(std::time::Duration::from_secs(45 * 60))).build().unwrap().get(url_string.as_str()).send().unwrap().bytes().unwrap();
-        std::fs::write(&image_location, bytes).unwrap();
-
-        println!("Image download complete");
-        sender.send(()).unwrap();
+        let result: Result<String> = if err != NIL {
+            Err(unsafe { NSError(StrongPtr::retain(err)) }.into())
+        } else {
+            let url: Id = unsafe { msg_send![image, URL] };
+            let url_string: NSString = unsafe { NSString(StrongPtr::retain(msg_send![url, absoluteString])) };
+            Ok(url_string.as_str().to_string())
+        };
+        let _ = sender.send(result);
     });
 
     let fetch_latest_image_url_block = fetch_latest_image_url_block.copy();
     let fetch_latest_image_url_block: &Block<(Id, Id), ()> = &fetch_latest_image_url_block;
     let _: () = unsafe {msg_send![class!(VZMacOSRestoreImage), fetchLatestSupportedWithCompletionHandler: fetch_latest_image_url_block]};
-    listener.recv().unwrap();
+    listener.recv().map_err(|_| completion_handler_dropped("fetching the latest restore image"))?
+}
+
+/// Download the latest restore image supported by this host to `image_location`
+///
+/// An interrupted download is resumed from `<image_location>.part` on the next call.
+pub fn download_new_macos_image<F: FnMut(InstallProgress) + 'static>(image_location: &str, progress: F) -> Result<()> {
+    download_image(image_location, Rc::new(RefCell::new(progress)))
+}
+
+fn download_image(image_location: &str, progress: SharedProgress) -> Result<()> {
+    let url = fetch_latest_supported_image_url()?;
+
+    (progress.borrow_mut())(InstallProgress::DownloadStarted { url: url.clone() });
+    let download_progress = progress.clone();
+    DownloadBuilder::new()
+        .url(url)
+        .destination(image_location)
+        .progress(move |downloaded| (download_progress.borrow_mut())(InstallProgress::Downloading(downloaded)))
+        .build()
+        .run()?;
+    (progress.borrow_mut())(InstallProgress::DownloadFinished);
+    Ok(())
 }
 
 //fn setup_virtual_machine_with_macos_configuration_requirements(conf_req: VZMacOsConfigurationRequirements) {
@@ -222,8 +348,6 @@ fn download_new_macos_image(image_location: String) {
 //    let _: () = unsafe { msg_send![configuration, setPlatform:*platform_configuration.0] };
 //    let _: () = unsafe { msg_send![configuration, setCPUCount:CPU_COUNT] };
 //    let _: () = unsafe { msg_send![configuration, setMemorySize:MEMORY_SIZE] };
-//    
-//    create_disk_image();
 //
 //    let _: () = unsafe { msg_send![configuration, setBootLoader:create_bootloader_configuration()] };
 //
@@ -265,7 +389,3 @@ fn download_new_macos_image(image_location: String) {
 //    let bootloader: Id = unsafe { msg_send![class!(VZMacOSBootLoader), alloc] };
 //    unsafe { msg_send![bootloader, init] }
 //}
-//
-//fn create_disk_image() {
-//    // TODO: Create the disk image
-//}
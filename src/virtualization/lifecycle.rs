@@ -0,0 +1,374 @@
+//! virtual machine lifecycle module
+//!
+//! The states each lifecycle operation may be started from and the states it moves through,
+//! as documented for VZVirtualMachine. Virtualization.framework raises an Objective-C exception
+//! when an operation is started from another state, so backends check [`LifecycleOperation::check`]
+//! before calling into the framework. [`Lifecycle`] applies the same rules to a state kept in Rust.
+
+use crate::{
+    error::{Error, FrameworkError, Result, VZErrorCode},
+    virtualization::{events::VirtualMachineEvent, virtual_machine::VZVirtualMachineState},
+};
+
+use VZVirtualMachineState::*;
+
+/// operation changing the state of a virtual machine
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LifecycleOperation {
+    Start,
+    /// stop without giving the guest a chance to shut down
+    Stop,
+    /// ask the guest to shut down
+    RequestStop,
+    Pause,
+    Resume,
+}
+
+impl LifecycleOperation {
+    pub const ALL: [LifecycleOperation; 5] = [
+        LifecycleOperation::Start,
+        LifecycleOperation::Stop,
+        LifecycleOperation::RequestStop,
+        LifecycleOperation::Pause,
+        LifecycleOperation::Resume,
+    ];
+
+    /// states the operation can be started from
+    pub fn allowed_from(self) -> &'static [VZVirtualMachineState] {
+        match self {
+            LifecycleOperation::Start => {
+                &[VZVirtualMachineStateStopped, VZVirtualMachineStateError]
+            }
+            LifecycleOperation::Stop => &[
+                VZVirtualMachineStateRunning,
+                VZVirtualMachineStatePaused,
+                VZVirtualMachineStateError,
+            ],
+            LifecycleOperation::RequestStop => &[VZVirtualMachineStateRunning],
+            LifecycleOperation::Pause => &[VZVirtualMachineStateRunning],
+            LifecycleOperation::Resume => &[VZVirtualMachineStatePaused],
+        }
+    }
+
+    /// intermediate and final state of the operation, `None` for a stop request whose outcome
+    /// is decided by the guest
+    pub fn transition(self) -> Option<(VZVirtualMachineState, VZVirtualMachineState)> {
+        match self {
+            LifecycleOperation::Start => {
+                Some((VZVirtualMachineStateStarting, VZVirtualMachineStateRunning))
+            }
+            LifecycleOperation::Stop => {
+                Some((VZVirtualMachineStateStopping, VZVirtualMachineStateStopped))
+            }
+            LifecycleOperation::RequestStop => None,
+            LifecycleOperation::Pause => {
+                Some((VZVirtualMachineStatePausing, VZVirtualMachineStatePaused))
+            }
+            LifecycleOperation::Resume => {
+                Some((VZVirtualMachineStateResuming, VZVirtualMachineStateRunning))
+            }
+        }
+    }
+
+    /// state the virtual machine is left in when the operation, started from `from`, fails
+    ///
+    /// A failed start leaves it in the Error state, the other operations leave it where they
+    /// were started from.
+    pub fn failed_state(self, from: VZVirtualMachineState) -> VZVirtualMachineState {
+        match self {
+            LifecycleOperation::Start => VZVirtualMachineStateError,
+            _ => from,
+        }
+    }
+
+    pub fn is_allowed(self, state: VZVirtualMachineState) -> bool {
+        self.allowed_from().contains(&state)
+    }
+
+    /// `InvalidVirtualMachineStateTransition` unless the operation can be started from `state`
+    pub fn check(self, state: VZVirtualMachineState) -> Result<()> {
+        if self.is_allowed(state) {
+            Ok(())
+        } else {
+            Err(invalid_state_transition(self, state))
+        }
+    }
+}
+
+/// error for an operation started from a state that does not allow it
+pub fn invalid_state_transition(
+    operation: LifecycleOperation,
+    state: VZVirtualMachineState,
+) -> Error {
+    FrameworkError::new(
+        VZErrorCode::InvalidVirtualMachineStateTransition,
+        format!("cannot {:?} in the {:?} state", operation, state),
+    )
+    .into()
+}
+
+/// whether `err` kept an operation from being started at all, rather than being reported by the
+/// framework once it had started
+fn rejected(err: &Error) -> bool {
+    match err {
+        Error::Framework(err) => {
+            err.vz_code() == Some(VZErrorCode::InvalidVirtualMachineStateTransition)
+        }
+        _ => true,
+    }
+}
+
+/// operations that can be started from `state`
+pub fn allowed_operations(state: VZVirtualMachineState) -> Vec<LifecycleOperation> {
+    LifecycleOperation::ALL
+        .iter()
+        .copied()
+        .filter(|operation| operation.is_allowed(state))
+        .collect()
+}
+
+/// state machine of a virtual machine, driven by the operations started on it and their results
+/// # Examples
+/// ```rust
+/// use virtualization_rs::virtualization::lifecycle::{Lifecycle, LifecycleOperation};
+/// use virtualization_rs::virtualization::virtual_machine::VZVirtualMachineState;
+///
+/// let mut lifecycle = Lifecycle::new();
+/// lifecycle.begin(LifecycleOperation::Start).unwrap();
+/// assert!(lifecycle.begin(LifecycleOperation::Pause).is_err());
+/// lifecycle.complete(&Ok(()));
+/// assert_eq!(lifecycle.state(), VZVirtualMachineState::VZVirtualMachineStateRunning);
+/// assert!(lifecycle.can(LifecycleOperation::Pause));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lifecycle {
+    state: VZVirtualMachineState,
+    pending: Option<LifecycleOperation>,
+    /// state the pending operation was started from
+    before: VZVirtualMachineState,
+}
+
+impl Lifecycle {
+    /// lifecycle of a virtual machine that was just created
+    pub fn new() -> Self {
+        Lifecycle {
+            state: VZVirtualMachineStateStopped,
+            pending: None,
+            before: VZVirtualMachineStateStopped,
+        }
+    }
+}
+
+impl Default for Lifecycle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Lifecycle {
+    pub fn state(&self) -> VZVirtualMachineState {
+        self.state
+    }
+
+    /// operation waiting for its completion handler
+    pub fn pending(&self) -> Option<LifecycleOperation> {
+        self.pending
+    }
+
+    /// whether `operation` can be started now
+    pub fn can(&self, operation: LifecycleOperation) -> bool {
+        self.pending.is_none() && operation.is_allowed(self.state)
+    }
+
+    /// start `operation`, entering its intermediate state
+    pub fn begin(&mut self, operation: LifecycleOperation) -> Result<()> {
+        if !self.can(operation) {
+            return Err(invalid_state_transition(operation, self.state));
+        }
+        if let Some((through, _)) = operation.transition() {
+            self.before = self.state;
+            self.state = through;
+            self.pending = Some(operation);
+        }
+        Ok(())
+    }
+
+    /// the pending operation finished
+    ///
+    /// A failure reported by the framework enters [`LifecycleOperation::failed_state`], one that
+    /// kept the operation from starting, like an invalid transition or an unsupported macOS
+    /// version, returns to the state the operation was started from.
+    pub fn complete(&mut self, result: &Result<()>) {
+        if let Some(operation) = self.pending.take() {
+            self.state = match (result, operation.transition()) {
+                (Ok(()), Some((_, to))) => to,
+                (Err(err), _) if rejected(err) => self.before,
+                _ => operation.failed_state(self.before),
+            };
+        }
+    }
+
+    /// the guest shut down, cancelling a pending operation
+    pub fn guest_did_stop(&mut self) {
+        self.pending = None;
+        self.state = VZVirtualMachineStateStopped;
+    }
+
+    /// the virtual machine stopped because of an error, cancelling a pending operation
+    pub fn did_stop_with_error(&mut self) {
+        self.pending = None;
+        self.state = VZVirtualMachineStateError;
+    }
+
+    /// follow an event reported by the virtual machine
+    pub fn apply(&mut self, event: &VirtualMachineEvent) {
+        match event {
+            VirtualMachineEvent::GuestDidStop => self.guest_did_stop(),
+            VirtualMachineEvent::DidStopWithError(_) => self.did_stop_with_error(),
+            VirtualMachineEvent::StateChanged(state) => {
+                self.state = *state;
+                if self
+                    .pending
+                    .and_then(|operation| operation.transition())
+                    .is_some_and(|(through, _)| through != *state)
+                {
+                    self.pending = None;
+                }
+            }
+            VirtualMachineEvent::NetworkAttachmentWasDisconnected { .. } => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::virtualization::events::VirtualMachineEvent;
+
+    fn failure(code: VZErrorCode) -> Result<()> {
+        Err(FrameworkError::new(code, "failed").into())
+    }
+
+    fn running() -> Lifecycle {
+        let mut lifecycle = Lifecycle::new();
+        lifecycle.begin(LifecycleOperation::Start).unwrap();
+        lifecycle.complete(&Ok(()));
+        lifecycle
+    }
+
+    #[test]
+    fn operations_move_through_their_transitions() {
+        let mut lifecycle = running();
+        assert_eq!(lifecycle.state(), VZVirtualMachineStateRunning);
+
+        lifecycle.begin(LifecycleOperation::Pause).unwrap();
+        assert_eq!(lifecycle.state(), VZVirtualMachineStatePausing);
+        assert_eq!(lifecycle.pending(), Some(LifecycleOperation::Pause));
+        lifecycle.complete(&Ok(()));
+        assert_eq!(lifecycle.state(), VZVirtualMachineStatePaused);
+        assert_eq!(lifecycle.pending(), None);
+
+        lifecycle.begin(LifecycleOperation::Resume).unwrap();
+        assert_eq!(lifecycle.state(), VZVirtualMachineStateResuming);
+        lifecycle.complete(&Ok(()));
+        assert_eq!(lifecycle.state(), VZVirtualMachineStateRunning);
+
+        lifecycle.begin(LifecycleOperation::Stop).unwrap();
+        assert_eq!(lifecycle.state(), VZVirtualMachineStateStopping);
+        lifecycle.complete(&Ok(()));
+        assert_eq!(lifecycle.state(), VZVirtualMachineStateStopped);
+    }
+
+    #[test]
+    fn illegal_transitions_are_rejected_without_changing_the_state() {
+        let mut lifecycle = Lifecycle::new();
+        for operation in &[
+            LifecycleOperation::Stop,
+            LifecycleOperation::RequestStop,
+            LifecycleOperation::Pause,
+            LifecycleOperation::Resume,
+        ] {
+            let err = lifecycle.begin(*operation).unwrap_err();
+            assert_eq!(
+                err.vz_code(),
+                Some(VZErrorCode::InvalidVirtualMachineStateTransition)
+            );
+            assert_eq!(lifecycle.state(), VZVirtualMachineStateStopped);
+        }
+
+        // nothing can be started while an operation is pending
+        lifecycle.begin(LifecycleOperation::Start).unwrap();
+        for operation in LifecycleOperation::ALL.iter() {
+            assert!(!lifecycle.can(*operation));
+            assert!(lifecycle.begin(*operation).is_err());
+        }
+        assert_eq!(lifecycle.state(), VZVirtualMachineStateStarting);
+
+        assert_eq!(
+            allowed_operations(VZVirtualMachineStatePaused),
+            vec![LifecycleOperation::Stop, LifecycleOperation::Resume]
+        );
+    }
+
+    #[test]
+    fn a_failed_start_enters_the_error_state() {
+        let mut lifecycle = Lifecycle::new();
+        lifecycle.begin(LifecycleOperation::Start).unwrap();
+        lifecycle.complete(&failure(VZErrorCode::Internal));
+        assert_eq!(lifecycle.state(), VZVirtualMachineStateError);
+        assert!(lifecycle.can(LifecycleOperation::Start));
+    }
+
+    #[test]
+    fn other_failures_return_to_the_previous_state() {
+        let mut lifecycle = running();
+        lifecycle.begin(LifecycleOperation::Pause).unwrap();
+        lifecycle.complete(&failure(VZErrorCode::Internal));
+        assert_eq!(lifecycle.state(), VZVirtualMachineStateRunning);
+
+        lifecycle.begin(LifecycleOperation::Pause).unwrap();
+        lifecycle.complete(&Ok(()));
+        lifecycle.begin(LifecycleOperation::Resume).unwrap();
+        lifecycle.complete(&failure(VZErrorCode::Internal));
+        assert_eq!(lifecycle.state(), VZVirtualMachineStatePaused);
+
+        lifecycle.begin(LifecycleOperation::Stop).unwrap();
+        lifecycle.complete(&failure(VZErrorCode::Internal));
+        assert_eq!(lifecycle.state(), VZVirtualMachineStatePaused);
+    }
+
+    #[test]
+    fn rejected_operations_return_to_the_previous_state() {
+        let mut lifecycle = Lifecycle::new();
+        lifecycle.begin(LifecycleOperation::Start).unwrap();
+        lifecycle.complete(&failure(VZErrorCode::InvalidVirtualMachineStateTransition));
+        assert_eq!(lifecycle.state(), VZVirtualMachineStateStopped);
+
+        let mut lifecycle = running();
+        lifecycle.begin(LifecycleOperation::Stop).unwrap();
+        lifecycle.complete(&Err(Error::Platform("requires macOS 12".to_string())));
+        assert_eq!(lifecycle.state(), VZVirtualMachineStateRunning);
+    }
+
+    #[test]
+    fn events_cancel_the_pending_operation() {
+        let mut lifecycle = running();
+        lifecycle.begin(LifecycleOperation::Pause).unwrap();
+        lifecycle.apply(&VirtualMachineEvent::GuestDidStop);
+        assert_eq!(lifecycle.state(), VZVirtualMachineStateStopped);
+        assert_eq!(lifecycle.pending(), None);
+
+        let mut lifecycle = running();
+        lifecycle.begin(LifecycleOperation::Pause).unwrap();
+        lifecycle.apply(&VirtualMachineEvent::StateChanged(
+            VZVirtualMachineStatePausing,
+        ));
+        assert_eq!(lifecycle.pending(), Some(LifecycleOperation::Pause));
+        lifecycle.apply(&VirtualMachineEvent::DidStopWithError(FrameworkError::new(
+            VZErrorCode::Internal,
+            "crashed",
+        )));
+        assert_eq!(lifecycle.state(), VZVirtualMachineStateError);
+        assert_eq!(lifecycle.pending(), None);
+    }
+}
@@ -2,8 +2,8 @@
 use crate::base::Id;
 use crate::virtualization::boot_loader::VZBootLoader;
 
-use objc::rc::StrongPtr;
-use objc::{class, msg_send, sel, sel_impl};
+use crate::sys::StrongPtr;
+use crate::sys::{class, msg_send, sel, sel_impl};
 
 ///  bootLoader for Linux kernel
 pub struct VZMacOSBootLoader(pub StrongPtr);
@@ -18,6 +18,12 @@ impl VZMacOSBootLoader {
     }
 }
 
+impl Default for VZMacOSBootLoader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl VZBootLoader for VZMacOSBootLoader {
     fn id(&self) -> Id {
         *self.0
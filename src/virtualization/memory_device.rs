@@ -2,8 +2,8 @@
 
 use crate::base::Id;
 
-use objc::rc::StrongPtr;
-use objc::{class, msg_send, sel, sel_impl};
+use crate::sys::StrongPtr;
+use crate::sys::{class, msg_send, sel, sel_impl};
 
 /// common configure of memory balloon device
 pub trait VZMemoryBalloonDeviceConfiguration {
@@ -25,6 +25,12 @@ impl VZVirtioTraditionalMemoryBalloonDeviceConfiguration {
     }
 }
 
+impl Default for VZVirtioTraditionalMemoryBalloonDeviceConfiguration {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl VZMemoryBalloonDeviceConfiguration for VZVirtioTraditionalMemoryBalloonDeviceConfiguration {
     fn id(&self) -> Id {
         *self.0
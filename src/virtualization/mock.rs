@@ -0,0 +1,644 @@
+//! mock virtual machine module
+//!
+//! [`MockVirtualMachine`] is an in-memory, deterministic stand-in for VZVirtualMachine. It runs
+//! on a virtual clock that only moves when [`MockVirtualMachine::advance`] is called, so tests
+//! decide exactly when the Starting, Stopping, Pausing, Resuming and guest shutdown transitions
+//! complete.
+
+use crate::{
+    error::{FrameworkError, Result, VZErrorCode},
+    virtualization::{
+        backend::{CompletionHandler, VirtualMachineBackend},
+        events::{EventBus, EventStream, VirtualMachineEvent},
+        future::{block_on, CompletionFuture},
+        lifecycle::{invalid_state_transition, LifecycleOperation},
+        shutdown::ShutdownTarget,
+        virtual_machine::VZVirtualMachineState,
+    },
+};
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// operations of MockVirtualMachine that can be configured to fail
+pub type MockOperation = LifecycleOperation;
+
+/// builder for MockVirtualMachine
+/// # Examples
+/// ```rust
+/// use std::time::Duration;
+/// use virtualization_rs::error::{FrameworkError, VZErrorCode};
+/// use virtualization_rs::virtualization::mock::{MockOperation, MockVirtualMachineBuilder};
+///
+/// let vm = MockVirtualMachineBuilder::new()
+///     .start_delay(Duration::from_secs(2))
+///     .fail(
+///         MockOperation::Pause,
+///         FrameworkError::new(VZErrorCode::Internal, "pause failed"),
+///     )
+///     .build();
+/// ```
+pub struct MockVirtualMachineBuilder {
+    start_delay: Duration,
+    stop_delay: Duration,
+    pause_delay: Duration,
+    resume_delay: Duration,
+    guest_stop_delay: Option<Duration>,
+    failures: HashMap<MockOperation, FrameworkError>,
+}
+
+impl MockVirtualMachineBuilder {
+    pub fn new() -> Self {
+        MockVirtualMachineBuilder {
+            start_delay: Duration::from_secs(0),
+            stop_delay: Duration::from_secs(0),
+            pause_delay: Duration::from_secs(0),
+            resume_delay: Duration::from_secs(0),
+            guest_stop_delay: Some(Duration::from_secs(0)),
+            failures: HashMap::new(),
+        }
+    }
+}
+
+impl Default for MockVirtualMachineBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockVirtualMachineBuilder {
+    /// time spent in the Starting state
+    pub fn start_delay(mut self, delay: Duration) -> Self {
+        self.start_delay = delay;
+        self
+    }
+
+    /// time spent in the Stopping state
+    pub fn stop_delay(mut self, delay: Duration) -> Self {
+        self.stop_delay = delay;
+        self
+    }
+
+    /// time spent in the Pausing state
+    pub fn pause_delay(mut self, delay: Duration) -> Self {
+        self.pause_delay = delay;
+        self
+    }
+
+    /// time spent in the Resuming state
+    pub fn resume_delay(mut self, delay: Duration) -> Self {
+        self.resume_delay = delay;
+        self
+    }
+
+    /// time the guest takes to shut down after a stop request
+    pub fn guest_stop_delay(mut self, delay: Duration) -> Self {
+        self.guest_stop_delay = Some(delay);
+        self
+    }
+
+    /// the guest accepts stop requests but never shuts down
+    pub fn ignore_stop_requests(mut self) -> Self {
+        self.guest_stop_delay = None;
+        self
+    }
+
+    /// make `operation` fail with `error`
+    pub fn fail(mut self, operation: MockOperation, error: FrameworkError) -> Self {
+        self.failures.insert(operation, error);
+        self
+    }
+
+    pub fn build(self) -> MockVirtualMachine {
+        MockVirtualMachine {
+            state: VZVirtualMachineState::VZVirtualMachineStateStopped,
+            now: Duration::from_secs(0),
+            start_delay: self.start_delay,
+            stop_delay: self.stop_delay,
+            pause_delay: self.pause_delay,
+            resume_delay: self.resume_delay,
+            guest_stop_delay: self.guest_stop_delay,
+            failures: self.failures,
+            pending: None,
+            transitions: Vec::new(),
+            events: EventBus::new(),
+        }
+    }
+}
+
+/// transition that completes once the virtual clock reaches `at`
+struct PendingTransition {
+    at: Duration,
+    target: VZVirtualMachineState,
+    failure: Option<FrameworkError>,
+    /// state entered when the transition fails
+    failed: VZVirtualMachineState,
+    completion_handler: Option<CompletionHandler>,
+}
+
+/// deterministic in-memory virtual machine
+pub struct MockVirtualMachine {
+    state: VZVirtualMachineState,
+    now: Duration,
+    start_delay: Duration,
+    stop_delay: Duration,
+    pause_delay: Duration,
+    resume_delay: Duration,
+    guest_stop_delay: Option<Duration>,
+    failures: HashMap<MockOperation, FrameworkError>,
+    pending: Option<PendingTransition>,
+    transitions: Vec<(Duration, VZVirtualMachineState)>,
+    events: EventBus,
+}
+
+impl MockVirtualMachine {
+    /// current time of the virtual clock
+    pub fn now(&self) -> Duration {
+        self.now
+    }
+
+    /// every state entered so far, with the virtual time it was entered at
+    pub fn transitions(&self) -> &[(Duration, VZVirtualMachineState)] {
+        &self.transitions
+    }
+
+    /// stream of the events published from now on, as a VirtualMachineObserver would deliver them
+    pub fn subscribe(&self) -> EventStream {
+        self.events.subscribe()
+    }
+
+    /// whether a transition is waiting for the virtual clock
+    pub fn is_transitioning(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    /// make `operation` fail with `error`, or succeed again with `None`
+    pub fn set_failure(&mut self, operation: MockOperation, error: Option<FrameworkError>) {
+        match error {
+            Some(error) => self.failures.insert(operation, error),
+            None => self.failures.remove(&operation),
+        };
+    }
+
+    /// move the virtual clock forward and complete the transitions that became due
+    pub fn advance(&mut self, duration: Duration) {
+        self.now += duration;
+        self.complete_due_transition();
+    }
+
+    /// the guest shuts itself down, a pending operation is cancelled
+    pub fn guest_stop(&mut self) {
+        let pending = self.pending.take();
+        self.enter(VZVirtualMachineState::VZVirtualMachineStateStopped);
+        self.events.publish(VirtualMachineEvent::GuestDidStop);
+        if let Some(completion_handler) = pending.and_then(|p| p.completion_handler) {
+            completion_handler(Err(FrameworkError::new(
+                VZErrorCode::OperationCancelled,
+                "the guest stopped",
+            )
+            .into()));
+        }
+    }
+
+    /// the virtual machine hits an internal error, a pending operation fails with `error`
+    pub fn crash(&mut self, error: FrameworkError) {
+        let pending = self.pending.take();
+        self.enter(VZVirtualMachineState::VZVirtualMachineStateError);
+        self.events
+            .publish(VirtualMachineEvent::DidStopWithError(error.clone()));
+        if let Some(completion_handler) = pending.and_then(|p| p.completion_handler) {
+            completion_handler(Err(error.into()));
+        }
+    }
+
+    /// the attachment of the network device at `device_index` is disconnected
+    pub fn disconnect_network(&mut self, device_index: usize, error: FrameworkError) {
+        self.events
+            .publish(VirtualMachineEvent::NetworkAttachmentWasDisconnected {
+                device_index: Some(device_index),
+                error,
+            });
+    }
+
+    fn begin(
+        &mut self,
+        operation: MockOperation,
+        delay: Duration,
+        completion_handler: CompletionHandler,
+    ) {
+        let (through, to) = match operation.transition() {
+            Some(transition) if operation.is_allowed(self.state) && self.pending.is_none() => {
+                transition
+            }
+            _ => {
+                completion_handler(Err(invalid_state_transition(operation, self.state)));
+                return;
+            }
+        };
+        let failed = operation.failed_state(self.state);
+        self.enter(through);
+        self.pending = Some(PendingTransition {
+            at: self.now + delay,
+            target: to,
+            failure: self.failures.get(&operation).cloned(),
+            failed,
+            completion_handler: Some(completion_handler),
+        });
+        self.complete_due_transition();
+    }
+
+    fn complete_due_transition(&mut self) {
+        let due = match &self.pending {
+            Some(pending) => pending.at <= self.now,
+            None => false,
+        };
+        if !due {
+            return;
+        }
+        let pending = self.pending.take().unwrap();
+        let result = match pending.failure {
+            Some(error) => {
+                self.enter(pending.failed);
+                Err(error.into())
+            }
+            None => {
+                self.enter(pending.target);
+                Ok(())
+            }
+        };
+        match pending.completion_handler {
+            Some(completion_handler) => completion_handler(result),
+            // only a stop request is completed by the guest rather than a completion handler
+            None => self.events.publish(VirtualMachineEvent::GuestDidStop),
+        }
+    }
+
+    fn enter(&mut self, state: VZVirtualMachineState) {
+        self.state = state;
+        self.transitions.push((self.now, state));
+        self.events
+            .publish(VirtualMachineEvent::StateChanged(state));
+    }
+}
+
+impl VirtualMachineBackend for MockVirtualMachine {
+    fn start(&mut self, completion_handler: CompletionHandler) {
+        self.begin(MockOperation::Start, self.start_delay, completion_handler);
+    }
+
+    fn stop(&mut self, completion_handler: CompletionHandler) {
+        // a hard stop overtakes a guest shutdown in progress
+        if let Some(PendingTransition {
+            completion_handler: None,
+            ..
+        }) = self.pending
+        {
+            self.pending = None;
+        }
+        self.begin(MockOperation::Stop, self.stop_delay, completion_handler);
+    }
+
+    fn pause(&mut self, completion_handler: CompletionHandler) {
+        self.begin(MockOperation::Pause, self.pause_delay, completion_handler);
+    }
+
+    fn resume(&mut self, completion_handler: CompletionHandler) {
+        self.begin(MockOperation::Resume, self.resume_delay, completion_handler);
+    }
+
+    fn request_stop(&mut self) -> Result<bool> {
+        if let Some(error) = self.failures.get(&MockOperation::RequestStop) {
+            return Err(error.clone().into());
+        }
+        if !MockOperation::RequestStop.is_allowed(self.state) || self.pending.is_some() {
+            return Err(invalid_state_transition(
+                MockOperation::RequestStop,
+                self.state,
+            ));
+        }
+        if let Some(delay) = self.guest_stop_delay {
+            self.pending = Some(PendingTransition {
+                at: self.now + delay,
+                target: VZVirtualMachineState::VZVirtualMachineStateStopped,
+                failure: None,
+                failed: self.state,
+                completion_handler: None,
+            });
+            self.complete_due_transition();
+        }
+        Ok(true)
+    }
+
+    fn state(&self) -> VZVirtualMachineState {
+        self.state
+    }
+}
+
+impl ShutdownTarget for MockVirtualMachine {
+    fn state(&mut self) -> VZVirtualMachineState {
+        self.state
+    }
+
+    fn request_stop(&mut self) -> Result<bool> {
+        VirtualMachineBackend::request_stop(self)
+    }
+
+    /// stop and move the virtual clock to the end of the transition
+    fn force_stop(&mut self) -> Result<()> {
+        let (completion_handler, future) = CompletionFuture::new();
+        self.stop(completion_handler);
+        if let Some(at) = self.pending.as_ref().map(|pending| pending.at) {
+            self.advance(at.saturating_sub(self.now));
+        }
+        block_on(future)
+    }
+
+    fn now(&mut self) -> Duration {
+        self.now
+    }
+
+    fn sleep(&mut self, duration: Duration) {
+        self.advance(duration);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+
+    use std::sync::{Arc, Mutex};
+    use VZVirtualMachineState::*;
+
+    /// completion handler recording its result
+    fn recorder() -> (CompletionHandler, Arc<Mutex<Option<Result<()>>>>) {
+        let result = Arc::new(Mutex::new(None));
+        let recorded = result.clone();
+        let completion_handler: CompletionHandler = Box::new(move |r| {
+            *recorded.lock().unwrap() = Some(r);
+        });
+        (completion_handler, result)
+    }
+
+    fn taken(result: &Arc<Mutex<Option<Result<()>>>>) -> Option<Result<()>> {
+        result.lock().unwrap().take()
+    }
+
+    fn states(vm: &MockVirtualMachine) -> Vec<VZVirtualMachineState> {
+        vm.transitions().iter().map(|&(_, state)| state).collect()
+    }
+
+    #[test]
+    fn start_completes_when_the_clock_reaches_the_delay() {
+        let mut vm = MockVirtualMachineBuilder::new()
+            .start_delay(Duration::from_secs(2))
+            .build();
+        let (completion_handler, result) = recorder();
+        vm.start(completion_handler);
+        assert_eq!(
+            VirtualMachineBackend::state(&vm),
+            VZVirtualMachineStateStarting
+        );
+        assert!(vm.is_transitioning());
+
+        vm.advance(Duration::from_secs(1));
+        assert!(taken(&result).is_none());
+        vm.advance(Duration::from_secs(1));
+        assert!(taken(&result).unwrap().is_ok());
+        assert_eq!(
+            VirtualMachineBackend::state(&vm),
+            VZVirtualMachineStateRunning
+        );
+        assert_eq!(
+            vm.transitions(),
+            &[
+                (Duration::from_secs(0), VZVirtualMachineStateStarting),
+                (Duration::from_secs(2), VZVirtualMachineStateRunning),
+            ]
+        );
+    }
+
+    #[test]
+    fn zero_delays_complete_immediately() {
+        let mut vm = MockVirtualMachineBuilder::new().build();
+        for operation in &[
+            MockOperation::Start,
+            MockOperation::Pause,
+            MockOperation::Resume,
+            MockOperation::Stop,
+        ] {
+            let (completion_handler, result) = recorder();
+            match operation {
+                MockOperation::Start => vm.start(completion_handler),
+                MockOperation::Pause => vm.pause(completion_handler),
+                MockOperation::Resume => vm.resume(completion_handler),
+                _ => vm.stop(completion_handler),
+            }
+            assert!(taken(&result).unwrap().is_ok(), "{:?}", operation);
+        }
+        assert_eq!(
+            states(&vm),
+            vec![
+                VZVirtualMachineStateStarting,
+                VZVirtualMachineStateRunning,
+                VZVirtualMachineStatePausing,
+                VZVirtualMachineStatePaused,
+                VZVirtualMachineStateResuming,
+                VZVirtualMachineStateRunning,
+                VZVirtualMachineStateStopping,
+                VZVirtualMachineStateStopped,
+            ]
+        );
+    }
+
+    #[test]
+    fn operations_from_the_wrong_state_fail_without_a_transition() {
+        let mut vm = MockVirtualMachineBuilder::new().build();
+        let (completion_handler, result) = recorder();
+        vm.pause(completion_handler);
+        let err = taken(&result).unwrap().unwrap_err();
+        assert_eq!(
+            err.vz_code(),
+            Some(VZErrorCode::InvalidVirtualMachineStateTransition)
+        );
+        assert!(vm.transitions().is_empty());
+        assert!(VirtualMachineBackend::request_stop(&mut vm).is_err());
+    }
+
+    #[test]
+    fn operations_are_refused_while_transitioning() {
+        let mut vm = MockVirtualMachineBuilder::new()
+            .start_delay(Duration::from_secs(1))
+            .build();
+        let (completion_handler, first) = recorder();
+        vm.start(completion_handler);
+        let (completion_handler, second) = recorder();
+        vm.start(completion_handler);
+        assert!(taken(&second).unwrap().is_err());
+
+        vm.advance(Duration::from_secs(1));
+        assert!(taken(&first).unwrap().is_ok());
+    }
+
+    #[test]
+    fn configured_failure_enters_the_error_state() {
+        let error = FrameworkError::new(VZErrorCode::Internal, "start failed");
+        let mut vm = MockVirtualMachineBuilder::new()
+            .fail(MockOperation::Start, error.clone())
+            .build();
+        let events = vm.subscribe();
+        let (completion_handler, result) = recorder();
+        vm.start(completion_handler);
+        match taken(&result).unwrap() {
+            Err(Error::Framework(err)) => assert_eq!(err, error),
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(
+            VirtualMachineBackend::state(&vm),
+            VZVirtualMachineStateError
+        );
+        assert_eq!(
+            events.try_recv(),
+            Some(VirtualMachineEvent::StateChanged(
+                VZVirtualMachineStateStarting
+            ))
+        );
+        assert_eq!(
+            events.try_recv(),
+            Some(VirtualMachineEvent::StateChanged(
+                VZVirtualMachineStateError
+            ))
+        );
+
+        // the failure can be cleared and the virtual machine started from the Error state
+        vm.set_failure(MockOperation::Start, None);
+        let (completion_handler, result) = recorder();
+        vm.start(completion_handler);
+        assert!(taken(&result).unwrap().is_ok());
+    }
+
+    #[test]
+    fn failed_pause_returns_to_running() {
+        let mut vm = MockVirtualMachineBuilder::new()
+            .fail(
+                MockOperation::Pause,
+                FrameworkError::new(VZErrorCode::Internal, "pause failed"),
+            )
+            .build();
+        vm.start(Box::new(|_| {}));
+        let (completion_handler, result) = recorder();
+        vm.pause(completion_handler);
+        assert!(taken(&result).unwrap().is_err());
+        assert_eq!(
+            VirtualMachineBackend::state(&vm),
+            VZVirtualMachineStateRunning
+        );
+    }
+
+    #[test]
+    fn stop_request_is_completed_by_the_guest() {
+        let mut vm = MockVirtualMachineBuilder::new()
+            .guest_stop_delay(Duration::from_secs(5))
+            .build();
+        vm.start(Box::new(|_| {}));
+        let events = vm.subscribe();
+        assert!(VirtualMachineBackend::request_stop(&mut vm).unwrap());
+        vm.advance(Duration::from_secs(4));
+        assert_eq!(
+            VirtualMachineBackend::state(&vm),
+            VZVirtualMachineStateRunning
+        );
+        vm.advance(Duration::from_secs(1));
+        assert_eq!(
+            VirtualMachineBackend::state(&vm),
+            VZVirtualMachineStateStopped
+        );
+        assert_eq!(
+            events.try_recv(),
+            Some(VirtualMachineEvent::StateChanged(
+                VZVirtualMachineStateStopped
+            ))
+        );
+        assert_eq!(events.try_recv(), Some(VirtualMachineEvent::GuestDidStop));
+    }
+
+    #[test]
+    fn ignored_stop_request_can_be_overtaken_by_a_stop() {
+        let mut vm = MockVirtualMachineBuilder::new()
+            .ignore_stop_requests()
+            .build();
+        vm.start(Box::new(|_| {}));
+        assert!(VirtualMachineBackend::request_stop(&mut vm).unwrap());
+        vm.advance(Duration::from_secs(3600));
+        assert_eq!(
+            VirtualMachineBackend::state(&vm),
+            VZVirtualMachineStateRunning
+        );
+
+        let (completion_handler, result) = recorder();
+        vm.stop(completion_handler);
+        assert!(taken(&result).unwrap().is_ok());
+        assert_eq!(
+            VirtualMachineBackend::state(&vm),
+            VZVirtualMachineStateStopped
+        );
+    }
+
+    #[test]
+    fn guest_stop_cancels_the_pending_operation() {
+        let mut vm = MockVirtualMachineBuilder::new()
+            .pause_delay(Duration::from_secs(1))
+            .build();
+        vm.start(Box::new(|_| {}));
+        let (completion_handler, result) = recorder();
+        vm.pause(completion_handler);
+        vm.guest_stop();
+        assert_eq!(
+            taken(&result).unwrap().unwrap_err().vz_code(),
+            Some(VZErrorCode::OperationCancelled)
+        );
+        assert!(!vm.is_transitioning());
+        assert_eq!(
+            VirtualMachineBackend::state(&vm),
+            VZVirtualMachineStateStopped
+        );
+    }
+
+    #[test]
+    fn crash_fails_the_pending_operation() {
+        let mut vm = MockVirtualMachineBuilder::new()
+            .resume_delay(Duration::from_secs(1))
+            .build();
+        vm.start(Box::new(|_| {}));
+        vm.pause(Box::new(|_| {}));
+        let events = vm.subscribe();
+        let (completion_handler, result) = recorder();
+        vm.resume(completion_handler);
+        let error = FrameworkError::new(VZErrorCode::Internal, "crashed");
+        vm.crash(error.clone());
+        assert!(taken(&result).unwrap().is_err());
+        assert_eq!(
+            VirtualMachineBackend::state(&vm),
+            VZVirtualMachineStateError
+        );
+        let events: Vec<_> = std::iter::from_fn(|| events.try_recv()).collect();
+        assert_eq!(
+            events.last(),
+            Some(&VirtualMachineEvent::DidStopWithError(error))
+        );
+    }
+
+    #[test]
+    fn force_stop_moves_the_clock_to_the_end_of_the_stop() {
+        let mut vm = MockVirtualMachineBuilder::new()
+            .stop_delay(Duration::from_secs(3))
+            .build();
+        vm.start(Box::new(|_| {}));
+        ShutdownTarget::force_stop(&mut vm).unwrap();
+        assert_eq!(vm.now(), Duration::from_secs(3));
+        assert_eq!(
+            VirtualMachineBackend::state(&vm),
+            VZVirtualMachineStateStopped
+        );
+    }
+}
@@ -10,5 +10,14 @@ pub mod storage_device;
 pub mod virtual_machine;
 pub mod image_installer;
 pub mod macos_boot_loader;
+pub mod efi_boot_loader;
 pub mod mac_platform_configuration;
 pub mod graphics_device;
+pub mod backend;
+pub mod mock;
+pub mod future;
+pub mod lifecycle;
+pub mod events;
+pub mod delegate;
+pub mod handle;
+pub mod shutdown;
@@ -2,8 +2,8 @@
 
 use crate::base::{Id, NSString};
 
-use objc::rc::StrongPtr;
-use objc::{class, msg_send, sel, sel_impl};
+use crate::sys::StrongPtr;
+use crate::sys::{class, msg_send, sel, sel_impl};
 
 /// common behaviors for network device attachment
 pub trait VZNetworkDeviceAttachment {
@@ -22,6 +22,12 @@ impl VZNATNetworkDeviceAttachment {
     }
 }
 
+impl Default for VZNATNetworkDeviceAttachment {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl VZNetworkDeviceAttachment for VZNATNetworkDeviceAttachment {
     fn id(&self) -> Id {
         *self.0
@@ -82,12 +88,20 @@ impl VZMACAddress {
 
     pub fn init_with_string(s: &str) -> VZMACAddress {
         let string = NSString::new(s);
-        let p =
-            unsafe { StrongPtr::new(msg_send![class!(VZMACAddress), initWithString:*string.0]) };
+        let p = unsafe {
+            let obj: Id = msg_send![class!(VZMACAddress), alloc];
+            StrongPtr::new(msg_send![obj, initWithString:*string.0])
+        };
         VZMACAddress(p)
     }
 }
 
+impl Default for VZMACAddress {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 /// common configure of network device
 pub trait VZNetworkDeviceConfiguration {
     fn id(&self) -> Id;
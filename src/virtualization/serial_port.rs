@@ -2,8 +2,8 @@
 
 use crate::base::{Id, NSFileHandle};
 
-use objc::rc::StrongPtr;
-use objc::{class, msg_send, sel, sel_impl};
+use crate::sys::StrongPtr;
+use crate::sys::{class, msg_send, sel, sel_impl};
 
 /// common configure for serial port attachment
 pub trait VZSerialPortAttachment {
@@ -12,7 +12,7 @@ pub trait VZSerialPortAttachment {
 
 /// builder for VZFileHandleSerialPortAttachment
 /// # Examples
-/// ```rust
+/// ```rust,ignore
 /// let attachement = VZFileHandleSerialPortAttachmentBuilder::new()
 ///     .file_handle_for_reading(file_handle_for_reading)
 ///     .file_handle_for_writing(file_handle_for_writing)
@@ -32,13 +32,19 @@ impl VZFileHandleSerialPortAttachmentBuilder<(), ()> {
     }
 }
 
+impl Default for VZFileHandleSerialPortAttachmentBuilder<(), ()> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<R, W> VZFileHandleSerialPortAttachmentBuilder<R, W> {
     pub fn file_handle_for_reading(
         self,
         file_handle_for_reading: NSFileHandle,
     ) -> VZFileHandleSerialPortAttachmentBuilder<NSFileHandle, W> {
         VZFileHandleSerialPortAttachmentBuilder {
-            file_handle_for_reading: file_handle_for_reading,
+            file_handle_for_reading,
             file_handle_for_writing: self.file_handle_for_writing,
         }
     }
@@ -49,7 +55,7 @@ impl<R, W> VZFileHandleSerialPortAttachmentBuilder<R, W> {
     ) -> VZFileHandleSerialPortAttachmentBuilder<R, NSFileHandle> {
         VZFileHandleSerialPortAttachmentBuilder {
             file_handle_for_reading: self.file_handle_for_reading,
-            file_handle_for_writing: file_handle_for_writing,
+            file_handle_for_writing,
         }
     }
 }
@@ -0,0 +1,491 @@
+//! graceful shutdown module
+//!
+//! [`Shutdown`] asks the guest to stop, waits for the Stopped state until a deadline and then
+//! falls back to a hard stop, reporting which path was taken. An operation still in progress,
+//! like a start or a pause, is waited for first, since neither can be made during it. It drives
+//! any [`ShutdownTarget`]: a [`VirtualMachineHandle`] waits in real time, a
+//! [`MockVirtualMachine`](crate::virtualization::mock::MockVirtualMachine) on its virtual clock.
+
+use crate::{
+    dispatch::SerialQueue,
+    error::Result,
+    virtualization::{
+        backend::VirtualMachineBackend, future::block_on, handle::VirtualMachineHandle,
+        lifecycle::LifecycleOperation, virtual_machine::VZVirtualMachineState,
+    },
+};
+
+use std::sync::OnceLock;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// virtual machine that can be shut down
+pub trait ShutdownTarget {
+    fn state(&mut self) -> VZVirtualMachineState;
+
+    /// ask the guest to stop, returns whether the request was delivered
+    fn request_stop(&mut self) -> Result<bool>;
+
+    /// stop without giving the guest a chance to shut down and wait until it is done
+    fn force_stop(&mut self) -> Result<()>;
+
+    /// monotonic time, only differences between values matter
+    fn now(&mut self) -> Duration;
+
+    /// let `duration` pass
+    fn sleep(&mut self, duration: Duration);
+}
+
+/// why the guest did not stop by itself
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FallbackReason {
+    /// the guest did not stop before the deadline
+    Timeout,
+    /// a stop request cannot be made in this state
+    RequestNotAllowed(VZVirtualMachineState),
+    /// the stop request failed with this message
+    RequestFailed(String),
+    /// the stop request was not delivered to the guest
+    RequestRejected,
+    /// the virtual machine entered the Error state while waiting
+    EnteredErrorState,
+}
+
+/// path a shutdown took
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ShutdownOutcome {
+    /// the virtual machine was not running, or stopped once the operation in progress finished
+    AlreadyStopped,
+    /// the guest stopped by itself
+    Graceful,
+    /// the virtual machine was stopped without the guest
+    Forced(FallbackReason),
+    /// the guest did not stop and forcing was disabled or failed, or the virtual machine was
+    /// still in a transitional state at the deadline
+    NotStopped(FallbackReason),
+}
+
+/// result of a shutdown
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShutdownReport {
+    pub outcome: ShutdownOutcome,
+    /// time from the start of the shutdown until it was decided
+    pub elapsed: Duration,
+    /// state of the virtual machine at the end
+    pub state: VZVirtualMachineState,
+    /// message of the hard stop that failed, the outcome is then `NotStopped`
+    pub force_stop_error: Option<String>,
+}
+
+/// builder for Shutdown
+/// # Examples
+/// ```rust
+/// use std::time::Duration;
+/// use virtualization_rs::virtualization::mock::MockVirtualMachineBuilder;
+/// use virtualization_rs::virtualization::shutdown::{FallbackReason, ShutdownBuilder, ShutdownOutcome};
+/// use virtualization_rs::virtualization::backend::VirtualMachineBackend;
+///
+/// let mut vm = MockVirtualMachineBuilder::new().ignore_stop_requests().build();
+/// vm.start(Box::new(|result| result.unwrap()));
+///
+/// let shutdown = ShutdownBuilder::new()
+///     .timeout(Duration::from_secs(30))
+///     .poll_interval(Duration::from_secs(1))
+///     .build();
+/// let report = shutdown.run(&mut vm).unwrap();
+/// assert_eq!(report.outcome, ShutdownOutcome::Forced(FallbackReason::Timeout));
+/// assert_eq!(report.elapsed, Duration::from_secs(30));
+/// ```
+pub struct ShutdownBuilder {
+    timeout: Duration,
+    poll_interval: Duration,
+    force: bool,
+}
+
+impl ShutdownBuilder {
+    pub fn new() -> Self {
+        ShutdownBuilder {
+            timeout: Duration::from_secs(60),
+            poll_interval: Duration::from_millis(250),
+            force: true,
+        }
+    }
+}
+
+impl Default for ShutdownBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ShutdownBuilder {
+    /// time the guest is given to stop, 60 seconds by default
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// interval between two checks of the state, 250 milliseconds by default
+    pub fn poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    /// whether to fall back to a hard stop, enabled by default
+    pub fn force(mut self, force: bool) -> Self {
+        self.force = force;
+        self
+    }
+
+    pub fn build(self) -> Shutdown {
+        Shutdown {
+            timeout: self.timeout,
+            poll_interval: self.poll_interval.max(Duration::from_millis(1)),
+            force: self.force,
+        }
+    }
+}
+
+/// shutdown policy
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    timeout: Duration,
+    poll_interval: Duration,
+    force: bool,
+}
+
+impl Shutdown {
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub fn poll_interval(&self) -> Duration {
+        self.poll_interval
+    }
+
+    pub fn force(&self) -> bool {
+        self.force
+    }
+
+    /// shut `target` down
+    ///
+    /// A failed hard stop is reported in [`ShutdownReport::force_stop_error`] rather than
+    /// returned, so the report is never lost.
+    pub fn run<T: ShutdownTarget + ?Sized>(&self, target: &mut T) -> Result<ShutdownReport> {
+        let started = target.now();
+        let state = self.settle(target, started);
+        if state == VZVirtualMachineState::VZVirtualMachineStateStopped {
+            return Ok(ShutdownReport {
+                outcome: ShutdownOutcome::AlreadyStopped,
+                elapsed: target.now() - started,
+                state,
+                force_stop_error: None,
+            });
+        }
+
+        let reason = if is_transitional(state) {
+            FallbackReason::Timeout
+        } else if !LifecycleOperation::RequestStop.is_allowed(state) {
+            FallbackReason::RequestNotAllowed(state)
+        } else {
+            match target.request_stop() {
+                Ok(true) => match self.wait(target, started) {
+                    Waited::Stopped(elapsed) => {
+                        return Ok(ShutdownReport {
+                            outcome: ShutdownOutcome::Graceful,
+                            elapsed,
+                            state: VZVirtualMachineState::VZVirtualMachineStateStopped,
+                            force_stop_error: None,
+                        })
+                    }
+                    Waited::Error => FallbackReason::EnteredErrorState,
+                    Waited::Deadline => FallbackReason::Timeout,
+                },
+                Ok(false) => FallbackReason::RequestRejected,
+                Err(err) => FallbackReason::RequestFailed(err.to_string()),
+            }
+        };
+
+        let elapsed = target.now() - started;
+        let mut force_stop_error = None;
+        let outcome = if self.force && LifecycleOperation::Stop.is_allowed(target.state()) {
+            match target.force_stop() {
+                Ok(()) => ShutdownOutcome::Forced(reason),
+                Err(err) => {
+                    force_stop_error = Some(err.to_string());
+                    ShutdownOutcome::NotStopped(reason)
+                }
+            }
+        } else {
+            ShutdownOutcome::NotStopped(reason)
+        };
+        Ok(ShutdownReport {
+            outcome,
+            elapsed,
+            state: target.state(),
+            force_stop_error,
+        })
+    }
+
+    /// poll the state of `target` until it leaves a transitional state or the deadline passes
+    fn settle<T: ShutdownTarget + ?Sized>(
+        &self,
+        target: &mut T,
+        started: Duration,
+    ) -> VZVirtualMachineState {
+        loop {
+            let state = target.state();
+            let elapsed = target.now() - started;
+            if !is_transitional(state) || elapsed >= self.timeout {
+                return state;
+            }
+            target.sleep(self.poll_interval.min(self.timeout - elapsed));
+        }
+    }
+
+    /// poll the state of `target` until it stops, fails or the deadline passes
+    fn wait<T: ShutdownTarget + ?Sized>(&self, target: &mut T, started: Duration) -> Waited {
+        loop {
+            let elapsed = target.now() - started;
+            match target.state() {
+                VZVirtualMachineState::VZVirtualMachineStateStopped => {
+                    return Waited::Stopped(elapsed)
+                }
+                VZVirtualMachineState::VZVirtualMachineStateError => return Waited::Error,
+                _ => {}
+            }
+            if elapsed >= self.timeout {
+                return Waited::Deadline;
+            }
+            target.sleep(self.poll_interval.min(self.timeout - elapsed));
+        }
+    }
+}
+
+/// whether `state` is the intermediate state of an operation in progress
+fn is_transitional(state: VZVirtualMachineState) -> bool {
+    LifecycleOperation::ALL.iter().any(|operation| {
+        operation
+            .transition()
+            .is_some_and(|(through, _)| through == state)
+    })
+}
+
+enum Waited {
+    Stopped(Duration),
+    Error,
+    Deadline,
+}
+
+/// time since the first call, shared by every handle
+fn monotonic_now() -> Duration {
+    static EPOCH: OnceLock<Instant> = OnceLock::new();
+    EPOCH.get_or_init(Instant::now).elapsed()
+}
+
+impl<B: VirtualMachineBackend + 'static, Q: SerialQueue> ShutdownTarget
+    for VirtualMachineHandle<B, Q>
+{
+    fn state(&mut self) -> VZVirtualMachineState {
+        VirtualMachineHandle::state(self)
+    }
+
+    fn request_stop(&mut self) -> Result<bool> {
+        VirtualMachineHandle::request_stop(self)
+    }
+
+    fn force_stop(&mut self) -> Result<()> {
+        block_on(self.stop())
+    }
+
+    fn now(&mut self) -> Duration {
+        monotonic_now()
+    }
+
+    fn sleep(&mut self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::{FrameworkError, VZErrorCode};
+    use crate::virtualization::mock::{
+        MockOperation, MockVirtualMachine, MockVirtualMachineBuilder,
+    };
+
+    use VZVirtualMachineState::*;
+
+    fn shutdown() -> Shutdown {
+        ShutdownBuilder::new()
+            .timeout(Duration::from_secs(30))
+            .poll_interval(Duration::from_secs(1))
+            .build()
+    }
+
+    fn running(builder: MockVirtualMachineBuilder) -> MockVirtualMachine {
+        let mut vm = builder.build();
+        vm.start(Box::new(|result| result.unwrap()));
+        vm.advance(Duration::from_secs(60));
+        vm
+    }
+
+    #[test]
+    fn guest_stops_before_the_deadline() {
+        let mut vm =
+            running(MockVirtualMachineBuilder::new().guest_stop_delay(Duration::from_secs(5)));
+        let report = shutdown().run(&mut vm).unwrap();
+        assert_eq!(report.outcome, ShutdownOutcome::Graceful);
+        assert_eq!(report.elapsed, Duration::from_secs(5));
+        assert_eq!(report.state, VZVirtualMachineStateStopped);
+    }
+
+    #[test]
+    fn stopped_virtual_machine_is_left_alone() {
+        let mut vm = MockVirtualMachineBuilder::new().build();
+        let report = shutdown().run(&mut vm).unwrap();
+        assert_eq!(report.outcome, ShutdownOutcome::AlreadyStopped);
+        assert_eq!(report.elapsed, Duration::ZERO);
+        assert_eq!(vm.transitions().len(), 0);
+    }
+
+    #[test]
+    fn timeout_falls_back_to_a_hard_stop() {
+        let mut vm = running(MockVirtualMachineBuilder::new().ignore_stop_requests());
+        let report = shutdown().run(&mut vm).unwrap();
+        assert_eq!(
+            report.outcome,
+            ShutdownOutcome::Forced(FallbackReason::Timeout)
+        );
+        assert_eq!(report.elapsed, Duration::from_secs(30));
+        assert_eq!(report.state, VZVirtualMachineStateStopped);
+        assert_eq!(report.force_stop_error, None);
+    }
+
+    #[test]
+    fn failed_hard_stop_is_reported() {
+        let mut vm = running(
+            MockVirtualMachineBuilder::new()
+                .ignore_stop_requests()
+                .fail(
+                    MockOperation::Stop,
+                    FrameworkError::new(VZErrorCode::Internal, "stop failed"),
+                ),
+        );
+        let report = shutdown().run(&mut vm).unwrap();
+        assert_eq!(
+            report.outcome,
+            ShutdownOutcome::NotStopped(FallbackReason::Timeout)
+        );
+        assert_eq!(report.elapsed, Duration::from_secs(30));
+        assert_eq!(report.state, VZVirtualMachineStateRunning);
+        assert!(report.force_stop_error.unwrap().contains("stop failed"));
+    }
+
+    #[test]
+    fn timeout_without_force_leaves_the_guest_running() {
+        let mut vm = running(MockVirtualMachineBuilder::new().ignore_stop_requests());
+        let shutdown = ShutdownBuilder::new()
+            .timeout(Duration::from_secs(10))
+            .force(false)
+            .build();
+        let report = shutdown.run(&mut vm).unwrap();
+        assert_eq!(
+            report.outcome,
+            ShutdownOutcome::NotStopped(FallbackReason::Timeout)
+        );
+        assert_eq!(report.state, VZVirtualMachineStateRunning);
+    }
+
+    #[test]
+    fn failed_request_falls_back_to_a_hard_stop() {
+        let mut vm = running(MockVirtualMachineBuilder::new().fail(
+            MockOperation::RequestStop,
+            FrameworkError::new(VZErrorCode::Internal, "request failed"),
+        ));
+        let report = shutdown().run(&mut vm).unwrap();
+        match report.outcome {
+            ShutdownOutcome::Forced(FallbackReason::RequestFailed(_)) => {}
+            other => panic!("unexpected outcome {:?}", other),
+        }
+        assert_eq!(report.elapsed, Duration::ZERO);
+        assert_eq!(report.state, VZVirtualMachineStateStopped);
+    }
+
+    #[test]
+    fn paused_and_failed_virtual_machines_are_stopped_hard() {
+        let mut vm = running(MockVirtualMachineBuilder::new());
+        vm.pause(Box::new(|result| result.unwrap()));
+        let report = shutdown().run(&mut vm).unwrap();
+        assert_eq!(
+            report.outcome,
+            ShutdownOutcome::Forced(FallbackReason::RequestNotAllowed(
+                VZVirtualMachineStatePaused
+            ))
+        );
+        assert_eq!(report.state, VZVirtualMachineStateStopped);
+
+        let mut vm = running(MockVirtualMachineBuilder::new());
+        vm.crash(FrameworkError::new(VZErrorCode::Internal, "crashed"));
+        let report = shutdown().run(&mut vm).unwrap();
+        assert_eq!(
+            report.outcome,
+            ShutdownOutcome::Forced(FallbackReason::RequestNotAllowed(
+                VZVirtualMachineStateError
+            ))
+        );
+        assert_eq!(report.state, VZVirtualMachineStateStopped);
+    }
+
+    #[test]
+    fn starting_virtual_machine_is_waited_for_then_asked_to_stop() {
+        let mut vm = MockVirtualMachineBuilder::new()
+            .start_delay(Duration::from_secs(3))
+            .guest_stop_delay(Duration::from_secs(5))
+            .build();
+        vm.start(Box::new(|result| result.unwrap()));
+        let report = shutdown().run(&mut vm).unwrap();
+        assert_eq!(report.outcome, ShutdownOutcome::Graceful);
+        assert_eq!(report.elapsed, Duration::from_secs(8));
+    }
+
+    #[test]
+    fn pausing_virtual_machine_is_waited_for_then_stopped_hard() {
+        let mut vm = running(MockVirtualMachineBuilder::new().pause_delay(Duration::from_secs(2)));
+        vm.pause(Box::new(|result| result.unwrap()));
+        let report = shutdown().run(&mut vm).unwrap();
+        assert_eq!(
+            report.outcome,
+            ShutdownOutcome::Forced(FallbackReason::RequestNotAllowed(
+                VZVirtualMachineStatePaused
+            ))
+        );
+        assert_eq!(report.elapsed, Duration::from_secs(2));
+        assert_eq!(report.state, VZVirtualMachineStateStopped);
+    }
+
+    #[test]
+    fn stop_in_progress_counts_as_stopped() {
+        let mut vm = running(MockVirtualMachineBuilder::new().stop_delay(Duration::from_secs(2)));
+        vm.stop(Box::new(|result| result.unwrap()));
+        let report = shutdown().run(&mut vm).unwrap();
+        assert_eq!(report.outcome, ShutdownOutcome::AlreadyStopped);
+        assert_eq!(report.elapsed, Duration::from_secs(2));
+    }
+
+    #[test]
+    fn transition_outlasting_the_deadline_is_not_forced() {
+        let mut vm = MockVirtualMachineBuilder::new()
+            .start_delay(Duration::from_secs(60))
+            .build();
+        vm.start(Box::new(|_| {}));
+        let report = shutdown().run(&mut vm).unwrap();
+        assert_eq!(
+            report.outcome,
+            ShutdownOutcome::NotStopped(FallbackReason::Timeout)
+        );
+        assert_eq!(report.elapsed, Duration::from_secs(30));
+        assert_eq!(report.state, VZVirtualMachineStateStarting);
+    }
+}
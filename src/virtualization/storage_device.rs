@@ -1,26 +1,83 @@
 //! storage device module
 
-use crate::base::{Id, NSError, NSURL};
+use crate::base::{Id, NSError, NIL, NSURL};
+use crate::disk::{clone::clone_disk, raw::RawDiskImageBuilder};
+use crate::error::{Error, Result};
+use crate::lock::{FileLock, LockMode};
+use crate::version::compare_versions;
 
-use objc::runtime::BOOL;
-use objc::{class, msg_send, sel, sel_impl};
-use objc::{rc::StrongPtr, runtime::NO, runtime::YES};
+use crate::sys::{self, BOOL};
+use crate::sys::{class, msg_send, sel, sel_impl};
+use crate::sys::{StrongPtr, NO, YES};
+
+use std::cmp::Ordering;
+use std::fs;
+use std::path::PathBuf;
 
 /// common configure of storage device attachment
 pub trait VZStorageDeviceAttachment {
     fn id(&self) -> Id;
+
+    /// lock of the attached file, kept by the configuration using the attachment
+    fn take_lock(&mut self) -> Option<FileLock> {
+        None
+    }
+}
+
+/// first macOS version with the caching and synchronization modes of disk image attachments
+pub const DISK_IMAGE_MODES_MACOS_VERSION: &str = "12.0";
+
+/// whether macOS `version` supports caching and synchronization modes other than the defaults
+pub fn supports_disk_image_modes(version: &str) -> bool {
+    compare_versions(version, DISK_IMAGE_MODES_MACOS_VERSION) != Ordering::Less
+}
+
+/// how the host caches the data of a disk image, requires macOS 12 unless automatic
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum VZDiskImageCachingMode {
+    /// the framework chooses, the default
+    #[default]
+    Automatic = 0,
+    /// the host does not cache the data, which suits disks also accessed outside the guest
+    Uncached = 1,
+    Cached = 2,
+}
+
+/// how writes of the guest reach the disk image, requires macOS 12 unless full
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum VZDiskImageSynchronizationMode {
+    /// flushes of the guest are written to permanent storage, the default
+    #[default]
+    Full = 1,
+    /// flushes of the guest only `fsync(2)` the image, so data may be lost on a power failure
+    Fsync = 2,
+    /// flushes of the guest are ignored, for disposable disks only
+    None = 3,
 }
 
 /// builder for VZDiskImageStorageDeviceAttachment
+///
+/// The disk image is locked when the attachment is built, shared when it is read-only and
+/// exclusively otherwise, see [`FileLock`]. Building fails with
+/// [`Error::Locked`] while another process uses it. A read-only image in a directory that cannot
+/// hold a lock file, such as a read-only mount, is attached without a lock.
+///
+/// With [`create`](Self::create) a sparse raw image is created at the path first, with
+/// [`clone_from`](Self::clone_from) a template is cloned there. Such an image is removed again
+/// when the attachment cannot be built, so it is created and attached together or not at all.
+///
+/// Building with a [`caching_mode`](Self::caching_mode) or
+/// [`synchronization_mode`](Self::synchronization_mode) other than the default fails with
+/// [`Error::Platform`] before macOS 12.
 /// # Examples
-/// ```rust
+/// ```rust,ignore
 /// let block_attachment = match VZDiskImageStorageDeviceAttachmentBuilder::new()
 ///     .path(canonicalize(&disk).unwrap().into_os_string().into_string().unwrap())
 ///     .build()
 /// {
 ///     Ok(x) => x,
 ///     Err(err) => {
-///         err.dump();
+///         println!("{}", err);
 ///         return;
 ///     }
 /// };
@@ -28,6 +85,17 @@ pub trait VZStorageDeviceAttachment {
 pub struct VZDiskImageStorageDeviceAttachmentBuilder<Path, ReadOnly> {
     path: Path,
     read_only: ReadOnly,
+    caching_mode: VZDiskImageCachingMode,
+    synchronization_mode: VZDiskImageSynchronizationMode,
+    lock: bool,
+    image: NewImage,
+}
+
+/// image created at the path of the attachment when building
+enum NewImage {
+    None,
+    Create(u64),
+    Clone(PathBuf),
 }
 
 impl VZDiskImageStorageDeviceAttachmentBuilder<(), bool> {
@@ -35,10 +103,20 @@ impl VZDiskImageStorageDeviceAttachmentBuilder<(), bool> {
         VZDiskImageStorageDeviceAttachmentBuilder {
             path: (),
             read_only: true,
+            caching_mode: VZDiskImageCachingMode::default(),
+            synchronization_mode: VZDiskImageSynchronizationMode::default(),
+            lock: true,
+            image: NewImage::None,
         }
     }
 }
 
+impl Default for VZDiskImageStorageDeviceAttachmentBuilder<(), bool> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<Path, ReadOnly> VZDiskImageStorageDeviceAttachmentBuilder<Path, ReadOnly> {
     pub fn path<T: Into<String>>(
         self,
@@ -47,6 +125,10 @@ impl<Path, ReadOnly> VZDiskImageStorageDeviceAttachmentBuilder<Path, ReadOnly> {
         VZDiskImageStorageDeviceAttachmentBuilder {
             path: path.into(),
             read_only: self.read_only,
+            caching_mode: self.caching_mode,
+            synchronization_mode: self.synchronization_mode,
+            lock: self.lock,
+            image: self.image,
         }
     }
 
@@ -56,36 +138,134 @@ impl<Path, ReadOnly> VZDiskImageStorageDeviceAttachmentBuilder<Path, ReadOnly> {
     ) -> VZDiskImageStorageDeviceAttachmentBuilder<Path, bool> {
         VZDiskImageStorageDeviceAttachmentBuilder {
             path: self.path,
-            read_only: read_only,
+            read_only,
+            caching_mode: self.caching_mode,
+            synchronization_mode: self.synchronization_mode,
+            lock: self.lock,
+            image: self.image,
         }
     }
+
+    /// how the host caches the data of the image, see [`VZDiskImageCachingMode`]
+    pub fn caching_mode(mut self, caching_mode: VZDiskImageCachingMode) -> Self {
+        self.caching_mode = caching_mode;
+        self
+    }
+
+    /// how writes of the guest reach the image, see [`VZDiskImageSynchronizationMode`]
+    pub fn synchronization_mode(
+        mut self,
+        synchronization_mode: VZDiskImageSynchronizationMode,
+    ) -> Self {
+        self.synchronization_mode = synchronization_mode;
+        self
+    }
+
+    /// whether to lock the disk image, enabled by default
+    pub fn lock(mut self, lock: bool) -> Self {
+        self.lock = lock;
+        self
+    }
+
+    /// create a sparse raw image of `size` bytes at the path when building, which must not exist
+    pub fn create(mut self, size: u64) -> Self {
+        self.image = NewImage::Create(size);
+        self
+    }
+
+    /// clone the disk image `template` to the path when building, which must not exist, see
+    /// [`clone_disk`]
+    pub fn clone_from<T: Into<PathBuf>>(mut self, template: T) -> Self {
+        self.image = NewImage::Clone(template.into());
+        self
+    }
 }
 
 impl VZDiskImageStorageDeviceAttachmentBuilder<String, bool> {
-    pub fn build(self) -> Result<VZDiskImageStorageDeviceAttachment, NSError> {
+    pub fn build(self) -> Result<VZDiskImageStorageDeviceAttachment> {
+        let modes = if self.caching_mode == VZDiskImageCachingMode::default()
+            && self.synchronization_mode == VZDiskImageSynchronizationMode::default()
+        {
+            None
+        } else {
+            if let Some(version) = sys::macos_version() {
+                if !supports_disk_image_modes(&version) {
+                    return Err(Error::Platform(format!(
+                        "disk image caching and synchronization modes require macOS {}, running {}",
+                        DISK_IMAGE_MODES_MACOS_VERSION, version
+                    )));
+                }
+            }
+            Some((self.caching_mode, self.synchronization_mode))
+        };
+        let lock = if !self.lock {
+            None
+        } else if self.read_only {
+            FileLock::acquire_shared_unless_read_only(self.path.as_ref())?
+        } else {
+            Some(FileLock::acquire(&self.path, LockMode::Exclusive)?)
+        };
+        match &self.image {
+            NewImage::None => {}
+            NewImage::Create(size) => {
+                RawDiskImageBuilder::new()
+                    .path(&self.path)
+                    .size(*size)
+                    .build()?;
+            }
+            NewImage::Clone(template) => {
+                clone_disk(template, &self.path)?;
+            }
+        }
         let read_only = if self.read_only { YES } else { NO };
-        unsafe { VZDiskImageStorageDeviceAttachment::new(self.path.as_str(), read_only) }
+        let attachment = unsafe {
+            VZDiskImageStorageDeviceAttachment::new(self.path.as_str(), read_only, modes)
+        };
+        match attachment {
+            Ok(attachment) => Ok(VZDiskImageStorageDeviceAttachment(attachment.0, lock)),
+            Err(err) => {
+                if !matches!(self.image, NewImage::None) {
+                    let _ = fs::remove_file(&self.path);
+                }
+                // dropping the lock after the image removes its lock file too
+                drop(lock);
+                Err(err)
+            }
+        }
     }
 }
 
 /// configure of disk image storage device attachment
-pub struct VZDiskImageStorageDeviceAttachment(StrongPtr);
+pub struct VZDiskImageStorageDeviceAttachment(StrongPtr, Option<FileLock>);
 
 impl VZDiskImageStorageDeviceAttachment {
+    /// `modes` uses the initializer of macOS 12, `None` the one of macOS 11
     unsafe fn new(
         path: &str,
         read_only: BOOL,
-    ) -> Result<VZDiskImageStorageDeviceAttachment, NSError> {
+        modes: Option<(VZDiskImageCachingMode, VZDiskImageSynchronizationMode)>,
+    ) -> Result<VZDiskImageStorageDeviceAttachment> {
         let i: Id = msg_send![class!(VZDiskImageStorageDeviceAttachment), alloc];
         let path_nsurl = NSURL::file_url_with_path(path, false);
-        let error = NSError::nil();
-        let p = StrongPtr::new(
-            msg_send![i, initWithURL:*path_nsurl.0 readOnly:read_only error:&(*error.0)],
-        );
-        if error.code() != 0 {
-            Err(error)
+        let mut error: Id = NIL;
+        let p = match modes {
+            None => StrongPtr::new(
+                msg_send![i, initWithURL:*path_nsurl.0 readOnly:read_only error:&mut error],
+            ),
+            Some((caching_mode, synchronization_mode)) => {
+                let caching_mode = caching_mode as isize;
+                let synchronization_mode = synchronization_mode as isize;
+                StrongPtr::new(msg_send![i, initWithURL:*path_nsurl.0
+                    readOnly:read_only
+                    cachingMode:caching_mode
+                    synchronizationMode:synchronization_mode
+                    error:&mut error])
+            }
+        };
+        if error != NIL {
+            Err(NSError(StrongPtr::retain(error)).into())
         } else {
-            Ok(VZDiskImageStorageDeviceAttachment(p))
+            Ok(VZDiskImageStorageDeviceAttachment(p, None))
         }
     }
 }
@@ -94,22 +274,33 @@ impl VZStorageDeviceAttachment for VZDiskImageStorageDeviceAttachment {
     fn id(&self) -> Id {
         *self.0
     }
+
+    fn take_lock(&mut self) -> Option<FileLock> {
+        self.1.take()
+    }
 }
 
 /// configure of storage device
 pub trait VZStorageDeviceConfiguration {
     fn id(&self) -> Id;
+
+    /// lock of the attached file, kept by the virtual machine configuration
+    fn take_lock(&mut self) -> Option<FileLock> {
+        None
+    }
 }
 
 /// configure of storage device through the Virtio interface
-pub struct VZVirtioBlockDeviceConfiguration(StrongPtr);
+pub struct VZVirtioBlockDeviceConfiguration(StrongPtr, Option<FileLock>);
 
 impl VZVirtioBlockDeviceConfiguration {
-    pub fn new<T: VZStorageDeviceAttachment>(attachment: T) -> VZVirtioBlockDeviceConfiguration {
+    pub fn new<T: VZStorageDeviceAttachment>(
+        mut attachment: T,
+    ) -> VZVirtioBlockDeviceConfiguration {
         unsafe {
             let i: Id = msg_send![class!(VZVirtioBlockDeviceConfiguration), alloc];
             let p = StrongPtr::new(msg_send![i, initWithAttachment:attachment.id()]);
-            VZVirtioBlockDeviceConfiguration(p)
+            VZVirtioBlockDeviceConfiguration(p, attachment.take_lock())
         }
     }
 }
@@ -118,4 +309,69 @@ impl VZStorageDeviceConfiguration for VZVirtioBlockDeviceConfiguration {
     fn id(&self) -> Id {
         *self.0
     }
+
+    fn take_lock(&mut self) -> Option<FileLock> {
+        self.1.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lock::lock_file_path;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn disk_image_modes_require_macos_12() {
+        assert!(!supports_disk_image_modes("11.6.2"));
+        assert!(supports_disk_image_modes("12.0"));
+        assert!(supports_disk_image_modes("13.1"));
+    }
+
+    #[test]
+    fn disk_image_modes_match_the_framework() {
+        assert_eq!(VZDiskImageCachingMode::default() as isize, 0);
+        assert_eq!(VZDiskImageCachingMode::Uncached as isize, 1);
+        assert_eq!(VZDiskImageCachingMode::Cached as isize, 2);
+        assert_eq!(VZDiskImageSynchronizationMode::default() as isize, 1);
+        assert_eq!(VZDiskImageSynchronizationMode::Fsync as isize, 2);
+        assert_eq!(VZDiskImageSynchronizationMode::None as isize, 3);
+    }
+
+    #[test]
+    fn attaches_read_only_images_in_read_only_directories() {
+        let dir = std::env::temp_dir().join(format!("attach-read-only-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("installer.iso");
+        fs::write(&path, b"installer").unwrap();
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o555)).unwrap();
+
+        let attachment = VZDiskImageStorageDeviceAttachmentBuilder::new()
+            .path(path.to_str().unwrap())
+            .read_only(true)
+            .build();
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o755)).unwrap();
+        drop(attachment.unwrap());
+        assert!(!lock_file_path(&path).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn locks_writable_images_exclusively() {
+        let dir = std::env::temp_dir().join(format!("attach-writable-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("disk.img");
+        fs::write(&path, b"disk").unwrap();
+
+        let lock = FileLock::acquire(&path, LockMode::Shared).unwrap();
+        let attachment = VZDiskImageStorageDeviceAttachmentBuilder::new()
+            .path(path.to_str().unwrap())
+            .read_only(false)
+            .build();
+        assert!(matches!(attachment, Err(Error::Locked { .. })));
+        drop(lock);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
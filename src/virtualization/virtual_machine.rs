@@ -1,7 +1,12 @@
 //! virtual machine module
 
 use crate::{
-    base::{Id, NSArray, NSError},
+    base::{Id, NSArray, NSError, NIL},
+    dispatch::{AssertSend, DispatchQueue, Queue},
+    error::{Error, Result},
+    lock::FileLock,
+    sys,
+    version::compare_versions,
     virtualization::mac_platform_configuration::VZMacPlatformConfiguration,
     virtualization::boot_loader::VZBootLoader,
     virtualization::entropy_device::VZEntropyDeviceConfiguration,
@@ -13,14 +18,39 @@ use crate::{
     virtualization::graphics_device::VZMacGraphicsDeviceConfiguration,
 };
 
-use block::Block;
-use objc::runtime::BOOL;
-use objc::{class, msg_send, sel, sel_impl};
-use objc::{rc::StrongPtr, runtime::YES};
+use crate::sys::Block;
+use crate::sys::BOOL;
+use crate::sys::{class, msg_send, sel, sel_impl};
+use crate::sys::{StrongPtr, YES};
+
+use std::cmp::Ordering;
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+/// first macOS version that can stop a virtual machine without asking the guest
+pub const STOP_MACOS_VERSION: &str = "12.0";
+
+/// whether macOS `version` can stop a virtual machine without asking the guest
+pub fn supports_stop(version: &str) -> bool {
+    compare_versions(version, STOP_MACOS_VERSION) != Ordering::Less
+}
+
+/// error unless the running macOS can stop a virtual machine without asking the guest
+pub(crate) fn check_stop_supported() -> Result<()> {
+    if let Some(version) = sys::macos_version() {
+        if !supports_stop(&version) {
+            return Err(Error::Platform(format!(
+                "stopping a virtual machine requires macOS {}, running {}",
+                STOP_MACOS_VERSION, version
+            )));
+        }
+    }
+    Ok(())
+}
 
 /// builder for VZVirtualMachineConfiguration
 /// # Examples
-/// ```rust
+/// ```rust,ignore
 /// let conf = VZVirtualMachineConfigurationBuilder::new()
 ///     .boot_loader(boot_loader)
 ///     .cpu_count(cpu_count)
@@ -118,17 +148,49 @@ impl VZVirtualMachineConfigurationBuilder {
     }
 }
 
+impl Default for VZVirtualMachineConfigurationBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// locks held for a configuration
+#[derive(Default)]
+struct ConfigurationLocks {
+    storage: Vec<FileLock>,
+    other: Vec<FileLock>,
+}
+
 /// configure of virtual machine
-pub struct VZVirtualMachineConfiguration(StrongPtr);
+///
+/// The locks of its disk images, and any added with [`hold_lock`](Self::hold_lock), are held by
+/// the configuration and then by the virtual machine created from it.
+pub struct VZVirtualMachineConfiguration(StrongPtr, ConfigurationLocks);
 
 impl VZVirtualMachineConfiguration {
     fn new() -> VZVirtualMachineConfiguration {
         unsafe {
             let obj = StrongPtr::new(msg_send![class!(VZVirtualMachineConfiguration), new]);
-            VZVirtualMachineConfiguration(obj)
+            VZVirtualMachineConfiguration(obj, ConfigurationLocks::default())
         }
     }
 
+    /// keep `lock` until the configuration, or the virtual machine created from it, is dropped
+    pub fn hold_lock(&mut self, lock: FileLock) {
+        self.1.other.push(lock);
+    }
+
+    /// locks held for the configuration
+    pub fn locks(&self) -> impl Iterator<Item = &FileLock> {
+        self.1.storage.iter().chain(self.1.other.iter())
+    }
+
+    fn into_locks(self) -> Vec<FileLock> {
+        let mut locks = self.1.storage;
+        locks.extend(self.1.other);
+        locks
+    }
+
     fn set_graphics_devices(&mut self, conf: Vec<VZMacGraphicsDeviceConfiguration>) {
         let array: NSArray<Id> = NSArray::array_with_objects(conf.into_iter().map(|value| *value.0).collect());
         unsafe {
@@ -203,7 +265,8 @@ impl VZVirtualMachineConfiguration {
         }
     }
 
-    fn set_storage_devices<T: VZStorageDeviceConfiguration>(&mut self, devices: Vec<T>) {
+    fn set_storage_devices<T: VZStorageDeviceConfiguration>(&mut self, mut devices: Vec<T>) {
+        self.1.storage = devices.iter_mut().filter_map(|x| x.take_lock()).collect();
         let device_ids = devices.iter().map(|x| x.id()).collect();
         let arr: NSArray<T> = NSArray::array_with_objects(device_ids);
         unsafe {
@@ -211,25 +274,41 @@ impl VZVirtualMachineConfiguration {
         }
     }
 
-    pub fn validate_with_error(&self) -> Result<BOOL, NSError> {
+    pub fn validate_with_error(&self) -> Result<()> {
         unsafe {
-            let error = NSError(StrongPtr::new(0 as Id));
-            let obj: BOOL = msg_send![*self.0, validateWithError: &(*error.0)];
-            if error.code() != 0 {
-                Err(error)
+            let mut error: Id = NIL;
+            let _: BOOL = msg_send![*self.0, validateWithError: &mut error];
+            if error != NIL {
+                Err(NSError(StrongPtr::retain(error)).into())
             } else {
-                Ok(obj)
+                Ok(())
             }
         }
     }
 }
 
 /// virtual machine
+///
+/// A VZVirtualMachine may only be used from the queue it was created with. The safe accessors
+/// such as [`state`](VZVirtualMachine::state) hop onto that queue with `exec_sync`, or run
+/// directly when already on it; [`on_queue`](VZVirtualMachine::on_queue) does the same for a
+/// closure receiving a [`VirtualMachineOnQueue`].
+///
+/// The locks of its configuration are released when the last clone is dropped.
 #[derive(Clone)]
-pub struct VZVirtualMachine(pub StrongPtr);
+pub struct VZVirtualMachine(pub StrongPtr, DispatchQueue, Arc<Vec<FileLock>>);
+
+/// access to a VZVirtualMachine from its queue
+///
+/// It only exists while running on the queue of the virtual machine and cannot be sent to another
+/// thread, so its methods are safe.
+pub struct VirtualMachineOnQueue<'a> {
+    vm: &'a VZVirtualMachine,
+    _not_send: PhantomData<*const ()>,
+}
 
 /// state of virtual machine
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum VZVirtualMachineState {
     /// Initial state before the virtual machine is started.
     VZVirtualMachineStateStopped,
@@ -252,16 +331,32 @@ pub enum VZVirtualMachineState {
     /// The virtual machine is being resumed. This is the intermediate state between VZVirtualMachineStatePaused and VZVirtualMachineStateRunning. */
     VZVirtualMachineStateResuming,
 
+    /// The virtual machine is being stopped by `stopWithCompletionHandler:`, available since macOS 12.
+    VZVirtualMachineStateStopping,
+
     /// Other
     Other,
 }
 
 impl VZVirtualMachine {
-    pub fn new(conf: VZVirtualMachineConfiguration, queue: Id) -> VZVirtualMachine {
+    /// virtual machine used from `queue`, prefer [`VZVirtualMachine::with_queue`]
+    ///
+    /// # Safety
+    /// `queue` must be a valid serial dispatch queue, it is retained by the virtual machine.
+    pub unsafe fn new(conf: VZVirtualMachineConfiguration, queue: Id) -> VZVirtualMachine {
+        Self::with_queue(conf, &DispatchQueue::from_raw(queue))
+    }
+
+    /// virtual machine used from `queue`
+    pub fn with_queue(
+        conf: VZVirtualMachineConfiguration,
+        queue: &DispatchQueue,
+    ) -> VZVirtualMachine {
         unsafe {
             let i: Id = msg_send![class!(VZVirtualMachine), alloc];
-            let p = StrongPtr::new(msg_send![i, initWithConfiguration:*conf.0 queue:queue]);
-            VZVirtualMachine(p)
+            let queue_raw = queue.as_raw();
+            let p = StrongPtr::new(msg_send![i, initWithConfiguration:*conf.0 queue:queue_raw]);
+            VZVirtualMachine(p, queue.clone(), Arc::new(conf.into_locks()))
         }
     }
 
@@ -269,26 +364,81 @@ impl VZVirtualMachine {
         unsafe {
             let i: Id = msg_send![class!(VZVirtualMachine), alloc];
             let p = StrongPtr::new(msg_send![i, initWithConfiguration:*conf.0]);
-            VZVirtualMachine(p)
+            VZVirtualMachine(p, DispatchQueue::main(), Arc::new(conf.into_locks()))
         }
     }
 
-    pub fn start_with_completion_handler(&mut self, completion_handler: &Block<(Id,), ()>) {
-        unsafe {
-            let _: Id = msg_send![*self.0, startWithCompletionHandler: completion_handler];
-        }
+    /// the queue the virtual machine was created with
+    pub fn queue(&self) -> &DispatchQueue {
+        &self.1
+    }
+
+    /// locks taken over from the configuration
+    pub fn locks(&self) -> &[FileLock] {
+        &self.2
+    }
+
+    /// whether the current thread is running on the queue of the virtual machine
+    pub fn is_on_queue(&self) -> bool {
+        self.1.is_current()
     }
 
-    pub unsafe fn request_stop_with_error(&mut self) -> Result<bool, NSError> {
-        let error = NSError(StrongPtr::new(0 as Id));
-        let ret: BOOL = msg_send![*self.0, requestStopWithError:*error.0];
-        if error.code() != 0 {
-            Err(error)
-        } else {
-            Ok(ret)
+    /// run `f` on the queue of the virtual machine and return its result
+    ///
+    /// Runs `f` directly when already on the queue, otherwise waits for the queue. Must not be
+    /// called from another queue that the queue of the virtual machine is itself waiting for.
+    pub fn on_queue<R, F>(&self, f: F) -> R
+    where
+        F: FnOnce(&VirtualMachineOnQueue<'_>) -> R + Send,
+        R: Send,
+    {
+        let vm = AssertSend(self as *const VZVirtualMachine);
+        self.1.exec_sync(move || f(&unsafe { (*vm.into_inner()).assume_on_queue() }))
+    }
+
+    /// # Safety
+    /// Must be called on the queue the virtual machine was created with.
+    pub unsafe fn assume_on_queue(&self) -> VirtualMachineOnQueue<'_> {
+        VirtualMachineOnQueue {
+            vm: self,
+            _not_send: PhantomData,
         }
     }
 
+    /// start the virtual machine from its queue
+    pub fn start_with_completion_handler(&mut self, completion_handler: &Block<(Id,), ()>) {
+        let completion_handler = AssertSend(completion_handler);
+        self.on_queue(move |vm| vm.start_with_completion_handler(completion_handler.into_inner()))
+    }
+
+    /// pause the virtual machine from its queue
+    pub fn pause_with_completion_handler(&mut self, completion_handler: &Block<(Id,), ()>) {
+        let completion_handler = AssertSend(completion_handler);
+        self.on_queue(move |vm| vm.pause_with_completion_handler(completion_handler.into_inner()))
+    }
+
+    /// resume the virtual machine from its queue
+    pub fn resume_with_completion_handler(&mut self, completion_handler: &Block<(Id,), ()>) {
+        let completion_handler = AssertSend(completion_handler);
+        self.on_queue(move |vm| vm.resume_with_completion_handler(completion_handler.into_inner()))
+    }
+
+    /// stop the virtual machine from its queue without giving the guest a chance to shut down
+    ///
+    /// Fails without calling `completion_handler` before macOS 12.
+    pub fn stop_with_completion_handler(
+        &mut self,
+        completion_handler: &Block<(Id,), ()>,
+    ) -> Result<()> {
+        let completion_handler = AssertSend(completion_handler);
+        self.on_queue(move |vm| vm.stop_with_completion_handler(completion_handler.into_inner()))
+    }
+
+    /// ask the guest to stop, returns whether the request was delivered
+    pub fn request_stop_with_error(&mut self) -> Result<bool> {
+        self.on_queue(|vm| vm.request_stop())
+    }
+
     pub fn supported() -> bool {
         unsafe {
             let b: BOOL = msg_send![class!(VZVirtualMachine), isSupported];
@@ -296,8 +446,120 @@ impl VZVirtualMachine {
         }
     }
 
-    pub unsafe fn state(&self) -> VZVirtualMachineState {
-        let n: isize = msg_send![*self.0, state];
+    pub fn can_start(&self) -> bool {
+        self.on_queue(|vm| vm.can_start())
+    }
+
+    /// whether `stop_with_completion_handler` can be called, always `false` before macOS 12
+    pub fn can_stop(&self) -> bool {
+        self.on_queue(|vm| vm.can_stop())
+    }
+
+    pub fn can_request_stop(&self) -> bool {
+        self.on_queue(|vm| vm.can_request_stop())
+    }
+
+    pub fn can_pause(&self) -> bool {
+        self.on_queue(|vm| vm.can_pause())
+    }
+
+    pub fn can_resume(&self) -> bool {
+        self.on_queue(|vm| vm.can_resume())
+    }
+
+    pub fn state(&self) -> VZVirtualMachineState {
+        self.on_queue(|vm| vm.state())
+    }
+}
+
+impl VirtualMachineOnQueue<'_> {
+    pub fn virtual_machine(&self) -> &VZVirtualMachine {
+        self.vm
+    }
+
+    pub fn state(&self) -> VZVirtualMachineState {
+        let n: isize = unsafe { msg_send![*self.vm.0, state] };
+        VZVirtualMachineState::from_raw(n)
+    }
+
+    pub fn start_with_completion_handler(&self, completion_handler: &Block<(Id,), ()>) {
+        unsafe {
+            let _: Id = msg_send![*self.vm.0, startWithCompletionHandler: completion_handler];
+        }
+    }
+
+    pub fn pause_with_completion_handler(&self, completion_handler: &Block<(Id,), ()>) {
+        unsafe {
+            let _: Id = msg_send![*self.vm.0, pauseWithCompletionHandler: completion_handler];
+        }
+    }
+
+    pub fn resume_with_completion_handler(&self, completion_handler: &Block<(Id,), ()>) {
+        unsafe {
+            let _: Id = msg_send![*self.vm.0, resumeWithCompletionHandler: completion_handler];
+        }
+    }
+
+    /// stop the virtual machine without giving the guest a chance to shut down
+    ///
+    /// Fails without calling `completion_handler` before macOS 12.
+    pub fn stop_with_completion_handler(
+        &self,
+        completion_handler: &Block<(Id,), ()>,
+    ) -> Result<()> {
+        check_stop_supported()?;
+        unsafe {
+            let _: Id = msg_send![*self.vm.0, stopWithCompletionHandler: completion_handler];
+        }
+        Ok(())
+    }
+
+    /// ask the guest to stop, returns whether the request was delivered
+    pub fn request_stop(&self) -> Result<bool> {
+        unsafe {
+            let mut error: Id = NIL;
+            let ret: BOOL = msg_send![*self.vm.0, requestStopWithError: &mut error];
+            if error != NIL {
+                Err(NSError(StrongPtr::retain(error)).into())
+            } else {
+                Ok(ret == YES)
+            }
+        }
+    }
+
+    pub fn can_start(&self) -> bool {
+        let b: BOOL = unsafe { msg_send![*self.vm.0, canStart] };
+        b == YES
+    }
+
+    /// whether `stop_with_completion_handler` can be called, always `false` before macOS 12
+    pub fn can_stop(&self) -> bool {
+        if check_stop_supported().is_err() {
+            return false;
+        }
+        let b: BOOL = unsafe { msg_send![*self.vm.0, canStop] };
+        b == YES
+    }
+
+    pub fn can_request_stop(&self) -> bool {
+        let b: BOOL = unsafe { msg_send![*self.vm.0, canRequestStop] };
+        b == YES
+    }
+
+    pub fn can_pause(&self) -> bool {
+        let b: BOOL = unsafe { msg_send![*self.vm.0, canPause] };
+        b == YES
+    }
+
+    pub fn can_resume(&self) -> bool {
+        let b: BOOL = unsafe { msg_send![*self.vm.0, canResume] };
+        b == YES
+    }
+}
+
+impl VZVirtualMachineState {
+    /// state of the `VZVirtualMachineState` value `n`
+    pub fn from_raw(n: isize) -> VZVirtualMachineState {
         match n {
             0 => VZVirtualMachineState::VZVirtualMachineStateStopped,
             1 => VZVirtualMachineState::VZVirtualMachineStateRunning,
@@ -306,7 +568,28 @@ impl VZVirtualMachine {
             4 => VZVirtualMachineState::VZVirtualMachineStateStarting,
             5 => VZVirtualMachineState::VZVirtualMachineStatePausing,
             6 => VZVirtualMachineState::VZVirtualMachineStateResuming,
+            7 => VZVirtualMachineState::VZVirtualMachineStateStopping,
             _ => VZVirtualMachineState::Other,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stop_requires_macos_12() {
+        assert!(!supports_stop("11.6.8"));
+        assert!(supports_stop("12"));
+        assert!(supports_stop("12.0.1"));
+        assert!(supports_stop("13.4"));
+    }
+
+    #[test]
+    fn stop_is_not_gated_off_macos() {
+        if sys::macos_version().is_none() {
+            assert!(check_stop_supported().is_ok());
+        }
+    }
+}